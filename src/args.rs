@@ -1,5 +1,9 @@
+use std::net::Ipv6Addr;
 use std::path::PathBuf;
 use clap::{Parser, Subcommand};
+use crate::mse::EncryptionPolicy;
+use crate::storage::{Allocation, Backend};
+use crate::peer_pool::IpPreference;
 
 #[derive(Parser)]
 pub struct Args {
@@ -20,4 +24,66 @@ pub enum Command {
         torrent: PathBuf,
         piece: usize,
     },
+    Download {
+        // the file of a single-file torrent, the root directory of a multi-file one, `-` for stdout
+        #[arg(short)]
+        output: PathBuf,
+        torrent: PathBuf,
+        #[arg(long, default_value_t = 6881)]
+        port: u16,
+        #[arg(long, default_value_t = 30)]
+        max_peers: usize,
+        // address family to use for peers reachable over both IPv4 and IPv6
+        #[arg(long, value_enum, default_value_t = IpPreference::Ipv6)]
+        ip_preference: IpPreference,
+        // IPv6 address announced to trackers and peers, detected automatically when omitted
+        #[arg(long)]
+        ipv6: Option<Ipv6Addr>,
+        #[arg(long)]
+        no_dht: bool,
+        #[arg(long)]
+        no_pex: bool,
+        // skip multicast announces to peers on the local network
+        #[arg(long)]
+        no_lsd: bool,
+        // Message Stream Encryption for peer connections
+        #[arg(long, value_enum, default_value_t = EncryptionPolicy::Prefer)]
+        encryption: EncryptionPolicy,
+        // connect to peers over TCP only
+        #[arg(long)]
+        no_utp: bool,
+        // how disk space for the downloaded files is allocated
+        #[arg(long, value_enum, default_value_t = Allocation::Sparse)]
+        allocation: Allocation,
+        // files to download by index or by glob on their path in the torrent, the others are skipped
+        #[arg(long)]
+        only: Vec<String>,
+        // file priority as index_or_glob=skip|low|normal|high, applied in order after --only
+        #[arg(long)]
+        priority: Vec<String>,
+        // download pieces in order, for previewing files while they download
+        #[arg(long)]
+        sequential: bool,
+        // serve the files over HTTP on this local port while downloading, with Range support
+        #[arg(long)]
+        stream_port: Option<u16>,
+        // how downloaded files are read and written
+        #[arg(long, value_enum, default_value_t = Backend::File)]
+        storage: Backend,
+        // worker threads doing disk reads and writes
+        #[arg(long, default_value_t = 4)]
+        disk_threads: usize,
+        // MiB of downloaded blocks held in memory before they are written
+        #[arg(long, default_value_t = 32)]
+        write_cache_mb: usize,
+        // MiB of pieces kept in memory for serving peers
+        #[arg(long, default_value_t = 16)]
+        read_cache_mb: usize,
+        // host:port of nodes used to join the DHT, well known routers when omitted
+        #[arg(long)]
+        dht_bootstrap: Vec<String>,
+        // file the DHT routing table is saved to between runs, under ~/.cache when omitted
+        #[arg(long)]
+        dht_cache: Option<PathBuf>,
+    },
 }
\ No newline at end of file
@@ -0,0 +1,97 @@
+/*
+    Bitfield as sent in the BITFIELD message: the high bit of the first byte corresponds to piece 0.
+    Spare bits at the end are always kept cleared.
+*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bitfield {
+    bytes: Vec<u8>,
+    len: usize,
+}
+
+impl Bitfield {
+    pub fn new(len: usize) -> Self {
+        Bitfield { bytes: vec![0; len.div_ceil(8)], len }
+    }
+
+    // every piece set, what HAVE ALL stands for
+    pub fn full(len: usize) -> Self {
+        let mut bitfield = Bitfield { bytes: vec![0xff; len.div_ceil(8)], len };
+        bitfield.clear_spare_bits();
+        bitfield
+    }
+
+    // None unless the bytes are exactly what a peer may send for `len` pieces, spare bits included
+    pub fn from_bytes(bytes: &[u8], len: usize) -> Option<Self> {
+        if bytes.len() != len.div_ceil(8) {
+            return None;
+        }
+        let mut bitfield = Bitfield { bytes: bytes.to_vec(), len };
+        bitfield.clear_spare_bits();
+        (bitfield.bytes == bytes).then_some(bitfield)
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn get(&self, index: usize) -> bool {
+        index < self.len && self.bytes[index / 8] & (0x80 >> (index % 8)) != 0
+    }
+
+    pub fn set(&mut self, index: usize) {
+        if index < self.len {
+            self.bytes[index / 8] |= 0x80 >> (index % 8);
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.bytes.iter().map(|byte| byte.count_ones() as usize).sum()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.count() == self.len
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn iter_set(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.len).filter(|&index| self.get(index))
+    }
+
+    fn clear_spare_bits(&mut self) {
+        let spare = self.bytes.len() * 8 - self.len;
+        if spare > 0 {
+            if let Some(last) = self.bytes.last_mut() {
+                *last &= 0xff << spare;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bitfield_from_the_wire() {
+        let bitfield = Bitfield::from_bytes(&[0b1010_0000, 0b1000_0000], 9).unwrap();
+        assert_eq!(bitfield.iter_set().collect::<Vec<_>>(), vec![0, 2, 8]);
+        assert!(Bitfield::from_bytes(&[0xff], 8).unwrap().is_complete());
+        assert!(Bitfield::from_bytes(&[], 0).is_some());
+    }
+
+    #[test]
+    fn bitfield_of_the_wrong_size_is_refused() {
+        assert!(Bitfield::from_bytes(&[0xff], 9).is_none());
+        assert!(Bitfield::from_bytes(&[0xff, 0x80, 0], 9).is_none());
+        assert!(Bitfield::from_bytes(&[], 1).is_none());
+    }
+
+    #[test]
+    fn bitfield_with_spare_bits_set_is_refused() {
+        assert!(Bitfield::from_bytes(&[0xff, 0xc0], 9).is_none());
+        assert!(Bitfield::from_bytes(&[0x01], 7).is_none());
+    }
+}
@@ -0,0 +1,729 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{Error, ErrorKind};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::Path;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use tokio::net::UdpSocket;
+use tokio::sync::oneshot;
+use tokio::task::JoinSet;
+
+use crate::krpc::{decode_nodes, decode_peer, encode_nodes, encode_peer, node_id, KrpcMessage, NodeId, NodeInfo,
+                  QueryArguments, ResponseValues, ANNOUNCE_PEER, FIND_NODE, GENERIC_ERROR, GET_PEERS, METHOD_UNKNOWN,
+                  PING, PROTOCOL_ERROR};
+use crate::peers::Peer;
+use crate::random;
+
+// bucket size and lookup parallelism from the Kademlia paper
+const K: usize = 8;
+const ALPHA: usize = 3;
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+// node that did not answer this many queries in a row is removed from the routing table
+const MAX_NODE_FAILURES: u32 = 3;
+const TOKEN_ROTATION: Duration = Duration::from_secs(5 * 60);
+const PEER_EXPIRY: Duration = Duration::from_secs(30 * 60);
+const MAX_PEERS_PER_TORRENT: usize = 100;
+const MAX_STORED_TORRENTS: usize = 2000;
+pub const DEFAULT_BOOTSTRAP_NODES: [&str; 3] = [
+    "router.bittorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+    "router.utorrent.com:6881",
+];
+
+struct RoutingNode {
+    info: NodeInfo,
+    last_seen: Instant,
+    failures: u32,
+}
+
+/*
+    Kademlia routing table. Bucket i holds nodes whose distance to our id has exactly i leading zero bits,
+    so the buckets close to our id are the ones that are populated in detail.
+*/
+struct RoutingTable {
+    own_id: NodeId,
+    buckets: Vec<Vec<RoutingNode>>,
+}
+
+impl RoutingTable {
+    fn new(own_id: NodeId) -> Self {
+        RoutingTable { own_id, buckets: (0..160).map(|_| vec![]).collect() }
+    }
+
+    fn bucket_index(&self, id: &NodeId) -> Option<usize> {
+        let distance = distance(&self.own_id, id);
+        let leading_zeros = leading_zeros(&distance);
+        (leading_zeros < 160).then_some(leading_zeros)
+    }
+
+    // node responded to us or queried us
+    fn insert(&mut self, info: NodeInfo) {
+        let Some(index) = self.bucket_index(&info.id) else {
+            return;
+        };
+        let bucket = &mut self.buckets[index];
+        if let Some(node) = bucket.iter_mut().find(|node| node.info.id == info.id) {
+            node.info.address = info.address;
+            node.last_seen = Instant::now();
+            node.failures = 0;
+            return;
+        }
+        if bucket.len() >= K {
+            // replace a node that stopped answering, otherwise keep the long lived ones
+            match bucket.iter().position(|node| node.failures > 0) {
+                Some(position) => {
+                    bucket.remove(position);
+                }
+                None => return,
+            }
+        }
+        bucket.push(RoutingNode { info, last_seen: Instant::now(), failures: 0 });
+    }
+
+    fn register_failure(&mut self, address: &SocketAddr) {
+        for bucket in self.buckets.iter_mut() {
+            if let Some(position) = bucket.iter().position(|node| node.info.address == *address) {
+                bucket[position].failures += 1;
+                if bucket[position].failures >= MAX_NODE_FAILURES {
+                    bucket.remove(position);
+                }
+                return;
+            }
+        }
+    }
+
+    fn closest(&self, target: &NodeId, count: usize) -> Vec<NodeInfo> {
+        let mut nodes: Vec<NodeInfo> = self.buckets.iter().flatten().map(|node| node.info).collect();
+        nodes.sort_by_key(|node| distance(&node.id, target));
+        nodes.truncate(count);
+        nodes
+    }
+
+    fn len(&self) -> usize {
+        self.buckets.iter().map(Vec::len).sum()
+    }
+
+    // most recently seen first, the order they are persisted in
+    fn nodes(&self) -> Vec<NodeInfo> {
+        let mut nodes: Vec<&RoutingNode> = self.buckets.iter().flatten().collect();
+        nodes.sort_by_key(|node| std::cmp::Reverse(node.last_seen));
+        nodes.into_iter().map(|node| node.info).collect()
+    }
+}
+
+pub fn distance(a: &NodeId, b: &NodeId) -> NodeId {
+    let mut result = [0u8; 20];
+    for i in 0..20 {
+        result[i] = a[i] ^ b[i];
+    }
+    result
+}
+
+fn leading_zeros(id: &NodeId) -> usize {
+    let mut zeros = 0;
+    for byte in id {
+        if *byte == 0 {
+            zeros += 8;
+        } else {
+            return zeros + byte.leading_zeros() as usize;
+        }
+    }
+    zeros
+}
+
+struct TokenSecrets {
+    current: [u8; 20],
+    previous: [u8; 20],
+    rotated_at: Instant,
+}
+
+impl TokenSecrets {
+    fn rotate_if_needed(&mut self) {
+        if self.rotated_at.elapsed() >= TOKEN_ROTATION {
+            self.previous = self.current;
+            self.current = random::bytes();
+            self.rotated_at = Instant::now();
+        }
+    }
+
+    fn token(secret: &[u8; 20], address: &SocketAddr) -> Vec<u8> {
+        let mut hasher = Sha1::new();
+        hasher.update(secret);
+        hasher.update(address.ip().to_string().as_bytes());
+        hasher.finalize()[..8].to_vec()
+    }
+
+    fn issue(&mut self, address: &SocketAddr) -> Vec<u8> {
+        self.rotate_if_needed();
+        TokenSecrets::token(&self.current, address)
+    }
+
+    // tokens stay valid for up to two rotations
+    fn validate(&mut self, token: &[u8], address: &SocketAddr) -> bool {
+        self.rotate_if_needed();
+        token == TokenSecrets::token(&self.current, address) || token == TokenSecrets::token(&self.previous, address)
+    }
+}
+
+struct DhtState {
+    table: RoutingTable,
+    secrets: TokenSecrets,
+    // peers announced to us, per info hash
+    peers: HashMap<[u8; 20], HashMap<SocketAddr, Instant>>,
+}
+
+impl DhtState {
+    /*
+        Records a peer announced to us, making room by dropping expired announcements first. Both the peers per
+        info hash and the info hashes themselves are capped so that announcements cannot grow the store unbounded.
+    */
+    fn store_peer(&mut self, info_hash: [u8; 20], peer: SocketAddr) -> Result<(), &'static str> {
+        if !self.peers.contains_key(&info_hash) && self.peers.len() >= MAX_STORED_TORRENTS {
+            self.sweep_peers();
+            if self.peers.len() >= MAX_STORED_TORRENTS {
+                return Err("too many torrents");
+            }
+        }
+        let peers = self.peers.entry(info_hash).or_default();
+        peers.retain(|_, announced| announced.elapsed() < PEER_EXPIRY);
+        if peers.len() >= MAX_PEERS_PER_TORRENT && !peers.contains_key(&peer) {
+            return Err("too many peers for torrent");
+        }
+        peers.insert(peer, Instant::now());
+        Ok(())
+    }
+
+    // drops expired announcements and the info hashes left without peers
+    fn sweep_peers(&mut self) {
+        self.peers.retain(|_, peers| {
+            peers.retain(|_, announced| announced.elapsed() < PEER_EXPIRY);
+            !peers.is_empty()
+        });
+    }
+}
+
+/*
+    Node cache persisted between runs so that we do not depend on the bootstrap routers every time.
+*/
+#[derive(Serialize, Deserialize)]
+struct NodeCache {
+    #[serde(with = "serde_bytes")]
+    id: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    nodes: Vec<u8>,
+}
+
+// node the query was sent to and where to deliver its reply, by transaction id
+type PendingQuery = (SocketAddr, oneshot::Sender<KrpcMessage>);
+
+pub struct LookupResult {
+    pub peers: Vec<Peer>,
+    // nodes closest to the target that gave us a token to announce with
+    pub tokens: Vec<(NodeInfo, Vec<u8>)>,
+}
+
+/*
+    Mainline DHT node (BEP 5) over IPv4 UDP. Answers ping, find_node, get_peers and announce_peer queries
+    from other nodes and performs iterative lookups for peers of our torrents.
+*/
+pub struct Dht {
+    id: NodeId,
+    socket: Arc<UdpSocket>,
+    state: Mutex<DhtState>,
+    pending: Mutex<HashMap<Vec<u8>, PendingQuery>>,
+    next_transaction: AtomicU16,
+}
+
+impl Dht {
+    /*
+        Runs on a socket the caller reads from, since the listen port's UDP socket is shared with uTP:
+        the caller passes every DHT packet to `handle_packet`.
+    */
+    pub fn new(socket: Arc<UdpSocket>, cache: Option<&Path>) -> Arc<Dht> {
+        let cached = cache.and_then(|path| std::fs::read(path).ok())
+            .and_then(|bytes| serde_bencode::from_bytes::<NodeCache>(&bytes).ok());
+        let id = cached.as_ref().and_then(|cache| node_id(&cache.id)).unwrap_or_else(random::bytes);
+        let mut table = RoutingTable::new(id);
+        if let Some(cache) = cached {
+            decode_nodes(&cache.nodes).into_iter().for_each(|node| table.insert(node));
+        }
+        Arc::new(Dht {
+            id,
+            socket,
+            state: Mutex::new(DhtState {
+                table,
+                secrets: TokenSecrets { current: random::bytes(), previous: random::bytes(), rotated_at: Instant::now() },
+                peers: HashMap::new(),
+            }),
+            pending: Mutex::new(HashMap::new()),
+            next_transaction: AtomicU16::new(0),
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        self.socket.local_addr()
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.lock().table.len()
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), Error> {
+        let cache = NodeCache { id: self.id.to_vec(), nodes: encode_nodes(&self.lock().table.nodes()) };
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(path, serde_bencode::to_bytes(&cache).map_err(|err| Error::new(ErrorKind::InvalidData, err))?)
+    }
+
+    /*
+        Pings the given nodes (host:port, resolved through DNS) and looks up our own id,
+        which fills the buckets close to us.
+    */
+    // periodic maintenance: forgets peers whose announcements have expired
+    pub fn expire_peers(&self) {
+        self.lock().sweep_peers();
+    }
+
+    pub async fn bootstrap(self: &Arc<Self>, nodes: &[String]) {
+        let mut addresses = vec![];
+        for node in nodes {
+            match tokio::net::lookup_host(node.as_str()).await {
+                Ok(resolved) => addresses.extend(resolved.filter(SocketAddr::is_ipv4)),
+                Err(err) => eprintln!("cannot resolve DHT bootstrap node {}: {}", node, err),
+            }
+        }
+        let mut pings = JoinSet::new();
+        for address in addresses {
+            let dht = self.clone();
+            pings.spawn(async move { dht.ping(address).await });
+        }
+        while pings.join_next().await.is_some() {}
+        self.lookup(self.id, false).await;
+    }
+
+    // peer told us its DHT port with a PORT message
+    pub async fn add_node(&self, address: SocketAddr) {
+        let _ = self.ping(address).await;
+    }
+
+    pub async fn ping(&self, address: SocketAddr) -> Result<NodeId, Error> {
+        let response = self.query(address, PING, self.arguments()).await?;
+        node_id(&response.id).ok_or(Error::new(ErrorKind::InvalidData, "response without node id"))
+    }
+
+    pub async fn get_peers(self: &Arc<Self>, info_hash: [u8; 20]) -> LookupResult {
+        self.lookup(info_hash, true).await
+    }
+
+    /*
+        Looks up peers of the torrent and tells the closest nodes we are downloading it too.
+    */
+    pub async fn announce(self: &Arc<Self>, info_hash: [u8; 20], port: u16) -> Vec<Peer> {
+        let result = self.get_peers(info_hash).await;
+        let mut announces = JoinSet::new();
+        for (node, token) in result.tokens {
+            let dht = self.clone();
+            let mut arguments = self.arguments();
+            arguments.info_hash = Some(info_hash.to_vec());
+            arguments.port = Some(port);
+            arguments.token = Some(token);
+            arguments.implied_port = Some(0);
+            announces.spawn(async move { dht.query(node.address, ANNOUNCE_PEER, arguments).await });
+        }
+        while announces.join_next().await.is_some() {}
+        result.peers
+    }
+
+    /*
+        Iterative lookup: repeatedly queries the ALPHA closest nodes not queried yet until
+        the K closest nodes we know of have all answered or failed.
+    */
+    async fn lookup(self: &Arc<Self>, target: NodeId, get_peers: bool) -> LookupResult {
+        let mut shortlist: BTreeMap<NodeId, NodeInfo> = self.lock().table.closest(&target, K).into_iter()
+            .map(|node| (distance(&node.id, &target), node))
+            .collect();
+        let mut queried: HashSet<SocketAddr> = HashSet::new();
+        let mut responded: BTreeMap<NodeId, (NodeInfo, Option<Vec<u8>>)> = BTreeMap::new();
+        let mut peers: HashSet<SocketAddr> = HashSet::new();
+        loop {
+            let batch: Vec<NodeInfo> = shortlist.values()
+                .take(K)
+                .filter(|node| !queried.contains(&node.address))
+                .take(ALPHA)
+                .copied()
+                .collect();
+            if batch.is_empty() {
+                break;
+            }
+            let mut queries = JoinSet::new();
+            for node in batch {
+                queried.insert(node.address);
+                let dht = self.clone();
+                let mut arguments = self.arguments();
+                let method = if get_peers {
+                    arguments.info_hash = Some(target.to_vec());
+                    GET_PEERS
+                } else {
+                    arguments.target = Some(target.to_vec());
+                    FIND_NODE
+                };
+                queries.spawn(async move { (node, dht.query(node.address, method, arguments).await) });
+            }
+            while let Some(joined) = queries.join_next().await {
+                let Ok((node, result)) = joined else {
+                    continue;
+                };
+                let Ok(response) = result else {
+                    shortlist.remove(&distance(&node.id, &target));
+                    continue;
+                };
+                for found in decode_nodes(response.nodes.as_deref().unwrap_or_default()) {
+                    if found.id != self.id {
+                        shortlist.entry(distance(&found.id, &target)).or_insert(found);
+                    }
+                }
+                for value in response.values.iter().flatten() {
+                    if let Some(peer) = decode_peer(value) {
+                        peers.insert(peer);
+                    }
+                }
+                responded.insert(distance(&node.id, &target), (node, response.token));
+            }
+        }
+        LookupResult {
+            peers: peers.into_iter().map(|address| Peer::new(address.ip(), address.port())).collect(),
+            tokens: responded.into_values()
+                .take(K)
+                .filter_map(|(node, token)| token.map(|token| (node, token)))
+                .collect(),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, DhtState> {
+        self.state.lock().expect("dht lock poisoned")
+    }
+
+    fn arguments(&self) -> QueryArguments {
+        QueryArguments { id: self.id.to_vec(), ..Default::default() }
+    }
+
+    async fn query(&self, address: SocketAddr, method: &str, arguments: QueryArguments) -> Result<ResponseValues, Error> {
+        let transaction = self.next_transaction.fetch_add(1, Ordering::Relaxed).to_be_bytes().to_vec();
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().expect("dht lock poisoned").insert(transaction.clone(), (address, tx));
+        let message = KrpcMessage::query(&transaction, method, arguments);
+        if let Err(err) = self.socket.send_to(&message.to_bytes(), address).await {
+            self.pending.lock().expect("dht lock poisoned").remove(&transaction);
+            return Err(err);
+        }
+        let reply = tokio::time::timeout(QUERY_TIMEOUT, rx).await;
+        self.pending.lock().expect("dht lock poisoned").remove(&transaction);
+        let reply = match reply {
+            Ok(Ok(reply)) => reply,
+            _ => {
+                self.lock().table.register_failure(&address);
+                return Err(Error::new(ErrorKind::TimedOut, format!("DHT node {} did not answer {}", address, method)));
+            }
+        };
+        if let Some(error) = reply.e {
+            return Err(Error::other(format!("DHT node {} returned error {}: {}", address, error.code, error.message)));
+        }
+        let values = reply.r.ok_or(Error::new(ErrorKind::InvalidData, "DHT response without values"))?;
+        let id = node_id(&values.id).ok_or(Error::new(ErrorKind::InvalidData, "DHT response without node id"))?;
+        self.lock().table.insert(NodeInfo { id, address });
+        Ok(values)
+    }
+
+    pub async fn handle_packet(&self, packet: &[u8], from: SocketAddr) {
+        let Ok(message) = KrpcMessage::from_bytes(packet) else {
+            return;
+        };
+        match message.y.as_str() {
+            "r" | "e" => {
+                let waiting = self.pending.lock().expect("dht lock poisoned").remove(&message.t);
+                if let Some((address, tx)) = waiting {
+                    if address == from {
+                        let _ = tx.send(message);
+                    }
+                }
+            }
+            "q" => {
+                let reply = self.answer(&message, from);
+                let _ = self.socket.send_to(&reply.to_bytes(), from).await;
+            }
+            _ => {}
+        }
+    }
+
+    fn answer(&self, message: &KrpcMessage, from: SocketAddr) -> KrpcMessage {
+        let (Some(method), Some(arguments)) = (&message.q, &message.a) else {
+            return KrpcMessage::error(&message.t, PROTOCOL_ERROR, "query without method or arguments");
+        };
+        let Some(sender) = node_id(&arguments.id) else {
+            return KrpcMessage::error(&message.t, PROTOCOL_ERROR, "invalid node id");
+        };
+        let mut state = self.lock();
+        state.table.insert(NodeInfo { id: sender, address: from });
+        let mut values = ResponseValues { id: self.id.to_vec(), ..Default::default() };
+        match method.as_str() {
+            PING => {}
+            FIND_NODE => {
+                let Some(target) = arguments.target.as_deref().and_then(node_id) else {
+                    return KrpcMessage::error(&message.t, PROTOCOL_ERROR, "missing target");
+                };
+                values.nodes = Some(encode_nodes(&state.table.closest(&target, K)));
+            }
+            GET_PEERS => {
+                let Some(info_hash) = arguments.info_hash.as_deref().and_then(node_id) else {
+                    return KrpcMessage::error(&message.t, PROTOCOL_ERROR, "missing info_hash");
+                };
+                values.token = Some(state.secrets.issue(&from));
+                let peers: Vec<_> = state.peers.get_mut(&info_hash)
+                    .map(|peers| {
+                        peers.retain(|_, announced| announced.elapsed() < PEER_EXPIRY);
+                        peers.keys().filter_map(encode_peer).collect()
+                    })
+                    .unwrap_or_default();
+                if peers.is_empty() {
+                    values.nodes = Some(encode_nodes(&state.table.closest(&info_hash, K)));
+                } else {
+                    values.values = Some(peers);
+                }
+            }
+            ANNOUNCE_PEER => {
+                let (Some(info_hash), Some(token)) = (arguments.info_hash.as_deref().and_then(node_id), &arguments.token) else {
+                    return KrpcMessage::error(&message.t, PROTOCOL_ERROR, "missing info_hash or token");
+                };
+                if !state.secrets.validate(token, &from) {
+                    return KrpcMessage::error(&message.t, PROTOCOL_ERROR, "bad token");
+                }
+                let port = match (arguments.implied_port, arguments.port) {
+                    (Some(1), _) => from.port(),
+                    (_, Some(port)) => port,
+                    _ => return KrpcMessage::error(&message.t, PROTOCOL_ERROR, "missing port"),
+                };
+                if let Err(reason) = state.store_peer(info_hash, SocketAddr::new(from.ip(), port)) {
+                    return KrpcMessage::error(&message.t, GENERIC_ERROR, reason);
+                }
+            }
+            _ => return KrpcMessage::error(&message.t, METHOD_UNKNOWN, "method unknown"),
+        }
+        KrpcMessage::response(&message.t, values)
+    }
+}
+
+pub fn default_address(port: u16) -> SocketAddr {
+    SocketAddr::from((Ipv4Addr::UNSPECIFIED, port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // id differing from `base` first in bit `bit`, counted from the most significant one
+    fn id_at_distance(base: &NodeId, bit: usize, salt: u8) -> NodeId {
+        let mut id = *base;
+        id[bit / 8] ^= 0x80 >> (bit % 8);
+        id[19] ^= salt;
+        id
+    }
+
+    fn node(id: NodeId, port: u16) -> NodeInfo {
+        NodeInfo { id, address: SocketAddr::from(([10, 0, 0, 1], port)) }
+    }
+
+    #[test]
+    fn nodes_land_in_the_bucket_of_their_distance() {
+        let own = [0x55; 20];
+        let mut table = RoutingTable::new(own);
+        table.insert(node(id_at_distance(&own, 0, 0), 1));
+        table.insert(node(id_at_distance(&own, 9, 0), 2));
+        table.insert(node(id_at_distance(&own, 159, 0), 3));
+        // our own id has no bucket
+        table.insert(node(own, 4));
+        assert_eq!(table.len(), 3);
+        assert_eq!(table.buckets[0].len(), 1);
+        assert_eq!(table.buckets[9].len(), 1);
+        assert_eq!(table.buckets[159].len(), 1);
+    }
+
+    #[test]
+    fn full_bucket_keeps_live_nodes_and_evicts_failing_ones() {
+        let own = [0; 20];
+        let mut table = RoutingTable::new(own);
+        for salt in 0..K as u8 {
+            table.insert(node(id_at_distance(&own, 3, salt), 100 + salt as u16));
+        }
+        let newcomer = node(id_at_distance(&own, 3, 0x40), 200);
+        table.insert(newcomer);
+        assert_eq!(table.buckets[3].len(), K);
+        assert!(!table.buckets[3].iter().any(|entry| entry.info == newcomer));
+
+        // one failure makes a node replaceable, further failures remove it outright
+        table.register_failure(&SocketAddr::from(([10, 0, 0, 1], 101)));
+        table.insert(newcomer);
+        assert_eq!(table.buckets[3].len(), K);
+        assert!(table.buckets[3].iter().any(|entry| entry.info == newcomer));
+        assert!(!table.buckets[3].iter().any(|entry| entry.info.address.port() == 101));
+        for _ in 0..MAX_NODE_FAILURES {
+            table.register_failure(&SocketAddr::from(([10, 0, 0, 1], 102)));
+        }
+        assert_eq!(table.buckets[3].len(), K - 1);
+    }
+
+    #[test]
+    fn reinserting_a_node_updates_its_address() {
+        let mut table = RoutingTable::new([0; 20]);
+        let id = [1; 20];
+        table.insert(node(id, 1));
+        table.insert(node(id, 2));
+        assert_eq!(table.len(), 1);
+        assert_eq!(table.nodes()[0].address.port(), 2);
+    }
+
+    #[test]
+    fn closest_orders_by_xor_distance() {
+        let mut table = RoutingTable::new([0; 20]);
+        for byte in [0x80, 0x01, 0x10, 0x03] {
+            let mut id = [0; 20];
+            id[0] = byte;
+            table.insert(node(id, byte as u16));
+        }
+        let ports: Vec<u16> = table.closest(&[0; 20], 3).iter().map(|node| node.address.port()).collect();
+        assert_eq!(ports, vec![0x01, 0x03, 0x10]);
+    }
+
+    #[test]
+    fn tokens_are_bound_to_the_address_and_survive_one_rotation() {
+        let mut secrets = TokenSecrets { current: [1; 20], previous: [2; 20], rotated_at: Instant::now() };
+        let address = SocketAddr::from(([10, 0, 0, 1], 6881));
+        let token = secrets.issue(&address);
+        assert!(secrets.validate(&token, &address));
+        assert!(!secrets.validate(&token, &SocketAddr::from(([10, 0, 0, 2], 6881))));
+        secrets.previous = secrets.current;
+        secrets.current = [3; 20];
+        assert!(secrets.validate(&token, &address));
+        secrets.previous = secrets.current;
+        assert!(!secrets.validate(&token, &address));
+    }
+
+    fn state() -> DhtState {
+        DhtState {
+            table: RoutingTable::new([0; 20]),
+            secrets: TokenSecrets { current: [1; 20], previous: [2; 20], rotated_at: Instant::now() },
+            peers: HashMap::new(),
+        }
+    }
+
+    fn peer(index: usize) -> SocketAddr {
+        SocketAddr::from(([10, 0, (index >> 8) as u8, index as u8], 6881))
+    }
+
+    fn expired() -> Instant {
+        Instant::now().checked_sub(PEER_EXPIRY + Duration::from_secs(1)).unwrap()
+    }
+
+    #[test]
+    fn expired_peers_make_room_in_a_full_torrent() {
+        let mut state = state();
+        for index in 0..MAX_PEERS_PER_TORRENT {
+            state.store_peer([1; 20], peer(index)).unwrap();
+        }
+        assert_eq!(state.store_peer([1; 20], peer(1000)), Err("too many peers for torrent"));
+        // re-announcing a known peer only refreshes it
+        assert_eq!(state.store_peer([1; 20], peer(0)), Ok(()));
+
+        *state.peers.get_mut(&[1; 20]).unwrap().get_mut(&peer(0)).unwrap() = expired();
+        assert_eq!(state.store_peer([1; 20], peer(1000)), Ok(()));
+        let peers = &state.peers[&[1; 20]];
+        assert_eq!(peers.len(), MAX_PEERS_PER_TORRENT);
+        assert!(!peers.contains_key(&peer(0)));
+    }
+
+    #[test]
+    fn stored_info_hashes_are_capped() {
+        let mut state = state();
+        for index in 0..MAX_STORED_TORRENTS {
+            let mut info_hash = [0; 20];
+            info_hash[..8].copy_from_slice(&(index as u64).to_be_bytes());
+            state.store_peer(info_hash, peer(0)).unwrap();
+        }
+        assert_eq!(state.store_peer([0xff; 20], peer(0)), Err("too many torrents"));
+        // known info hashes still take peers
+        assert_eq!(state.store_peer([0; 20], peer(1)), Ok(()));
+
+        // a torrent whose peers all expired is swept to make room
+        state.peers.get_mut(&[0; 20]).unwrap().values_mut().for_each(|announced| *announced = expired());
+        assert_eq!(state.store_peer([0xff; 20], peer(0)), Ok(()));
+        assert!(!state.peers.contains_key(&[0; 20]));
+        assert_eq!(state.peers.len(), MAX_STORED_TORRENTS);
+    }
+
+    #[test]
+    fn sweep_drops_expired_peers_and_empty_torrents() {
+        let mut state = state();
+        state.store_peer([1; 20], peer(0)).unwrap();
+        state.store_peer([1; 20], peer(1)).unwrap();
+        state.store_peer([2; 20], peer(0)).unwrap();
+        *state.peers.get_mut(&[1; 20]).unwrap().get_mut(&peer(0)).unwrap() = expired();
+        *state.peers.get_mut(&[2; 20]).unwrap().get_mut(&peer(0)).unwrap() = expired();
+        state.sweep_peers();
+        assert_eq!(state.peers.len(), 1);
+        assert_eq!(state.peers[&[1; 20]].keys().collect::<Vec<_>>(), vec![&peer(1)]);
+    }
+
+    async fn spawn_node() -> Arc<Dht> {
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let dht = Dht::new(socket.clone(), None);
+        let receiver = dht.clone();
+        tokio::spawn(async move {
+            let mut buf = vec![0; 2048];
+            while let Ok((length, from)) = socket.recv_from(&mut buf).await {
+                receiver.handle_packet(&buf[..length], from).await;
+            }
+        });
+        dht
+    }
+
+    #[tokio::test]
+    async fn loopback_nodes_find_an_announced_peer() {
+        let router = spawn_node().await;
+        let router_address = router.local_addr().unwrap().to_string();
+        let mut nodes = vec![];
+        for _ in 0..6 {
+            let node = spawn_node().await;
+            node.bootstrap(std::slice::from_ref(&router_address)).await;
+            nodes.push(node);
+        }
+        assert!(nodes.iter().all(|node| node.node_count() > 0));
+        assert!(router.node_count() >= 6);
+
+        let info_hash = [0xab; 20];
+        let seeder = &nodes[0];
+        assert!(seeder.announce(info_hash, 51413).await.is_empty());
+        let found = nodes[5].get_peers(info_hash).await;
+        let addresses: Vec<SocketAddr> = found.peers.iter().map(Peer::address).collect();
+        assert_eq!(addresses, vec![SocketAddr::from(([127, 0, 0, 1], 51413))]);
+        assert!(!found.tokens.is_empty());
+    }
+
+    #[tokio::test]
+    async fn answers_unknown_methods_and_bad_tokens_with_errors() {
+        let node = spawn_node().await;
+        let address = node.local_addr().unwrap();
+        let client = spawn_node().await;
+        let mut arguments = client.arguments();
+        arguments.info_hash = Some(vec![1; 20]);
+        arguments.port = Some(1);
+        arguments.token = Some(b"forged".to_vec());
+        let error = client.query(address, ANNOUNCE_PEER, arguments).await.unwrap_err();
+        assert!(error.to_string().contains("bad token"), "{}", error);
+        let error = client.query(address, "vote", client.arguments()).await.unwrap_err();
+        assert!(error.to_string().contains("204"), "{}", error);
+    }
+}
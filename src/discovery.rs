@@ -0,0 +1,102 @@
+/*
+    Peer discovery. Every source runs its own loop and reports the peers it found on the same channel;
+    the session merges them into its peer pool, which deduplicates addresses coming from several sources.
+*/
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::mpsc;
+use tokio::time::timeout;
+
+use crate::dht::Dht;
+use crate::lsd::Lsd;
+use crate::peers::Peer;
+use crate::tracker::tracker::{TrackerManager, TrackerRequest};
+
+const STOP_ANNOUNCE_TIMEOUT: Duration = Duration::from_secs(5);
+const DHT_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(15 * 60);
+// retry sooner while the DHT has not found anybody yet
+const DHT_RETRY_INTERVAL: Duration = Duration::from_secs(30);
+// below this many nodes in the routing table we go back to the bootstrap nodes
+const DHT_MIN_NODES: usize = 8;
+// BEP 14 asks for no more than one announce per torrent a minute, we go well below that
+const LSD_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/*
+    Announces to trackers every interval they ask for. `request` builds the announce with up to date
+    statistics. An event received on `events` ("completed") is announced right away, "stopped" or the
+    sender going away ends the loop with a final announce that says so.
+*/
+pub async fn tracker_loop(
+    mut manager: TrackerManager,
+    info_hash: [u8; 20],
+    request: impl Fn(Option<&str>) -> TrackerRequest,
+    found: mpsc::UnboundedSender<Vec<Peer>>,
+    mut events: mpsc::UnboundedReceiver<&'static str>,
+) {
+    if manager.is_empty() {
+        return;
+    }
+    let info_hash = info_hash.to_vec();
+    let mut event = Some("started");
+    loop {
+        match manager.announce(&request(event), &info_hash).await {
+            Ok(response) => {
+                event = None;
+                let _ = found.send(response.peers());
+            }
+            Err(err) => eprintln!("announce failed: {}", err),
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(manager.next_announce()) => {}
+            next = events.recv() => match next {
+                Some("stopped") | None => break,
+                next => event = next,
+            },
+        }
+    }
+    let _ = timeout(STOP_ANNOUNCE_TIMEOUT, manager.announce(&request(Some("stopped")), &info_hash)).await;
+}
+
+/*
+    Looks the torrent up in the DHT and announces our listen port to the nodes closest to it.
+*/
+pub async fn dht_loop(dht: Arc<Dht>, info_hash: [u8; 20], port: u16, bootstrap: Vec<String>, found: mpsc::UnboundedSender<Vec<Peer>>) {
+    loop {
+        dht.expire_peers();
+        if dht.node_count() < DHT_MIN_NODES {
+            dht.bootstrap(&bootstrap).await;
+        }
+        let peers = dht.announce(info_hash, port).await;
+        let interval = if peers.is_empty() { DHT_RETRY_INTERVAL } else { DHT_ANNOUNCE_INTERVAL };
+        if found.send(peers).is_err() {
+            return;
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+/*
+    Multicasts our listen port to the local network and reports the peers announcing the same torrent there.
+*/
+pub async fn lsd_loop(lsd: Lsd, info_hash: [u8; 20], port: u16, found: mpsc::UnboundedSender<Vec<Peer>>) {
+    let mut announce_timer = tokio::time::interval(LSD_ANNOUNCE_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = announce_timer.tick() => lsd.announce(&[info_hash], port).await,
+            received = lsd.recv() => match received {
+                Ok((announce, source)) if announce.info_hashes.contains(&info_hash) => {
+                    if found.send(vec![Peer::new(source.ip(), announce.port)]).is_err() {
+                        return;
+                    }
+                }
+                Ok(_) => {}
+                Err(err) => {
+                    eprintln!("local service discovery stopped: {}", err);
+                    return;
+                }
+            },
+        }
+    }
+}
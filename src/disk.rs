@@ -0,0 +1,452 @@
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::io::{Error, ErrorKind};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+use bytes::Bytes;
+use tokio::sync::{oneshot, Notify};
+
+use crate::metainfo::Info;
+use crate::storage::Storage;
+
+type Job = Box<dyn FnOnce(&dyn Storage) + Send>;
+
+pub struct DiskConfig {
+    pub threads: usize,
+    // bytes of received blocks held before pieces are flushed early
+    pub write_cache: usize,
+    // bytes of verified pieces kept for serving requests
+    pub read_cache: usize,
+}
+
+/*
+    Who fills a piece: a peer connection or a web seed. In end game several of them download the same
+    piece, each one's blocks are kept apart so one giving up does not take the others' blocks along.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Writer(u64);
+
+// blocks of a piece still being downloaded by one writer, by offset
+#[derive(Default)]
+struct PendingPiece {
+    blocks: BTreeMap<usize, Bytes>,
+    // some blocks were written out early to make room in the cache
+    spilled: bool,
+    // early writes still in progress
+    flushing: usize,
+}
+
+#[derive(Default)]
+struct Cache {
+    pending: HashMap<(usize, Writer), PendingPiece>,
+    // bytes of pending blocks, including those on their way to the disk
+    write_bytes: usize,
+    read: HashMap<usize, Bytes>,
+    // least recently used first
+    read_order: VecDeque<usize>,
+    read_bytes: usize,
+    // pieces verified and written, blocks still arriving for them are leftovers from end game
+    verified: HashSet<usize>,
+}
+
+impl Cache {
+    fn insert(&mut self, piece: usize, writer: Writer, begin: usize, block: Bytes) {
+        self.write_bytes += block.len();
+        // a block requested again after a choke can arrive twice
+        if let Some(previous) = self.pending.entry((piece, writer)).or_default().blocks.insert(begin, block) {
+            self.write_bytes -= previous.len();
+        }
+    }
+
+    /*
+        The writer whose blocks go to the disk early: the one with the most cached blocks among pieces
+        with a single writer. Blocks of a piece several writers fill would land on top of each other.
+    */
+    fn spill_victim(&self) -> Option<(usize, Writer)> {
+        let mut writers: HashMap<usize, usize> = HashMap::new();
+        self.pending.keys().for_each(|(piece, _)| *writers.entry(*piece).or_default() += 1);
+        self.pending.iter()
+            .filter(|((piece, _), pending)| !pending.blocks.is_empty() && writers[piece] == 1)
+            .max_by_key(|(_, pending)| pending.blocks.len())
+            .map(|(key, _)| *key)
+    }
+}
+
+/*
+    Disk I/O of a torrent. Storage calls run on a pool of worker threads fed from a job queue, so peer
+    tasks never block the runtime on the file system. Received blocks stay in a write-back cache until
+    their piece is complete, then the piece is hashed and written with a single call. Pieces read for
+    serving requests are kept in a read cache. When the write cache is full writers wait while the
+    largest incomplete pieces are flushed, which slows down the peers feeding them.
+*/
+pub struct DiskIo {
+    jobs: mpsc::Sender<Job>,
+    piece_length: usize,
+    total_length: usize,
+    write_cache: usize,
+    read_cache: usize,
+    cache: Mutex<Cache>,
+    // woken whenever cached blocks leave memory
+    released: Notify,
+    next_writer: AtomicU64,
+}
+
+impl DiskIo {
+    pub fn new(storage: Arc<dyn Storage>, info: &Info, config: &DiskConfig) -> DiskIo {
+        let (jobs, queue) = mpsc::channel::<Job>();
+        let queue = Arc::new(Mutex::new(queue));
+        for _ in 0..config.threads.max(1) {
+            let queue = queue.clone();
+            let storage = storage.clone();
+            // workers stop once the sender is dropped with the DiskIo
+            thread::spawn(move || loop {
+                let job = queue.lock().expect("disk queue lock poisoned").recv();
+                match job {
+                    Ok(job) => job(storage.as_ref()),
+                    Err(_) => break,
+                }
+            });
+        }
+        DiskIo {
+            jobs,
+            piece_length: info.piece_length,
+            total_length: info.total_length(),
+            write_cache: config.write_cache,
+            read_cache: config.read_cache,
+            cache: Mutex::new(Cache::default()),
+            released: Notify::new(),
+            next_writer: AtomicU64::new(0),
+        }
+    }
+
+    pub fn writer(&self) -> Writer {
+        Writer(self.next_writer.fetch_add(1, Ordering::Relaxed))
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Cache> {
+        self.cache.lock().expect("disk cache lock poisoned")
+    }
+
+    // runs `operation` on a worker thread
+    async fn run<T: Send + 'static>(&self, operation: impl FnOnce(&dyn Storage) -> Result<T, Error> + Send + 'static) -> Result<T, Error> {
+        let (result_tx, result_rx) = oneshot::channel();
+        let job: Job = Box::new(move |storage| {
+            let _ = result_tx.send(operation(storage));
+        });
+        self.jobs.send(job).map_err(|_| Error::other("disk workers stopped"))?;
+        result_rx.await.map_err(|_| Error::other("disk worker failed"))?
+    }
+
+    fn piece_len(&self, piece: usize) -> usize {
+        self.piece_length.min(self.total_length.saturating_sub(piece * self.piece_length))
+    }
+
+    // caches a received block, waiting for room when the write cache is full
+    pub async fn write_block(&self, piece: usize, writer: Writer, begin: usize, block: Bytes) -> Result<(), Error> {
+        loop {
+            let released = self.released.notified();
+            let (victim, blocks) = {
+                let mut cache = self.lock();
+                if cache.verified.contains(&piece) {
+                    return Ok(());
+                }
+                // a cache smaller than one block still takes it when empty
+                if cache.write_bytes + block.len() <= self.write_cache || cache.write_bytes == 0 {
+                    cache.insert(piece, writer, begin, block);
+                    return Ok(());
+                }
+                match cache.spill_victim() {
+                    Some(victim) => {
+                        let pending = cache.pending.get_mut(&victim).expect("found above");
+                        pending.spilled = true;
+                        pending.flushing += 1;
+                        (victim, std::mem::take(&mut pending.blocks))
+                    }
+                    // nothing can be spilled and nothing is on its way out, waiting would never end
+                    None if cache.pending.values().all(|pending| pending.flushing == 0) => {
+                        cache.insert(piece, writer, begin, block);
+                        return Ok(());
+                    }
+                    None => ((0, writer), BTreeMap::new()),
+                }
+            };
+            if blocks.is_empty() {
+                // everything cached is already being flushed by other writers
+                released.await;
+                continue;
+            }
+            let bytes = blocks.values().map(Bytes::len).sum::<usize>();
+            let result = self.run(move |storage| {
+                blocks.iter().try_for_each(|(begin, block)| storage.write(victim.0, *begin, block))
+            }).await;
+            {
+                let mut cache = self.lock();
+                cache.write_bytes -= bytes;
+                // the piece is gone when its download was given up meanwhile
+                if let Some(pending) = cache.pending.get_mut(&victim) {
+                    pending.flushing -= 1;
+                }
+            }
+            self.released.notify_waiters();
+            result?;
+        }
+    }
+
+    fn release(&self, bytes: usize) {
+        self.lock().write_bytes -= bytes;
+        self.released.notify_waiters();
+    }
+
+    // takes a writer's piece out of the write cache once no early write of the piece is in progress
+    async fn take_pending(&self, piece: usize, writer: Writer) -> PendingPiece {
+        loop {
+            let released = self.released.notified();
+            {
+                let mut cache = self.lock();
+                // a write of another writer's blocks landing after ours would overwrite the verified piece
+                if cache.pending.iter().all(|((index, _), pending)| *index != piece || pending.flushing == 0) {
+                    return cache.pending.remove(&(piece, writer)).unwrap_or_default();
+                }
+            }
+            released.await;
+        }
+    }
+
+    /*
+        Checks a piece whose blocks the writer has all cached or flushed with `verify` and writes it
+        out. Returns whether it matched, a mismatching piece is dropped from the cache. Once a piece
+        matched, other writers' blocks of it are dropped and further ones are ignored.
+    */
+    pub async fn finish_piece(&self, piece: usize, writer: Writer, verify: impl FnOnce(&[u8]) -> bool + Send + 'static) -> Result<bool, Error> {
+        let pending = self.take_pending(piece, writer).await;
+        let bytes = pending.blocks.values().map(Bytes::len).sum::<usize>();
+        // another writer got there first
+        if self.lock().verified.contains(&piece) {
+            self.release(bytes);
+            return Ok(true);
+        }
+        let length = self.piece_len(piece);
+        let result = self.run(move |storage| {
+            // a piece that went to storage whole is checked where it lies
+            if pending.spilled && pending.blocks.is_empty() {
+                let data = storage.read_bytes(piece, 0, length)?;
+                return Ok(verify(&data).then_some(data));
+            }
+            let mut data = if pending.spilled { storage.read(piece, 0, length)? } else { vec![0; length] };
+            for (begin, block) in &pending.blocks {
+                data[*begin..*begin + block.len()].copy_from_slice(block);
+            }
+            if !verify(&data) {
+                return Ok(None);
+            }
+            storage.write(piece, 0, &data)?;
+            Ok(Some(Bytes::from(data)))
+        }).await;
+        self.release(bytes);
+        match result? {
+            Some(data) => {
+                let leftovers = {
+                    let mut cache = self.lock();
+                    cache.verified.insert(piece);
+                    let writers: Vec<(usize, Writer)> = cache.pending.keys().filter(|(index, _)| *index == piece).copied().collect();
+                    writers.iter().filter_map(|key| cache.pending.remove(key)).map(|pending| pending.blocks.values().map(Bytes::len).sum::<usize>()).sum()
+                };
+                self.release(leftovers);
+                // peers tend to request pieces right after we announce them
+                self.cache_read(piece, data);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    // drops the cached blocks of a piece the writer no longer downloads, other writers' stay
+    pub fn discard(&self, piece: usize, writer: Writer) {
+        let Some(pending) = self.lock().pending.remove(&(piece, writer)) else {
+            return;
+        };
+        self.release(pending.blocks.values().map(Bytes::len).sum());
+    }
+
+    // reads part of a verified piece, through the read cache
+    pub async fn read(&self, piece: usize, begin: usize, length: usize) -> Result<Bytes, Error> {
+        if begin + length > self.piece_len(piece) {
+            return Err(Error::new(ErrorKind::InvalidInput, "range is past the end of the piece"));
+        }
+        {
+            let mut cache = self.lock();
+            if let Some(data) = cache.read.get(&piece).cloned() {
+                cache.read_order.retain(|index| *index != piece);
+                cache.read_order.push_back(piece);
+                return Ok(data.slice(begin..begin + length));
+            }
+        }
+        let piece_length = self.piece_len(piece);
+        if piece_length > self.read_cache {
+            return self.run(move |storage| storage.read_bytes(piece, begin, length)).await;
+        }
+        let data = self.run(move |storage| storage.read_bytes(piece, 0, piece_length)).await?;
+        self.cache_read(piece, data.clone());
+        Ok(data.slice(begin..begin + length))
+    }
+
+    fn cache_read(&self, piece: usize, data: Bytes) {
+        if data.len() > self.read_cache {
+            return;
+        }
+        let mut cache = self.lock();
+        let cache = &mut *cache;
+        if let Some(previous) = cache.read.insert(piece, data.clone()) {
+            cache.read_bytes -= previous.len();
+            cache.read_order.retain(|index| *index != piece);
+        }
+        cache.read_bytes += data.len();
+        cache.read_order.push_back(piece);
+        while cache.read_bytes > self.read_cache {
+            let Some(oldest) = cache.read_order.pop_front() else {
+                break;
+            };
+            if let Some(evicted) = cache.read.remove(&oldest) {
+                cache.read_bytes -= evicted.len();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    const PIECE_LENGTH: usize = 64;
+
+    fn info(length: usize) -> Info {
+        Info {
+            name: "file".to_string(),
+            length: Some(length),
+            files: None,
+            piece_length: PIECE_LENGTH,
+            pieces: vec![0; length.div_ceil(PIECE_LENGTH) * 20],
+            private: None,
+            attr: None,
+            meta_version: None,
+            file_tree: None,
+        }
+    }
+
+    fn disk(write_cache: usize) -> (DiskIo, Arc<MemoryStorage>, Vec<u8>) {
+        let info = info(PIECE_LENGTH * 4);
+        let storage = Arc::new(MemoryStorage::new(&info));
+        let config = DiskConfig { threads: 2, write_cache, read_cache: PIECE_LENGTH * 2 };
+        let content = (0..info.total_length()).map(|index| (index * 13) as u8).collect();
+        (DiskIo::new(storage.clone(), &info, &config), storage, content)
+    }
+
+    fn piece(content: &[u8], index: usize) -> &[u8] {
+        &content[index * PIECE_LENGTH..(index + 1) * PIECE_LENGTH]
+    }
+
+    async fn write_blocks(disk: &DiskIo, writer: Writer, content: &[u8], index: usize, blocks: std::ops::Range<usize>) {
+        for block in blocks {
+            let data = Bytes::copy_from_slice(&piece(content, index)[block * 16..(block + 1) * 16]);
+            disk.write_block(index, writer, block * 16, data).await.unwrap();
+        }
+    }
+
+    fn verifier(content: &[u8], index: usize) -> impl FnOnce(&[u8]) -> bool + Send + 'static {
+        let expected = piece(content, index).to_vec();
+        move |data| data == expected
+    }
+
+    #[tokio::test]
+    async fn writers_of_one_piece_keep_their_own_blocks() {
+        let (disk, storage, content) = disk(1 << 20);
+        let (first, second) = (disk.writer(), disk.writer());
+        write_blocks(&disk, first, &content, 1, 0..3).await;
+        write_blocks(&disk, second, &content, 1, 0..2).await;
+        // the first peer disconnects in end game, the second one finishes the piece
+        disk.discard(1, first);
+        write_blocks(&disk, second, &content, 1, 2..4).await;
+        assert!(disk.finish_piece(1, second, verifier(&content, 1)).await.unwrap());
+        assert_eq!(storage.read(1, 0, PIECE_LENGTH).unwrap(), piece(&content, 1));
+        assert_eq!(disk.lock().write_bytes, 0);
+    }
+
+    #[tokio::test]
+    async fn web_seed_piece_does_not_replace_a_peers_blocks() {
+        let (disk, _, content) = disk(1 << 20);
+        let (peer, web_seed) = (disk.writer(), disk.writer());
+        write_blocks(&disk, peer, &content, 2, 0..2).await;
+        // a web seed serving a different version of the file
+        disk.write_block(2, web_seed, 0, Bytes::from(vec![0xff; PIECE_LENGTH])).await.unwrap();
+        write_blocks(&disk, peer, &content, 2, 2..4).await;
+        assert!(disk.finish_piece(2, peer, verifier(&content, 2)).await.unwrap());
+        assert_eq!(disk.read(2, 0, PIECE_LENGTH).await.unwrap(), piece(&content, 2));
+        // its whole piece was dropped once the peer's matched
+        assert_eq!(disk.lock().write_bytes, 0);
+    }
+
+    #[tokio::test]
+    async fn full_cache_spills_blocks_to_storage() {
+        // room for three blocks, so the pieces are written out early while they download
+        let (disk, storage, content) = disk(48);
+        let (first, second) = (disk.writer(), disk.writer());
+        write_blocks(&disk, first, &content, 0, 0..2).await;
+        write_blocks(&disk, second, &content, 3, 0..4).await;
+        write_blocks(&disk, first, &content, 0, 2..4).await;
+        assert!(disk.lock().write_bytes <= 48);
+        assert!(disk.finish_piece(0, first, verifier(&content, 0)).await.unwrap());
+        assert!(disk.finish_piece(3, second, verifier(&content, 3)).await.unwrap());
+        assert_eq!(storage.read(0, 0, PIECE_LENGTH).unwrap(), piece(&content, 0));
+        assert_eq!(storage.read(3, 0, PIECE_LENGTH).unwrap(), piece(&content, 3));
+        assert_eq!(disk.lock().write_bytes, 0);
+    }
+
+    #[tokio::test]
+    async fn corrupt_piece_is_not_written() {
+        let (disk, storage, content) = disk(1 << 20);
+        let writer = disk.writer();
+        write_blocks(&disk, writer, &content, 1, 0..3).await;
+        disk.write_block(1, writer, 48, Bytes::from(vec![0xff; 16])).await.unwrap();
+        assert!(!disk.finish_piece(1, writer, verifier(&content, 1)).await.unwrap());
+        assert_eq!(storage.read(1, 0, PIECE_LENGTH).unwrap(), vec![0; PIECE_LENGTH]);
+        assert_eq!(disk.lock().write_bytes, 0);
+    }
+
+    #[tokio::test]
+    async fn blocks_of_a_verified_piece_never_reach_the_disk() {
+        // room for three blocks
+        let (disk, storage, content) = disk(48);
+        let (late, finisher, other) = (disk.writer(), disk.writer(), disk.writer());
+        disk.write_block(1, late, 0, Bytes::from(vec![0xff; 16])).await.unwrap();
+        disk.write_block(1, finisher, 0, Bytes::copy_from_slice(piece(&content, 1))).await.unwrap();
+        assert!(disk.finish_piece(1, finisher, verifier(&content, 1)).await.unwrap());
+        // the late writer's blocks were dropped with the verification, further ones are ignored
+        assert_eq!(disk.lock().write_bytes, 0);
+        disk.write_block(1, late, 16, Bytes::from(vec![0xff; 16])).await.unwrap();
+        assert_eq!(disk.lock().write_bytes, 0);
+        assert!(disk.finish_piece(1, late, verifier(&content, 1)).await.unwrap());
+        // filling the cache spills the other writer's piece, never the verified one
+        write_blocks(&disk, other, &content, 2, 0..4).await;
+        assert_eq!(storage.read(1, 0, PIECE_LENGTH).unwrap(), piece(&content, 1));
+        assert!(disk.finish_piece(2, other, verifier(&content, 2)).await.unwrap());
+        assert_eq!(storage.read(1, 0, PIECE_LENGTH).unwrap(), piece(&content, 1));
+        assert_eq!(disk.lock().write_bytes, 0);
+    }
+
+    #[tokio::test]
+    async fn piece_with_several_writers_is_not_spilled() {
+        let (disk, storage, content) = disk(48);
+        let (honest, corrupt) = (disk.writer(), disk.writer());
+        write_blocks(&disk, honest, &content, 0, 0..2).await;
+        disk.write_block(0, corrupt, 32, Bytes::from(vec![0xff; 16])).await.unwrap();
+        // the cache goes over its size rather than mixing both writers' blocks on the disk
+        write_blocks(&disk, honest, &content, 0, 2..4).await;
+        assert_eq!(disk.lock().write_bytes, 80);
+        assert_eq!(storage.read(0, 0, PIECE_LENGTH).unwrap(), vec![0; PIECE_LENGTH]);
+        assert!(disk.finish_piece(0, honest, verifier(&content, 0)).await.unwrap());
+        assert_eq!(storage.read(0, 0, PIECE_LENGTH).unwrap(), piece(&content, 0));
+        assert_eq!(disk.lock().write_bytes, 0);
+    }
+}
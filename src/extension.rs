@@ -0,0 +1,120 @@
+use std::collections::BTreeMap;
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use serde::{Deserialize, Serialize};
+use crate::peers::{PeerMessage, PeerMessageTag};
+
+/*
+    Extension protocol (BEP 10). Every extended message has id 20, first byte of the payload is
+    the extended message id, 0 being the extension handshake, and the rest is a bencoded dictionary.
+*/
+pub const HANDSHAKE_ID: u8 = 0;
+pub const CLIENT_NAME: &str = "rust-bittorrent-client 0.1.0";
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ExtensionHandshake {
+    // extension name -> message id the sender wants to receive it with
+    #[serde(default)]
+    pub m: BTreeMap<String, i64>,
+    // listen port of the sender
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub p: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub v: Option<String>,
+    // address of the receiver as the sender sees it, 4 or 16 bytes
+    #[serde(skip_serializing_if = "Option::is_none", default, with = "serde_bytes")]
+    pub yourip: Option<Vec<u8>>,
+    // addresses the sender can also be reached on
+    #[serde(skip_serializing_if = "Option::is_none", default, with = "serde_bytes")]
+    pub ipv6: Option<Vec<u8>>,
+    #[serde(skip_serializing_if = "Option::is_none", default, with = "serde_bytes")]
+    pub ipv4: Option<Vec<u8>>,
+    // number of outstanding requests the sender supports
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub reqq: Option<u32>,
+}
+
+impl ExtensionHandshake {
+    pub fn to_message(&self) -> PeerMessage {
+        let mut payload = vec![HANDSHAKE_ID];
+        payload.extend(serde_bencode::to_bytes(self).expect("failed to serialize extension handshake"));
+        PeerMessage::new(PeerMessageTag::Extended, payload)
+    }
+
+    pub fn from_payload(payload: &[u8]) -> Result<Self, Error> {
+        serde_bencode::from_bytes(payload).map_err(|err| Error::new(ErrorKind::InvalidData, err))
+    }
+
+    pub fn yourip(&self) -> Option<IpAddr> {
+        self.yourip.as_deref().and_then(ip_from_bytes)
+    }
+
+    pub fn ipv6(&self) -> Option<Ipv6Addr> {
+        self.ipv6.as_deref().and_then(|bytes| <[u8; 16]>::try_from(bytes).ok()).map(Ipv6Addr::from)
+    }
+
+    pub fn ipv4(&self) -> Option<Ipv4Addr> {
+        self.ipv4.as_deref().and_then(|bytes| <[u8; 4]>::try_from(bytes).ok()).map(Ipv4Addr::from)
+    }
+}
+
+pub fn ip_to_bytes(ip: IpAddr) -> Vec<u8> {
+    match ip {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    }
+}
+
+pub fn ip_from_bytes(bytes: &[u8]) -> Option<IpAddr> {
+    if let Ok(octets) = <[u8; 4]>::try_from(bytes) {
+        Some(IpAddr::from(octets))
+    } else if let Ok(octets) = <[u8; 16]>::try_from(bytes) {
+        Some(IpAddr::from(octets))
+    } else {
+        None
+    }
+}
+
+/*
+    Message payload of extended message split into extended id and the rest.
+*/
+pub fn split_extended(message: &PeerMessage) -> Result<(u8, &[u8]), Error> {
+    match message.payload.split_first() {
+        Some((id, rest)) => Ok((*id, rest)),
+        None => Err(Error::new(ErrorKind::InvalidData, "extended message without extended id")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn handshake_round_trip() {
+        let mut handshake = ExtensionHandshake { p: Some(6881), reqq: Some(250), v: Some(CLIENT_NAME.to_string()), ..Default::default() };
+        handshake.m.insert("ut_pex".to_string(), 1);
+        handshake.yourip = Some(ip_to_bytes("203.0.113.7".parse().unwrap()));
+        handshake.ipv6 = Some(ip_to_bytes("2001:db8::1".parse().unwrap()));
+        handshake.ipv4 = Some(ip_to_bytes("198.51.100.1".parse().unwrap()));
+        let message = handshake.to_message();
+        let (id, payload) = split_extended(&message).unwrap();
+        assert_eq!(id, HANDSHAKE_ID);
+        let decoded = ExtensionHandshake::from_payload(payload).unwrap();
+        assert_eq!((decoded.p, decoded.reqq, decoded.m.get("ut_pex")), (Some(6881), Some(250), Some(&1)));
+        assert_eq!(decoded.yourip(), Some("203.0.113.7".parse().unwrap()));
+        assert_eq!(decoded.ipv6(), Some("2001:db8::1".parse().unwrap()));
+        assert_eq!(decoded.ipv4(), Some("198.51.100.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn addresses_of_the_wrong_length_are_ignored() {
+        let decoded = ExtensionHandshake::from_payload(b"d4:ipv44:\x01\x02\x03\x044:ipv64:\x01\x02\x03\x046:yourip3:abce").unwrap();
+        assert_eq!((decoded.ipv6(), decoded.yourip()), (None, None));
+        assert_eq!(decoded.ipv4(), Some(Ipv4Addr::new(1, 2, 3, 4)));
+        // nothing but the dictionary is required
+        let decoded = ExtensionHandshake::from_payload(b"de").unwrap();
+        assert!(decoded.m.is_empty() && decoded.p.is_none());
+        assert!(ExtensionHandshake::from_payload(b"i1e").is_err());
+        assert!(split_extended(&PeerMessage::new(PeerMessageTag::Extended, vec![])).is_err());
+    }
+}
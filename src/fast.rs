@@ -0,0 +1,66 @@
+use std::net::IpAddr;
+use sha1::{Digest, Sha1};
+
+/*
+    Fast Extension (BEP 6). Besides the shorter HAVE ALL / HAVE NONE and the REJECT that makes
+    every request get an answer, a peer grants an "allowed fast" set of pieces others may download
+    from it while choked, so newcomers can get their first pieces quickly.
+*/
+pub const ALLOWED_FAST_COUNT: usize = 10;
+// SUGGEST PIECE messages we remember from a single peer
+pub const MAX_SUGGESTIONS: usize = 16;
+
+/*
+    Canonical allowed fast set: the peer's /24 network followed by the info hash is hashed repeatedly,
+    every 4 bytes of each digest select a piece. BEP 6 defines it for IPv4 only, IPv6 peers get no set.
+*/
+pub fn allowed_fast_set(info_hash: &[u8; 20], ip: IpAddr, piece_count: usize, count: usize) -> Vec<u32> {
+    let ip = match ip {
+        IpAddr::V4(ip) => ip,
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => ip,
+            None => return vec![],
+        },
+    };
+    let count = count.min(piece_count);
+    let mut set: Vec<u32> = Vec::with_capacity(count);
+    let mut x = (u32::from(ip) & 0xffffff00).to_be_bytes().to_vec();
+    x.extend_from_slice(info_hash);
+    while set.len() < count {
+        x = Sha1::digest(&x).to_vec();
+        for chunk in x.chunks_exact(4) {
+            if set.len() == count {
+                break;
+            }
+            let index = (u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]) as u64 % piece_count as u64) as u32;
+            if !set.contains(&index) {
+                set.push(index);
+            }
+        }
+    }
+    set
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_the_bep_6_example() {
+        let ip = IpAddr::from([80, 4, 4, 200]);
+        assert_eq!(allowed_fast_set(&[0xaa; 20], ip, 1313, 9), vec![1059, 431, 808, 1217, 287, 376, 1188, 353, 508]);
+        assert_eq!(allowed_fast_set(&[0xaa; 20], ip, 1313, 7), vec![1059, 431, 808, 1217, 287, 376, 1188]);
+        // only the /24 network counts
+        assert_eq!(allowed_fast_set(&[0xaa; 20], IpAddr::from([80, 4, 4, 1]), 1313, 9)[..2], [1059, 431]);
+    }
+
+    #[test]
+    fn small_torrents_and_ipv6_peers() {
+        let mut set = allowed_fast_set(&[0xaa; 20], IpAddr::from([80, 4, 4, 200]), 4, ALLOWED_FAST_COUNT);
+        set.sort_unstable();
+        assert_eq!(set, vec![0, 1, 2, 3]);
+        let mapped = IpAddr::from(std::net::Ipv4Addr::new(80, 4, 4, 200).to_ipv6_mapped());
+        assert_eq!(allowed_fast_set(&[0xaa; 20], mapped, 1313, 9)[0], 1059);
+        assert!(allowed_fast_set(&[0xaa; 20], "2001:db8::1".parse().unwrap(), 1313, 9).is_empty());
+    }
+}
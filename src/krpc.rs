@@ -0,0 +1,256 @@
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_bencode::value::Value;
+use serde_bytes::ByteBuf;
+
+/*
+    KRPC messages of the mainline DHT (BEP 5). Every message is a bencoded dictionary with a transaction id `t`
+    and type `y`: "q" for queries (method in `q`, arguments in `a`), "r" for responses (values in `r`)
+    and "e" for errors (`e` holds the code and message).
+*/
+pub const PING: &str = "ping";
+pub const FIND_NODE: &str = "find_node";
+pub const GET_PEERS: &str = "get_peers";
+pub const ANNOUNCE_PEER: &str = "announce_peer";
+
+pub const GENERIC_ERROR: i64 = 201;
+pub const PROTOCOL_ERROR: i64 = 203;
+pub const METHOD_UNKNOWN: i64 = 204;
+
+pub type NodeId = [u8; 20];
+
+// id (20 bytes), IPv4 address (4 bytes) and port (2 bytes)
+const COMPACT_NODE_LENGTH: usize = 26;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KrpcMessage {
+    #[serde(with = "serde_bytes")]
+    pub t: Vec<u8>,
+    pub y: String,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub q: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub a: Option<QueryArguments>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub r: Option<ResponseValues>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub e: Option<KrpcError>,
+    #[serde(skip_serializing_if = "Option::is_none", default, with = "serde_bytes")]
+    pub v: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct QueryArguments {
+    #[serde(with = "serde_bytes")]
+    pub id: Vec<u8>,
+    #[serde(skip_serializing_if = "Option::is_none", default, with = "serde_bytes")]
+    pub target: Option<Vec<u8>>,
+    #[serde(skip_serializing_if = "Option::is_none", default, with = "serde_bytes")]
+    pub info_hash: Option<Vec<u8>>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub port: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none", default, with = "serde_bytes")]
+    pub token: Option<Vec<u8>>,
+    // when set, the port the announce_peer query was sent from is used instead of `port`
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub implied_port: Option<u8>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ResponseValues {
+    #[serde(with = "serde_bytes")]
+    pub id: Vec<u8>,
+    #[serde(skip_serializing_if = "Option::is_none", default, with = "serde_bytes")]
+    pub nodes: Option<Vec<u8>>,
+    // compact peer addresses, 6 bytes each
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub values: Option<Vec<ByteBuf>>,
+    #[serde(skip_serializing_if = "Option::is_none", default, with = "serde_bytes")]
+    pub token: Option<Vec<u8>>,
+}
+
+// `e` of an error message, a list of the error code and a message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KrpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl Serialize for KrpcError {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        (self.code, &self.message).serialize(serializer)
+    }
+}
+
+// decoded through a list of values, serde_bencode loses its place in the message after a tuple
+impl<'de> Deserialize<'de> for KrpcError {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        match Vec::<Value>::deserialize(deserializer)?.as_slice() {
+            [Value::Int(code), Value::Bytes(message), ..] => {
+                Ok(KrpcError { code: *code, message: String::from_utf8_lossy(message).into_owned() })
+            }
+            _ => Err(serde::de::Error::custom("error is not a list of a code and a message")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NodeInfo {
+    pub id: NodeId,
+    pub address: SocketAddr,
+}
+
+impl KrpcMessage {
+    pub fn query(transaction: &[u8], method: &str, arguments: QueryArguments) -> Self {
+        KrpcMessage { t: transaction.to_vec(), y: "q".to_string(), q: Some(method.to_string()), a: Some(arguments), r: None, e: None, v: None }
+    }
+
+    pub fn response(transaction: &[u8], values: ResponseValues) -> Self {
+        KrpcMessage { t: transaction.to_vec(), y: "r".to_string(), q: None, a: None, r: Some(values), e: None, v: None }
+    }
+
+    pub fn error(transaction: &[u8], code: i64, message: &str) -> Self {
+        KrpcMessage { t: transaction.to_vec(), y: "e".to_string(), q: None, a: None, r: None, e: Some(KrpcError { code, message: message.to_string() }), v: None }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        serde_bencode::to_bytes(self).expect("failed to serialize krpc message")
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        serde_bencode::from_bytes(bytes).map_err(|err| Error::new(ErrorKind::InvalidData, err))
+    }
+}
+
+pub fn node_id(bytes: &[u8]) -> Option<NodeId> {
+    <[u8; 20]>::try_from(bytes).ok()
+}
+
+pub fn encode_nodes(nodes: &[NodeInfo]) -> Vec<u8> {
+    let mut encoded = Vec::with_capacity(nodes.len() * COMPACT_NODE_LENGTH);
+    for node in nodes {
+        if let IpAddr::V4(ip) = node.address.ip() {
+            encoded.extend_from_slice(&node.id);
+            encoded.extend_from_slice(&ip.octets());
+            encoded.extend_from_slice(&node.address.port().to_be_bytes());
+        }
+    }
+    encoded
+}
+
+pub fn decode_nodes(bytes: &[u8]) -> Vec<NodeInfo> {
+    bytes.chunks_exact(COMPACT_NODE_LENGTH).filter_map(|chunk| {
+        let id = node_id(&chunk[..20])?;
+        let ip = Ipv4Addr::new(chunk[20], chunk[21], chunk[22], chunk[23]);
+        let port = u16::from_be_bytes([chunk[24], chunk[25]]);
+        (port != 0).then_some(NodeInfo { id, address: SocketAddr::from((ip, port)) })
+    }).collect()
+}
+
+pub fn encode_peer(address: &SocketAddr) -> Option<ByteBuf> {
+    match address.ip() {
+        IpAddr::V4(ip) => {
+            let mut encoded = ip.octets().to_vec();
+            encoded.extend_from_slice(&address.port().to_be_bytes());
+            Some(ByteBuf::from(encoded))
+        }
+        IpAddr::V6(_) => None,
+    }
+}
+
+pub fn decode_peer(bytes: &[u8]) -> Option<SocketAddr> {
+    match bytes {
+        [a, b, c, d, high, low] => Some(SocketAddr::from((Ipv4Addr::new(*a, *b, *c, *d), u16::from_be_bytes([*high, *low])))),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_bep5_ping_query() {
+        let message = KrpcMessage::from_bytes(b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe").unwrap();
+        assert_eq!(message.t, b"aa");
+        assert_eq!(message.y, "q");
+        assert_eq!(message.q.as_deref(), Some(PING));
+        assert_eq!(message.a.unwrap().id, b"abcdefghij0123456789");
+    }
+
+    #[test]
+    fn encodes_bep5_ping_query() {
+        let arguments = QueryArguments { id: b"abcdefghij0123456789".to_vec(), ..Default::default() };
+        let message = KrpcMessage::query(b"aa", PING, arguments);
+        assert_eq!(message.to_bytes(), b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe");
+    }
+
+    #[test]
+    fn get_peers_response_round_trip() {
+        let values = ResponseValues {
+            id: vec![7; 20],
+            values: Some(vec![ByteBuf::from(vec![127, 0, 0, 1, 0x1a, 0xe1])]),
+            token: Some(b"token".to_vec()),
+            ..Default::default()
+        };
+        let decoded = KrpcMessage::from_bytes(&KrpcMessage::response(b"t1", values).to_bytes()).unwrap();
+        assert_eq!(decoded.y, "r");
+        let values = decoded.r.unwrap();
+        assert_eq!(values.id, vec![7; 20]);
+        assert_eq!(values.token.as_deref(), Some(&b"token"[..]));
+        let peers: Vec<_> = values.values.unwrap().iter().filter_map(|value| decode_peer(value)).collect();
+        assert_eq!(peers, vec!["127.0.0.1:6881".parse().unwrap()]);
+        assert!(values.nodes.is_none());
+    }
+
+    #[test]
+    fn error_round_trip() {
+        let encoded = KrpcMessage::error(b"xy", METHOD_UNKNOWN, "method unknown").to_bytes();
+        assert_eq!(encoded, b"d1:eli204e14:method unknowne1:t2:xy1:y1:ee");
+        let decoded = KrpcMessage::from_bytes(&encoded).unwrap();
+        assert_eq!(decoded.e, Some(KrpcError { code: METHOD_UNKNOWN, message: "method unknown".to_string() }));
+        assert_eq!(decoded.t, b"xy");
+    }
+
+    #[test]
+    fn rejects_malformed_messages() {
+        assert!(KrpcMessage::from_bytes(b"").is_err());
+        assert!(KrpcMessage::from_bytes(b"d1:t2:aae").is_err());
+        assert!(KrpcMessage::from_bytes(b"li1ee").is_err());
+    }
+
+    #[test]
+    fn compact_nodes_round_trip() {
+        let nodes = vec![
+            NodeInfo { id: [1; 20], address: "10.0.0.1:6881".parse().unwrap() },
+            NodeInfo { id: [2; 20], address: "192.168.1.2:51413".parse().unwrap() },
+        ];
+        let encoded = encode_nodes(&nodes);
+        assert_eq!(encoded.len(), 2 * COMPACT_NODE_LENGTH);
+        assert_eq!(decode_nodes(&encoded), nodes);
+    }
+
+    #[test]
+    fn compact_nodes_skip_ipv6_port_zero_and_trailing_bytes() {
+        let nodes = [
+            NodeInfo { id: [1; 20], address: "[::1]:6881".parse().unwrap() },
+            NodeInfo { id: [2; 20], address: "10.0.0.2:0".parse().unwrap() },
+            NodeInfo { id: [3; 20], address: "10.0.0.3:6881".parse().unwrap() },
+        ];
+        let mut encoded = encode_nodes(&nodes);
+        assert_eq!(encoded.len(), 2 * COMPACT_NODE_LENGTH);
+        encoded.extend_from_slice(&[0xff; 5]);
+        assert_eq!(decode_nodes(&encoded), vec![nodes[2]]);
+    }
+
+    #[test]
+    fn compact_peers() {
+        let address: SocketAddr = "1.2.3.4:258".parse().unwrap();
+        let encoded = encode_peer(&address).unwrap();
+        assert_eq!(encoded.as_slice(), &[1, 2, 3, 4, 1, 2]);
+        assert_eq!(decode_peer(&encoded), Some(address));
+        assert!(encode_peer(&"[::1]:1".parse().unwrap()).is_none());
+        assert_eq!(decode_peer(&[1, 2, 3]), None);
+    }
+}
@@ -0,0 +1,214 @@
+use std::io::Error;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::UdpSocket;
+use crate::random;
+
+/*
+    Local Service Discovery (BEP 14). Peers on the same network announce the torrents they have by
+    multicasting HTTP-like `BT-SEARCH` messages; every client on the network listens on the same port,
+    so a cookie in the message lets us recognize and drop our own announces.
+*/
+pub const IPV4_GROUP: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(239, 192, 152, 143), 6771);
+pub const IPV6_GROUP: SocketAddrV6 = SocketAddrV6::new(Ipv6Addr::new(0xff15, 0, 0, 0, 0, 0, 0xefc0, 0x988f), 6771, 0, 0);
+
+// larger datagrams are not announces
+const MAX_MESSAGE_SIZE: usize = 1400;
+
+#[derive(Debug)]
+pub struct Announce {
+    pub port: u16,
+    pub info_hashes: Vec<[u8; 20]>,
+    pub cookie: Option<String>,
+}
+
+pub struct Lsd {
+    ipv4: Option<UdpSocket>,
+    ipv6: Option<UdpSocket>,
+    cookie: String,
+}
+
+impl Lsd {
+    // joins both multicast groups, fails only when neither family is usable
+    pub fn bind() -> Result<Lsd, Error> {
+        let ipv4 = multicast_socket(Domain::IPV4, |socket| {
+            socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, IPV4_GROUP.port())).into())?;
+            socket.join_multicast_v4(IPV4_GROUP.ip(), &Ipv4Addr::UNSPECIFIED)?;
+            socket.set_multicast_loop_v4(true)
+        });
+        let ipv6 = multicast_socket(Domain::IPV6, |socket| {
+            socket.set_only_v6(true)?;
+            socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, IPV6_GROUP.port())).into())?;
+            socket.join_multicast_v6(IPV6_GROUP.ip(), 0)?;
+            socket.set_multicast_loop_v6(true)
+        });
+        let (ipv4, ipv6) = match (ipv4, ipv6) {
+            (Err(err), Err(_)) => return Err(err),
+            (ipv4, ipv6) => (ipv4.ok(), ipv6.ok()),
+        };
+        Ok(Lsd { ipv4, ipv6, cookie: hex::encode(random::bytes::<8>()) })
+    }
+
+    pub async fn announce(&self, info_hashes: &[[u8; 20]], port: u16) {
+        if let Some(socket) = &self.ipv4 {
+            let message = self.message(&IPV4_GROUP.to_string(), info_hashes, port);
+            if let Err(err) = socket.send_to(message.as_bytes(), IPV4_GROUP).await {
+                eprintln!("failed to send local service discovery announce: {}", err);
+            }
+        }
+        if let Some(socket) = &self.ipv6 {
+            let message = self.message(&IPV6_GROUP.to_string(), info_hashes, port);
+            if let Err(err) = socket.send_to(message.as_bytes(), IPV6_GROUP).await {
+                eprintln!("failed to send local service discovery announce: {}", err);
+            }
+        }
+    }
+
+    // next announce from another client, along with the address it came from
+    pub async fn recv(&self) -> Result<(Announce, SocketAddr), Error> {
+        let mut buf_v4 = [0u8; MAX_MESSAGE_SIZE];
+        let mut buf_v6 = [0u8; MAX_MESSAGE_SIZE];
+        loop {
+            let (bytes, source) = tokio::select! {
+                received = recv_from(&self.ipv4, &mut buf_v4) => {
+                    let (size, source) = received?;
+                    (&buf_v4[..size], source)
+                }
+                received = recv_from(&self.ipv6, &mut buf_v6) => {
+                    let (size, source) = received?;
+                    (&buf_v6[..size], source)
+                }
+            };
+            match parse(bytes) {
+                Some(announce) if announce.cookie.as_deref() != Some(self.cookie.as_str()) => return Ok((announce, source)),
+                _ => continue,
+            }
+        }
+    }
+
+    fn message(&self, host: &str, info_hashes: &[[u8; 20]], port: u16) -> String {
+        let mut message = format!("BT-SEARCH * HTTP/1.1\r\nHost: {}\r\nPort: {}\r\n", host, port);
+        for info_hash in info_hashes {
+            message.push_str(&format!("Infohash: {}\r\n", hex::encode(info_hash)));
+        }
+        message.push_str(&format!("cookie: {}\r\n\r\n\r\n", self.cookie));
+        message
+    }
+}
+
+fn multicast_socket(domain: Domain, configure: impl FnOnce(&Socket) -> Result<(), Error>) -> Result<UdpSocket, Error> {
+    let socket = Socket::new(domain, Type::DGRAM, Some(Protocol::UDP))?;
+    // every client on the host listens on the same port
+    socket.set_reuse_address(true)?;
+    configure(&socket)?;
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket.into())
+}
+
+async fn recv_from(socket: &Option<UdpSocket>, buf: &mut [u8]) -> Result<(usize, SocketAddr), Error> {
+    match socket {
+        Some(socket) => socket.recv_from(buf).await,
+        None => std::future::pending().await,
+    }
+}
+
+fn parse(bytes: &[u8]) -> Option<Announce> {
+    let text = std::str::from_utf8(bytes).ok()?;
+    let mut lines = text.split("\r\n");
+    if lines.next()? != "BT-SEARCH * HTTP/1.1" {
+        return None;
+    }
+    let mut port = None;
+    let mut info_hashes = vec![];
+    let mut cookie = None;
+    for line in lines.take_while(|line| !line.is_empty()) {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        match name.trim().to_ascii_lowercase().as_str() {
+            "port" => port = value.parse::<u16>().ok().filter(|port| *port != 0),
+            "infohash" => {
+                let mut info_hash = [0u8; 20];
+                if hex::decode_to_slice(value, &mut info_hash).is_ok() {
+                    info_hashes.push(info_hash);
+                }
+            }
+            "cookie" => cookie = Some(value.to_string()),
+            _ => {}
+        }
+    }
+    Some(Announce { port: port?, info_hashes, cookie })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    const INFO_HASH: [u8; 20] = [0xab; 20];
+
+    #[test]
+    fn parses_an_announce() {
+        let message = format!("BT-SEARCH * HTTP/1.1\r\nHost: 239.192.152.143:6771\r\nPort: 6881\r\nInfohash: {}\r\n\
+                               Infohash: {}\r\ncookie: abc\r\n\r\n\r\n", hex::encode(INFO_HASH), hex::encode([1; 20]));
+        let announce = parse(message.as_bytes()).unwrap();
+        assert_eq!(announce.port, 6881);
+        assert_eq!(announce.info_hashes, vec![INFO_HASH, [1; 20]]);
+        assert_eq!(announce.cookie.as_deref(), Some("abc"));
+    }
+
+    #[test]
+    fn header_names_are_case_insensitive_and_bad_hashes_skipped() {
+        let message = "BT-SEARCH * HTTP/1.1\r\nPORT:  51413 \r\ninfohash: nothex\r\nINFOHASH: abab\r\n\r\n";
+        let announce = parse(message.as_bytes()).unwrap();
+        assert_eq!(announce.port, 51413);
+        assert!(announce.info_hashes.is_empty());
+        assert_eq!(announce.cookie, None);
+    }
+
+    #[test]
+    fn rejects_other_messages() {
+        assert!(parse(b"M-SEARCH * HTTP/1.1\r\nPort: 6881\r\n\r\n").is_none());
+        assert!(parse(b"BT-SEARCH * HTTP/1.1\r\nHost: x\r\n\r\n").is_none());
+        assert!(parse(b"BT-SEARCH * HTTP/1.1\r\nPort: 0\r\n\r\n").is_none());
+        assert!(parse(b"BT-SEARCH * HTTP/1.1\r\nPort: 99999\r\n\r\n").is_none());
+        assert!(parse(&[0xff, 0xfe]).is_none());
+        // headers after the blank line are the body
+        assert!(parse(b"BT-SEARCH * HTTP/1.1\r\n\r\nPort: 6881\r\n\r\n").is_none());
+    }
+
+    #[test]
+    fn messages_round_trip_with_our_cookie() {
+        let lsd = Lsd { ipv4: None, ipv6: None, cookie: "c00c1e".to_string() };
+        let message = lsd.message(&IPV4_GROUP.to_string(), &[INFO_HASH], 6881);
+        assert!(message.starts_with("BT-SEARCH * HTTP/1.1\r\nHost: 239.192.152.143:6771\r\n"));
+        let announce = parse(message.as_bytes()).unwrap();
+        assert_eq!((announce.port, announce.info_hashes), (6881, vec![INFO_HASH]));
+        assert_eq!(announce.cookie.as_deref(), Some("c00c1e"));
+    }
+
+    // skipped when the host has no multicast route, as in many containers
+    #[tokio::test]
+    async fn loopback_announces_skip_our_own() {
+        let (Ok(first), Ok(second)) = (Lsd::bind(), Lsd::bind()) else {
+            eprintln!("skipping, cannot join the local service discovery groups");
+            return;
+        };
+        first.announce(&[INFO_HASH], 40001).await;
+        second.announce(&[INFO_HASH], 40002).await;
+        let receive = |lsd: Lsd| async move {
+            // other clients on the host may be announcing too
+            loop {
+                let (announce, _) = lsd.recv().await?;
+                if announce.info_hashes == [INFO_HASH] {
+                    return Ok::<_, Error>(announce.port);
+                }
+            }
+        };
+        match tokio::time::timeout(Duration::from_secs(2), async { tokio::join!(receive(first), receive(second)) }).await {
+            Ok((first, second)) => assert_eq!((first.unwrap(), second.unwrap()), (40002, 40001)),
+            Err(_) => eprintln!("skipping, multicast announces are not delivered on this host"),
+        }
+    }
+}
@@ -1,24 +1,49 @@
 use std::{env, vec};
+use std::io::Write;
 use std::net::ToSocketAddrs;
 use std::path::{Path, PathBuf};
-use serde_json;
+use std::sync::Arc;
 use serde_json::Number;
 use clap::Parser;
-use crate::metainfo::Meta;
+use crate::disk::DiskConfig;
+use crate::metainfo::{Info, Meta};
+use crate::selection::Priority;
+use crate::storage::{Allocation, Backend, DiskStorage, MemoryStorage, MmapStorage, Storage};
 
 mod metainfo;
 mod args;
 mod tracker;
 mod peers;
+mod bitfield;
+mod extension;
+mod peer_pool;
+mod picker;
+mod session;
+mod random;
+mod krpc;
+mod dht;
+mod discovery;
+mod pex;
+mod lsd;
+mod fast;
+mod mse;
+mod utp;
+mod transport;
+mod storage;
+mod disk;
+mod selection;
+mod stream;
+mod webseed;
+mod merkle;
 
 fn decode_bencoded_string(encoded_string: &str) -> (serde_json::Value, usize) {
-    return match encoded_string.chars().nth(0).expect("fail to create iterator over input string") {
+    match encoded_string.chars().next().expect("fail to create iterator over input string") {
         'i' => {
             let delimiter = encoded_string.find('e').expect("supplied string starts like integer, but missing enclosing symbol");
             let parsed_number = encoded_string[1..delimiter].parse::<i64>().expect("unable to parse integer");
             (serde_json::Value::Number(Number::from(parsed_number)), delimiter + 1)
         }
-        item if item.is_digit(10) => {
+        item if item.is_ascii_digit() => {
             let delimiter = encoded_string.find(':');
             match delimiter {
                 None => {
@@ -34,10 +59,10 @@ fn decode_bencoded_string(encoded_string: &str) -> (serde_json::Value, usize) {
                     });
                     size += 1;
                     let safe_string = String::from_utf8(bytes.clone());
-                    return match safe_string {
+                    match safe_string {
                         Ok(formatted) => { (serde_json::Value::String(formatted), (delimiter_safe + size + 1)) }
                         Err(_) => { (serde_json::Value::from(bytes), (delimiter_safe + size + 1)) }
-                    };
+                    }
                 }
             }
         }
@@ -70,7 +95,14 @@ fn decode_bencoded_string(encoded_string: &str) -> (serde_json::Value, usize) {
         _ => {
             panic!("unknown input {}", encoded_string)
         }
-    };
+    }
+}
+
+fn create_storage(info: &Info, root: &Path, backend: Backend, allocation: Allocation, priorities: &[Priority]) -> Result<Arc<dyn Storage>, std::io::Error> {
+    Ok(match backend {
+        Backend::File => Arc::new(DiskStorage::new(info, root, allocation, priorities)?),
+        Backend::Mmap => Arc::new(MmapStorage::new(info, root, allocation, priorities)?),
+    })
 }
 
 #[tokio::main]
@@ -79,18 +111,48 @@ async fn main() {
     match &formatted.command {
         args::Command::Decode { input } => {
             let decoded_value = decode_bencoded_string(input);
-            println!("{}", decoded_value.0.to_string());
+            println!("{}", decoded_value.0);
         }
         args::Command::Info { file_path } => {
             let result = read_meta_from_args_filepath(file_path);
             match result {
                 Ok(content) => {
                     let hash_hexed = content.calculate_info_hash_hexed();
-                    println!("Tracker URL: {}\n Length: {}\n Info Hash: {}", content.announce, content.info.length, hash_hexed);
+                    println!("Tracker URL: {}\n Length: {}\n Info Hash: {}", content.announce, content.info.total_length(), hash_hexed);
                     println!("Piece Length: {}", content.info.piece_length);
+                    println!("Private: {}", if content.info.is_private() { "yes" } else { "no" });
+                    if content.info.is_v2() {
+                        println!("Info Hash v2: {}", base16::encode_lower(&content.calculate_info_hash_v2()));
+                        println!("Version: {}", if content.info.is_v1() { "hybrid" } else { "v2" });
+                        for (path, file) in content.info.tree_files() {
+                            println!("Tree File: {} ({} bytes)", path.join("/"), file.length);
+                            let Some(root) = &file.pieces_root else {
+                                continue;
+                            };
+                            println!("  Pieces Root: {}", base16::encode_lower(root));
+                            match content.piece_layer(root) {
+                                Some(layer) => println!("  Piece Layer: {} hashes", layer.len() / 32),
+                                None if file.length > content.info.piece_length => println!("  Piece Layer: missing"),
+                                None => {}
+                            }
+                        }
+                    }
+                    // a v2-only torrent's files are the tree files above
+                    if content.info.is_v1() && content.info.is_multi_file() {
+                        for (index, file) in content.info.file_list().into_iter().enumerate() {
+                            let attributes = file.attr.as_deref().map(|attr| format!(" [{}]", attr)).unwrap_or_default();
+                            println!("File {}: {} ({} bytes){}", index, file.path.join("/"), file.length, attributes);
+                            if let Some(target) = file.symlink_target() {
+                                println!("  Symlink to: {}", target.join("/"));
+                            }
+                            if let Some(sha1) = &file.sha1 {
+                                println!("  SHA1: {}", base16::encode_lower(sha1));
+                            }
+                        }
+                    }
                     let mut iterator = content.info.pieces.chunks_exact(20);
                     iterator.clone().for_each(|chunk| println!("{}", base16::encode_lower(&chunk.to_vec())));
-                    if !iterator.next().is_none() {
+                    if iterator.next().is_some() {
                         println!("{}", base16::encode_lower(&iterator.remainder().to_vec()))
                     }
                 }
@@ -104,7 +166,20 @@ async fn main() {
             let result = read_meta_from_args_filepath(file_path);
             match result {
                 Ok(meta_data) => {
-                    connect_to_tracker(&meta_data).await.expect("Failed to load peers list for tracker");
+                    match connect_to_tracker(&meta_data).await {
+                        Ok(response) => {
+                            if let (Some(seeders), Some(leechers)) = (response.complete, response.incomplete) {
+                                eprintln!("seeders: {}, leechers: {}", seeders, leechers);
+                            }
+                            for peer in response.peers() {
+                                println!("{}:{}", peer.ip_address, peer.port)
+                            }
+                        }
+                        Err(err) => {
+                            eprintln!("failed to load peers list from tracker: {}", err);
+                            std::process::exit(1);
+                        }
+                    }
                 }
                 Err(err) => {
                     panic!("failed to parse torrent file. error: {}", err)
@@ -115,20 +190,17 @@ async fn main() {
         args::Command::Handshake { file_path, peer_address } => {
             use tracker::tracker::handshake_with_peer;
             let file = read_meta_from_args_filepath(file_path);
-            match file {
-                Ok(meta_data) => {
-                    let mut address_iterator = peer_address.as_str().to_socket_addrs().expect("invalid address supplied");
-                    let address = address_iterator.next();
-                    match address {
-                        None => {
-                            eprintln!("address iterator is empty")
-                        }
-                        Some(addr) => {
-                            handshake_with_peer(addr.ip(), addr.port(), &meta_data).await.expect("handshake failed");
-                        }
+            if let Ok(meta_data) = file {
+                let mut address_iterator = peer_address.as_str().to_socket_addrs().expect("invalid address supplied");
+                let address = address_iterator.next();
+                match address {
+                    None => {
+                        eprintln!("address iterator is empty")
+                    }
+                    Some(addr) => {
+                        handshake_with_peer(addr.ip(), addr.port(), &meta_data).await.expect("handshake failed");
                     }
                 }
-                Err(_) => {}
             }
         }
 
@@ -144,16 +216,85 @@ async fn main() {
                 }
             }
         }
+
+        args::Command::Download { output, torrent, port, max_peers, ip_preference, ipv6, no_dht, no_pex, no_lsd, encryption, no_utp, only, priority, sequential, stream_port, allocation, storage, disk_threads, write_cache_mb, read_cache_mb, dht_bootstrap, dht_cache } => {
+            let meta_data = match read_meta_from_args_filepath(torrent) {
+                Ok(meta_data) => meta_data,
+                Err(err) => {
+                    eprintln!("failed to parse torrent file. error: {}", err);
+                    std::process::exit(1);
+                }
+            };
+            let file_priorities = match selection::file_priorities(&meta_data.info, only, priority) {
+                Ok(file_priorities) => file_priorities,
+                Err(err) => {
+                    eprintln!("{}", err);
+                    std::process::exit(1);
+                }
+            };
+            let config = session::SessionConfig {
+                listen_port: *port,
+                max_peers: *max_peers,
+                ip_preference: *ip_preference,
+                ipv6: *ipv6,
+                dht: (!no_dht).then(|| session::DhtConfig {
+                    bootstrap: if dht_bootstrap.is_empty() {
+                        dht::DEFAULT_BOOTSTRAP_NODES.iter().map(|node| node.to_string()).collect()
+                    } else {
+                        dht_bootstrap.clone()
+                    },
+                    cache: dht_cache.clone().or_else(default_dht_cache),
+                }),
+                pex: !no_pex,
+                lsd: !no_lsd,
+                encryption: *encryption,
+                utp: !no_utp,
+                disk: DiskConfig { threads: *disk_threads, write_cache: write_cache_mb << 20, read_cache: read_cache_mb << 20 },
+                file_priorities: file_priorities.clone(),
+                sequential: *sequential,
+                stream_port: *stream_port,
+            };
+            // `-o -` keeps the content in memory and writes it to stdout at the end
+            let memory = (output.as_os_str() == "-").then(|| Arc::new(MemoryStorage::new(&meta_data.info)));
+            let storage: Arc<dyn Storage> = match &memory {
+                Some(memory) => memory.clone(),
+                None => match create_storage(&meta_data.info, output, *storage, *allocation, &file_priorities) {
+                    Ok(storage) => storage,
+                    Err(err) => {
+                        eprintln!("failed to create {}: {}", output.display(), err);
+                        std::process::exit(1);
+                    }
+                },
+            };
+            match session::download(meta_data, storage, config).await {
+                Ok(()) => match memory {
+                    Some(memory) => {
+                        if let Err(err) = std::io::stdout().write_all(&memory.contents()) {
+                            eprintln!("failed to write to stdout: {}", err);
+                            std::process::exit(1);
+                        }
+                    }
+                    None => println!("Downloaded {} to {}.", torrent.display(), output.display()),
+                },
+                Err(err) => {
+                    eprintln!("download failed: {}", err);
+                    std::process::exit(1);
+                }
+            }
+        }
     }
 }
 
 fn get_current_dir_path() -> PathBuf {
-    let path = if env::args().any(|item| item == "--directory") {
-        PathBuf::from(env::args().last().unwrap())
+    if env::args().any(|item| item == "--directory") {
+        PathBuf::from(env::args().next_back().unwrap())
     } else {
-        env::current_dir().unwrap_or(PathBuf::new())
-    };
-    path
+        env::current_dir().unwrap_or_default()
+    }
+}
+
+fn default_dht_cache() -> Option<PathBuf> {
+    env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache/rust-bittorrent-client/dht_nodes"))
 }
 
 fn read_meta_from_args_filepath(file_name: &PathBuf) -> Result<Meta, anyhow::Error> {
@@ -0,0 +1,548 @@
+use std::io::{Error, ErrorKind};
+
+use sha2::{Digest, Sha256};
+
+use crate::metainfo::Meta;
+use crate::peers::HashRequest;
+
+// bytes of a file under every leaf of its merkle tree
+pub const BLOCK_SIZE: usize = 1 << 14;
+// most hashes asked for in one hash request, as BEP 52 suggests
+const MAX_HASHES: usize = 512;
+
+pub type Hash = [u8; 32];
+
+pub fn hash_block(block: &[u8]) -> Hash {
+    Sha256::digest(block).into()
+}
+
+// leaves over the blocks of `data`, the last block may be short
+pub fn block_hashes(data: &[u8]) -> Vec<Hash> {
+    data.chunks(BLOCK_SIZE).map(hash_block).collect()
+}
+
+fn hash_pair(left: &Hash, right: &Hash) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+// root of a subtree `level` layers high whose leaves all lie past the end of the file, which are zeros
+fn pad_hash(level: u32) -> Hash {
+    (0..level).fold([0; 32], |hash, _| hash_pair(&hash, &hash))
+}
+
+// the layer above `nodes`, which are `level` layers above the leaves
+fn parent_layer(nodes: &[Hash], level: u32) -> Vec<Hash> {
+    let pad = pad_hash(level);
+    nodes.chunks(2).map(|pair| hash_pair(&pair[0], pair.get(1).unwrap_or(&pad))).collect()
+}
+
+// root over `nodes` of layer `level`, padded to `width` nodes, a power of two no smaller than the nodes
+fn subtree_root(nodes: &[Hash], level: u32, width: usize) -> Hash {
+    let mut layer = nodes.to_vec();
+    layer.resize(width.max(1), pad_hash(level));
+    let mut level = level;
+    while layer.len() > 1 {
+        layer = parent_layer(&layer, level);
+        level += 1;
+    }
+    layer[0]
+}
+
+/*
+    Merkle tree of a v2 file, with the SHA-256 of its 16 KiB blocks as leaves, padded with zero leaves
+    to a power of two, up to the pieces root from the metainfo. A file longer than a piece is verified
+    piece by piece against its piece layer, the layer whose nodes each cover one piece, which comes from
+    the torrent or from peers. A file of at most one piece is its own piece, with the root as its node.
+*/
+struct FileHashes {
+    pieces_root: Hash,
+    // index of the file's first piece in the torrent, v2 pieces never span files
+    first_piece: usize,
+    length: usize,
+    piece_length: usize,
+    // leaves under a node of the piece layer
+    piece_width: usize,
+    // nodes of the piece layer verified so far, by piece of the file
+    piece_layer: Vec<Option<Hash>>,
+}
+
+impl FileHashes {
+    fn new(pieces_root: Hash, first_piece: usize, length: usize, piece_length: usize) -> FileHashes {
+        let (piece_width, piece_layer) = if length <= piece_length {
+            (length.div_ceil(BLOCK_SIZE).next_power_of_two(), vec![Some(pieces_root)])
+        } else {
+            (piece_length / BLOCK_SIZE, vec![None; length.div_ceil(piece_length)])
+        };
+        FileHashes { pieces_root, first_piece, length, piece_length, piece_width, piece_layer }
+    }
+
+    fn piece_level(&self) -> u32 {
+        self.piece_width.trailing_zeros()
+    }
+
+    fn root_level(&self) -> u32 {
+        self.piece_level() + self.piece_layer.len().next_power_of_two().trailing_zeros()
+    }
+
+    // the piece layer as listed in `piece layers`, only taken when it leads to the pieces root
+    fn set_piece_layer(&mut self, layer: &[u8]) -> bool {
+        let nodes: Vec<Hash> = layer.chunks_exact(32).map(|node| node.try_into().expect("chunks are 32 bytes")).collect();
+        let width = self.piece_layer.len().next_power_of_two();
+        if nodes.len() != self.piece_layer.len() || subtree_root(&nodes, self.piece_level(), width) != self.pieces_root {
+            return false;
+        }
+        self.piece_layer = nodes.into_iter().map(Some).collect();
+        true
+    }
+
+    // node of the tree we can vouch for: the root, or a verified piece layer node
+    fn known_node(&self, level: u32, index: usize) -> Option<Hash> {
+        if level == self.root_level() {
+            return (index == 0).then_some(self.pieces_root);
+        }
+        if level == self.piece_level() {
+            return self.piece_layer.get(index).copied().flatten();
+        }
+        None
+    }
+
+    /*
+        Nodes of layer `level` with the index of the first one. Layers from the piece layer up need the
+        whole piece layer, the ones below it are only known within the piece whose `leaves` are given.
+    */
+    fn layer(&self, level: u32, leaves: Option<(usize, &[Hash])>) -> Option<(usize, Vec<Hash>)> {
+        let piece_level = self.piece_level();
+        if level > self.root_level() {
+            return None;
+        }
+        if level >= piece_level {
+            let mut nodes = self.piece_layer.iter().copied().collect::<Option<Vec<Hash>>>()?;
+            nodes.resize(self.piece_layer.len().next_power_of_two(), pad_hash(piece_level));
+            for below in piece_level..level {
+                nodes = parent_layer(&nodes, below);
+            }
+            return Some((0, nodes));
+        }
+        let (piece, leaves) = leaves?;
+        let mut nodes = leaves.to_vec();
+        nodes.resize(self.piece_width, [0; 32]);
+        for below in 0..level {
+            nodes = parent_layer(&nodes, below);
+        }
+        Some((piece * (self.piece_width >> level), nodes))
+    }
+}
+
+// what a downloaded piece of a v2 file has to hash to
+pub struct PieceCheck {
+    hash: Hash,
+    width: usize,
+    // bytes of the file in the piece, the rest is padding up to the next file
+    length: usize,
+}
+
+impl PieceCheck {
+    pub fn matches(&self, data: &[u8]) -> bool {
+        let leaves = block_hashes(&data[..self.length.min(data.len())]);
+        leaves.len() <= self.width && subtree_root(&leaves, 0, self.width) == self.hash
+    }
+}
+
+/*
+    BEP 52 hashes of a v2 or hybrid torrent's files. Pieces are verified against their file's merkle
+    tree, and blocks against leaf hashes a peer sent for the piece, so a corrupt block is caught on
+    arrival. Missing piece layers and leaf hashes are asked for with hash requests, whose answers are
+    only taken when their proof leads to a node we already trust.
+*/
+pub struct MerkleHashes {
+    files: Vec<FileHashes>,
+}
+
+impl MerkleHashes {
+    // None for torrents without v2 hashes, piece layers in the metainfo that do not match their root are dropped
+    pub fn new(meta: &Meta) -> Option<MerkleHashes> {
+        if !meta.info.is_v2() {
+            return None;
+        }
+        let piece_length = meta.info.piece_length;
+        let mut files = vec![];
+        let mut offset = 0;
+        for (path, file) in meta.info.tree_files() {
+            if file.length == 0 {
+                continue;
+            }
+            let pieces_root = file.pieces_root.as_ref().and_then(|root| Hash::try_from(root.as_slice()).ok());
+            if let Some(pieces_root) = pieces_root {
+                let mut hashes = FileHashes::new(pieces_root, offset / piece_length, file.length, piece_length);
+                if let Some(layer) = meta.piece_layer(&pieces_root).filter(|_| hashes.piece_layer.len() > 1) {
+                    if !hashes.set_piece_layer(layer) {
+                        eprintln!("piece layer of {} does not match its pieces root", path.join("/"));
+                    }
+                }
+                files.push(hashes);
+            }
+            offset += file.length.next_multiple_of(piece_length);
+        }
+        Some(MerkleHashes { files })
+    }
+
+    // the file a piece belongs to and the piece's index in it
+    fn file_of_piece(&self, piece: usize) -> Option<(&FileHashes, usize)> {
+        self.files.iter()
+            .find(|file| (file.first_piece..file.first_piece + file.piece_layer.len()).contains(&piece))
+            .map(|file| (file, piece - file.first_piece))
+    }
+
+    // bytes of its file in a piece, the end of a file's last piece is padding without leaves
+    pub fn file_bytes(&self, piece: usize) -> Option<usize> {
+        let (file, index) = self.file_of_piece(piece)?;
+        Some(file.piece_length.min(file.length - index * file.piece_length))
+    }
+
+    // None until the piece layer node of the piece is known
+    pub fn piece_check(&self, piece: usize) -> Option<PieceCheck> {
+        let (file, index) = self.file_of_piece(piece)?;
+        Some(PieceCheck {
+            hash: file.piece_layer[index]?,
+            width: file.piece_width,
+            length: self.file_bytes(piece)?,
+        })
+    }
+
+    // requests for the parts of piece layers we do not have, each proven up to its file's root
+    pub fn missing_piece_layers(&self) -> Vec<HashRequest> {
+        let mut requests = vec![];
+        for file in &self.files {
+            let chunk = file.piece_layer.len().next_power_of_two().min(MAX_HASHES);
+            for start in (0..file.piece_layer.len()).step_by(chunk) {
+                let end = (start + chunk).min(file.piece_layer.len());
+                if file.piece_layer[start..end].iter().all(Option::is_some) {
+                    continue;
+                }
+                let top = file.piece_level() + chunk.trailing_zeros();
+                requests.push(HashRequest {
+                    pieces_root: file.pieces_root,
+                    base_layer: file.piece_level(),
+                    index: start as u32,
+                    length: chunk as u32,
+                    proof_layers: file.root_level() - top,
+                });
+            }
+        }
+        requests
+    }
+
+    // the leaf hashes of a piece, which its piece layer node proves, when blocks can be checked on their own
+    pub fn block_hashes_request(&self, piece: usize) -> Option<HashRequest> {
+        let (file, index) = self.file_of_piece(piece)?;
+        if file.piece_width < 2 || file.piece_width > MAX_HASHES || file.piece_layer[index].is_none() {
+            return None;
+        }
+        Some(HashRequest {
+            pieces_root: file.pieces_root,
+            base_layer: 0,
+            index: (index * file.piece_width) as u32,
+            length: file.piece_width as u32,
+            proof_layers: 0,
+        })
+    }
+
+    /*
+        Verifies the answer to one of our hash requests. Piece layer nodes are kept, leaf hashes are
+        returned with their piece for checking its blocks.
+    */
+    pub fn add_hashes(&mut self, request: &HashRequest, hashes: &[Hash]) -> Result<Option<(usize, Vec<Hash>)>, Error> {
+        let invalid = |reason: &str| Error::new(ErrorKind::InvalidData, format!("invalid hashes: {}", reason));
+        let file = self.files.iter_mut().find(|file| file.pieces_root == request.pieces_root)
+            .ok_or_else(|| invalid("unknown pieces root"))?;
+        let length = request.length as usize;
+        if !length.is_power_of_two() || !(request.index as usize).is_multiple_of(length) || hashes.len() < length {
+            return Err(invalid("malformed request"));
+        }
+        let (nodes, proof) = hashes.split_at(length);
+        let top = request.base_layer.saturating_add(length.trailing_zeros());
+        if top.saturating_add(proof.len() as u32) > file.root_level() {
+            return Err(invalid("proof reaches past the root"));
+        }
+        let mut hash = subtree_root(nodes, request.base_layer, length);
+        let mut index = request.index as usize / length;
+        for uncle in proof {
+            hash = if index.is_multiple_of(2) { hash_pair(&hash, uncle) } else { hash_pair(uncle, &hash) };
+            index /= 2;
+        }
+        if file.known_node(top + proof.len() as u32, index) != Some(hash) {
+            return Err(invalid("proof does not lead to a known node"));
+        }
+        if request.base_layer == file.piece_level() {
+            let start = request.index as usize;
+            for (slot, node) in file.piece_layer.iter_mut().skip(start).zip(nodes) {
+                *slot = Some(*node);
+            }
+            return Ok(None);
+        }
+        if request.base_layer == 0 && length == file.piece_width {
+            return Ok(Some((file.first_piece + request.index as usize / length, nodes.to_vec())));
+        }
+        Ok(None)
+    }
+
+    // the piece whose data is needed to answer a request for nodes below the piece layer
+    pub fn piece_for_request(&self, request: &HashRequest) -> Option<usize> {
+        let file = self.files.iter().find(|file| file.pieces_root == request.pieces_root)?;
+        if request.base_layer >= file.piece_level() {
+            return None;
+        }
+        let first_leaf = (request.index as usize).checked_shl(request.base_layer)?;
+        Some(file.first_piece + first_leaf / file.piece_width).filter(|piece| *piece < file.first_piece + file.piece_layer.len())
+    }
+
+    /*
+        Nodes and proof answering a peer's hash request, None when we cannot. Nodes below the piece layer
+        are computed from `piece_data`, the data of the piece piece_for_request named.
+    */
+    pub fn serve(&self, request: &HashRequest, piece_data: Option<&[u8]>) -> Option<Vec<Hash>> {
+        let file = self.files.iter().find(|file| file.pieces_root == request.pieces_root)?;
+        let length = request.length as usize;
+        if !length.is_power_of_two() || length > MAX_HASHES || !(request.index as usize).is_multiple_of(length) {
+            return None;
+        }
+        let leaves = match (self.piece_for_request(request), piece_data) {
+            (Some(piece), Some(data)) => {
+                let index = piece - file.first_piece;
+                let file_bytes = file.piece_length.min(file.length - index * file.piece_length);
+                Some((index, block_hashes(&data[..file_bytes.min(data.len())])))
+            }
+            _ => None,
+        };
+        let leaves = leaves.as_ref().map(|(index, leaves)| (*index, leaves.as_slice()));
+        let (first, nodes) = file.layer(request.base_layer, leaves)?;
+        let start = (request.index as usize).checked_sub(first)?;
+        let mut result = nodes.get(start..start + length)?.to_vec();
+        let top = request.base_layer + length.trailing_zeros();
+        let mut index = request.index as usize / length;
+        for level in top..top.saturating_add(request.proof_layers).min(file.root_level()) {
+            let (first, nodes) = file.layer(level, leaves)?;
+            result.push(*nodes.get((index ^ 1).checked_sub(first)?)?);
+            index /= 2;
+        }
+        Some(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use serde_bytes::ByteBuf;
+    use crate::metainfo::{FileTree, Info, TreeFile};
+
+    const PIECE_LENGTH: usize = 4 * BLOCK_SIZE;
+    // a file of 2.5 blocks, its own piece, and one of 5 pieces and 1.5 blocks
+    const SMALL: usize = 2 * BLOCK_SIZE + BLOCK_SIZE / 2;
+    const LARGE: usize = 5 * PIECE_LENGTH + BLOCK_SIZE + BLOCK_SIZE / 2;
+
+    fn content(length: usize, seed: usize) -> Vec<u8> {
+        (0..length).map(|index| (index * 7 + index / 251 + seed) as u8).collect()
+    }
+
+    // pieces root and piece layer of a file from the full tree over its leaves padded with zero hashes
+    fn tree(data: &[u8]) -> (Hash, Vec<Hash>) {
+        let pieces = data.len().div_ceil(PIECE_LENGTH);
+        let mut layer = block_hashes(data);
+        layer.resize((pieces * PIECE_LENGTH / BLOCK_SIZE).next_power_of_two().max(1), [0; 32]);
+        let mut piece_layer = vec![];
+        let mut width = 1;
+        while layer.len() > 1 {
+            if width == PIECE_LENGTH / BLOCK_SIZE {
+                piece_layer = layer[..pieces].to_vec();
+            }
+            layer = layer.chunks(2).map(|pair| hash_pair(&pair[0], &pair[1])).collect();
+            width *= 2;
+        }
+        (layer[0], if pieces > 1 { piece_layer } else { vec![] })
+    }
+
+    // a v2 torrent of the small and the large file, with the large one's piece layer when it is given
+    fn meta(small: &[u8], large: &[u8], piece_layer: Option<&[Hash]>) -> Meta {
+        let file = |data: &[u8]| FileTree::File {
+            file: TreeFile { length: data.len(), pieces_root: Some(ByteBuf::from(tree(data).0.to_vec())) },
+        };
+        let info = Info {
+            name: "torrent".to_string(),
+            length: None,
+            files: None,
+            piece_length: PIECE_LENGTH,
+            pieces: vec![],
+            private: None,
+            attr: None,
+            meta_version: Some(2),
+            file_tree: Some(BTreeMap::from([("a".to_string(), file(small)), ("b".to_string(), file(large))])),
+        };
+        let mut torrent = b"d4:info".to_vec();
+        torrent.extend(serde_bencode::to_bytes(&info).unwrap());
+        if let Some(layer) = piece_layer {
+            torrent.extend(b"12:piece layersd32:");
+            torrent.extend(tree(large).0);
+            torrent.extend(format!("{}:", layer.len() * 32).as_bytes());
+            torrent.extend(layer.concat());
+            torrent.push(b'e');
+        }
+        torrent.push(b'e');
+        serde_bencode::from_bytes(&torrent).unwrap()
+    }
+
+    struct Torrent {
+        small: Vec<u8>,
+        large: Vec<u8>,
+        root: Hash,
+        layer: Vec<Hash>,
+    }
+
+    impl Torrent {
+        fn new() -> Torrent {
+            let (small, large) = (content(SMALL, 1), content(LARGE, 2));
+            let (root, layer) = tree(&large);
+            Torrent { small, large, root, layer }
+        }
+
+        fn hashes(&self, with_layer: bool) -> MerkleHashes {
+            MerkleHashes::new(&meta(&self.small, &self.large, with_layer.then_some(&self.layer[..]))).unwrap()
+        }
+
+        // data of a piece of the torrent, the small file is piece 0
+        fn piece(&self, piece: usize) -> &[u8] {
+            match piece {
+                0 => &self.small,
+                piece => &self.large[(piece - 1) * PIECE_LENGTH..(piece * PIECE_LENGTH).min(LARGE)],
+            }
+        }
+
+        fn request(&self, base_layer: u32, index: u32, length: u32, proof_layers: u32) -> HashRequest {
+            HashRequest { pieces_root: self.root, base_layer, index, length, proof_layers }
+        }
+    }
+
+    #[test]
+    fn piece_layers_lead_to_the_pieces_root() {
+        let torrent = Torrent::new();
+        assert_eq!(torrent.layer.len(), 6);
+        let hashes = torrent.hashes(true);
+        assert!(hashes.missing_piece_layers().is_empty());
+        for piece in 0..7 {
+            assert!(hashes.piece_check(piece).unwrap().matches(torrent.piece(piece)), "piece {}", piece);
+        }
+        assert!(hashes.piece_check(7).is_none());
+
+        // a layer that does not hash to the root is dropped and asked for instead
+        let mut tampered = torrent.layer.clone();
+        tampered[3][0] ^= 1;
+        let mut hashes = MerkleHashes::new(&meta(&torrent.small, &torrent.large, Some(&tampered))).unwrap();
+        assert!(hashes.piece_check(1).is_none());
+        assert_eq!(hashes.missing_piece_layers(), [torrent.request(2, 0, 8, 0)]);
+        let file = &mut hashes.files[1];
+        assert!(!file.set_piece_layer(&tampered.concat()));
+        assert!(!file.set_piece_layer(&torrent.layer[..5].concat()));
+        assert!(file.piece_layer.iter().all(Option::is_none));
+        assert!(file.set_piece_layer(&torrent.layer.concat()));
+        assert!(hashes.piece_check(1).is_some());
+    }
+
+    #[test]
+    fn short_last_pieces_are_checked_without_padding() {
+        let torrent = Torrent::new();
+        let hashes = torrent.hashes(true);
+        assert_eq!(hashes.file_bytes(6), Some(BLOCK_SIZE + BLOCK_SIZE / 2));
+        let check = hashes.piece_check(6).unwrap();
+        let last = torrent.piece(6).to_vec();
+        assert!(check.matches(&last));
+        // the zeros a hybrid torrent pads the file with up to the piece boundary are no leaves
+        let mut padded = last.clone();
+        padded.resize(PIECE_LENGTH, 0);
+        assert!(check.matches(&padded));
+        assert!(!check.matches(&last[..last.len() - 1]));
+        let mut corrupt = last.clone();
+        corrupt[BLOCK_SIZE + 10] ^= 1;
+        assert!(!check.matches(&corrupt));
+
+        let check = hashes.piece_check(0).unwrap();
+        assert!(check.matches(&torrent.small));
+        assert!(!check.matches(&torrent.small[..BLOCK_SIZE]));
+    }
+
+    #[test]
+    fn served_hashes_are_taken_with_their_proof() {
+        let torrent = Torrent::new();
+        let seeder = torrent.hashes(true);
+
+        // the whole layer of a non-power-of-two piece count, padded up to 8 nodes
+        let mut leecher = torrent.hashes(false);
+        let request = leecher.missing_piece_layers().remove(0);
+        let answer = seeder.serve(&request, None).unwrap();
+        assert_eq!(answer.len(), 8);
+        assert_eq!(answer[..6], torrent.layer);
+        assert_eq!(leecher.add_hashes(&request, &answer).unwrap(), None);
+        assert!((1..7).all(|piece| leecher.piece_check(piece).is_some()));
+
+        // part of the layer, with the uncles up to the root
+        let mut leecher = torrent.hashes(false);
+        let request = torrent.request(2, 4, 2, 2);
+        let answer = seeder.serve(&request, None).unwrap();
+        assert_eq!(answer.len(), 4);
+        assert_eq!(leecher.add_hashes(&request, &answer).unwrap(), None);
+        assert!(leecher.piece_check(5).is_some() && leecher.piece_check(6).is_some());
+        assert!(leecher.piece_check(4).is_none());
+        // more proof layers than the tree has are cut at the root
+        assert_eq!(seeder.serve(&torrent.request(2, 4, 2, 10), None).unwrap(), answer);
+
+        // leaf hashes of the short last piece, proven by its piece layer node
+        let request = leecher.block_hashes_request(6).unwrap();
+        assert_eq!(request, torrent.request(0, 20, 4, 0));
+        assert_eq!(seeder.piece_for_request(&request), Some(6));
+        assert!(seeder.serve(&request, None).is_none());
+        let leaves = seeder.serve(&request, Some(torrent.piece(6))).unwrap();
+        assert_eq!(leaves[..2], block_hashes(torrent.piece(6)));
+        assert_eq!(leaves[2..], [[0; 32]; 2]);
+        assert_eq!(leecher.add_hashes(&request, &leaves).unwrap(), Some((6, leaves)));
+    }
+
+    #[test]
+    fn tampered_or_overlong_proofs_are_refused() {
+        let torrent = Torrent::new();
+        let seeder = torrent.hashes(true);
+        let mut leecher = torrent.hashes(false);
+        let request = torrent.request(2, 4, 2, 2);
+        let answer = seeder.serve(&request, None).unwrap();
+        let refused = |leecher: &mut MerkleHashes, request: &HashRequest, hashes: &[Hash]| {
+            let err = leecher.add_hashes(request, hashes).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+            err.to_string()
+        };
+
+        let mut tampered = answer.clone();
+        tampered[3][31] ^= 1;
+        assert!(refused(&mut leecher, &request, &tampered).contains("known node"));
+        let mut tampered = answer.clone();
+        tampered[0][0] ^= 1;
+        assert!(refused(&mut leecher, &request, &tampered).contains("known node"));
+        assert!(leecher.piece_check(5).is_none());
+
+        // an extra uncle above the root
+        let mut overlong = answer.clone();
+        overlong.push(torrent.root);
+        assert!(refused(&mut leecher, &request, &overlong).contains("past the root"));
+        // nodes of a layer above the root
+        assert!(refused(&mut leecher, &torrent.request(6, 0, 1, 0), &[torrent.root]).contains("past the root"));
+        assert!(refused(&mut leecher, &torrent.request(4, 0, 2, 1), &answer[..3]).contains("past the root"));
+
+        assert!(refused(&mut leecher, &torrent.request(2, 4, 3, 0), &answer).contains("malformed"));
+        assert!(refused(&mut leecher, &torrent.request(2, 4, 2, 2), &answer[..1]).contains("malformed"));
+        let mut unknown = torrent.request(2, 4, 2, 2);
+        unknown.pieces_root[0] ^= 1;
+        assert!(refused(&mut leecher, &unknown, &answer).contains("unknown pieces root"));
+
+        assert_eq!(leecher.add_hashes(&request, &answer).unwrap(), None);
+    }
+}
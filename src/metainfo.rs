@@ -1,22 +1,114 @@
+use std::collections::{BTreeMap, HashMap};
 use std::fmt::{Debug, Display, Formatter};
 use std::path::PathBuf;
 use anyhow::Context;
 use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
 use sha1::{Digest, Sha1};
+use sha2::Sha256;
 
 #[derive(Debug, Deserialize)]
 pub struct Meta {
+    // missing in trackerless torrents
+    #[serde(default)]
     pub announce: String,
-    pub info: Info
+    #[serde(rename = "announce-list", default)]
+    pub announce_list: Option<Vec<Vec<String>>>,
+    // BEP 19 web seeds, a single URL or a list of them
+    #[serde(rename = "url-list", default, deserialize_with = "one_or_many")]
+    pub url_list: Vec<String>,
+    // BEP 17 HTTP seeds
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub httpseeds: Vec<String>,
+    pub info: Info,
+    // BEP 52, the hashes of each file's piece layer of the merkle tree by the file's pieces root
+    #[serde(rename = "piece layers", default)]
+    pub piece_layers: HashMap<ByteBuf, ByteBuf>,
+    // info dictionary exactly as it appears in the torrent file, keys we do not model are part of the info-hash too
+    #[serde(skip)]
+    raw_info: Option<Vec<u8>>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Info {
     pub name: String,
-    pub length: usize,
+    // single-file torrents have the length, multi-file ones the list of files
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub length: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub files: Option<Vec<FileEntry>>,
     #[serde(rename= "piece length")] pub piece_length: usize,
-    #[serde(with = "serde_bytes")]
-    pub pieces: Vec<u8>
+    // missing in v2-only torrents
+    #[serde(default, with = "serde_bytes")]
+    pub pieces: Vec<u8>,
+    // BEP 27, peers of private torrents must only come from its trackers
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub private: Option<u8>,
+    // BEP 47 attributes of a single-file torrent's file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attr: Option<String>,
+    // BEP 52, 2 for v2 and hybrid torrents
+    #[serde(rename = "meta version", default, skip_serializing_if = "Option::is_none")]
+    pub meta_version: Option<u8>,
+    // v2 file layout, directory names down to the file's node
+    #[serde(rename = "file tree", default, skip_serializing_if = "Option::is_none")]
+    pub file_tree: Option<BTreeMap<String, FileTree>>,
+}
+
+/*
+    Node of a v2 file tree. A file is a dictionary with just an empty key holding its length and
+    pieces root, anything else is a directory of named nodes.
+*/
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum FileTree {
+    File {
+        #[serde(rename = "")]
+        file: TreeFile,
+    },
+    Directory(BTreeMap<String, FileTree>),
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TreeFile {
+    pub length: usize,
+    // root of the file's merkle tree over 16 KiB blocks, missing for empty files
+    #[serde(rename = "pieces root", default, skip_serializing_if = "Option::is_none")]
+    pub pieces_root: Option<ByteBuf>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct FileEntry {
+    pub length: usize,
+    // directories and the file name, relative to the torrent's root directory
+    pub path: Vec<String>,
+    // BEP 47 flags: `p` padding, `x` executable, `h` hidden, `l` symlink
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attr: Option<String>,
+    // target of a symlink, relative to the torrent's root directory
+    #[serde(rename = "symlink path", default, skip_serializing_if = "Option::is_none")]
+    pub symlink_path: Option<Vec<String>>,
+    #[serde(default, with = "serde_bytes", skip_serializing_if = "Option::is_none")]
+    pub sha1: Option<Vec<u8>>,
+}
+
+impl FileEntry {
+    fn has_attr(&self, flag: char) -> bool {
+        self.attr.as_deref().is_some_and(|attr| attr.contains(flag))
+    }
+
+    // aligns the next file to a piece boundary, its content is all zeros and never stored
+    pub fn is_padding(&self) -> bool {
+        self.has_attr('p')
+    }
+
+    pub fn is_executable(&self) -> bool {
+        self.has_attr('x')
+    }
+
+    pub fn symlink_target(&self) -> Option<&[String]> {
+        self.symlink_path.as_deref().filter(|_| self.has_attr('l'))
+    }
 }
 
 impl Display for Meta {
@@ -27,28 +119,281 @@ impl Display for Meta {
 
 impl Display for Info {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "length: {}, name: {}, pieces length: {}", self.length, self.name, self.piece_length)
+        write!(f, "length: {}, name: {}, pieces length: {}", self.total_length(), self.name, self.piece_length)
+    }
+}
+
+impl Info {
+    pub fn is_private(&self) -> bool {
+        self.private == Some(1)
+    }
+
+    pub fn total_length(&self) -> usize {
+        match &self.files {
+            Some(files) => files.iter().map(|file| file.length).sum(),
+            None if !self.is_v1() => self.file_list().iter().map(|file| file.length).sum(),
+            None => self.length.unwrap_or_default(),
+        }
+    }
+
+    // has the v1 piece hashes, which hybrid torrents carry next to the v2 fields
+    pub fn is_v1(&self) -> bool {
+        !self.pieces.is_empty()
+    }
+
+    pub fn is_v2(&self) -> bool {
+        self.meta_version == Some(2) && self.file_tree.is_some()
+    }
+
+    // files of the v2 file tree with their paths, in the order their content follows each other
+    pub fn tree_files(&self) -> Vec<(Vec<String>, &TreeFile)> {
+        let mut result = vec![];
+        // directories left to walk, in reverse so the names come out sorted
+        let mut stack = vec![];
+        if let Some(tree) = &self.file_tree {
+            stack.extend(tree.iter().rev().map(|(name, node)| (vec![name.clone()], node)));
+        }
+        while let Some((path, node)) = stack.pop() {
+            match node {
+                FileTree::File { file } => result.push((path, file)),
+                FileTree::Directory(children) => {
+                    stack.extend(children.iter().rev().map(|(name, child)| {
+                        let mut path = path.clone();
+                        path.push(name.clone());
+                        (path, child)
+                    }));
+                }
+            }
+        }
+        result
+    }
+
+    /*
+        Files in the order their content follows each other in the pieces, a single-file torrent has just its name.
+        v2 pieces never span files, so the files of a v2-only torrent are followed by the padding a hybrid
+        torrent would list up to the next piece boundary.
+    */
+    pub fn file_list(&self) -> Vec<FileEntry> {
+        match &self.files {
+            Some(files) => files.clone(),
+            None if !self.is_v1() => {
+                let tree = self.tree_files();
+                let mut entries = vec![];
+                for (position, (path, file)) in tree.iter().enumerate() {
+                    entries.push(FileEntry { length: file.length, path: path.clone(), attr: None, symlink_path: None, sha1: None });
+                    let padding = file.length.next_multiple_of(self.piece_length) - file.length;
+                    if padding > 0 && tree[position + 1..].iter().any(|(_, next)| next.length > 0) {
+                        entries.push(FileEntry {
+                            length: padding,
+                            path: vec![".pad".to_string(), padding.to_string()],
+                            attr: Some("p".to_string()),
+                            symlink_path: None,
+                            sha1: None,
+                        });
+                    }
+                }
+                entries
+            }
+            None => vec![FileEntry {
+                length: self.length.unwrap_or_default(),
+                path: vec![self.name.clone()],
+                attr: self.attr.clone(),
+                symlink_path: None,
+                sha1: None,
+            }],
+        }
+    }
+
+    // a v2-only torrent holds a single file when its tree is just the torrent's name
+    pub fn is_multi_file(&self) -> bool {
+        self.files.is_some() || (!self.is_v1() && self.tree_files().iter().any(|(path, _)| *path != [self.name.as_str()]))
+    }
+
+    pub fn piece_count(&self) -> usize {
+        if self.is_v1() {
+            self.pieces.len() / 20
+        } else {
+            self.total_length().div_ceil(self.piece_length)
+        }
+    }
+
+    // last piece is usually shorter than the others
+    pub fn piece_len(&self, index: usize) -> usize {
+        let start = index * self.piece_length;
+        self.piece_length.min(self.total_length().saturating_sub(start))
+    }
+
+    pub fn piece_hash(&self, index: usize) -> &[u8] {
+        &self.pieces[index * 20..(index + 1) * 20]
+    }
+
+    // BEP 52 pieces are a power of two of at least one block, so every piece covers whole leaves of the merkle tree
+    fn check_piece_length(&self) -> Result<(), anyhow::Error> {
+        if self.piece_length == 0 {
+            anyhow::bail!("piece length is 0");
+        }
+        if self.is_v2() && (self.piece_length < crate::merkle::BLOCK_SIZE || !self.piece_length.is_power_of_two()) {
+            anyhow::bail!("piece length {} of a v2 torrent is not a power of two of at least {}", self.piece_length, crate::merkle::BLOCK_SIZE);
+        }
+        Ok(())
     }
 }
 
 impl Meta {
     pub fn read_from_file(file_path: &PathBuf) -> Result<Self, anyhow::Error> {
         let torrent_file = std::fs::read(file_path).context("parse torrent file")?;
-        let parsed = serde_bencode::from_bytes(&torrent_file).context("parse torrent file");
+        let parsed = serde_bencode::from_bytes::<Meta>(&torrent_file).context("parse torrent file");
         match parsed {
-            Ok(meta) => { Ok(meta) }
+            Ok(mut meta) => {
+                meta.info.check_piece_length()?;
+                meta.raw_info = raw_info(&torrent_file).map(|info| info.to_vec());
+                Ok(meta)
+            }
             Err(body) => { Err(body) }
         }
     }
 
     pub fn calculate_info_hash_hexed(&self) -> String {
-        let encoded = serde_bencode::to_bytes(&self.info).expect("failed to serialize info");
-        let output = Sha1::digest(encoded);
-        base16::encode_lower(&output)
+        base16::encode_lower(&self.calculate_info_hash())
     }
 
+    // the hash peers and trackers know the torrent by: v1 for v1 and hybrid torrents, else the truncated v2 hash
     pub fn calculate_info_hash(&self) -> Vec<u8> {
-        let encoded = serde_bencode::to_bytes(&self.info).expect("failed to serialize info");
-        Vec::from(Sha1::digest(encoded).as_slice())
+        if self.info.is_v1() {
+            Vec::from(Sha1::digest(self.encoded_info()).as_slice())
+        } else {
+            self.calculate_info_hash_v2()[..20].to_vec()
+        }
+    }
+
+    // BEP 52, SHA-256 of the info dictionary
+    pub fn calculate_info_hash_v2(&self) -> [u8; 32] {
+        Sha256::digest(self.encoded_info()).into()
+    }
+
+    fn encoded_info(&self) -> Vec<u8> {
+        match &self.raw_info {
+            Some(raw_info) => raw_info.clone(),
+            None => serde_bencode::to_bytes(&self.info).expect("failed to serialize info"),
+        }
+    }
+
+    /*
+        Hashes of a file's piece layer, the merkle tree level whose nodes each cover one piece. Files of
+        at most one piece have none, their pieces root covers them whole.
+    */
+    pub fn piece_layer(&self, pieces_root: &[u8]) -> Option<&[u8]> {
+        self.piece_layers.get(serde_bytes::Bytes::new(pieces_root)).map(|layer| layer.as_slice())
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OneOrMany {
+    One(String),
+    Many(Vec<String>),
+}
+
+// empty strings, which some torrent creators write when there are no URLs, are dropped
+fn one_or_many<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    let urls = match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(url) => vec![url],
+        OneOrMany::Many(urls) => urls,
+    };
+    Ok(urls.into_iter().filter(|url| !url.is_empty()).collect())
+}
+
+// slice of the top level dictionary holding the value of the `info` key
+fn raw_info(torrent: &[u8]) -> Option<&[u8]> {
+    if torrent.first() != Some(&b'd') {
+        return None;
+    }
+    let mut cursor = 1;
+    while torrent.get(cursor) != Some(&b'e') {
+        let key_end = value_end(torrent, cursor)?;
+        let end = value_end(torrent, key_end)?;
+        if &torrent[cursor..key_end] == b"4:info" {
+            return Some(&torrent[key_end..end]);
+        }
+        cursor = end;
     }
-}
\ No newline at end of file
+    None
+}
+
+// position right after the bencoded value starting at `start`
+fn value_end(bytes: &[u8], start: usize) -> Option<usize> {
+    // open lists and dictionaries, walked iteratively so nesting depth cannot overflow the stack
+    let mut depth = 0usize;
+    let mut cursor = start;
+    loop {
+        match *bytes.get(cursor)? {
+            b'i' => cursor += bytes[cursor..].iter().position(|byte| *byte == b'e')? + 1,
+            b'l' | b'd' => {
+                depth += 1;
+                cursor += 1;
+            }
+            b'e' if depth > 0 => {
+                depth -= 1;
+                cursor += 1;
+            }
+            b'0'..=b'9' => {
+                let colon = cursor + bytes[cursor..].iter().position(|byte| *byte == b':')?;
+                let length: usize = std::str::from_utf8(&bytes[cursor..colon]).ok()?.parse().ok()?;
+                cursor = colon.checked_add(1 + length).filter(|end| *end <= bytes.len())?;
+            }
+            _ => return None,
+        }
+        if depth == 0 {
+            return Some(cursor);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(torrent: &[u8]) -> Result<Meta, anyhow::Error> {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("test.torrent");
+        std::fs::write(&path, torrent).unwrap();
+        Meta::read_from_file(&path)
+    }
+
+    fn v1(piece_length: usize) -> Vec<u8> {
+        format!("d4:infod6:lengthi100e4:name1:a12:piece lengthi{}e6:pieces20:{}ee", piece_length, "x".repeat(20)).into_bytes()
+    }
+
+    fn v2(piece_length: usize) -> Vec<u8> {
+        let mut torrent = b"d4:infod9:file treed1:ad0:d6:lengthi100000e11:pieces root32:".to_vec();
+        torrent.extend_from_slice(&[7; 32]);
+        torrent.extend_from_slice(format!("eee12:meta versioni2e4:name1:a12:piece lengthi{}eee", piece_length).as_bytes());
+        torrent
+    }
+
+    #[test]
+    fn reads_v1_and_v2_torrents() {
+        let meta = read(&v1(50)).unwrap();
+        assert_eq!((meta.info.piece_count(), meta.info.total_length()), (1, 100));
+        // v1 pieces need not be a power of two
+        read(&v1(3)).unwrap();
+
+        let meta = read(&v2(1 << 14)).unwrap();
+        assert!(meta.info.is_v2() && !meta.info.is_v1());
+        assert_eq!(meta.info.piece_count(), 7);
+        read(&v2(1 << 22)).unwrap();
+    }
+
+    #[test]
+    fn zero_piece_length_is_refused() {
+        assert!(read(&v1(0)).is_err());
+        assert!(read(&v2(0)).is_err());
+    }
+
+    #[test]
+    fn v2_piece_length_must_cover_whole_blocks() {
+        for piece_length in [1, 1 << 13, 3 << 14, (1 << 14) + 1] {
+            assert!(read(&v2(piece_length)).is_err(), "{}", piece_length);
+        }
+    }
+}
@@ -0,0 +1,614 @@
+use std::io::{Error, ErrorKind};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use sha1::{Digest, Sha1};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+use crate::peers::PROTOCOL;
+use crate::random;
+
+/*
+    Message Stream Encryption (MSE/PE). Both sides exchange Diffie-Hellman public keys followed by random
+    padding, derive RC4 keys from the shared secret and the info hash (SKEY) and agree on either RC4 or
+    plaintext for the rest of the connection. The initiator proves it knows the info hash without
+    revealing it, which lets the receiver pick the torrent among the ones it serves.
+*/
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum EncryptionPolicy {
+    // plaintext only, encrypted connections are refused
+    Disabled,
+    // encrypt outgoing connections and fall back to plaintext for peers without MSE
+    #[default]
+    Prefer,
+    // RC4 encrypted connections only
+    Require,
+}
+
+// 768 bit safe prime the specification uses, generator is 2
+const PRIME: [u8; KEY_LENGTH] = [
+    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xc9, 0x0f, 0xda, 0xa2, 0x21, 0x68, 0xc2, 0x34,
+    0xc4, 0xc6, 0x62, 0x8b, 0x80, 0xdc, 0x1c, 0xd1, 0x29, 0x02, 0x4e, 0x08, 0x8a, 0x67, 0xcc, 0x74,
+    0x02, 0x0b, 0xbe, 0xa6, 0x3b, 0x13, 0x9b, 0x22, 0x51, 0x4a, 0x08, 0x79, 0x8e, 0x34, 0x04, 0xdd,
+    0xef, 0x95, 0x19, 0xb3, 0xcd, 0x3a, 0x43, 0x1b, 0x30, 0x2b, 0x0a, 0x6d, 0xf2, 0x5f, 0x14, 0x37,
+    0x4f, 0xe1, 0x35, 0x6d, 0x6d, 0x51, 0xc2, 0x45, 0xe4, 0x85, 0xb5, 0x76, 0x62, 0x5e, 0x7e, 0xc6,
+    0xf4, 0x4c, 0x42, 0xe9, 0xa6, 0x3a, 0x36, 0x21, 0x00, 0x00, 0x00, 0x00, 0x00, 0x09, 0x05, 0x63,
+];
+const GENERATOR: u64 = 2;
+const KEY_LENGTH: usize = 96;
+const PRIVATE_KEY_LENGTH: usize = 20;
+const MAX_PADDING: usize = 512;
+// verification constant, 8 zero bytes
+const VC: [u8; 8] = [0; 8];
+const CRYPTO_PLAINTEXT: u32 = 0x01;
+const CRYPTO_RC4: u32 = 0x02;
+// RC4 keystream bytes thrown away before use
+const RC4_DISCARD: usize = 1024;
+
+/*
+    Connection to a peer after the MSE handshake, or the underlying stream left in plaintext. Bytes read
+    past the handshake (the initial payload of an incoming connection) are handed out before anything else.
+*/
+pub struct PeerStream<S> {
+    inner: S,
+    decrypt: Option<Rc4>,
+    encrypt: Option<Rc4>,
+    pending: Vec<u8>,
+    // encrypted bytes accepted by poll_write that the socket has not taken yet
+    unsent: Vec<u8>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> PeerStream<S> {
+    pub fn plain(inner: S) -> Self {
+        PeerStream { inner, decrypt: None, encrypt: None, pending: vec![], unsent: vec![] }
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.encrypt.is_some()
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    fn poll_send_unsent(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        while !self.unsent.is_empty() {
+            match Pin::new(&mut self.inner).poll_write(cx, &self.unsent) {
+                Poll::Ready(Ok(0)) => return Poll::Ready(Err(Error::from(ErrorKind::WriteZero))),
+                Poll::Ready(Ok(written)) => {
+                    self.unsent.drain(..written);
+                }
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for PeerStream<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<Result<(), Error>> {
+        let this = self.get_mut();
+        if !this.pending.is_empty() {
+            let size = this.pending.len().min(buf.remaining());
+            buf.put_slice(&this.pending[..size]);
+            this.pending.drain(..size);
+            return Poll::Ready(Ok(()));
+        }
+        let filled = buf.filled().len();
+        let result = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if let (Poll::Ready(Ok(())), Some(decrypt)) = (&result, this.decrypt.as_mut()) {
+            decrypt.apply(&mut buf.filled_mut()[filled..]);
+        }
+        result
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for PeerStream<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize, Error>> {
+        let this = self.get_mut();
+        if this.encrypt.is_none() {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        }
+        // every byte goes through the keystream exactly once, so earlier output has to leave first
+        if this.poll_send_unsent(cx)?.is_pending() {
+            return Poll::Pending;
+        }
+        let mut encrypted = buf.to_vec();
+        if let Some(encrypt) = this.encrypt.as_mut() {
+            encrypt.apply(&mut encrypted);
+        }
+        this.unsent = encrypted;
+        if let Poll::Ready(Err(err)) = this.poll_send_unsent(cx) {
+            return Poll::Ready(Err(err));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        let this = self.get_mut();
+        if this.poll_send_unsent(cx)?.is_pending() {
+            return Poll::Pending;
+        }
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        let this = self.get_mut();
+        if this.poll_send_unsent(cx)?.is_pending() {
+            return Poll::Pending;
+        }
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+/*
+    Runs the initiating side of the handshake on an outgoing connection. With `Prefer` the peer may
+    choose plaintext, `Require` only offers RC4.
+*/
+pub async fn initiate<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S, info_hash: &[u8; 20], policy: EncryptionPolicy) -> Result<PeerStream<S>, Error> {
+    let provide = match policy {
+        EncryptionPolicy::Disabled => return Ok(PeerStream::plain(stream)),
+        EncryptionPolicy::Prefer => CRYPTO_RC4 | CRYPTO_PLAINTEXT,
+        EncryptionPolicy::Require => CRYPTO_RC4,
+    };
+    let private_key = random::secure_bytes::<PRIVATE_KEY_LENGTH>()?;
+    stream.write_all(&with_padding(public_key(&private_key))).await?;
+    let mut their_key = [0u8; KEY_LENGTH];
+    stream.read_exact(&mut their_key).await?;
+    let secret = shared_secret(&their_key, &private_key)?;
+
+    let mut encrypt = Rc4::new(&hash(&[b"keyA", &secret, info_hash]));
+    let mut decrypt = Rc4::new(&hash(&[b"keyB", &secret, info_hash]));
+    let mut message = hash(&[b"req1", &secret]).to_vec();
+    message.extend(xor(&hash(&[b"req2", info_hash]), &hash(&[b"req3", &secret])));
+    let mut negotiation = VC.to_vec();
+    negotiation.extend_from_slice(&provide.to_be_bytes());
+    // no padding and no initial payload, the BitTorrent handshake follows on the encrypted stream
+    negotiation.extend_from_slice(&0u16.to_be_bytes());
+    negotiation.extend_from_slice(&0u16.to_be_bytes());
+    encrypt.apply(&mut negotiation);
+    message.extend(negotiation);
+    stream.write_all(&message).await?;
+
+    // the responder's padding ends where the encrypted verification constant starts
+    let mut encrypted_vc = VC;
+    decrypt.apply(&mut encrypted_vc);
+    synchronize(&mut stream, &encrypted_vc, MAX_PADDING).await?;
+    let mut select = [0u8; 6];
+    stream.read_exact(&mut select).await?;
+    decrypt.apply(&mut select);
+    let selected = u32::from_be_bytes([select[0], select[1], select[2], select[3]]);
+    let padding = u16::from_be_bytes([select[4], select[5]]) as usize;
+    if padding > MAX_PADDING {
+        return Err(Error::new(ErrorKind::InvalidData, "encryption padding is too long"));
+    }
+    let mut pad = vec![0u8; padding];
+    stream.read_exact(&mut pad).await?;
+    decrypt.apply(&mut pad);
+    match selected {
+        CRYPTO_RC4 if provide & CRYPTO_RC4 != 0 => Ok(PeerStream { inner: stream, decrypt: Some(decrypt), encrypt: Some(encrypt), pending: vec![], unsent: vec![] }),
+        CRYPTO_PLAINTEXT if provide & CRYPTO_PLAINTEXT != 0 => Ok(PeerStream::plain(stream)),
+        _ => Err(Error::new(ErrorKind::InvalidData, "peer selected an encryption method we did not offer")),
+    }
+}
+
+/*
+    Accepts an incoming connection, which either starts with a plaintext BitTorrent handshake or with
+    an MSE handshake for one of the torrents in `info_hashes`. Returns the info hash the peer asked for
+    when it used MSE.
+*/
+pub async fn accept<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S, info_hashes: &[[u8; 20]], policy: EncryptionPolicy) -> Result<(PeerStream<S>, Option<[u8; 20]>), Error> {
+    // as long as the plaintext protocol header, and shorter than any public key
+    let mut start = [0u8; 20];
+    stream.read_exact(&mut start).await?;
+    if start[0] as usize == PROTOCOL.len() && &start[1..] == PROTOCOL {
+        if policy == EncryptionPolicy::Require {
+            return Err(Error::new(ErrorKind::PermissionDenied, "peer does not encrypt the connection"));
+        }
+        let mut plain = PeerStream::plain(stream);
+        plain.pending = start.to_vec();
+        return Ok((plain, None));
+    }
+    if policy == EncryptionPolicy::Disabled {
+        return Err(Error::new(ErrorKind::PermissionDenied, "encrypted connections are disabled"));
+    }
+
+    let mut their_key = [0u8; KEY_LENGTH];
+    their_key[..start.len()].copy_from_slice(&start);
+    stream.read_exact(&mut their_key[start.len()..]).await?;
+    let private_key = random::secure_bytes::<PRIVATE_KEY_LENGTH>()?;
+    let secret = shared_secret(&their_key, &private_key)?;
+    stream.write_all(&with_padding(public_key(&private_key))).await?;
+
+    synchronize(&mut stream, &hash(&[b"req1", &secret]), MAX_PADDING).await?;
+    let mut obfuscated = [0u8; 20];
+    stream.read_exact(&mut obfuscated).await?;
+    let skey_hash = xor(&obfuscated, &hash(&[b"req3", &secret]));
+    let info_hash = *info_hashes.iter()
+        .find(|info_hash| hash(&[b"req2", info_hash.as_slice()]) == skey_hash)
+        .ok_or(Error::new(ErrorKind::NotFound, "peer asked for a torrent we do not have"))?;
+
+    let mut decrypt = Rc4::new(&hash(&[b"keyA", &secret, &info_hash]));
+    let mut encrypt = Rc4::new(&hash(&[b"keyB", &secret, &info_hash]));
+    let mut negotiation = [0u8; 14];
+    stream.read_exact(&mut negotiation).await?;
+    decrypt.apply(&mut negotiation);
+    if negotiation[..8] != VC {
+        return Err(Error::new(ErrorKind::InvalidData, "invalid encryption verification constant"));
+    }
+    let provide = u32::from_be_bytes([negotiation[8], negotiation[9], negotiation[10], negotiation[11]]);
+    let padding = u16::from_be_bytes([negotiation[12], negotiation[13]]) as usize;
+    if padding > MAX_PADDING {
+        return Err(Error::new(ErrorKind::InvalidData, "encryption padding is too long"));
+    }
+    let mut pad = vec![0u8; padding + 2];
+    stream.read_exact(&mut pad).await?;
+    decrypt.apply(&mut pad);
+    let mut initial_payload = vec![0u8; u16::from_be_bytes([pad[padding], pad[padding + 1]]) as usize];
+    stream.read_exact(&mut initial_payload).await?;
+    decrypt.apply(&mut initial_payload);
+
+    let selected = if provide & CRYPTO_RC4 != 0 {
+        CRYPTO_RC4
+    } else if provide & CRYPTO_PLAINTEXT != 0 && policy == EncryptionPolicy::Prefer {
+        CRYPTO_PLAINTEXT
+    } else {
+        return Err(Error::new(ErrorKind::PermissionDenied, "no acceptable encryption method offered"));
+    };
+    let mut answer = VC.to_vec();
+    answer.extend_from_slice(&selected.to_be_bytes());
+    answer.extend_from_slice(&0u16.to_be_bytes());
+    encrypt.apply(&mut answer);
+    stream.write_all(&answer).await?;
+
+    let peer_stream = match selected {
+        CRYPTO_RC4 => PeerStream { inner: stream, decrypt: Some(decrypt), encrypt: Some(encrypt), pending: initial_payload, unsent: vec![] },
+        _ => PeerStream { pending: initial_payload, ..PeerStream::plain(stream) },
+    };
+    Ok((peer_stream, Some(info_hash)))
+}
+
+// reads until `marker` went by, failing when it does not show up within `max_skip` bytes
+async fn synchronize<S: AsyncRead + Unpin>(stream: &mut S, marker: &[u8], max_skip: usize) -> Result<(), Error> {
+    let mut window: Vec<u8> = Vec::with_capacity(max_skip + marker.len());
+    while !window.ends_with(marker) {
+        if window.len() == max_skip + marker.len() {
+            return Err(Error::new(ErrorKind::InvalidData, "encryption handshake did not synchronize"));
+        }
+        window.push(stream.read_u8().await?);
+    }
+    Ok(())
+}
+
+fn with_padding(public_key: [u8; KEY_LENGTH]) -> Vec<u8> {
+    let [length] = random::bytes::<1>();
+    let mut message = public_key.to_vec();
+    let mut padding = vec![0u8; length as usize % (MAX_PADDING + 1)];
+    random::fill(&mut padding);
+    message.extend(padding);
+    message
+}
+
+fn hash(parts: &[&[u8]]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    parts.iter().for_each(|part| hasher.update(part));
+    hasher.finalize().into()
+}
+
+fn xor(a: &[u8; 20], b: &[u8; 20]) -> [u8; 20] {
+    let mut result = [0u8; 20];
+    result.iter_mut().zip(a.iter().zip(b)).for_each(|(out, (a, b))| *out = a ^ b);
+    result
+}
+
+fn public_key(private_key: &[u8]) -> [u8; KEY_LENGTH] {
+    let prime = BigUint::from_be_bytes(&PRIME);
+    BigUint::from_u64(GENERATOR).pow_mod(private_key, &prime).to_be_bytes()
+}
+
+fn shared_secret(their_key: &[u8; KEY_LENGTH], private_key: &[u8]) -> Result<[u8; KEY_LENGTH], Error> {
+    let prime = BigUint::from_be_bytes(&PRIME);
+    let mut last = PRIME;
+    last[KEY_LENGTH - 1] -= 1;
+    let their_key = BigUint::from_be_bytes(their_key);
+    // 0, 1 and p - 1 would make the secret 0, 1 or ±1, known to anyone watching
+    if !BigUint::from_u64(1).less_than(&their_key) || !their_key.less_than(&BigUint::from_be_bytes(&last)) {
+        return Err(Error::new(ErrorKind::InvalidData, "invalid Diffie-Hellman public key"));
+    }
+    Ok(their_key.pow_mod(private_key, &prime).to_be_bytes())
+}
+
+struct Rc4 {
+    state: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+impl Rc4 {
+    fn new(key: &[u8]) -> Self {
+        let mut rc4 = Rc4::without_discard(key);
+        rc4.apply(&mut [0u8; RC4_DISCARD]);
+        rc4
+    }
+
+    // plain RC4 as in the published test vectors
+    fn without_discard(key: &[u8]) -> Self {
+        let mut state = [0u8; 256];
+        state.iter_mut().enumerate().for_each(|(index, value)| *value = index as u8);
+        let mut j = 0u8;
+        for i in 0..256 {
+            j = j.wrapping_add(state[i]).wrapping_add(key[i % key.len()]);
+            state.swap(i, j as usize);
+        }
+        Rc4 { state, i: 0, j: 0 }
+    }
+
+    fn apply(&mut self, data: &mut [u8]) {
+        for byte in data {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.state[self.i as usize]);
+            self.state.swap(self.i as usize, self.j as usize);
+            let index = self.state[self.i as usize].wrapping_add(self.state[self.j as usize]);
+            *byte ^= self.state[index as usize];
+        }
+    }
+}
+
+const LIMBS: usize = KEY_LENGTH / 8;
+
+/*
+    Just enough unsigned big integer arithmetic for the key exchange: 768 bit numbers, least significant
+    limb first. Multiplication is done by doubling and adding modulo the prime, which keeps every
+    intermediate value below twice the prime.
+*/
+#[derive(Clone, Copy)]
+struct BigUint([u64; LIMBS]);
+
+impl BigUint {
+    fn from_u64(value: u64) -> Self {
+        let mut limbs = [0u64; LIMBS];
+        limbs[0] = value;
+        BigUint(limbs)
+    }
+
+    fn from_be_bytes(bytes: &[u8; KEY_LENGTH]) -> Self {
+        let mut limbs = [0u64; LIMBS];
+        for (limb, chunk) in limbs.iter_mut().zip(bytes.rchunks_exact(8)) {
+            *limb = u64::from_be_bytes(chunk.try_into().expect("chunks are 8 bytes"));
+        }
+        BigUint(limbs)
+    }
+
+    fn to_be_bytes(self) -> [u8; KEY_LENGTH] {
+        let mut bytes = [0u8; KEY_LENGTH];
+        for (limb, chunk) in self.0.iter().zip(bytes.rchunks_exact_mut(8)) {
+            chunk.copy_from_slice(&limb.to_be_bytes());
+        }
+        bytes
+    }
+
+    fn less_than(&self, other: &BigUint) -> bool {
+        self.0.iter().rev().cmp(other.0.iter().rev()) == std::cmp::Ordering::Less
+    }
+
+    fn bit(&self, index: usize) -> bool {
+        self.0[index / 64] >> (index % 64) & 1 == 1
+    }
+
+    // (self + other) mod modulus, both operands already reduced
+    fn add_mod(&self, other: &BigUint, modulus: &BigUint) -> BigUint {
+        let mut sum = [0u64; LIMBS];
+        let mut carry = false;
+        for (index, limb) in sum.iter_mut().enumerate() {
+            let (partial, first) = self.0[index].overflowing_add(other.0[index]);
+            let (total, second) = partial.overflowing_add(carry as u64);
+            *limb = total;
+            carry = first || second;
+        }
+        let sum = BigUint(sum);
+        if carry || !sum.less_than(modulus) {
+            sum.wrapping_sub(modulus)
+        } else {
+            sum
+        }
+    }
+
+    fn wrapping_sub(&self, other: &BigUint) -> BigUint {
+        let mut difference = [0u64; LIMBS];
+        let mut borrow = false;
+        for (index, limb) in difference.iter_mut().enumerate() {
+            let (partial, first) = self.0[index].overflowing_sub(other.0[index]);
+            let (total, second) = partial.overflowing_sub(borrow as u64);
+            *limb = total;
+            borrow = first || second;
+        }
+        BigUint(difference)
+    }
+
+    fn mul_mod(&self, other: &BigUint, modulus: &BigUint) -> BigUint {
+        let mut result = BigUint::from_u64(0);
+        for index in (0..LIMBS * 64).rev() {
+            result = result.add_mod(&result, modulus);
+            if other.bit(index) {
+                result = result.add_mod(self, modulus);
+            }
+        }
+        result
+    }
+
+    // exponent is big endian, like the private keys
+    fn pow_mod(&self, exponent: &[u8], modulus: &BigUint) -> BigUint {
+        let mut result = BigUint::from_u64(1);
+        for byte in exponent {
+            for shift in (0..8).rev() {
+                result = result.mul_mod(&result, modulus);
+                if byte >> shift & 1 == 1 {
+                    result = result.mul_mod(self, modulus);
+                }
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{duplex, DuplexStream};
+
+    const INFO_HASH: [u8; 20] = [0x42; 20];
+
+    fn encrypt(key: &[u8], plaintext: &[u8]) -> String {
+        let mut data = plaintext.to_vec();
+        Rc4::without_discard(key).apply(&mut data);
+        hex::encode(data)
+    }
+
+    #[test]
+    fn rc4_known_answers() {
+        assert_eq!(encrypt(b"Key", b"Plaintext"), "bbf316e8d940af0ad3");
+        assert_eq!(encrypt(b"Wiki", b"pedia"), "1021bf0420");
+        assert_eq!(encrypt(b"Secret", b"Attack at dawn"), "45a01f645fc35b383552544b9bf5");
+    }
+
+    #[test]
+    fn rc4_discards_the_start_of_the_keystream() {
+        let mut keystream = [0u8; 8];
+        Rc4::new(b"Key").apply(&mut keystream);
+        assert_eq!(hex::encode(keystream), "ca88075fe00acd8a");
+    }
+
+    #[test]
+    fn public_key_matches_modular_exponentiation() {
+        assert_eq!(public_key(&[0, 0, 3])[..KEY_LENGTH - 1], [0; KEY_LENGTH - 1]);
+        assert_eq!(public_key(&[0, 0, 3])[KEY_LENGTH - 1], 8);
+        let private_key = hex::decode("0123456789abcdef0123456789abcdef01234567").unwrap();
+        // pow(2, private_key, PRIME) computed independently
+        let expected = "6fd4bc7aa649593205ec30348a3ccc737b61fa01e9e1762c2c53eb69033afecbdf7c13b8ac3643af78d0760b0f42db00\
+                        9f2b96c970f009d060faf617f117d0f1c221cea0561b9a86e852fc70a6f09ad0f82378603aa5e56b811deb3f534bf276";
+        assert_eq!(hex::encode(public_key(&private_key)), expected);
+    }
+
+    #[test]
+    fn diffie_hellman_agreement() {
+        let ours = random::bytes::<PRIVATE_KEY_LENGTH>();
+        let theirs = random::bytes::<PRIVATE_KEY_LENGTH>();
+        let secret = shared_secret(&public_key(&theirs), &ours).unwrap();
+        assert_eq!(secret, shared_secret(&public_key(&ours), &theirs).unwrap());
+        assert_ne!(secret, [0; KEY_LENGTH]);
+        assert!(shared_secret(&PRIME, &ours).is_err());
+        assert!(shared_secret(&[0xff; KEY_LENGTH], &ours).is_err());
+    }
+
+    #[test]
+    fn degenerate_public_keys_are_refused() {
+        let ours = random::bytes::<PRIVATE_KEY_LENGTH>();
+        let mut key = [0u8; KEY_LENGTH];
+        assert!(shared_secret(&key, &ours).is_err());
+        key[KEY_LENGTH - 1] = 1;
+        assert!(shared_secret(&key, &ours).is_err());
+        key[KEY_LENGTH - 1] = 2;
+        assert!(shared_secret(&key, &ours).is_ok());
+        let mut key = PRIME;
+        key[KEY_LENGTH - 1] -= 1;
+        assert!(shared_secret(&key, &ours).is_err());
+        key[KEY_LENGTH - 1] -= 1;
+        assert!(shared_secret(&key, &ours).is_ok());
+    }
+
+    // runs both sides of the handshake over an in-memory pipe
+    async fn handshake(initiator: EncryptionPolicy, acceptor: EncryptionPolicy)
+        -> (Result<PeerStream<DuplexStream>, Error>, Result<(PeerStream<DuplexStream>, Option<[u8; 20]>), Error>) {
+        let (outgoing, incoming) = duplex(64 * 1024);
+        let initiate = async move {
+            let mut stream = initiate(outgoing, &INFO_HASH, initiator).await?;
+            // what run_peer sends first, the acceptor tells plaintext connections apart by it
+            let mut bittorrent_handshake = vec![PROTOCOL.len() as u8];
+            bittorrent_handshake.extend_from_slice(PROTOCOL);
+            stream.write_all(&bittorrent_handshake).await?;
+            // encrypted writes may be buffered when the pipe is busy, as with any buffered writer
+            stream.flush().await?;
+            Ok(stream)
+        };
+        tokio::join!(initiate, accept(incoming, &[[1; 20], INFO_HASH], acceptor))
+    }
+
+    // the BitTorrent protocol header and some data in both directions
+    async fn exchange(mut initiator: PeerStream<DuplexStream>, mut acceptor: PeerStream<DuplexStream>) {
+        let mut header = [0u8; 20];
+        acceptor.read_exact(&mut header).await.unwrap();
+        assert_eq!(&header[1..], PROTOCOL);
+        initiator.write_all(b"ping").await.unwrap();
+        initiator.flush().await.unwrap();
+        acceptor.write_all(b"pong").await.unwrap();
+        acceptor.flush().await.unwrap();
+        let (mut ping, mut pong) = ([0u8; 4], [0u8; 4]);
+        acceptor.read_exact(&mut ping).await.unwrap();
+        initiator.read_exact(&mut pong).await.unwrap();
+        assert_eq!((&ping, &pong), (b"ping", b"pong"));
+    }
+
+    #[tokio::test]
+    async fn encrypted_handshake_for_each_accepting_policy() {
+        for (initiator, acceptor) in [
+            (EncryptionPolicy::Prefer, EncryptionPolicy::Prefer),
+            (EncryptionPolicy::Prefer, EncryptionPolicy::Require),
+            (EncryptionPolicy::Require, EncryptionPolicy::Prefer),
+            (EncryptionPolicy::Require, EncryptionPolicy::Require),
+        ] {
+            let (outgoing, incoming) = handshake(initiator, acceptor).await;
+            let (outgoing, (incoming, info_hash)) = (outgoing.unwrap(), incoming.unwrap());
+            assert!(outgoing.is_encrypted() && incoming.is_encrypted(), "{:?} to {:?}", initiator, acceptor);
+            assert_eq!(info_hash, Some(INFO_HASH));
+            exchange(outgoing, incoming).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn plaintext_handshake_when_encryption_is_disabled() {
+        for acceptor in [EncryptionPolicy::Disabled, EncryptionPolicy::Prefer] {
+            let (outgoing, incoming) = handshake(EncryptionPolicy::Disabled, acceptor).await;
+            let (outgoing, (incoming, info_hash)) = (outgoing.unwrap(), incoming.unwrap());
+            assert!(!outgoing.is_encrypted() && !incoming.is_encrypted());
+            assert_eq!(info_hash, None);
+            exchange(outgoing, incoming).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn require_refuses_plaintext_peers() {
+        let (_, incoming) = handshake(EncryptionPolicy::Disabled, EncryptionPolicy::Require).await;
+        assert_eq!(incoming.err().unwrap().kind(), ErrorKind::PermissionDenied);
+    }
+
+    #[tokio::test]
+    async fn disabled_refuses_encrypted_peers() {
+        let (outgoing, incoming) = handshake(EncryptionPolicy::Require, EncryptionPolicy::Disabled).await;
+        assert_eq!(incoming.err().unwrap().kind(), ErrorKind::PermissionDenied);
+        assert!(outgoing.is_err());
+    }
+
+    #[tokio::test]
+    async fn unknown_torrents_are_refused() {
+        let (outgoing, incoming) = duplex(64 * 1024);
+        let (initiated, accepted) = tokio::join!(
+            initiate(outgoing, &[7; 20], EncryptionPolicy::Require),
+            accept(incoming, &[INFO_HASH], EncryptionPolicy::Require),
+        );
+        assert_eq!(accepted.err().unwrap().kind(), ErrorKind::NotFound);
+        assert!(initiated.is_err());
+    }
+
+    #[tokio::test]
+    async fn synchronize_gives_up_after_too_much_padding() {
+        let mut stream: &[u8] = &[1; MAX_PADDING + 8];
+        assert!(synchronize(&mut stream, &[0; 8], MAX_PADDING).await.is_err());
+        let mut data = vec![1; MAX_PADDING];
+        data.extend_from_slice(&[0; 8]);
+        data.push(9);
+        let mut stream: &[u8] = &data;
+        synchronize(&mut stream, &[0; 8], MAX_PADDING).await.unwrap();
+        assert_eq!(stream, &[9]);
+    }
+}
@@ -0,0 +1,236 @@
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+use crate::peers::Peer;
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(60);
+const BASE_RETRY_DELAY: Duration = Duration::from_secs(30);
+const MAX_FAILURES: u32 = 5;
+
+/*
+    Which address family to use when the same peer is known to be reachable over both IPv4 and IPv6.
+    The other family is only tried when the preferred address is failing.
+*/
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum IpPreference {
+    #[default]
+    Ipv6,
+    Ipv4,
+}
+
+impl IpPreference {
+    fn prefers(&self, address: &SocketAddr) -> bool {
+        match self {
+            IpPreference::Ipv6 => address.is_ipv6(),
+            IpPreference::Ipv4 => address.is_ipv4(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CandidateState {
+    Idle,
+    Connected,
+}
+
+struct Candidate {
+    peer_id: Option<[u8; 20]>,
+    state: CandidateState,
+    failures: u32,
+    retry_at: Option<Instant>,
+    // same peer reachable over the other address family
+    alternate: Option<SocketAddr>,
+}
+
+impl Candidate {
+    fn is_available(&self, now: Instant) -> bool {
+        self.state == CandidateState::Idle && self.retry_at.is_none_or(|retry_at| retry_at <= now)
+    }
+}
+
+/*
+    Addresses of peers we know about, from every discovery source, and when we may connect to them.
+*/
+pub struct PeerPool {
+    candidates: HashMap<SocketAddr, Candidate>,
+    preference: IpPreference,
+    // hosts that sent us corrupt data, never connected to again
+    banned: HashSet<IpAddr>,
+}
+
+impl PeerPool {
+    pub fn new(preference: IpPreference) -> Self {
+        PeerPool { candidates: HashMap::new(), preference, banned: HashSet::new() }
+    }
+
+    pub fn add(&mut self, peer: Peer) {
+        let address = peer.address();
+        if self.banned.contains(&address.ip()) {
+            return;
+        }
+        let candidate = self.candidates.entry(address).or_insert(Candidate {
+            peer_id: None,
+            state: CandidateState::Idle,
+            failures: 0,
+            retry_at: None,
+            alternate: None,
+        });
+        if candidate.peer_id.is_none() {
+            candidate.peer_id = peer.peer_id;
+        }
+        if let Some(peer_id) = peer.peer_id {
+            let alternate = self.candidates.iter()
+                .find(|(other, candidate)| other.is_ipv4() != address.is_ipv4() && candidate.peer_id == Some(peer_id))
+                .map(|(other, _)| *other);
+            if let Some(alternate) = alternate {
+                self.link(address, alternate);
+            }
+        }
+    }
+
+    // records that both addresses belong to the same peer
+    pub fn link(&mut self, address: SocketAddr, alternate: SocketAddr) {
+        if !self.candidates.contains_key(&address) || !self.candidates.contains_key(&alternate) {
+            return;
+        }
+        if let Some(candidate) = self.candidates.get_mut(&address) {
+            candidate.alternate = Some(alternate);
+        }
+        if let Some(candidate) = self.candidates.get_mut(&alternate) {
+            candidate.alternate = Some(address);
+        }
+    }
+
+    pub fn next_candidate(&mut self) -> Option<SocketAddr> {
+        let now = Instant::now();
+        let address = self.candidates.iter()
+            .filter(|(_, candidate)| candidate.is_available(now))
+            .filter(|(address, candidate)| !self.defers_to_alternate(address, candidate, now))
+            .min_by_key(|(address, candidate)| (!self.preference.prefers(address), candidate.failures))
+            .map(|(address, _)| *address)?;
+        if let Some(candidate) = self.candidates.get_mut(&address) {
+            candidate.state = CandidateState::Connected;
+        }
+        Some(address)
+    }
+
+    pub fn disconnected(&mut self, address: SocketAddr, failed: bool) {
+        let Some(candidate) = self.candidates.get_mut(&address) else {
+            return;
+        };
+        candidate.state = CandidateState::Idle;
+        if failed {
+            candidate.failures += 1;
+            if candidate.failures >= MAX_FAILURES {
+                self.candidates.remove(&address);
+                return;
+            }
+            candidate.retry_at = Some(Instant::now() + BASE_RETRY_DELAY * (1 << candidate.failures));
+        } else {
+            candidate.failures = 0;
+            candidate.retry_at = Some(Instant::now() + RECONNECT_DELAY);
+        }
+    }
+
+    pub fn ban(&mut self, ip: IpAddr) {
+        self.banned.insert(ip);
+        self.candidates.retain(|address, _| address.ip() != ip);
+    }
+
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        self.banned.contains(&ip)
+    }
+
+    fn defers_to_alternate(&self, address: &SocketAddr, candidate: &Candidate, now: Instant) -> bool {
+        let Some(alternate) = candidate.alternate.and_then(|alternate| self.candidates.get(&alternate)) else {
+            return false;
+        };
+        alternate.state == CandidateState::Connected
+            || (!self.preference.prefers(address) && alternate.is_available(now))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(address: &str, peer_id: Option<u8>) -> Peer {
+        let address: SocketAddr = address.parse().unwrap();
+        let mut peer = Peer::new(address.ip(), address.port());
+        peer.peer_id = peer_id.map(|id| [id; 20]);
+        peer
+    }
+
+    fn address(address: &str) -> SocketAddr {
+        address.parse().unwrap()
+    }
+
+    fn drain(pool: &mut PeerPool) -> Vec<SocketAddr> {
+        std::iter::from_fn(|| pool.next_candidate()).collect()
+    }
+
+    #[test]
+    fn preferred_family_comes_first() {
+        for (preference, first) in [(IpPreference::Ipv6, "[2001:db8::1]:1"), (IpPreference::Ipv4, "10.0.0.1:1")] {
+            let mut pool = PeerPool::new(preference);
+            pool.add(peer("10.0.0.1:1", None));
+            pool.add(peer("[2001:db8::1]:1", None));
+            let candidates = drain(&mut pool);
+            assert_eq!(candidates.len(), 2);
+            assert_eq!(candidates[0], address(first));
+        }
+    }
+
+    #[test]
+    fn peers_with_the_same_id_are_linked() {
+        let mut pool = PeerPool::new(IpPreference::Ipv6);
+        pool.add(peer("10.0.0.1:1", Some(1)));
+        pool.add(peer("10.0.0.2:1", Some(2)));
+        pool.add(peer("[2001:db8::1]:1", Some(1)));
+        // the IPv4 address of the peer is only used when its IPv6 one is not
+        assert_eq!(drain(&mut pool), vec![address("[2001:db8::1]:1"), address("10.0.0.2:1")]);
+        pool.disconnected(address("[2001:db8::1]:1"), true);
+        assert_eq!(drain(&mut pool), vec![address("10.0.0.1:1")]);
+    }
+
+    #[test]
+    fn linked_by_the_extension_handshake() {
+        let mut pool = PeerPool::new(IpPreference::Ipv4);
+        pool.add(peer("10.0.0.1:1", None));
+        pool.add(peer("[2001:db8::1]:1", None));
+        // linking addresses we do not know yet is a no-op
+        pool.link(address("10.0.0.1:1"), address("[2001:db8::9]:1"));
+        pool.link(address("[2001:db8::1]:1"), address("10.0.0.1:1"));
+        assert_eq!(drain(&mut pool), vec![address("10.0.0.1:1")]);
+        // while connected over one family the other address is left alone
+        assert!(drain(&mut pool).is_empty());
+        // and used once the preferred one waits to be reconnected
+        pool.disconnected(address("10.0.0.1:1"), false);
+        assert_eq!(drain(&mut pool), vec![address("[2001:db8::1]:1")]);
+    }
+
+    #[test]
+    fn failing_peers_are_retried_and_dropped() {
+        let mut pool = PeerPool::new(IpPreference::Ipv6);
+        pool.add(peer("10.0.0.1:1", None));
+        for _ in 0..MAX_FAILURES {
+            let candidate = pool.next_candidate().unwrap();
+            pool.disconnected(candidate, true);
+            // waits for the backoff
+            assert!(pool.next_candidate().is_none());
+            pool.candidates.values_mut().for_each(|candidate| candidate.retry_at = None);
+        }
+        assert!(pool.candidates.is_empty());
+    }
+
+    #[test]
+    fn banned_hosts_are_never_candidates() {
+        let mut pool = PeerPool::new(IpPreference::Ipv6);
+        pool.add(peer("10.0.0.1:1", None));
+        pool.add(peer("10.0.0.1:2", None));
+        pool.ban(address("10.0.0.1:1").ip());
+        pool.add(peer("10.0.0.1:3", None));
+        assert!(pool.is_banned(address("10.0.0.1:9").ip()));
+        assert!(drain(&mut pool).is_empty());
+    }
+}
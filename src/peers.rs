@@ -1,63 +1,415 @@
+use std::collections::HashMap;
 use std::fmt::Formatter;
-use std::net::{IpAddr, Ipv4Addr};
-use serde::de::{Error, Visitor};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use serde::de::{Error, SeqAccess, Visitor};
 use serde::{Deserialize, Deserializer};
+use tokio_util::codec::{Decoder, Encoder};
+
+// length prefix of a peer message
+const LENGTH_PREFIX: usize = 4;
+// well above a piece message with the largest block we serve and the bitfield of any reasonable torrent
+const MAX_MESSAGE_LENGTH: usize = 1 << 18;
 
 #[derive(Debug)]
 pub struct PeersContainer {
     pub peers: Vec<Peer>,
 }
 
+// BEP 7 compact IPv6 list, sent by trackers in `peers6`
+#[derive(Debug)]
+pub struct Peers6Container {
+    pub peers: Vec<Peer>,
+}
+
 struct PeersVisitor;
 
-#[derive(Debug)]
+struct Peers6Visitor;
+
+#[derive(Debug, Clone)]
 pub struct Peer {
     pub ip_address: IpAddr,
     pub port: u16,
+    pub peer_id: Option<[u8; 20]>,
+}
+
+// non-compact tracker responses describe every peer with a dictionary
+#[derive(Deserialize)]
+struct DictionaryPeer {
+    #[serde(rename = "peer id", default, with = "serde_bytes")]
+    peer_id: Option<Vec<u8>>,
+    ip: String,
+    port: u16,
+}
+
+impl Peer {
+    pub fn new(ip_address: IpAddr, port: u16) -> Self {
+        Peer { ip_address, port, peer_id: None }
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        SocketAddr::new(self.ip_address, self.port)
+    }
+}
+
+/*
+    Merges peer lists coming from different sources, keeping the first occurrence of every address.
+    Peer id is taken from a later duplicate if the first one did not have it.
+*/
+pub fn merge_peers(lists: impl IntoIterator<Item = Vec<Peer>>) -> Vec<Peer> {
+    let mut merged: Vec<Peer> = vec![];
+    let mut positions: HashMap<SocketAddr, usize> = HashMap::new();
+    for peer in lists.into_iter().flatten() {
+        match positions.get(&peer.address()) {
+            Some(&position) => {
+                if merged[position].peer_id.is_none() {
+                    merged[position].peer_id = peer.peer_id;
+                }
+            }
+            None => {
+                positions.insert(peer.address(), merged.len());
+                merged.push(peer);
+            }
+        }
+    }
+    merged
 }
 
 impl<'de> Visitor<'de> for PeersVisitor {
     type Value = PeersContainer;
 
     fn expecting(&self, formatter: &mut Formatter) -> std::fmt::Result {
-        formatter.write_str("list of peers. 6 bytes per peer, 4 bytes for IP address, 2 bytes for port number")
+        formatter.write_str("list of peers. either 6 bytes per peer, 4 bytes for IP address, 2 bytes for port number, or list of dictionaries with ip and port")
     }
 
     fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E> where E: Error {
         let items: Vec<Peer> = v.chunks_exact(6).map(|chunk| {
-            Peer {
-                ip_address: IpAddr::from(Ipv4Addr::new(chunk[0], chunk[1], chunk[2], chunk[3])),
-                port: u16::from_be_bytes([chunk[4], chunk[5]]),
-            }
+            Peer::new(
+                IpAddr::from(Ipv4Addr::new(chunk[0], chunk[1], chunk[2], chunk[3])),
+                u16::from_be_bytes([chunk[4], chunk[5]]),
+            )
         }).collect();
         Ok(PeersContainer {
             peers: items
         })
     }
+
+    fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error> where A: SeqAccess<'de> {
+        let mut items: Vec<Peer> = vec![];
+        while let Some(item) = seq.next_element::<DictionaryPeer>()? {
+            // ip may also be a dns name, we do not resolve those
+            let Ok(ip_address) = item.ip.parse::<IpAddr>() else {
+                continue;
+            };
+            let peer_id = item.peer_id.and_then(|id| <[u8; 20]>::try_from(id.as_slice()).ok());
+            items.push(Peer { ip_address, port: item.port, peer_id });
+        }
+        Ok(PeersContainer {
+            peers: items
+        })
+    }
 }
 
 impl<'de> Deserialize<'de> for PeersContainer {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error> where D: Deserializer<'de> {
-        deserializer.deserialize_bytes(PeersVisitor)
+        deserializer.deserialize_any(PeersVisitor)
+    }
+}
+
+impl<'de> Visitor<'de> for Peers6Visitor {
+    type Value = Peers6Container;
+
+    fn expecting(&self, formatter: &mut Formatter) -> std::fmt::Result {
+        formatter.write_str("list of peers. 18 bytes per peer, 16 bytes for IP address, 2 bytes for port number")
+    }
+
+    fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E> where E: Error {
+        if !v.len().is_multiple_of(18) {
+            return Err(E::invalid_length(v.len(), &self));
+        }
+        let items: Vec<Peer> = v.chunks_exact(18).map(|chunk| {
+            let mut address = [0u8; 16];
+            address.copy_from_slice(&chunk[..16]);
+            Peer::new(IpAddr::from(Ipv6Addr::from(address)), u16::from_be_bytes([chunk[16], chunk[17]]))
+        }).collect();
+        Ok(Peers6Container {
+            peers: items
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for Peers6Container {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error> where D: Deserializer<'de> {
+        deserializer.deserialize_bytes(Peers6Visitor)
+    }
+}
+
+/*
+    Handshake is the first thing sent on a connection: pstrlen (1 byte), pstr (19 bytes), reserved (8 bytes),
+    info hash (20 bytes) and peer id (20 bytes).
+*/
+pub const PROTOCOL: &[u8; 19] = b"BitTorrent protocol";
+pub const HANDSHAKE_LENGTH: usize = 68;
+
+// BEP 10, reserved byte 5, bit 0x10
+const EXTENSION_PROTOCOL_BIT: (usize, u8) = (5, 0x10);
+// BEP 5, last reserved bit
+const DHT_BIT: (usize, u8) = (7, 0x01);
+// BEP 6, Fast Extension
+const FAST_BIT: (usize, u8) = (7, 0x04);
+// BEP 52, set by peers that can upgrade a hybrid torrent's connection to v2
+const V2_BIT: (usize, u8) = (7, 0x10);
+
+#[derive(Debug, Clone)]
+pub struct Handshake {
+    pub reserved: [u8; 8],
+    pub info_hash: [u8; 20],
+    pub peer_id: [u8; 20],
+}
+
+impl Handshake {
+    pub fn new(info_hash: [u8; 20], peer_id: [u8; 20]) -> Self {
+        let mut reserved = [0u8; 8];
+        reserved[EXTENSION_PROTOCOL_BIT.0] |= EXTENSION_PROTOCOL_BIT.1;
+        reserved[FAST_BIT.0] |= FAST_BIT.1;
+        Handshake { reserved, info_hash, peer_id }
+    }
+
+    pub fn supports_fast(&self) -> bool {
+        self.reserved[FAST_BIT.0] & FAST_BIT.1 != 0
+    }
+
+    pub fn supports_extensions(&self) -> bool {
+        self.reserved[EXTENSION_PROTOCOL_BIT.0] & EXTENSION_PROTOCOL_BIT.1 != 0
+    }
+
+    pub fn enable_dht(&mut self) {
+        self.reserved[DHT_BIT.0] |= DHT_BIT.1;
+    }
+
+    pub fn supports_dht(&self) -> bool {
+        self.reserved[DHT_BIT.0] & DHT_BIT.1 != 0
+    }
+
+    pub fn enable_v2(&mut self) {
+        self.reserved[V2_BIT.0] |= V2_BIT.1;
+    }
+
+    pub fn supports_v2(&self) -> bool {
+        self.reserved[V2_BIT.0] & V2_BIT.1 != 0
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf: Vec<u8> = Vec::with_capacity(HANDSHAKE_LENGTH);
+        buf.push(PROTOCOL.len() as u8);
+        buf.extend_from_slice(PROTOCOL);
+        buf.extend_from_slice(&self.reserved);
+        buf.extend_from_slice(&self.info_hash);
+        buf.extend_from_slice(&self.peer_id);
+        buf
+    }
+
+    pub fn from_bytes(bytes: &[u8; HANDSHAKE_LENGTH]) -> Result<Self, std::io::Error> {
+        if bytes[0] as usize != PROTOCOL.len() || &bytes[1..20] != PROTOCOL {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "peer does not speak BitTorrent protocol"));
+        }
+        let mut handshake = Handshake { reserved: [0; 8], info_hash: [0; 20], peer_id: [0; 20] };
+        handshake.reserved.copy_from_slice(&bytes[20..28]);
+        handshake.info_hash.copy_from_slice(&bytes[28..48]);
+        handshake.peer_id.copy_from_slice(&bytes[48..68]);
+        Ok(handshake)
     }
 }
 
 /*
     Peer messages consist of a message length prefix (4 bytes), message id (1 byte) and a payload (variable size).
+    Zero length prefix without id is a keep-alive.
 */
-#[derive(Eq, PartialEq, Debug)]
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
 pub enum PeerMessageTag {
-    Heartbeat = 0,
+    Choke = 0,
     Unchoke = 1,
     Interested = 2,
+    NotInterested = 3,
+    Have = 4,
     Bitfield = 5,
     Request = 6,
-    Piece = 7
+    Piece = 7,
+    Cancel = 8,
+    // DHT port of the sender
+    Port = 9,
+    // Fast Extension messages, only valid when both sides set the reserved bit
+    SuggestPiece = 13,
+    HaveAll = 14,
+    HaveNone = 15,
+    RejectRequest = 16,
+    AllowedFast = 17,
+    Extended = 20,
+    // BEP 52 merkle tree hashes of v2 torrents
+    HashRequest = 21,
+    Hashes = 22,
+    HashReject = 23,
+}
+
+impl PeerMessageTag {
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(PeerMessageTag::Choke),
+            1 => Some(PeerMessageTag::Unchoke),
+            2 => Some(PeerMessageTag::Interested),
+            3 => Some(PeerMessageTag::NotInterested),
+            4 => Some(PeerMessageTag::Have),
+            5 => Some(PeerMessageTag::Bitfield),
+            6 => Some(PeerMessageTag::Request),
+            7 => Some(PeerMessageTag::Piece),
+            8 => Some(PeerMessageTag::Cancel),
+            9 => Some(PeerMessageTag::Port),
+            13 => Some(PeerMessageTag::SuggestPiece),
+            14 => Some(PeerMessageTag::HaveAll),
+            15 => Some(PeerMessageTag::HaveNone),
+            16 => Some(PeerMessageTag::RejectRequest),
+            17 => Some(PeerMessageTag::AllowedFast),
+            20 => Some(PeerMessageTag::Extended),
+            21 => Some(PeerMessageTag::HashRequest),
+            22 => Some(PeerMessageTag::Hashes),
+            23 => Some(PeerMessageTag::HashReject),
+            _ => None,
+        }
+    }
+
+    // whether a payload of this length is well formed for the message, bitfields are checked against the piece count on receipt
+    pub fn accepts_payload_length(self, length: usize) -> bool {
+        match self {
+            PeerMessageTag::Choke | PeerMessageTag::Unchoke | PeerMessageTag::Interested | PeerMessageTag::NotInterested
+            | PeerMessageTag::HaveAll | PeerMessageTag::HaveNone => length == 0,
+            PeerMessageTag::Have | PeerMessageTag::SuggestPiece | PeerMessageTag::AllowedFast => length == 4,
+            PeerMessageTag::Request | PeerMessageTag::Cancel | PeerMessageTag::RejectRequest => length == 12,
+            PeerMessageTag::Port => length == 2,
+            // index and begin followed by the block
+            PeerMessageTag::Piece => length >= 8,
+            PeerMessageTag::Bitfield => true,
+            // extended message id
+            PeerMessageTag::Extended => length >= 1,
+            PeerMessageTag::HashRequest | PeerMessageTag::HashReject => length == HASH_REQUEST_LENGTH,
+            // the request followed by the hashes
+            PeerMessageTag::Hashes => length >= HASH_REQUEST_LENGTH && (length - HASH_REQUEST_LENGTH).is_multiple_of(32),
+        }
+    }
 }
 
 pub struct PeerMessage {
     pub tag: PeerMessageTag,
-    pub payload: Vec<u8>
+    // received payloads share the read buffer instead of being copied out of it
+    pub payload: Bytes,
+}
+
+impl PeerMessage {
+    pub fn new(tag: PeerMessageTag, payload: Vec<u8>) -> Self {
+        PeerMessage { tag, payload: payload.into() }
+    }
+
+    pub fn have(index: u32) -> Self {
+        PeerMessage::new(PeerMessageTag::Have, index.to_be_bytes().to_vec())
+    }
+
+    pub fn allowed_fast(index: u32) -> Self {
+        PeerMessage::new(PeerMessageTag::AllowedFast, index.to_be_bytes().to_vec())
+    }
+
+    // request payload sent back to tell the peer we are not going to serve it
+    pub fn reject(request: &PeerMessage) -> Self {
+        PeerMessage { tag: PeerMessageTag::RejectRequest, payload: request.payload.clone() }
+    }
+
+    pub fn piece(index: u32, begin_offset: u32, block: &[u8]) -> Self {
+        let mut payload = Vec::with_capacity(8 + block.len());
+        payload.extend_from_slice(&index.to_be_bytes());
+        payload.extend_from_slice(&begin_offset.to_be_bytes());
+        payload.extend_from_slice(block);
+        PeerMessage::new(PeerMessageTag::Piece, payload)
+    }
+
+    pub fn hash_request(request: &HashRequest) -> Self {
+        PeerMessage::new(PeerMessageTag::HashRequest, request.to_bytes())
+    }
+
+    // the requested hashes followed by the proof, as HashRequest::proof_layers asked for
+    pub fn hashes(request: &HashRequest, hashes: &[[u8; 32]]) -> Self {
+        let mut payload = request.to_bytes();
+        hashes.iter().for_each(|hash| payload.extend_from_slice(hash));
+        PeerMessage::new(PeerMessageTag::Hashes, payload)
+    }
+
+    pub fn hash_reject(request: &HashRequest) -> Self {
+        PeerMessage::new(PeerMessageTag::HashReject, request.to_bytes())
+    }
+
+    // reads big endian u32 at given offset of the payload
+    pub fn read_u32(&self, offset: usize) -> Result<u32, std::io::Error> {
+        self.payload.get(offset..offset + 4)
+            .map(|bytes| u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+            .ok_or(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{:?} message is too short", self.tag)))
+    }
+}
+
+/*
+    Peer wire protocol framing, used through `Framed` over any byte stream: TCP, uTP, an encrypted
+    stream or an in-memory pipe. The length prefix comes from the peer, so it is checked against the
+    limit before we wait for (and buffer) that much data.
+*/
+pub struct PeerCodec;
+
+impl Decoder for PeerCodec {
+    type Item = PeerMessage;
+    type Error = std::io::Error;
+
+    fn decode(&mut self, buffer: &mut BytesMut) -> Result<Option<PeerMessage>, std::io::Error> {
+        loop {
+            if buffer.len() < LENGTH_PREFIX {
+                return Ok(None);
+            }
+            let mut message_length = [0u8; LENGTH_PREFIX];
+            message_length.copy_from_slice(&buffer[..LENGTH_PREFIX]);
+            let message_length = u32::from_be_bytes(message_length) as usize;
+
+            // keep-alive
+            if message_length == 0 {
+                buffer.advance(LENGTH_PREFIX);
+                continue;
+            }
+            if message_length > MAX_MESSAGE_LENGTH {
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidData,
+                                               format!("message of {} bytes exceeds the {} byte limit", message_length, MAX_MESSAGE_LENGTH)));
+            }
+            let frame_length = LENGTH_PREFIX + message_length;
+            if buffer.len() < frame_length {
+                buffer.reserve(frame_length - buffer.len());
+                return Ok(None);
+            }
+
+            let message_tag = buffer[LENGTH_PREFIX];
+            let tag = PeerMessageTag::from_id(message_tag)
+                .ok_or(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("unknown message tag received {}", message_tag)))?;
+            if !tag.accepts_payload_length(message_length - 1) {
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidData,
+                                               format!("{:?} message with {} byte payload", tag, message_length - 1)));
+            }
+            let payload = buffer.split_to(frame_length).freeze().slice(LENGTH_PREFIX + 1..);
+            return Ok(Some(PeerMessage { tag, payload }));
+        }
+    }
+}
+
+impl Encoder<PeerMessage> for PeerCodec {
+    type Error = std::io::Error;
+
+    fn encode(&mut self, message: PeerMessage, buffer: &mut BytesMut) -> Result<(), std::io::Error> {
+        buffer.reserve(LENGTH_PREFIX + 1 + message.payload.len());
+        buffer.put_u32(message.payload.len() as u32 + 1);
+        buffer.put_u8(message.tag as u8);
+        buffer.extend_from_slice(&message.payload);
+        Ok(())
+    }
 }
 
 
@@ -83,4 +435,228 @@ impl Request {
         result.extend(self.length);
         result
     }
-}
\ No newline at end of file
+}
+
+// pieces root, base layer, index, length and proof layers
+const HASH_REQUEST_LENGTH: usize = 48;
+
+/*
+    Asks for `length` consecutive nodes of a file's merkle tree, starting at `index` in the layer
+    `base_layer` levels above the leaves, plus the uncle hashes of `proof_layers` layers above them
+    that connect the nodes to the pieces root. Hashes and hash reject messages repeat the request.
+*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HashRequest {
+    pub pieces_root: [u8; 32],
+    pub base_layer: u32,
+    pub index: u32,
+    pub length: u32,
+    pub proof_layers: u32,
+}
+
+impl HashRequest {
+    pub fn from_payload(payload: &[u8]) -> Result<Self, std::io::Error> {
+        let Some(header) = payload.get(..HASH_REQUEST_LENGTH) else {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "hash request is too short"));
+        };
+        let field = |offset: usize| u32::from_be_bytes([header[offset], header[offset + 1], header[offset + 2], header[offset + 3]]);
+        let mut pieces_root = [0u8; 32];
+        pieces_root.copy_from_slice(&header[..32]);
+        Ok(HashRequest { pieces_root, base_layer: field(32), index: field(36), length: field(40), proof_layers: field(44) })
+    }
+
+    // the request a hashes message answers and the hashes following it
+    pub fn parse_hashes(payload: &[u8]) -> Result<(Self, Vec<[u8; 32]>), std::io::Error> {
+        let request = HashRequest::from_payload(payload)?;
+        let hashes = payload[HASH_REQUEST_LENGTH..].chunks_exact(32).map(|hash| hash.try_into().expect("chunks are 32 bytes")).collect();
+        Ok((request, hashes))
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HASH_REQUEST_LENGTH);
+        bytes.extend_from_slice(&self.pieces_root);
+        for field in [self.base_layer, self.index, self.length, self.proof_layers] {
+            bytes.extend_from_slice(&field.to_be_bytes());
+        }
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the peer lists of a tracker response
+    #[derive(Deserialize)]
+    struct Response {
+        #[serde(default)]
+        peers: Option<PeersContainer>,
+        #[serde(default)]
+        peers6: Option<Peers6Container>,
+    }
+
+    fn addresses(peers: &[Peer]) -> Vec<String> {
+        peers.iter().map(|peer| peer.address().to_string()).collect()
+    }
+
+    #[test]
+    fn compact_peers() {
+        let response: Response = serde_bencode::from_bytes(b"d5:peers12:\x0a\x00\x00\x01\x1a\xe1\xc0\xa8\x01\x02\x00\x50e").unwrap();
+        assert_eq!(addresses(&response.peers.unwrap().peers), ["10.0.0.1:6881", "192.168.1.2:80"]);
+    }
+
+    #[test]
+    fn dictionary_peers() {
+        let response: Response = serde_bencode::from_bytes(
+            b"d5:peersld2:ip8:10.0.0.17:peer id20:abcdefghij01234567894:porti6881eed2:ip3:::14:porti80eed2:ip11:example.org4:porti1eeee",
+        ).unwrap();
+        let peers = response.peers.unwrap().peers;
+        assert_eq!(addresses(&peers), ["10.0.0.1:6881", "[::1]:80"]);
+        assert_eq!(peers[0].peer_id, Some(*b"abcdefghij0123456789"));
+        assert_eq!(peers[1].peer_id, None);
+        // a peer id of the wrong length is dropped rather than the peer
+        let response: Response = serde_bencode::from_bytes(b"d5:peersld2:ip8:10.0.0.17:peer id3:abc4:porti1eeee").unwrap();
+        assert_eq!(response.peers.unwrap().peers[0].peer_id, None);
+    }
+
+    #[test]
+    fn compact_ipv6_peers() {
+        let mut entries = vec![];
+        entries.extend_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        entries.extend_from_slice(&6881u16.to_be_bytes());
+        entries.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        entries.extend_from_slice(&80u16.to_be_bytes());
+        let encode = |entries: &[u8]| [format!("d6:peers6{}:", entries.len()).as_bytes(), entries, b"e"].concat();
+        let response: Response = serde_bencode::from_bytes(&encode(&entries)).unwrap();
+        assert_eq!(addresses(&response.peers6.unwrap().peers), ["[2001:db8::1]:6881", "[::1]:80"]);
+        assert!(serde_bencode::from_bytes::<Response>(&encode(&entries[..35])).is_err());
+        assert!(serde_bencode::from_bytes::<Response>(&encode(&[])).unwrap().peers6.unwrap().peers.is_empty());
+    }
+
+    #[test]
+    fn merged_peers_are_unique_by_address() {
+        let mut with_id = Peer::new(IpAddr::from(Ipv4Addr::LOCALHOST), 1);
+        with_id.peer_id = Some([7; 20]);
+        let v6 = Peer::new(IpAddr::from(Ipv6Addr::LOCALHOST), 1);
+        let merged = merge_peers([
+            vec![Peer::new(IpAddr::from(Ipv4Addr::LOCALHOST), 1), Peer::new(IpAddr::from(Ipv4Addr::LOCALHOST), 2)],
+            vec![v6.clone(), with_id, v6],
+        ]);
+        assert_eq!(addresses(&merged), ["127.0.0.1:1", "127.0.0.1:2", "[::1]:1"]);
+        // the id known from the later duplicate is kept
+        assert_eq!(merged[0].peer_id, Some([7; 20]));
+
+        // a dictionary list may carry the IPv6 peers that are in `peers6` too
+        let mut encoded = b"d5:peersld2:ip3:::17:peer id20:abcdefghij01234567894:porti80eee6:peers618:".to_vec();
+        encoded.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        encoded.extend_from_slice(&80u16.to_be_bytes());
+        encoded.push(b'e');
+        let response: Response = serde_bencode::from_bytes(&encoded).unwrap();
+        let merged = merge_peers([response.peers.unwrap().peers, response.peers6.unwrap().peers]);
+        assert_eq!(addresses(&merged), ["[::1]:80"]);
+        assert_eq!(merged[0].peer_id, Some(*b"abcdefghij0123456789"));
+    }
+
+    fn random_below(bound: usize) -> usize {
+        u64::from_le_bytes(crate::random::bytes()) as usize % bound
+    }
+
+    fn random_bytes(len: usize) -> Vec<u8> {
+        let mut bytes = vec![0; len];
+        crate::random::fill(&mut bytes);
+        bytes
+    }
+
+    // feeds the stream in random sized chunks until it is used up or the decoder gives up on it
+    fn decode_in_chunks(stream: &[u8]) -> (Vec<PeerMessage>, Result<(), std::io::Error>) {
+        let (mut codec, mut buffer, mut messages) = (PeerCodec, BytesMut::new(), vec![]);
+        let mut rest = stream;
+        loop {
+            loop {
+                match codec.decode(&mut buffer) {
+                    Ok(Some(message)) => {
+                        assert!(message.payload.len() < MAX_MESSAGE_LENGTH);
+                        assert!(message.tag.accepts_payload_length(message.payload.len()));
+                        messages.push(message);
+                    }
+                    Ok(None) => break,
+                    Err(err) => return (messages, Err(err)),
+                }
+            }
+            // whatever the decoder waits for fits within the limit
+            assert!(buffer.len() < LENGTH_PREFIX + MAX_MESSAGE_LENGTH);
+            if rest.is_empty() {
+                return (messages, Ok(()));
+            }
+            let (chunk, remaining) = rest.split_at(1 + random_below(rest.len().min(64 * 1024)));
+            buffer.extend_from_slice(chunk);
+            rest = remaining;
+        }
+    }
+
+    fn encode(messages: Vec<PeerMessage>) -> Vec<u8> {
+        let mut buffer = BytesMut::new();
+        for message in messages {
+            PeerCodec.encode(message, &mut buffer).unwrap();
+        }
+        buffer.to_vec()
+    }
+
+    #[test]
+    fn split_stream_decodes_like_the_whole_one() {
+        let block = random_bytes(16 * 1024);
+        let messages = || vec![
+            PeerMessage::new(PeerMessageTag::Unchoke, vec![]),
+            PeerMessage::have(42),
+            PeerMessage::piece(1, 16 * 1024, &block),
+            PeerMessage::new(PeerMessageTag::Bitfield, vec![0xff, 0x80]),
+            PeerMessage::new(PeerMessageTag::Extended, vec![0, b'd', b'e']),
+        ];
+        let mut stream = encode(messages());
+        // keep-alives in between are skipped
+        stream.splice(5..5, [0, 0, 0, 0]);
+        stream.extend_from_slice(&[0, 0, 0, 0]);
+        for _ in 0..50 {
+            let (decoded, result) = decode_in_chunks(&stream);
+            result.unwrap();
+            assert_eq!(decoded.len(), 5);
+            for (decoded, expected) in decoded.iter().zip(messages()) {
+                assert_eq!((decoded.tag, &decoded.payload), (expected.tag, &expected.payload));
+            }
+        }
+    }
+
+    #[test]
+    fn random_streams_never_panic() {
+        for _ in 0..500 {
+            let _ = decode_in_chunks(&random_bytes(random_below(4096)));
+        }
+        // frames with plausible lengths and random tags and payloads get past the length check
+        for _ in 0..500 {
+            let mut stream = vec![];
+            for _ in 0..random_below(16) {
+                let length = random_below(40);
+                stream.extend_from_slice(&(length as u32).to_be_bytes());
+                stream.extend_from_slice(&random_bytes(length));
+            }
+            let _ = decode_in_chunks(&stream);
+        }
+    }
+
+    #[test]
+    fn oversized_length_is_refused_before_buffering() {
+        let mut buffer = BytesMut::from(&((MAX_MESSAGE_LENGTH + 1) as u32).to_be_bytes()[..]);
+        assert!(matches!(PeerCodec.decode(&mut buffer), Err(err) if err.kind() == std::io::ErrorKind::InvalidData));
+
+        let mut buffer = BytesMut::from(&(MAX_MESSAGE_LENGTH as u32).to_be_bytes()[..]);
+        assert!(PeerCodec.decode(&mut buffer).unwrap().is_none());
+    }
+
+    #[test]
+    fn malformed_payload_lengths_are_refused() {
+        for frame in [&[0, 0, 0, 2, 1, 0][..], &[0, 0, 0, 4, 4, 0, 0, 0], &[0, 0, 0, 5, 7, 0, 0, 0, 1], &[0, 0, 0, 1, 99]] {
+            let mut buffer = BytesMut::from(frame);
+            assert!(PeerCodec.decode(&mut buffer).is_err(), "{:?}", frame);
+        }
+    }
+}
@@ -0,0 +1,174 @@
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use serde::{Deserialize, Serialize};
+use crate::peers::{PeerMessage, PeerMessageTag};
+
+/*
+    Peer Exchange (BEP 11) message, sent as extended message `ut_pex`. Lists peers the sender connected to
+    or disconnected from since its previous message, in compact form, with one flags byte per added peer.
+*/
+pub const EXTENSION_NAME: &str = "ut_pex";
+// extended message id we ask others to use when sending ut_pex to us
+pub const LOCAL_ID: u8 = 1;
+// BEP 11 allows at most this many added and dropped peers in a single message
+pub const MAX_PEERS_PER_MESSAGE: usize = 50;
+
+pub const FLAG_PREFERS_ENCRYPTION: u8 = 0x01;
+pub const FLAG_SEED: u8 = 0x02;
+pub const FLAG_UTP: u8 = 0x04;
+// peer accepts incoming connections, we only know that for peers we connected to
+pub const FLAG_REACHABLE: u8 = 0x10;
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct PexMessage {
+    #[serde(default, with = "serde_bytes")]
+    pub added: Vec<u8>,
+    #[serde(rename = "added.f", default, with = "serde_bytes")]
+    pub added_flags: Vec<u8>,
+    #[serde(default, with = "serde_bytes")]
+    pub dropped: Vec<u8>,
+    #[serde(default, with = "serde_bytes")]
+    pub added6: Vec<u8>,
+    #[serde(rename = "added6.f", default, with = "serde_bytes")]
+    pub added6_flags: Vec<u8>,
+    #[serde(default, with = "serde_bytes")]
+    pub dropped6: Vec<u8>,
+}
+
+impl PexMessage {
+    pub fn new(added: &[(SocketAddr, u8)], dropped: &[SocketAddr]) -> Self {
+        let mut message = PexMessage::default();
+        for (address, flags) in added.iter().take(MAX_PEERS_PER_MESSAGE) {
+            match address.ip() {
+                IpAddr::V4(ip) => {
+                    message.added.extend_from_slice(&ip.octets());
+                    message.added.extend_from_slice(&address.port().to_be_bytes());
+                    message.added_flags.push(*flags);
+                }
+                IpAddr::V6(ip) => {
+                    message.added6.extend_from_slice(&ip.octets());
+                    message.added6.extend_from_slice(&address.port().to_be_bytes());
+                    message.added6_flags.push(*flags);
+                }
+            }
+        }
+        for address in dropped.iter().take(MAX_PEERS_PER_MESSAGE) {
+            let (target, ip) = match address.ip() {
+                IpAddr::V4(ip) => (&mut message.dropped, ip.octets().to_vec()),
+                IpAddr::V6(ip) => (&mut message.dropped6, ip.octets().to_vec()),
+            };
+            target.extend_from_slice(&ip);
+            target.extend_from_slice(&address.port().to_be_bytes());
+        }
+        message
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.dropped.is_empty() && self.added6.is_empty() && self.dropped6.is_empty()
+    }
+
+    pub fn to_message(&self, remote_id: u8) -> PeerMessage {
+        let mut payload = vec![remote_id];
+        payload.extend(serde_bencode::to_bytes(self).expect("failed to serialize pex message"));
+        PeerMessage::new(PeerMessageTag::Extended, payload)
+    }
+
+    pub fn from_payload(payload: &[u8]) -> Result<Self, Error> {
+        serde_bencode::from_bytes(payload).map_err(|err| Error::new(ErrorKind::InvalidData, err))
+    }
+
+    // added peers with their flags, flags default to 0 when the sender omitted them
+    pub fn added_peers(&self) -> Vec<(SocketAddr, u8)> {
+        let mut peers: Vec<(SocketAddr, u8)> = vec![];
+        for (index, chunk) in self.added.chunks_exact(6).enumerate() {
+            let ip = Ipv4Addr::new(chunk[0], chunk[1], chunk[2], chunk[3]);
+            let port = u16::from_be_bytes([chunk[4], chunk[5]]);
+            peers.push((SocketAddr::from((ip, port)), self.added_flags.get(index).copied().unwrap_or_default()));
+        }
+        for (index, chunk) in self.added6.chunks_exact(18).enumerate() {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&chunk[..16]);
+            let port = u16::from_be_bytes([chunk[16], chunk[17]]);
+            peers.push((SocketAddr::from((Ipv6Addr::from(octets), port)), self.added6_flags.get(index).copied().unwrap_or_default()));
+        }
+        peers.truncate(MAX_PEERS_PER_MESSAGE);
+        peers.retain(|(address, _)| address.port() != 0);
+        peers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v4(last: u8, port: u16) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, last], port))
+    }
+
+    fn v6(last: u16, port: u16) -> SocketAddr {
+        SocketAddr::from((Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, last), port))
+    }
+
+    // the bencoded payload of an extended message, after its id
+    fn payload(message: &PexMessage) -> Vec<u8> {
+        message.to_message(7).payload[1..].to_vec()
+    }
+
+    #[test]
+    fn round_trips_every_field_with_flags() {
+        let added = [(v4(1, 6881), FLAG_SEED), (v6(2, 6882), FLAG_UTP | FLAG_PREFERS_ENCRYPTION), (v4(3, 6883), FLAG_SEED | FLAG_UTP | FLAG_REACHABLE)];
+        let dropped = [v4(4, 6884), v6(5, 6885)];
+        let message = PexMessage::new(&added, &dropped);
+        let sent = message.to_message(7);
+        assert_eq!((sent.tag, sent.payload[0]), (PeerMessageTag::Extended, 7));
+
+        let received = PexMessage::from_payload(&payload(&message)).unwrap();
+        assert_eq!(received.added, [10, 0, 0, 1, 0x1a, 0xe1, 10, 0, 0, 3, 0x1a, 0xe3]);
+        assert_eq!(received.added_flags, [FLAG_SEED, FLAG_SEED | FLAG_UTP | FLAG_REACHABLE]);
+        assert_eq!(received.added6.len(), 18);
+        assert_eq!(received.added6_flags, [FLAG_UTP | FLAG_PREFERS_ENCRYPTION]);
+        assert_eq!(received.dropped, [10, 0, 0, 4, 0x1a, 0xe4]);
+        assert_eq!(received.dropped6[..16], Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 5).octets());
+        assert_eq!(received.dropped6[16..], 6885u16.to_be_bytes());
+        // IPv4 peers come first
+        assert_eq!(received.added_peers(), vec![added[0], added[2], added[1]]);
+    }
+
+    #[test]
+    fn keys_follow_bep_11() {
+        let message = PexMessage::new(&[(v4(1, 1), FLAG_SEED), (v6(1, 1), 0)], &[v4(2, 2), v6(2, 2)]);
+        let encoded = String::from_utf8_lossy(&payload(&message)).into_owned();
+        for key in ["5:added", "7:added.f", "6:added6", "8:added6.f", "7:dropped", "8:dropped6"] {
+            assert!(encoded.contains(key), "{} missing in {}", key, encoded);
+        }
+    }
+
+    #[test]
+    fn missing_fields_and_flags_default() {
+        let received = PexMessage::from_payload(b"d5:added6:\x0a\x00\x00\x01\x1a\xe1e").unwrap();
+        assert_eq!(received.added_peers(), vec![(v4(1, 6881), 0)]);
+        assert!(received.dropped.is_empty() && received.added6.is_empty());
+        assert!(PexMessage::from_payload(b"d5:added").is_err());
+        assert!(PexMessage::from_payload(b"de").unwrap().is_empty());
+    }
+
+    #[test]
+    fn peers_per_message_are_capped() {
+        let added: Vec<(SocketAddr, u8)> = (0..MAX_PEERS_PER_MESSAGE as u16 + 10).map(|port| (v4(1, port + 1), 0)).collect();
+        let dropped: Vec<SocketAddr> = (0..MAX_PEERS_PER_MESSAGE as u16 + 10).map(|port| v6(1, port + 1)).collect();
+        let message = PexMessage::new(&added, &dropped);
+        assert_eq!(message.added.len(), MAX_PEERS_PER_MESSAGE * 6);
+        assert_eq!(message.added_flags.len(), MAX_PEERS_PER_MESSAGE);
+        assert_eq!(message.dropped6.len(), MAX_PEERS_PER_MESSAGE * 18);
+
+        // a sender ignoring the limit gets only the first ones read, and port 0 is skipped
+        let mut oversized = PexMessage::default();
+        for port in 0..MAX_PEERS_PER_MESSAGE as u16 + 10 {
+            oversized.added.extend_from_slice(&[10, 0, 0, 1]);
+            oversized.added.extend_from_slice(&port.to_be_bytes());
+        }
+        let peers = PexMessage::from_payload(&payload(&oversized)).unwrap().added_peers();
+        assert_eq!(peers.len(), MAX_PEERS_PER_MESSAGE - 1);
+        assert_eq!(peers[0], (v4(1, 1), 0));
+    }
+}
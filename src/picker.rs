@@ -0,0 +1,227 @@
+use std::cmp::Reverse;
+
+use crate::bitfield::Bitfield;
+use crate::selection::Priority;
+
+// pieces from the read head on that are fetched before anything else
+const DEADLINE_WINDOW: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PieceState {
+    Missing,
+    // number of peers currently downloading the piece
+    Downloading(u32),
+    Have,
+}
+
+/*
+    Decides which piece a peer should download next. Pieces are picked by priority and rarest first
+    among the ones the peer has, or in order in sequential mode, skipped pieces never. Once a reader
+    set a read head, the DEADLINE_WINDOW pieces from it come first, nearest first. When every
+    remaining piece is already being downloaded, pieces are handed out again to several peers (end
+    game) so that a slow peer does not hold up the last pieces.
+*/
+pub struct PiecePicker {
+    states: Vec<PieceState>,
+    availability: Vec<u32>,
+    priorities: Vec<Priority>,
+    sequential: bool,
+    read_head: Option<usize>,
+}
+
+impl PiecePicker {
+    pub fn new(priorities: Vec<Priority>, sequential: bool) -> Self {
+        PiecePicker {
+            states: vec![PieceState::Missing; priorities.len()],
+            availability: vec![0; priorities.len()],
+            priorities,
+            sequential,
+            read_head: None,
+        }
+    }
+
+    // piece a reader is waiting for, the deadline window slides along with it
+    pub fn set_read_head(&mut self, index: usize) {
+        self.read_head = Some(index);
+    }
+
+    pub fn pick(&mut self, peer_has: &Bitfield) -> Option<usize> {
+        let candidate = self.best(peer_has, |state| state == PieceState::Missing)
+            .or_else(|| self.best(peer_has, |state| matches!(state, PieceState::Downloading(_))))?;
+        self.states[candidate] = match self.states[candidate] {
+            PieceState::Downloading(peers) => PieceState::Downloading(peers + 1),
+            _ => PieceState::Downloading(1),
+        };
+        Some(candidate)
+    }
+
+    // whether peer has anything we still need
+    pub fn is_interesting(&self, peer_has: &Bitfield) -> bool {
+        peer_has.iter_set().any(|index| self.is_wanted(index))
+    }
+
+    // not downloaded yet and not skipped
+    pub fn is_wanted(&self, index: usize) -> bool {
+        self.states[index] != PieceState::Have && self.priorities[index] != Priority::Skip
+    }
+
+    // peer gave up the piece without completing it
+    pub fn release(&mut self, index: usize) {
+        self.states[index] = match self.states[index] {
+            PieceState::Downloading(peers) if peers > 1 => PieceState::Downloading(peers - 1),
+            PieceState::Downloading(_) => PieceState::Missing,
+            state => state,
+        };
+    }
+
+    pub fn mark_have(&mut self, index: usize) {
+        self.states[index] = PieceState::Have;
+    }
+
+    pub fn has(&self, index: usize) -> bool {
+        self.states[index] == PieceState::Have
+    }
+
+    // everything but the skipped pieces is downloaded
+    pub fn is_complete(&self) -> bool {
+        (0..self.states.len()).all(|index| !self.is_wanted(index))
+    }
+
+    pub fn add_availability(&mut self, peer_has: &Bitfield) {
+        peer_has.iter_set().for_each(|index| self.availability[index] += 1);
+    }
+
+    pub fn remove_availability(&mut self, peer_has: &Bitfield) {
+        peer_has.iter_set().for_each(|index| self.availability[index] = self.availability[index].saturating_sub(1));
+    }
+
+    pub fn increment_availability(&mut self, index: usize) {
+        self.availability[index] += 1;
+    }
+
+    fn best(&self, peer_has: &Bitfield, filter: impl Fn(PieceState) -> bool) -> Option<usize> {
+        peer_has.iter_set()
+            .filter(|&index| filter(self.states[index]) && self.priorities[index] != Priority::Skip)
+            .min_by_key(|&index| self.order(index))
+    }
+
+    // lower goes first: the deadline window by distance to the read head, then by priority and index or availability
+    fn order(&self, index: usize) -> (bool, Reverse<Priority>, usize) {
+        match self.read_head {
+            Some(head) if (head..head + DEADLINE_WINDOW).contains(&index) => (false, Reverse(Priority::High), index - head),
+            _ if self.sequential => (true, Reverse(self.priorities[index]), index),
+            _ => (true, Reverse(self.priorities[index]), self.availability[index] as usize),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn has(pieces: &[usize], count: usize) -> Bitfield {
+        let mut bitfield = Bitfield::new(count);
+        pieces.iter().for_each(|index| bitfield.set(*index));
+        bitfield
+    }
+
+    // picks until nothing is left that no peer downloads yet
+    fn picks(picker: &mut PiecePicker, peer_has: &Bitfield) -> Vec<usize> {
+        let mut picked = vec![];
+        while let Some(index) = picker.pick(peer_has).filter(|index| !picked.contains(index)) {
+            picked.push(index);
+        }
+        picked
+    }
+
+    #[test]
+    fn sequential_mode_picks_in_order_by_priority() {
+        use Priority::{High, Low, Normal, Skip};
+        let mut picker = PiecePicker::new(vec![Normal, Low, Normal, Skip, High, Normal], true);
+        picker.add_availability(&has(&[5], 6));
+        assert_eq!(picks(&mut picker, &Bitfield::full(6)), [4, 0, 2, 5, 1]);
+    }
+
+    #[test]
+    fn rarest_pieces_come_first_within_a_priority() {
+        use Priority::{High, Normal};
+        let mut picker = PiecePicker::new(vec![Normal, Normal, Normal, High], false);
+        picker.add_availability(&has(&[0, 1, 2, 3], 4));
+        picker.add_availability(&has(&[0, 2, 3], 4));
+        picker.add_availability(&has(&[0], 4));
+        picker.increment_availability(3);
+        assert_eq!(picks(&mut picker, &Bitfield::full(4)), [3, 1, 2, 0]);
+
+        let mut picker = PiecePicker::new(vec![Normal; 3], false);
+        picker.add_availability(&has(&[0, 1], 3));
+        picker.add_availability(&has(&[0], 3));
+        picker.remove_availability(&has(&[0, 1], 3));
+        assert_eq!(picks(&mut picker, &has(&[0, 1], 3)), [1, 0]);
+    }
+
+    #[test]
+    fn read_head_window_comes_first_nearest_first() {
+        use Priority::{High, Low, Normal, Skip};
+        let mut priorities = vec![Normal; 20];
+        priorities[0] = High;
+        priorities[5] = Low;
+        priorities[7] = Skip;
+        let mut picker = PiecePicker::new(priorities, false);
+        picker.mark_have(4);
+        picker.set_read_head(3);
+        let picked = picks(&mut picker, &Bitfield::full(20));
+        // 3..11 is the window, the piece we have and the skipped one are left out
+        assert_eq!(picked[..6], [3, 5, 6, 8, 9, 10]);
+        assert_eq!(picked[6], 0);
+        assert_eq!(picked.len(), 18);
+
+        // the window slides with the read head
+        let mut picker = PiecePicker::new(vec![Normal; 20], true);
+        picker.set_read_head(2);
+        assert_eq!(picker.pick(&Bitfield::full(20)), Some(2));
+        picker.set_read_head(15);
+        assert_eq!(picker.pick(&Bitfield::full(20)), Some(15));
+        assert_eq!(picker.pick(&has(&[1, 16], 20)), Some(16));
+    }
+
+    #[test]
+    fn end_game_hands_out_pieces_again() {
+        let mut picker = PiecePicker::new(vec![Priority::Normal; 3], true);
+        let all = Bitfield::full(3);
+        assert_eq!(picks(&mut picker, &all), [0, 1, 2]);
+        picker.mark_have(0);
+        // every remaining piece is downloaded already, they get a second peer each
+        assert_eq!(picker.pick(&all), Some(1));
+        assert_eq!(picker.states[1], PieceState::Downloading(2));
+        assert_eq!(picker.pick(&has(&[0, 2], 3)), Some(2));
+        assert_eq!(picker.states[2], PieceState::Downloading(2));
+        assert_eq!(picker.pick(&has(&[0], 3)), None);
+        assert!(picker.is_interesting(&all));
+        assert!(!picker.is_interesting(&has(&[0], 3)));
+    }
+
+    #[test]
+    fn release_counts_the_peers_downloading_a_piece() {
+        let mut picker = PiecePicker::new(vec![Priority::Normal; 2], true);
+        let all = Bitfield::full(2);
+        assert_eq!(picker.pick(&all), Some(0));
+        assert_eq!(picker.pick(&all), Some(1));
+        assert_eq!(picker.pick(&all), Some(0));
+        assert_eq!(picker.states[0], PieceState::Downloading(2));
+        picker.release(0);
+        assert_eq!(picker.states[0], PieceState::Downloading(1));
+        picker.release(0);
+        assert_eq!(picker.states[0], PieceState::Missing);
+        picker.release(0);
+        assert_eq!(picker.states[0], PieceState::Missing);
+        // a missing piece goes before the ones being downloaded
+        assert_eq!(picker.pick(&all), Some(0));
+
+        picker.mark_have(1);
+        picker.release(1);
+        assert!(picker.has(1));
+        picker.mark_have(0);
+        assert!(picker.is_complete());
+        assert_eq!(picker.pick(&all), None);
+    }
+}
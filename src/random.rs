@@ -0,0 +1,42 @@
+use std::collections::hash_map::RandomState;
+use std::fs::File;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{Error, Read};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/*
+    Fills the buffer with random bytes. Kernel randomness is used where available; otherwise bytes come
+    from std's randomly keyed hasher mixed with time and a counter, which is unpredictable enough for
+    node ids, cookies and transaction ids.
+*/
+pub fn fill(buf: &mut [u8]) {
+    if fill_secure(buf).is_ok() {
+        return;
+    }
+    for chunk in buf.chunks_mut(8) {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+        hasher.write_u128(SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_nanos()).unwrap_or_default());
+        chunk.copy_from_slice(&hasher.finish().to_le_bytes()[..chunk.len()]);
+    }
+}
+
+// kernel randomness only, for key material where the fallback of `fill` is too weak
+pub fn fill_secure(buf: &mut [u8]) -> Result<(), Error> {
+    File::open("/dev/urandom")?.read_exact(buf)
+}
+
+pub fn bytes<const N: usize>() -> [u8; N] {
+    let mut buf = [0u8; N];
+    fill(&mut buf);
+    buf
+}
+
+pub fn secure_bytes<const N: usize>() -> Result<[u8; N], Error> {
+    let mut buf = [0u8; N];
+    fill_secure(&mut buf)?;
+    Ok(buf)
+}
@@ -0,0 +1,238 @@
+use std::io::{Error, ErrorKind};
+
+use regex::Regex;
+
+use crate::metainfo::Info;
+
+// how eagerly a file or piece is downloaded, skipped ones not at all
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Skip,
+    Low,
+    Normal,
+    High,
+}
+
+impl Priority {
+    fn parse(name: &str) -> Result<Priority, Error> {
+        match name.to_ascii_lowercase().as_str() {
+            "skip" => Ok(Priority::Skip),
+            "low" => Ok(Priority::Low),
+            "normal" => Ok(Priority::Normal),
+            "high" => Ok(Priority::High),
+            _ => Err(Error::new(ErrorKind::InvalidInput, format!("unknown priority {:?}, expected skip, low, normal or high", name))),
+        }
+    }
+}
+
+// picks files by their index in the torrent or by a glob on their path
+enum FileSelector {
+    Index(usize),
+    Glob(Regex),
+}
+
+impl FileSelector {
+    fn parse(spec: &str, file_count: usize) -> Result<FileSelector, Error> {
+        match spec.parse::<usize>() {
+            Ok(index) if index < file_count => Ok(FileSelector::Index(index)),
+            Ok(index) => Err(Error::new(ErrorKind::InvalidInput, format!("file index {} is out of range, the torrent has {} files", index, file_count))),
+            Err(_) => glob_to_regex(spec).map(FileSelector::Glob),
+        }
+    }
+
+    fn matches(&self, index: usize, path: &str) -> bool {
+        match self {
+            FileSelector::Index(selected) => *selected == index,
+            FileSelector::Glob(regex) => regex.is_match(path),
+        }
+    }
+}
+
+// `*` and `?` stay within a path component, `**` crosses directories
+fn glob_to_regex(glob: &str) -> Result<Regex, Error> {
+    let mut pattern = String::from("^");
+    let mut chars = glob.chars().peekable();
+    while let Some(char) = chars.next() {
+        match char {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                pattern.push_str(".*");
+            }
+            '*' => pattern.push_str("[^/]*"),
+            '?' => pattern.push_str("[^/]"),
+            char => pattern.push_str(&regex::escape(char.encode_utf8(&mut [0; 4]))),
+        }
+    }
+    pattern.push('$');
+    Regex::new(&pattern).map_err(|err| Error::new(ErrorKind::InvalidInput, format!("invalid glob {:?}: {}", glob, err)))
+}
+
+/*
+    Priorities of the torrent's files from the command line. With `only` every file not matching one of
+    its selectors is skipped. `priorities` are `selector=priority` pairs applied in order, so later ones
+    win. Selectors are file indexes or globs matched against the path inside the torrent.
+*/
+pub fn file_priorities(info: &Info, only: &[String], priorities: &[String]) -> Result<Vec<Priority>, Error> {
+    let files = info.file_list();
+    let paths: Vec<String> = files.iter().map(|file| file.path.join("/")).collect();
+    // which files a selector picks, one that picks none is most likely a typo
+    let matching = |spec: &str| -> Result<Vec<bool>, Error> {
+        let selector = FileSelector::parse(spec, paths.len())?;
+        let matches: Vec<bool> = paths.iter().enumerate().map(|(index, path)| selector.matches(index, path)).collect();
+        if !matches.contains(&true) {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("{:?} matches no file of the torrent", spec)));
+        }
+        Ok(matches)
+    };
+    let mut result = vec![Priority::Normal; paths.len()];
+    if !only.is_empty() {
+        let mut selected = vec![false; paths.len()];
+        for spec in only {
+            selected.iter_mut().zip(matching(spec)?).for_each(|(selected, matches)| *selected |= matches);
+        }
+        for (priority, selected) in result.iter_mut().zip(selected) {
+            if !selected {
+                *priority = Priority::Skip;
+            }
+        }
+    }
+    for spec in priorities {
+        let (selector, priority) = spec.rsplit_once('=')
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("priority {:?} is not of the form file=priority", spec)))?;
+        let priority = Priority::parse(priority)?;
+        for (current, matches) in result.iter_mut().zip(matching(selector)?) {
+            if matches {
+                *current = priority;
+            }
+        }
+    }
+    // padding is never downloaded for its own sake, whatever the selectors said
+    for (priority, file) in result.iter_mut().zip(&files) {
+        if file.is_padding() {
+            *priority = Priority::Skip;
+        }
+    }
+    Ok(result)
+}
+
+// a piece is as important as the most important file it overlaps, empty files overlap nothing
+pub fn piece_priorities(info: &Info, file_priorities: &[Priority]) -> Vec<Priority> {
+    let mut result = vec![Priority::Skip; info.piece_count()];
+    let mut offset = 0;
+    for (file, priority) in info.file_list().into_iter().zip(file_priorities) {
+        if file.length > 0 {
+            let first = offset / info.piece_length;
+            let last = ((offset + file.length - 1) / info.piece_length).min(result.len().saturating_sub(1));
+            for piece in result.iter_mut().take(last + 1).skip(first) {
+                *piece = (*piece).max(*priority);
+            }
+        }
+        offset += file.length;
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metainfo::FileEntry;
+    use crate::storage::{Allocation, DiskStorage, Storage};
+
+    const PIECE_LENGTH: usize = 100;
+
+    fn info(files: &[(usize, &str, Option<&str>)]) -> Info {
+        let total: usize = files.iter().map(|(length, _, _)| length).sum();
+        Info {
+            name: "torrent".to_string(),
+            length: None,
+            files: Some(files.iter().map(|(length, path, attr)| FileEntry {
+                length: *length,
+                path: path.split('/').map(str::to_string).collect(),
+                attr: attr.map(str::to_string),
+                symlink_path: None,
+                sha1: None,
+            }).collect()),
+            piece_length: PIECE_LENGTH,
+            pieces: vec![0; total.div_ceil(PIECE_LENGTH) * 20],
+            private: None,
+            attr: None,
+            meta_version: None,
+            file_tree: None,
+        }
+    }
+
+    fn strings(specs: &[&str]) -> Vec<String> {
+        specs.iter().map(|spec| spec.to_string()).collect()
+    }
+
+    fn selected(info: &Info, only: &[&str]) -> Result<Vec<Priority>, Error> {
+        file_priorities(info, &strings(only), &[])
+    }
+
+    #[test]
+    fn single_star_stays_within_a_directory() {
+        use Priority::{Normal, Skip};
+        let info = info(&[(10, "a.mkv", None), (10, "extras/b.mkv", None), (10, "extras/deep/c.mkv", None), (10, "d.txt", None)]);
+        assert_eq!(selected(&info, &["*.mkv"]).unwrap(), [Normal, Skip, Skip, Skip]);
+        assert_eq!(selected(&info, &["**.mkv"]).unwrap(), [Normal, Normal, Normal, Skip]);
+        assert_eq!(selected(&info, &["extras/*"]).unwrap(), [Skip, Normal, Skip, Skip]);
+        assert_eq!(selected(&info, &["extras/**"]).unwrap(), [Skip, Normal, Normal, Skip]);
+        assert_eq!(selected(&info, &["?.txt", "extras/?.mkv"]).unwrap(), [Skip, Normal, Skip, Normal]);
+    }
+
+    #[test]
+    fn index_selectors_must_be_in_range() {
+        let info = info(&[(10, "a", None), (10, "b", None), (10, "c", None)]);
+        assert_eq!(selected(&info, &["2", "0"]).unwrap(), [Priority::Normal, Priority::Skip, Priority::Normal]);
+        let err = selected(&info, &["3"]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert!(err.to_string().contains("out of range"), "{}", err);
+        assert!(file_priorities(&info, &[], &strings(&["7=high"])).is_err());
+    }
+
+    #[test]
+    fn selectors_matching_nothing_are_refused() {
+        let info = info(&[(10, "a.mkv", None), (10, "b.mkv", None)]);
+        let err = selected(&info, &["*.mkv", "*.srt"]).unwrap_err();
+        assert!(err.to_string().contains("matches no file"), "{}", err);
+        assert!(file_priorities(&info, &[], &strings(&["*.srt=high"])).is_err());
+        assert!(file_priorities(&info, &[], &strings(&["a.mkv"])).is_err());
+        assert!(file_priorities(&info, &[], &strings(&["a.mkv=urgent"])).is_err());
+    }
+
+    #[test]
+    fn later_priorities_override_earlier_ones() {
+        let info = info(&[(10, "a.mkv", None), (10, "b.mkv", None), (10, "c.txt", None)]);
+        let priorities = file_priorities(&info, &[], &strings(&["*.mkv=high", "1=low", "*=skip", "0=normal"])).unwrap();
+        assert_eq!(priorities, [Priority::Normal, Priority::Skip, Priority::Skip]);
+        let priorities = file_priorities(&info, &strings(&["*.mkv"]), &strings(&["c.txt=high", "b.mkv=low"])).unwrap();
+        assert_eq!(priorities, [Priority::Normal, Priority::Low, Priority::High]);
+    }
+
+    #[test]
+    fn padding_is_always_skipped() {
+        let info = info(&[(50, "a", None), (50, ".pad/50", Some("p")), (100, "b", None)]);
+        let priorities = file_priorities(&info, &strings(&["**"]), &strings(&["*=high", "1=high"])).unwrap();
+        assert_eq!(priorities, [Priority::High, Priority::Skip, Priority::High]);
+        assert_eq!(piece_priorities(&info, &priorities), [Priority::High, Priority::High]);
+    }
+
+    #[test]
+    fn boundary_pieces_of_skipped_files_go_to_the_partfile() {
+        // piece 1 holds the end of a.mkv and the start of b.nfo, piece 2 is b.nfo's alone
+        let info = info(&[(150, "a.mkv", None), (200, "b.nfo", None)]);
+        let priorities = selected(&info, &["*.mkv"]).unwrap();
+        assert_eq!(priorities, [Priority::Normal, Priority::Skip]);
+        assert_eq!(piece_priorities(&info, &priorities), [Priority::Normal, Priority::Normal, Priority::Skip, Priority::Skip]);
+
+        let directory = tempfile::tempdir().unwrap();
+        let root = directory.path().join("torrent");
+        let storage = DiskStorage::new(&info, &root, Allocation::Sparse, &priorities).unwrap();
+        let piece: Vec<u8> = (0..PIECE_LENGTH as u8).collect();
+        storage.write(1, 0, &piece).unwrap();
+        assert_eq!(storage.read(1, 0, PIECE_LENGTH).unwrap(), piece);
+        assert_eq!(std::fs::read(root.join("a.mkv")).unwrap()[100..], piece[..50]);
+        assert!(!root.join("b.nfo").exists());
+        assert!(std::fs::read(root.join(".parts")).unwrap().windows(50).any(|window| window == &piece[50..]));
+    }
+}
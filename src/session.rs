@@ -0,0 +1,1866 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use bytes::{Bytes, BytesMut};
+use futures::{SinkExt, StreamExt};
+use sha1::{Digest, Sha1};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, mpsc, Notify};
+use tokio::task::JoinSet;
+use tokio::time::timeout;
+use tokio_util::codec::Framed;
+
+use crate::bitfield::Bitfield;
+use crate::dht::{self, Dht};
+use crate::disk::{DiskConfig, DiskIo, Writer};
+use crate::discovery;
+use crate::fast::{self, allowed_fast_set};
+use crate::extension::{ip_to_bytes, split_extended, ExtensionHandshake, CLIENT_NAME, HANDSHAKE_ID};
+use crate::lsd::Lsd;
+use crate::merkle::{self, Hash, MerkleHashes};
+use crate::metainfo::Meta;
+use crate::mse::{self, EncryptionPolicy, PeerStream};
+use crate::peer_pool::{IpPreference, PeerPool};
+use crate::peers::{HashRequest, Handshake, Peer, PeerCodec, PeerMessage, PeerMessageTag, Request, HANDSHAKE_LENGTH};
+use crate::pex::{self, PexMessage};
+use crate::picker::PiecePicker;
+use crate::random;
+use crate::selection::{self, Priority};
+use crate::storage::Storage;
+use crate::stream;
+use crate::tracker::tracker::{TrackerManager, TrackerRequest};
+use crate::transport::Transport;
+use crate::utp::{self, UtpSocket, UtpStream};
+use crate::webseed::WebSeed;
+
+const BLOCK_SIZE: usize = 1 << 14;
+// Azureus style client prefix of our peer ids
+const PEER_ID_PREFIX: &[u8; 8] = b"-RB0100-";
+// largest block we are willing to serve, as most clients do
+const MAX_REQUEST_LENGTH: usize = 1 << 17;
+const PIPELINE_DEPTH: usize = 5;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+// largest UDP payload, so no uTP or DHT packet gets truncated
+const MAX_DATAGRAM_SIZE: usize = 65535;
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const IDLE_TIMEOUT: Duration = Duration::from_secs(3 * 60);
+const CANDIDATE_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+const PEX_INTERVAL: Duration = Duration::from_secs(60);
+// PEX from a peer arriving sooner than this after its previous one is ignored
+const PEX_MIN_INTERVAL: Duration = Duration::from_secs(45);
+
+pub struct SessionConfig {
+    pub listen_port: u16,
+    pub max_peers: usize,
+    pub ip_preference: IpPreference,
+    // IPv6 address to advertise, detected from the routing table when not set
+    pub ipv6: Option<Ipv6Addr>,
+    // DHT is disabled when not set
+    pub dht: Option<DhtConfig>,
+    pub pex: bool,
+    // Local Service Discovery
+    pub lsd: bool,
+    pub encryption: EncryptionPolicy,
+    // try uTP before TCP when connecting and accept uTP connections
+    pub utp: bool,
+    pub disk: DiskConfig,
+    // one per file of the torrent, pieces of skipped files are not downloaded
+    pub file_priorities: Vec<Priority>,
+    // download pieces in order instead of rarest first
+    pub sequential: bool,
+    // local port of the HTTP endpoint streaming the files, which keeps the session running once complete
+    pub stream_port: Option<u16>,
+}
+
+pub struct DhtConfig {
+    pub bootstrap: Vec<String>,
+    // where the routing table is kept between runs
+    pub cache: Option<PathBuf>,
+}
+
+// checks the data of a downloaded piece on a disk worker
+type PieceVerifier = Box<dyn FnOnce(&[u8]) -> bool + Send>;
+
+enum SessionEvent {
+    // peer told us in the extension handshake it is also reachable on another address
+    Alternate { address: SocketAddr, alternate: Peer },
+    Inbound(Transport, SocketAddr),
+    // host sent a block that does not match its merkle tree
+    Ban(IpAddr),
+}
+
+#[derive(Clone, Copy)]
+enum Direction {
+    Outbound,
+    Inbound,
+}
+
+// what we tell other peers about a connected peer through PEX
+struct ConnectedPeer {
+    // address the peer accepts connections on, unknown for inbound peers that did not tell us their port
+    listen_address: Option<SocketAddr>,
+    flags: u8,
+}
+
+struct TorrentState {
+    picker: PiecePicker,
+    have: Bitfield,
+    connected: HashMap<[u8; 20], ConnectedPeer>,
+    external_ipv4: Option<Ipv4Addr>,
+    external_ipv6: Option<Ipv6Addr>,
+    downloaded: usize,
+    uploaded: usize,
+    // merkle trees of v2 and hybrid torrents
+    hashes: Option<MerkleHashes>,
+}
+
+struct Shared {
+    meta: Meta,
+    disk: DiskIo,
+    info_hash: [u8; 20],
+    peer_id: [u8; 20],
+    listen_port: u16,
+    dht: Option<Arc<Dht>>,
+    utp: Option<Arc<UtpSocket>>,
+    pex: bool,
+    encryption: EncryptionPolicy,
+    state: Mutex<TorrentState>,
+    have_tx: broadcast::Sender<u32>,
+    events: mpsc::UnboundedSender<SessionEvent>,
+    // peers found by discovery sources and by PEX
+    found: mpsc::UnboundedSender<Vec<Peer>>,
+    completed: Notify,
+}
+
+impl Shared {
+    fn new(meta: Meta, storage: Arc<dyn Storage>, config: &SessionConfig, dht: Option<Arc<Dht>>, utp: Option<Arc<UtpSocket>>,
+           events: mpsc::UnboundedSender<SessionEvent>, found: mpsc::UnboundedSender<Vec<Peer>>) -> Shared {
+        let piece_count = meta.info.piece_count();
+        Shared {
+            info_hash: <[u8; 20]>::try_from(meta.calculate_info_hash().as_slice()).expect("sha1 is 20 bytes"),
+            peer_id: session_peer_id(),
+            listen_port: config.listen_port,
+            dht,
+            utp,
+            // BEP 27, PEX is off for private torrents
+            pex: config.pex && !meta.info.is_private(),
+            encryption: config.encryption,
+            state: Mutex::new(TorrentState {
+                picker: PiecePicker::new(selection::piece_priorities(&meta.info, &config.file_priorities), config.sequential),
+                have: Bitfield::new(piece_count),
+                connected: HashMap::new(),
+                external_ipv4: None,
+                external_ipv6: config.ipv6.or_else(detect_ipv6),
+                downloaded: 0,
+                uploaded: 0,
+                hashes: MerkleHashes::new(&meta),
+            }),
+            have_tx: broadcast::channel(256).0,
+            events,
+            found,
+            completed: Notify::new(),
+            disk: DiskIo::new(storage, &meta.info, &config.disk),
+            meta,
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, TorrentState> {
+        self.state.lock().expect("torrent state lock poisoned")
+    }
+
+    fn is_complete(&self) -> bool {
+        self.lock().picker.is_complete()
+    }
+
+    fn tracker_request(&self, event: Option<&str>) -> TrackerRequest {
+        let state = self.lock();
+        let left = (0..self.meta.info.piece_count())
+            .filter(|index| state.picker.is_wanted(*index))
+            .map(|index| self.meta.info.piece_len(index))
+            .sum();
+        let mut request = TrackerRequest::new(left);
+        request.peer_id = String::from_utf8_lossy(&self.peer_id).into_owned();
+        request.port = self.listen_port;
+        request.uploaded = state.uploaded;
+        request.downloaded = state.downloaded;
+        request.event = event.map(str::to_string);
+        request.ipv6 = state.external_ipv6.map(|ip| ip.to_string());
+        request
+    }
+
+    // how a downloaded piece is verified, v2 hashes first, None while a v2-only piece's hashes are missing
+    fn piece_verifier(&self, index: usize) -> Option<PieceVerifier> {
+        if let Some(check) = self.lock().hashes.as_ref().and_then(|hashes| hashes.piece_check(index)) {
+            return Some(Box::new(move |data: &[u8]| check.matches(data)));
+        }
+        if !self.meta.info.is_v1() {
+            return None;
+        }
+        let hash = <[u8; 20]>::try_from(self.meta.info.piece_hash(index)).expect("piece hashes are 20 bytes");
+        Some(Box::new(move |data: &[u8]| Sha1::digest(data).as_slice() == hash))
+    }
+
+    // the pieces among `pieces` worth picking, those that cannot be verified yet are left out
+    fn pickable(&self, state: &TorrentState, pieces: Bitfield) -> Bitfield {
+        let Some(hashes) = state.hashes.as_ref().filter(|_| !self.meta.info.is_v1()) else {
+            return pieces;
+        };
+        let mut verifiable = Bitfield::new(pieces.len());
+        pieces.iter_set().filter(|index| hashes.piece_check(*index).is_some()).for_each(|index| verifiable.set(index));
+        verifiable
+    }
+
+    fn complete_piece(&self, index: usize) {
+        let mut state = self.lock();
+        if state.picker.has(index) {
+            return;
+        }
+        state.picker.mark_have(index);
+        state.have.set(index);
+        if state.picker.is_complete() {
+            self.completed.notify_one();
+        }
+        drop(state);
+        let _ = self.have_tx.send(index as u32);
+    }
+
+    // listen addresses of connected peers other than the one we are going to send them to
+    fn pex_peers(&self, exclude: &[u8; 20]) -> HashMap<SocketAddr, u8> {
+        self.lock().connected.iter()
+            .filter(|(peer_id, _)| *peer_id != exclude)
+            .filter_map(|(_, peer)| peer.listen_address.map(|address| (address, peer.flags)))
+            .collect()
+    }
+}
+
+/*
+    Downloads the pieces of the torrent's files that are not skipped: announces to trackers, keeps up
+    to `max_peers` connections, accepts incoming TCP and uTP connections on both IPv4 and IPv6. Pieces
+    are verified and written to `storage` by the disk workers once all their blocks arrived.
+    Peers come from trackers and, unless disabled, from the DHT, PEX and Local Service
+    Discovery. Private torrents only get peers from their own trackers.
+*/
+pub async fn download(meta: Meta, storage: Arc<dyn Storage>, config: SessionConfig) -> Result<(), Error> {
+    let (events_tx, mut events) = mpsc::unbounded_channel();
+    let (found_tx, mut found) = mpsc::unbounded_channel();
+    let info_hash = <[u8; 20]>::try_from(meta.calculate_info_hash().as_slice()).expect("sha1 is 20 bytes");
+    // BEP 27, everything but the torrent's trackers is off for private torrents
+    let private = meta.info.is_private();
+    let dht_config = config.dht.as_ref().filter(|_| !private);
+    // the DHT and uTP share the UDP socket of the listen port, uTP also gets an IPv6 one
+    let udp = if dht_config.is_some() || config.utp {
+        Some(Arc::new(tokio::net::UdpSocket::bind(dht::default_address(config.listen_port)).await?))
+    } else {
+        None
+    };
+    let udp6 = if config.utp {
+        bind_udp6(config.listen_port).map_err(|err| eprintln!("uTP over IPv6 disabled: {}", err)).ok().map(Arc::new)
+    } else {
+        None
+    };
+    let dht = dht_config.zip(udp.clone()).map(|(dht_config, udp)| Dht::new(udp, dht_config.cache.as_deref()));
+    let (utp, utp_incoming) = if config.utp {
+        let (utp, incoming) = UtpSocket::new(udp.clone(), udp6.clone());
+        (Some(utp), Some(incoming))
+    } else {
+        (None, None)
+    };
+    let shared = Arc::new(Shared::new(meta, storage, &config, dht.clone(), utp.clone(), events_tx, found_tx.clone()));
+
+    let mut background = JoinSet::new();
+    for listener in listen(config.listen_port).await? {
+        background.spawn(accept_loop(listener, shared.events.clone()));
+    }
+    if let Some(udp) = udp {
+        background.spawn(udp_loop(udp, dht.clone(), utp.clone()));
+    }
+    if let Some(udp6) = udp6 {
+        background.spawn(udp_loop(udp6, None, utp.clone()));
+    }
+    if let Some(incoming) = utp_incoming {
+        background.spawn(utp_accept_loop(incoming, shared.events.clone()));
+    }
+    let (tracker_tx, tracker_rx) = mpsc::unbounded_channel();
+    let request_shared = shared.clone();
+    let announcer = tokio::spawn(discovery::tracker_loop(
+        TrackerManager::from_meta(&shared.meta),
+        info_hash,
+        move |event| request_shared.tracker_request(event),
+        found_tx.clone(),
+        tracker_rx,
+    ));
+    if let (Some(dht), Some(dht_config)) = (&dht, dht_config) {
+        background.spawn(discovery::dht_loop(dht.clone(), info_hash, config.listen_port, dht_config.bootstrap.clone(), found_tx.clone()));
+    }
+    if config.lsd && !private {
+        match Lsd::bind() {
+            Ok(lsd) => {
+                background.spawn(discovery::lsd_loop(lsd, info_hash, config.listen_port, found_tx));
+            }
+            Err(err) => eprintln!("local service discovery disabled: {}", err),
+        }
+    }
+
+    for url in &shared.meta.url_list {
+        background.spawn(web_seed_loop(shared.clone(), WebSeed::url_seed(url.clone())));
+    }
+    for url in &shared.meta.httpseeds {
+        background.spawn(web_seed_loop(shared.clone(), WebSeed::http_seed(url.clone(), info_hash)));
+    }
+    if let Some(port) = config.stream_port {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port)).await?;
+        background.spawn(stream::serve(listener, TorrentReader::new(shared.clone(), &config.file_priorities)));
+    }
+
+    let mut pool = PeerPool::new(config.ip_preference);
+    let mut peers: JoinSet<(SocketAddr, Result<(), Error>)> = JoinSet::new();
+    // trackers only hear "completed" from the session that finished the download
+    let resumed_complete = shared.is_complete();
+    let mut complete = false;
+    // with a stream server the session goes on seeding until interrupted
+    let interrupted = tokio::signal::ctrl_c();
+    tokio::pin!(interrupted);
+    loop {
+        if !complete && shared.is_complete() {
+            complete = true;
+            if !resumed_complete {
+                let _ = tracker_tx.send("completed");
+            }
+            if config.stream_port.is_none() {
+                break;
+            }
+            eprintln!("download complete, streaming and seeding until interrupted");
+        }
+        while !complete && peers.len() < config.max_peers {
+            let Some(address) = pool.next_candidate() else {
+                break;
+            };
+            peers.spawn(connect_peer(shared.clone(), address));
+        }
+        tokio::select! {
+            Some(discovered) = found.recv() => discovered.into_iter().for_each(|peer| pool.add(peer)),
+            Some(event) = events.recv() => match event {
+                SessionEvent::Alternate { address, alternate } => {
+                    let alternate_address = alternate.address();
+                    pool.add(alternate);
+                    pool.link(address, alternate_address);
+                }
+                SessionEvent::Inbound(stream, address) => {
+                    if peers.len() < config.max_peers && !pool.is_banned(address.ip()) {
+                        let shared = shared.clone();
+                        peers.spawn(async move {
+                            (address, accept_peer(shared, stream, address).await)
+                        });
+                    }
+                }
+                SessionEvent::Ban(ip) => {
+                    eprintln!("banning {} for sending corrupt data", ip);
+                    pool.ban(ip);
+                }
+            },
+            Some(joined) = peers.join_next() => {
+                if let Ok((address, result)) = joined {
+                    if let Err(err) = &result {
+                        eprintln!("peer {} disconnected: {}", address, err);
+                    }
+                    pool.disconnected(address, result.is_err());
+                }
+            }
+            _ = shared.completed.notified() => {}
+            _ = &mut interrupted, if config.stream_port.is_some() => break,
+            _ = tokio::time::sleep(CANDIDATE_RETRY_INTERVAL) => {}
+        }
+    }
+    peers.abort_all();
+    background.abort_all();
+    let _ = tracker_tx.send("stopped");
+    let _ = announcer.await;
+    if let (Some(dht), Some(cache)) = (&dht, dht_config.and_then(|dht_config| dht_config.cache.as_ref())) {
+        if let Err(err) = dht.save(cache) {
+            eprintln!("failed to save DHT nodes to {}: {}", cache.display(), err);
+        }
+    }
+    Ok(())
+}
+
+/*
+    Downloads pieces from a web or HTTP seed for as long as the picker hands out pieces. A web seed has every
+    piece but does not count towards availability, so peers still get the rarest pieces first.
+*/
+async fn web_seed_loop(shared: Arc<Shared>, mut seed: WebSeed) {
+    let everything = Bitfield::full(shared.meta.info.piece_count());
+    loop {
+        let picked = {
+            let mut state = shared.lock();
+            let pieces = shared.pickable(&state, everything.clone());
+            state.picker.pick(&pieces)
+        };
+        let Some(index) = picked else {
+            if shared.is_complete() {
+                return;
+            }
+            // the rest waits for piece layers from peers
+            tokio::time::sleep(CANDIDATE_RETRY_INTERVAL).await;
+            continue;
+        };
+        match web_seed_piece(&shared, &mut seed, index).await {
+            Ok(()) => seed.register_success(),
+            Err(err) => {
+                shared.lock().picker.release(index);
+                let delay = seed.register_failure();
+                eprintln!("web seed {} failed, retrying in {:?}: {}", seed.url, delay, err);
+                tokio::time::sleep(delay).await;
+            }
+        }
+    }
+}
+
+async fn web_seed_piece(shared: &Shared, seed: &mut WebSeed, index: usize) -> Result<(), Error> {
+    let data = seed.fetch_piece(&shared.meta.info, index).await?;
+    shared.lock().downloaded += data.len();
+    // a peer may have finished it meanwhile in end game
+    if shared.lock().have.get(index) {
+        return Ok(());
+    }
+    let verify = shared.piece_verifier(index).ok_or_else(|| Error::other(format!("hashes of piece {} are unknown", index)))?;
+    let writer = shared.disk.writer();
+    shared.disk.write_block(index, writer, 0, Bytes::from(data)).await?;
+    if !shared.disk.finish_piece(index, writer, verify).await? {
+        return Err(Error::new(ErrorKind::InvalidData, format!("piece {} failed hash check", index)));
+    }
+    shared.complete_piece(index);
+    Ok(())
+}
+
+/*
+    Reads the torrent's files while they download. A read waits until the pieces it needs are verified
+    and moves the picker's read head to them, so they are fetched before anything else.
+*/
+#[derive(Clone)]
+pub struct TorrentReader {
+    shared: Arc<Shared>,
+    // path, offset in the content and length of every file that is not skipped, by index
+    files: Arc<Vec<Option<(String, u64, u64)>>>,
+}
+
+impl TorrentReader {
+    fn new(shared: Arc<Shared>, priorities: &[Priority]) -> TorrentReader {
+        let mut offset = 0;
+        let files = shared.meta.info.file_list().into_iter().zip(priorities).map(|(file, priority)| {
+            let readable = (*priority != Priority::Skip).then(|| (file.path.join("/"), offset, file.length as u64));
+            offset += file.length as u64;
+            readable
+        }).collect();
+        TorrentReader { shared, files: Arc::new(files) }
+    }
+
+    // index, path and length of the files that can be read
+    pub fn files(&self) -> Vec<(usize, String, u64)> {
+        self.files.iter().enumerate()
+            .filter_map(|(index, file)| file.as_ref().map(|(path, _, length)| (index, path.clone(), *length)))
+            .collect()
+    }
+
+    pub async fn read(&self, file: usize, offset: u64, length: usize) -> Result<Bytes, Error> {
+        let Some((_, file_offset, file_length)) = self.files.get(file).and_then(Option::as_ref) else {
+            return Err(Error::new(ErrorKind::NotFound, format!("no file {} to read", file)));
+        };
+        if offset + length as u64 > *file_length {
+            return Err(Error::new(ErrorKind::InvalidInput, "read is past the end of the file"));
+        }
+        let piece_length = self.shared.meta.info.piece_length as u64;
+        let end = file_offset + offset + length as u64;
+        let mut position = file_offset + offset;
+        let mut data = BytesMut::with_capacity(length);
+        while position < end {
+            let piece = (position / piece_length) as usize;
+            let begin = position % piece_length;
+            let chunk = (piece_length - begin).min(end - position);
+            self.wait_for(piece).await?;
+            data.extend_from_slice(&self.shared.disk.read(piece, begin as usize, chunk as usize).await?);
+            position += chunk;
+        }
+        Ok(data.freeze())
+    }
+
+    async fn wait_for(&self, piece: usize) -> Result<(), Error> {
+        // subscribed before checking, so the piece cannot complete unnoticed in between
+        let mut have_rx = self.shared.have_tx.subscribe();
+        {
+            let mut state = self.shared.lock();
+            if state.have.get(piece) {
+                return Ok(());
+            }
+            // a skipped piece is never downloaded, the read would wait forever
+            if !state.picker.is_wanted(piece) {
+                return Err(Error::new(ErrorKind::NotFound, format!("piece {} is skipped and never downloaded", piece)));
+            }
+            state.picker.set_read_head(piece);
+        }
+        loop {
+            match have_rx.recv().await {
+                Ok(index) if index as usize == piece => return Ok(()),
+                Ok(_) => {}
+                Err(broadcast::error::RecvError::Lagged(_)) if self.shared.lock().have.get(piece) => return Ok(()),
+                Err(broadcast::error::RecvError::Lagged(_)) => {}
+                Err(broadcast::error::RecvError::Closed) => return Err(Error::other("session stopped")),
+            }
+        }
+    }
+}
+
+/*
+    Binds the listen port on both stacks. [::] is dual stack on most systems, in which case binding
+    0.0.0.0 afterwards fails and the single listener serves both families.
+*/
+async fn listen(port: u16) -> Result<Vec<TcpListener>, Error> {
+    let mut listeners = vec![];
+    let mut last_error = None;
+    for address in [SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)), SocketAddr::from((Ipv4Addr::UNSPECIFIED, port))] {
+        match TcpListener::bind(address).await {
+            Ok(listener) => listeners.push(listener),
+            Err(err) => last_error = Some(err),
+        }
+    }
+    match last_error {
+        Some(err) if listeners.is_empty() => Err(err),
+        _ => Ok(listeners),
+    }
+}
+
+async fn accept_loop(listener: TcpListener, events: mpsc::UnboundedSender<SessionEvent>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, address)) => {
+                // dual stack listener reports IPv4 peers as IPv4-mapped IPv6 addresses
+                let address = SocketAddr::new(address.ip().to_canonical(), address.port());
+                if events.send(SessionEvent::Inbound(Transport::Tcp(stream), address)).is_err() {
+                    return;
+                }
+            }
+            Err(err) => {
+                eprintln!("failed to accept connection: {}", err);
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+        }
+    }
+}
+
+async fn utp_accept_loop(mut incoming: mpsc::Receiver<(UtpStream, SocketAddr)>, events: mpsc::UnboundedSender<SessionEvent>) {
+    while let Some((stream, address)) = incoming.recv().await {
+        if events.send(SessionEvent::Inbound(Transport::Utp(stream), address)).is_err() {
+            return;
+        }
+    }
+}
+
+// IPv6 only, the IPv4 socket of the same port is bound separately for the DHT, which speaks IPv4 only
+fn bind_udp6(port: u16) -> Result<tokio::net::UdpSocket, Error> {
+    let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_only_v6(true)?;
+    socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)).into())?;
+    socket.set_nonblocking(true)?;
+    tokio::net::UdpSocket::from_std(socket.into())
+}
+
+// hands uTP packets to their connections and everything else to the DHT
+async fn udp_loop(socket: Arc<tokio::net::UdpSocket>, dht: Option<Arc<Dht>>, utp: Option<Arc<UtpSocket>>) {
+    let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+    loop {
+        let (length, from) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            // ICMP port unreachable from a previous send surfaces here on some systems
+            Err(_) => continue,
+        };
+        let packet = &buf[..length];
+        match (&utp, &dht) {
+            (Some(utp), _) if utp::is_utp_packet(packet) => utp.handle_packet(packet, from),
+            (_, Some(dht)) => dht.handle_packet(packet, from).await,
+            _ => {}
+        }
+    }
+}
+
+// every session gets its own peer id, so that several instances on one host do not take each other for themselves
+fn session_peer_id() -> [u8; 20] {
+    let mut peer_id = [0u8; 20];
+    peer_id[..PEER_ID_PREFIX.len()].copy_from_slice(PEER_ID_PREFIX);
+    random::fill(&mut peer_id[PEER_ID_PREFIX.len()..]);
+    peer_id[PEER_ID_PREFIX.len()..].iter_mut().for_each(|byte| *byte = b'0' + *byte % 10);
+    peer_id
+}
+
+/*
+    Finds the address we would use to reach the IPv6 internet. Connecting a UDP socket does not send
+    anything, it only selects the source address. Only global unicast addresses are worth advertising.
+*/
+fn detect_ipv6() -> Option<Ipv6Addr> {
+    let socket = UdpSocket::bind(SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0))).ok()?;
+    socket.connect("[2001:4860:4860::8888]:53").ok()?;
+    match socket.local_addr().ok()?.ip() {
+        IpAddr::V6(ip) if is_global_ipv6(&ip) => Some(ip),
+        _ => None,
+    }
+}
+
+// 2000::/3 is the only range handed out for global unicast
+fn is_global_ipv6(ip: &Ipv6Addr) -> bool {
+    ip.segments()[0] & 0xe000 == 0x2000
+}
+
+async fn connect_peer(shared: Arc<Shared>, address: SocketAddr) -> (SocketAddr, Result<(), Error>) {
+    let result = match connect(&shared, address).await {
+        Ok(stream) => run_peer(shared, stream, address, Direction::Outbound).await,
+        Err(err) => Err(err),
+    };
+    (address, result)
+}
+
+async fn connect(shared: &Shared, address: SocketAddr) -> Result<PeerStream<Transport>, Error> {
+    let stream = open_transport(shared, address, true).await?;
+    let utp = matches!(stream, Transport::Utp(_));
+    if shared.encryption == EncryptionPolicy::Disabled {
+        return Ok(PeerStream::plain(stream));
+    }
+    match timeout(HANDSHAKE_TIMEOUT, mse::initiate(stream, &shared.info_hash, shared.encryption)).await {
+        Ok(Ok(stream)) => Ok(stream),
+        // peers without MSE hang up on the key exchange, so the plaintext attempt needs a new connection
+        _ if shared.encryption == EncryptionPolicy::Prefer => Ok(PeerStream::plain(open_transport(shared, address, utp).await?)),
+        Ok(Err(err)) => Err(err),
+        Err(_) => Err(Error::new(ErrorKind::TimedOut, "encryption handshake timed out")),
+    }
+}
+
+// uTP first when enabled and `utp` allows it, TCP when the peer does not answer over uTP
+async fn open_transport(shared: &Shared, address: SocketAddr, utp: bool) -> Result<Transport, Error> {
+    if let (true, Some(socket)) = (utp, &shared.utp) {
+        if let Ok(stream) = socket.connect(address).await {
+            return Ok(Transport::Utp(stream));
+        }
+    }
+    let stream = timeout(CONNECT_TIMEOUT, TcpStream::connect(address)).await
+        .map_err(|_| Error::new(ErrorKind::TimedOut, "connect timed out"))??;
+    Ok(Transport::Tcp(stream))
+}
+
+async fn accept_peer(shared: Arc<Shared>, stream: Transport, address: SocketAddr) -> Result<(), Error> {
+    let (stream, _) = timeout(HANDSHAKE_TIMEOUT, mse::accept(stream, &[shared.info_hash], shared.encryption)).await
+        .map_err(|_| Error::new(ErrorKind::TimedOut, "encryption handshake timed out"))??;
+    run_peer(shared, stream, address, Direction::Inbound).await
+}
+
+async fn exchange_handshake<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S, ours: &Handshake, direction: Direction) -> Result<Handshake, Error> {
+    if let Direction::Outbound = direction {
+        stream.write_all(&ours.to_bytes()).await?;
+        stream.flush().await?;
+    }
+    let mut buf = [0u8; HANDSHAKE_LENGTH];
+    stream.read_exact(&mut buf).await?;
+    let theirs = Handshake::from_bytes(&buf)?;
+    if theirs.info_hash != ours.info_hash {
+        return Err(Error::new(ErrorKind::InvalidData, "peer handshake has different info hash"));
+    }
+    if let Direction::Inbound = direction {
+        stream.write_all(&ours.to_bytes()).await?;
+        stream.flush().await?;
+    }
+    Ok(theirs)
+}
+
+async fn run_peer(shared: Arc<Shared>, mut stream: PeerStream<Transport>, address: SocketAddr, direction: Direction) -> Result<(), Error> {
+    let mut ours = Handshake::new(shared.info_hash, shared.peer_id);
+    if shared.dht.is_some() {
+        ours.enable_dht();
+    }
+    if shared.meta.info.is_v2() {
+        ours.enable_v2();
+    }
+    let theirs = timeout(HANDSHAKE_TIMEOUT, exchange_handshake(&mut stream, &ours, direction)).await
+        .map_err(|_| Error::new(ErrorKind::TimedOut, "handshake timed out"))??;
+    if theirs.peer_id == shared.peer_id {
+        return Err(Error::new(ErrorKind::AddrInUse, "connected to ourselves"));
+    }
+    {
+        let mut state = shared.lock();
+        if state.connected.contains_key(&theirs.peer_id) {
+            return Err(Error::new(ErrorKind::AlreadyExists, "already connected to this peer"));
+        }
+        let mut connected = match direction {
+            Direction::Outbound => ConnectedPeer { listen_address: Some(address), flags: pex::FLAG_REACHABLE },
+            Direction::Inbound => ConnectedPeer { listen_address: None, flags: 0 },
+        };
+        if stream.is_encrypted() {
+            connected.flags |= pex::FLAG_PREFERS_ENCRYPTION;
+        }
+        if let Transport::Utp(_) = stream.get_ref() {
+            connected.flags |= pex::FLAG_UTP;
+        }
+        state.connected.insert(theirs.peer_id, connected);
+    }
+    let piece_count = shared.meta.info.piece_count();
+    let mut peer = PeerConnection {
+        shared: shared.clone(),
+        connection: Framed::new(stream, PeerCodec),
+        address,
+        direction,
+        peer_id: theirs.peer_id,
+        peer_has: Bitfield::new(piece_count),
+        peer_choking: true,
+        peer_interested: false,
+        am_choking: true,
+        am_interested: false,
+        current: None,
+        writer: shared.disk.writer(),
+        fast: ours.supports_fast() && theirs.supports_fast(),
+        // peers of a v2-only torrent speak v2 by definition, hybrid ones say so in the handshake
+        v2: shared.meta.info.is_v2() && (theirs.supports_v2() || !shared.meta.info.is_v1()),
+        allowed_fast: HashSet::new(),
+        peer_allowed_fast: HashSet::new(),
+        suggested: vec![],
+        remote_pex_id: None,
+        pex_sent: HashSet::new(),
+        last_pex_received: None,
+    };
+    let result = peer.run(&theirs).await;
+    peer.cleanup();
+    result
+}
+
+struct PieceDownload {
+    index: usize,
+    length: usize,
+    requested: Vec<bool>,
+    received: Vec<bool>,
+    // v2 leaf hashes of the piece from the peer, checked against the piece layer
+    block_hashes: Option<Vec<Hash>>,
+    // hashes of the blocks received so far, kept for checking them once the leaf hashes arrive
+    received_hashes: Vec<Option<Hash>>,
+    // bytes of the piece covered by leaves, less than its length in the padded last piece of a v2 file
+    file_bytes: usize,
+}
+
+impl PieceDownload {
+    fn new(index: usize, length: usize) -> Self {
+        let blocks = length.div_ceil(BLOCK_SIZE);
+        PieceDownload {
+            index,
+            length,
+            requested: vec![false; blocks],
+            received: vec![false; blocks],
+            block_hashes: None,
+            received_hashes: vec![None; blocks],
+            file_bytes: length,
+        }
+    }
+
+    fn outstanding(&self) -> usize {
+        self.requested.iter().zip(&self.received).filter(|(requested, received)| **requested && !**received).count()
+    }
+
+    // next block to request as (offset, length)
+    fn next_request(&mut self) -> Option<(usize, usize)> {
+        let block = self.requested.iter().position(|requested| !requested)?;
+        self.requested[block] = true;
+        let begin = block * BLOCK_SIZE;
+        Some((begin, BLOCK_SIZE.min(self.length - begin)))
+    }
+
+    fn add_block(&mut self, begin: usize, length: usize) -> Result<(), Error> {
+        let index = begin / BLOCK_SIZE;
+        let expected = BLOCK_SIZE.min(self.length.saturating_sub(begin));
+        if !begin.is_multiple_of(BLOCK_SIZE) || index >= self.received.len() || length != expected {
+            return Err(Error::new(ErrorKind::InvalidData, "peer sent a block we did not request"));
+        }
+        self.received[index] = true;
+        Ok(())
+    }
+
+    fn is_complete(&self) -> bool {
+        self.received.iter().all(|received| *received)
+    }
+
+    // records the hash of a received block, false when it is not the block the leaf hashes expect
+    fn check_block(&mut self, begin: usize, block: &[u8]) -> bool {
+        let Some(file_bytes) = self.file_bytes.checked_sub(begin).filter(|bytes| *bytes > 0) else {
+            // all padding, not part of the tree
+            return true;
+        };
+        let index = begin / BLOCK_SIZE;
+        let hash = merkle::hash_block(&block[..file_bytes.min(block.len())]);
+        self.received_hashes[index] = Some(hash);
+        self.block_hashes.as_ref().is_none_or(|expected| expected.get(index) == Some(&hash))
+    }
+
+    // false when a block received before the leaf hashes does not match them
+    fn set_block_hashes(&mut self, hashes: Vec<Hash>) -> bool {
+        let matching = self.received_hashes.iter().zip(&hashes).all(|(received, expected)| received.is_none_or(|hash| hash == *expected));
+        self.block_hashes = Some(hashes);
+        matching
+    }
+
+    // without the Fast Extension requests are silently dropped by a peer that chokes us
+    fn reset_requests(&mut self) {
+        self.requested.clone_from(&self.received);
+    }
+
+    fn reject(&mut self, begin: usize) {
+        let index = begin / BLOCK_SIZE;
+        if begin.is_multiple_of(BLOCK_SIZE) && index < self.received.len() && !self.received[index] {
+            self.requested[index] = false;
+        }
+    }
+}
+
+struct PeerConnection {
+    shared: Arc<Shared>,
+    connection: Framed<PeerStream<Transport>, PeerCodec>,
+    address: SocketAddr,
+    direction: Direction,
+    peer_id: [u8; 20],
+    peer_has: Bitfield,
+    peer_choking: bool,
+    peer_interested: bool,
+    am_choking: bool,
+    am_interested: bool,
+    current: Option<PieceDownload>,
+    // our blocks of `current` in the write cache
+    writer: Writer,
+    // both sides support the Fast Extension
+    fast: bool,
+    // both sides speak BitTorrent v2 and exchange merkle tree hashes
+    v2: bool,
+    // pieces the peer may request while we choke it
+    allowed_fast: HashSet<u32>,
+    // pieces we may request while the peer chokes us
+    peer_allowed_fast: HashSet<usize>,
+    suggested: Vec<usize>,
+    // extended message id the peer wants ut_pex sent with, when it supports PEX and PEX is enabled
+    remote_pex_id: Option<u8>,
+    // peers the remote knows about from our previous PEX messages
+    pex_sent: HashSet<SocketAddr>,
+    last_pex_received: Option<Instant>,
+}
+
+impl PeerConnection {
+    async fn run(&mut self, handshake: &Handshake) -> Result<(), Error> {
+        if handshake.supports_extensions() {
+            self.send_extension_handshake().await?;
+        }
+        // the opening messages are queued and go out together
+        let have = self.shared.lock().have.clone();
+        if self.fast && have.is_complete() {
+            self.connection.feed(PeerMessage::new(PeerMessageTag::HaveAll, vec![])).await?;
+        } else if self.fast && have.count() == 0 {
+            self.connection.feed(PeerMessage::new(PeerMessageTag::HaveNone, vec![])).await?;
+        } else if have.count() > 0 {
+            self.connection.feed(PeerMessage::new(PeerMessageTag::Bitfield, have.as_bytes().to_vec())).await?;
+        }
+        if self.fast {
+            let piece_count = self.shared.meta.info.piece_count();
+            for index in allowed_fast_set(&self.shared.info_hash, self.address.ip(), piece_count, fast::ALLOWED_FAST_COUNT) {
+                self.connection.feed(PeerMessage::allowed_fast(index)).await?;
+                self.allowed_fast.insert(index);
+            }
+        }
+        if let (Some(dht), true) = (&self.shared.dht, handshake.supports_dht()) {
+            let port = dht.local_addr()?.port();
+            self.connection.feed(PeerMessage::new(PeerMessageTag::Port, port.to_be_bytes().to_vec())).await?;
+        }
+        if self.v2 {
+            let requests = self.shared.lock().hashes.as_ref().map(MerkleHashes::missing_piece_layers).unwrap_or_default();
+            for request in requests {
+                self.connection.feed(PeerMessage::hash_request(&request)).await?;
+            }
+        }
+        self.connection.flush().await?;
+        let mut have_rx = self.shared.have_tx.subscribe();
+        let mut pex_timer = tokio::time::interval(PEX_INTERVAL);
+        loop {
+            self.update_interest().await?;
+            self.request_blocks().await?;
+            tokio::select! {
+                frame = timeout(IDLE_TIMEOUT, self.connection.next()) => match frame {
+                    Ok(Some(Ok(message))) => self.handle_message(message).await?,
+                    Ok(None) => return Ok(()),
+                    Ok(Some(Err(err))) => return Err(err),
+                    Err(_) => return Err(Error::new(ErrorKind::TimedOut, "peer went silent")),
+                },
+                have = have_rx.recv() => match have {
+                    Ok(index) => {
+                        if !self.peer_has.get(index as usize) {
+                            self.connection.send(PeerMessage::have(index)).await?;
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Lagged(_)) => {}
+                    Err(broadcast::error::RecvError::Closed) => return Ok(()),
+                },
+                _ = pex_timer.tick(), if self.remote_pex_id.is_some() => self.send_pex().await?,
+            }
+        }
+    }
+
+    fn cleanup(&mut self) {
+        let mut state = self.shared.lock();
+        state.picker.remove_availability(&self.peer_has);
+        if let Some(download) = self.current.take() {
+            state.picker.release(download.index);
+            self.shared.disk.discard(download.index, self.writer);
+        }
+        state.connected.remove(&self.peer_id);
+    }
+
+    // port the peer accepts connections on, outbound connections already use it
+    fn listen_address(&self, advertised_port: Option<u16>) -> SocketAddr {
+        match (self.direction, advertised_port) {
+            (Direction::Inbound, Some(port)) => SocketAddr::new(self.address.ip(), port),
+            _ => self.address,
+        }
+    }
+
+    async fn send_extension_handshake(&mut self) -> Result<(), Error> {
+        let handshake = {
+            let state = self.shared.lock();
+            let mut extensions = BTreeMap::new();
+            if self.shared.pex {
+                extensions.insert(pex::EXTENSION_NAME.to_string(), pex::LOCAL_ID as i64);
+            }
+            ExtensionHandshake {
+                m: extensions,
+                p: Some(self.shared.listen_port),
+                v: Some(CLIENT_NAME.to_string()),
+                yourip: Some(ip_to_bytes(self.address.ip())),
+                ipv6: state.external_ipv6.map(|ip| ip.octets().to_vec()),
+                ipv4: state.external_ipv4.map(|ip| ip.octets().to_vec()),
+                reqq: Some(250),
+            }
+        };
+        self.connection.send(handshake.to_message()).await
+    }
+
+    fn handle_extension_handshake(&mut self, handshake: ExtensionHandshake) {
+        match handshake.yourip() {
+            Some(IpAddr::V6(ip)) if is_global_ipv6(&ip) => {
+                self.shared.lock().external_ipv6.get_or_insert(ip);
+            }
+            Some(IpAddr::V4(ip)) => {
+                self.shared.lock().external_ipv4.get_or_insert(ip);
+            }
+            _ => {}
+        }
+        if self.shared.pex {
+            self.remote_pex_id = handshake.m.get(pex::EXTENSION_NAME)
+                .and_then(|id| u8::try_from(*id).ok())
+                .filter(|id| *id != 0);
+        }
+        let listen_address = self.listen_address(handshake.p);
+        if let Some(connected) = self.shared.lock().connected.get_mut(&self.peer_id) {
+            connected.listen_address.get_or_insert(listen_address);
+        }
+        let alternate_ip = match listen_address.ip() {
+            IpAddr::V4(_) => handshake.ipv6().map(IpAddr::V6),
+            IpAddr::V6(_) => handshake.ipv4().map(IpAddr::V4),
+        };
+        if let Some(ip) = alternate_ip {
+            let mut alternate = Peer::new(ip, listen_address.port());
+            alternate.peer_id = Some(self.peer_id);
+            let _ = self.shared.events.send(SessionEvent::Alternate { address: listen_address, alternate });
+        }
+    }
+
+    async fn update_interest(&mut self) -> Result<(), Error> {
+        let interesting = self.shared.lock().picker.is_interesting(&self.peer_has);
+        if interesting != self.am_interested {
+            self.am_interested = interesting;
+            let tag = if interesting { PeerMessageTag::Interested } else { PeerMessageTag::NotInterested };
+            self.connection.send(PeerMessage::new(tag, vec![])).await?;
+        }
+        Ok(())
+    }
+
+    async fn request_blocks(&mut self) -> Result<(), Error> {
+        if !self.am_interested || (self.peer_choking && self.peer_allowed_fast.is_empty()) {
+            return Ok(());
+        }
+        if self.current.is_none() {
+            let Some(index) = self.pick_piece() else {
+                return Ok(());
+            };
+            let mut download = PieceDownload::new(index, self.shared.meta.info.piece_len(index));
+            // leaf hashes let us check every block as it arrives
+            let request = match self.shared.lock().hashes.as_ref().filter(|_| self.v2) {
+                Some(hashes) => {
+                    download.file_bytes = hashes.file_bytes(index).unwrap_or(download.length);
+                    hashes.block_hashes_request(index)
+                }
+                None => None,
+            };
+            self.current = Some(download);
+            if let Some(request) = request {
+                self.connection.send(PeerMessage::hash_request(&request)).await?;
+            }
+        }
+        let Some(download) = self.current.as_mut() else {
+            return Ok(());
+        };
+        if self.peer_choking && !self.peer_allowed_fast.contains(&download.index) {
+            return Ok(());
+        }
+        while download.outstanding() < PIPELINE_DEPTH {
+            let Some((begin, length)) = download.next_request() else {
+                break;
+            };
+            let request = Request::new(download.index as u32, begin as u32, length as u32);
+            self.connection.send(PeerMessage::new(PeerMessageTag::Request, request.as_bytes_mute())).await?;
+        }
+        Ok(())
+    }
+
+    // while choked only allowed fast pieces can be requested, otherwise pieces the peer suggested go first
+    fn pick_piece(&self) -> Option<usize> {
+        let mut state = self.shared.lock();
+        if self.peer_choking {
+            let allowed = self.shared.pickable(&state, self.peer_has_among(self.peer_allowed_fast.iter().copied()));
+            return state.picker.pick(&allowed);
+        }
+        let suggested = self.shared.pickable(&state, self.peer_has_among(self.suggested.iter().copied()));
+        let all = self.shared.pickable(&state, self.peer_has.clone());
+        state.picker.pick(&suggested).or_else(|| state.picker.pick(&all))
+    }
+
+    fn peer_has_among(&self, pieces: impl Iterator<Item = usize>) -> Bitfield {
+        let mut bitfield = Bitfield::new(self.peer_has.len());
+        pieces.filter(|index| self.peer_has.get(*index)).for_each(|index| bitfield.set(index));
+        bitfield
+    }
+
+    fn require_fast(&self, tag: PeerMessageTag) -> Result<(), Error> {
+        if self.fast {
+            Ok(())
+        } else {
+            Err(Error::new(ErrorKind::InvalidData, format!("peer sent {:?} without negotiating the fast extension", tag)))
+        }
+    }
+
+    fn require_v2(&self, tag: PeerMessageTag) -> Result<(), Error> {
+        if self.v2 {
+            Ok(())
+        } else {
+            Err(Error::new(ErrorKind::InvalidData, format!("peer sent {:?} on a connection without v2", tag)))
+        }
+    }
+
+    fn replace_peer_has(&mut self, bitfield: Bitfield) {
+        let mut state = self.shared.lock();
+        state.picker.remove_availability(&self.peer_has);
+        state.picker.add_availability(&bitfield);
+        drop(state);
+        self.peer_has = bitfield;
+        self.update_seed_flag();
+    }
+
+    async fn handle_message(&mut self, message: PeerMessage) -> Result<(), Error> {
+        match message.tag {
+            PeerMessageTag::Choke => {
+                self.peer_choking = true;
+                // with the Fast Extension every pending request gets a piece or a reject
+                if let (Some(download), false) = (self.current.as_mut(), self.fast) {
+                    download.reset_requests();
+                }
+            }
+            PeerMessageTag::Unchoke => self.peer_choking = false,
+            PeerMessageTag::Interested => {
+                self.peer_interested = true;
+                if self.am_choking {
+                    self.am_choking = false;
+                    self.connection.send(PeerMessage::new(PeerMessageTag::Unchoke, vec![])).await?;
+                }
+            }
+            PeerMessageTag::NotInterested => self.peer_interested = false,
+            PeerMessageTag::Have => {
+                let index = message.read_u32(0)? as usize;
+                if index < self.peer_has.len() && !self.peer_has.get(index) {
+                    self.peer_has.set(index);
+                    self.shared.lock().picker.increment_availability(index);
+                    self.update_seed_flag();
+                }
+            }
+            PeerMessageTag::Bitfield => {
+                let bitfield = Bitfield::from_bytes(&message.payload, self.peer_has.len()).ok_or_else(|| {
+                    Error::new(ErrorKind::InvalidData, format!("bitfield of {} bytes does not match {} pieces", message.payload.len(), self.peer_has.len()))
+                })?;
+                self.replace_peer_has(bitfield);
+            }
+            PeerMessageTag::HaveAll | PeerMessageTag::HaveNone => {
+                self.require_fast(message.tag)?;
+                let piece_count = self.peer_has.len();
+                let all = message.tag == PeerMessageTag::HaveAll;
+                self.replace_peer_has(if all { Bitfield::full(piece_count) } else { Bitfield::new(piece_count) });
+            }
+            PeerMessageTag::SuggestPiece => {
+                self.require_fast(message.tag)?;
+                let index = message.read_u32(0)? as usize;
+                if index < self.peer_has.len() && !self.suggested.contains(&index) {
+                    if self.suggested.len() == fast::MAX_SUGGESTIONS {
+                        self.suggested.remove(0);
+                    }
+                    self.suggested.push(index);
+                }
+            }
+            PeerMessageTag::RejectRequest => {
+                self.require_fast(message.tag)?;
+                let index = message.read_u32(0)? as usize;
+                let begin = message.read_u32(4)? as usize;
+                if let Some(download) = self.current.as_mut().filter(|download| download.index == index) {
+                    download.reject(begin);
+                }
+            }
+            PeerMessageTag::AllowedFast => {
+                self.require_fast(message.tag)?;
+                let index = message.read_u32(0)? as usize;
+                if index < self.peer_has.len() {
+                    self.peer_allowed_fast.insert(index);
+                }
+            }
+            PeerMessageTag::Request => self.serve_request(&message).await?,
+            PeerMessageTag::Piece => self.receive_block(&message).await?,
+            PeerMessageTag::HashRequest => {
+                self.require_v2(message.tag)?;
+                self.serve_hashes(&message).await?;
+            }
+            PeerMessageTag::Hashes => {
+                self.require_v2(message.tag)?;
+                self.receive_hashes(&message)?;
+            }
+            // another peer may have the hashes
+            PeerMessageTag::HashReject => self.require_v2(message.tag)?,
+            PeerMessageTag::Cancel => {}
+            PeerMessageTag::Port => {
+                if let (Some(dht), [high, low]) = (&self.shared.dht, &message.payload[..]) {
+                    let dht = dht.clone();
+                    let node = SocketAddr::new(self.address.ip(), u16::from_be_bytes([*high, *low]));
+                    tokio::spawn(async move { dht.add_node(node).await });
+                }
+            }
+            PeerMessageTag::Extended => {
+                let (id, payload) = split_extended(&message)?;
+                if id == HANDSHAKE_ID {
+                    let handshake = ExtensionHandshake::from_payload(payload)?;
+                    self.handle_extension_handshake(handshake);
+                } else if id == pex::LOCAL_ID && self.shared.pex {
+                    self.receive_pex(payload)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn update_seed_flag(&mut self) {
+        if self.peer_has.is_complete() {
+            if let Some(connected) = self.shared.lock().connected.get_mut(&self.peer_id) {
+                connected.flags |= pex::FLAG_SEED;
+            }
+        }
+    }
+
+    // tells the peer which peers we connected to and disconnected from since the previous message
+    async fn send_pex(&mut self) -> Result<(), Error> {
+        let Some(remote_id) = self.remote_pex_id else {
+            return Ok(());
+        };
+        let current = self.shared.pex_peers(&self.peer_id);
+        let added: Vec<(SocketAddr, u8)> = current.iter()
+            .filter(|(address, _)| !self.pex_sent.contains(address))
+            .map(|(address, flags)| (*address, *flags))
+            .take(pex::MAX_PEERS_PER_MESSAGE)
+            .collect();
+        let dropped: Vec<SocketAddr> = self.pex_sent.iter()
+            .filter(|address| !current.contains_key(address))
+            .copied()
+            .take(pex::MAX_PEERS_PER_MESSAGE)
+            .collect();
+        let message = PexMessage::new(&added, &dropped);
+        if message.is_empty() {
+            return Ok(());
+        }
+        self.connection.send(message.to_message(remote_id)).await?;
+        dropped.iter().for_each(|address| { self.pex_sent.remove(address); });
+        self.pex_sent.extend(added.iter().map(|(address, _)| *address));
+        Ok(())
+    }
+
+    fn receive_pex(&mut self, payload: &[u8]) -> Result<(), Error> {
+        if self.last_pex_received.is_some_and(|received| received.elapsed() < PEX_MIN_INTERVAL) {
+            return Ok(());
+        }
+        self.last_pex_received = Some(Instant::now());
+        let message = PexMessage::from_payload(payload)?;
+        // seeds are of no use to us once we have everything
+        let complete = self.shared.is_complete();
+        let peers: Vec<Peer> = message.added_peers().into_iter()
+            .filter(|(_, flags)| !(complete && flags & pex::FLAG_SEED != 0))
+            .map(|(address, _)| Peer::new(address.ip(), address.port()))
+            .collect();
+        if !peers.is_empty() {
+            let _ = self.shared.found.send(peers);
+        }
+        Ok(())
+    }
+
+    async fn serve_request(&mut self, message: &PeerMessage) -> Result<(), Error> {
+        let index = message.read_u32(0)?;
+        let begin = message.read_u32(4)? as usize;
+        let length = message.read_u32(8)? as usize;
+        let allowed = !self.am_choking || (self.fast && self.allowed_fast.contains(&index));
+        let piece = index as usize;
+        let available = allowed && length <= MAX_REQUEST_LENGTH && piece < self.shared.meta.info.piece_count()
+            && begin + length <= self.shared.meta.info.piece_len(piece) && self.shared.lock().have.get(piece);
+        let block = if available { Some(self.shared.disk.read(piece, begin, length).await?) } else { None };
+        match block {
+            Some(block) => {
+                self.connection.send(PeerMessage::piece(index, begin as u32, &block)).await?;
+                self.shared.lock().uploaded += block.len();
+            }
+            None if self.fast => self.connection.send(PeerMessage::reject(message)).await?,
+            None => {}
+        }
+        Ok(())
+    }
+
+    async fn serve_hashes(&mut self, message: &PeerMessage) -> Result<(), Error> {
+        let request = HashRequest::from_payload(&message.payload)?;
+        // nodes below the piece layer are computed from the piece, which we need to have
+        let piece = self.shared.lock().hashes.as_ref().and_then(|hashes| hashes.piece_for_request(&request));
+        let data = match piece {
+            Some(piece) if self.shared.lock().have.get(piece) => {
+                Some(self.shared.disk.read(piece, 0, self.shared.meta.info.piece_len(piece)).await?)
+            }
+            _ => None,
+        };
+        let hashes = self.shared.lock().hashes.as_ref().and_then(|hashes| hashes.serve(&request, data.as_deref()));
+        match hashes {
+            Some(hashes) => self.connection.send(PeerMessage::hashes(&request, &hashes)).await,
+            None => self.connection.send(PeerMessage::hash_reject(&request)).await,
+        }
+    }
+
+    fn receive_hashes(&mut self, message: &PeerMessage) -> Result<(), Error> {
+        let (request, hashes) = HashRequest::parse_hashes(&message.payload)?;
+        let blocks = match self.shared.lock().hashes.as_mut() {
+            Some(merkle_hashes) => merkle_hashes.add_hashes(&request, &hashes)?,
+            None => None,
+        };
+        let Some((piece, block_hashes)) = blocks else {
+            return Ok(());
+        };
+        if let Some(download) = self.current.as_mut().filter(|download| download.index == piece) {
+            if !download.set_block_hashes(block_hashes) {
+                return Err(self.ban(piece));
+            }
+        }
+        Ok(())
+    }
+
+    // a block did not match its leaf hash, only the peer that sent it can be at fault
+    fn ban(&self, piece: usize) -> Error {
+        let _ = self.shared.events.send(SessionEvent::Ban(self.address.ip()));
+        Error::new(ErrorKind::InvalidData, format!("peer sent a corrupt block of piece {}", piece))
+    }
+
+    async fn receive_block(&mut self, message: &PeerMessage) -> Result<(), Error> {
+        let index = message.read_u32(0)? as usize;
+        let begin = message.read_u32(4)? as usize;
+        let block = message.payload.slice(8..);
+        let Some(download) = self.current.as_mut().filter(|download| download.index == index) else {
+            // late block for a piece we already gave up on
+            return Ok(());
+        };
+        download.add_block(begin, block.len())?;
+        if self.v2 && !download.check_block(begin, &block) {
+            return Err(self.ban(index));
+        }
+        self.shared.lock().downloaded += block.len();
+        let complete = download.is_complete();
+        // in end game another peer or a web seed may have finished the piece, its blocks are leftovers
+        if self.shared.lock().have.get(index) {
+            self.current = None;
+            self.shared.disk.discard(index, self.writer);
+            return Ok(());
+        }
+        // waits while the write cache is full
+        self.shared.disk.write_block(index, self.writer, begin, block).await?;
+        if !complete {
+            return Ok(());
+        }
+        self.current = None;
+        // another peer or a web seed finished it first, the cached blocks are leftovers
+        if self.shared.lock().have.get(index) {
+            self.shared.disk.discard(index, self.writer);
+            return Ok(());
+        }
+        let Some(verify) = self.shared.piece_verifier(index) else {
+            // only pickable pieces are downloaded, so this is a piece picked while its hashes were known
+            self.shared.disk.discard(index, self.writer);
+            self.shared.lock().picker.release(index);
+            return Ok(());
+        };
+        if !self.shared.disk.finish_piece(index, self.writer, verify).await? {
+            self.shared.lock().picker.release(index);
+            return Err(Error::new(ErrorKind::InvalidData, format!("piece {} failed hash check", index)));
+        }
+        self.shared.complete_piece(index);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{duplex, DuplexStream};
+    use tokio::task::JoinHandle;
+    use crate::disk::DiskConfig;
+    use std::collections::BTreeMap;
+    use serde_bytes::ByteBuf;
+    use crate::metainfo::{FileTree, Info, TreeFile};
+    use crate::storage::MemoryStorage;
+
+    const INFO_HASH: [u8; 20] = [0x11; 20];
+    const PIECE_LENGTH: usize = 2 * BLOCK_SIZE;
+    // how long a test waits for a message it expects
+    const MESSAGE_TIMEOUT: Duration = Duration::from_secs(5);
+
+    // content of a single-file v1 torrent of `pieces` pieces, the last one short
+    fn content(pieces: usize) -> Vec<u8> {
+        (0..(pieces - 1) * PIECE_LENGTH + 1000).map(|index| (index * 7 + index / 251) as u8).collect()
+    }
+
+    fn meta(content: &[u8], private: bool) -> Meta {
+        let info = Info {
+            name: "file".to_string(),
+            length: Some(content.len()),
+            files: None,
+            piece_length: PIECE_LENGTH,
+            pieces: content.chunks(PIECE_LENGTH).flat_map(|piece| Sha1::digest(piece).to_vec()).collect(),
+            private: private.then_some(1),
+            attr: None,
+            meta_version: None,
+            file_tree: None,
+        };
+        let mut torrent = b"d4:info".to_vec();
+        torrent.extend(serde_bencode::to_bytes(&info).unwrap());
+        torrent.push(b'e');
+        serde_bencode::from_bytes(&torrent).unwrap()
+    }
+
+    fn config() -> SessionConfig {
+        SessionConfig {
+            listen_port: 6881,
+            max_peers: 10,
+            ip_preference: IpPreference::default(),
+            ipv6: None,
+            dht: None,
+            pex: true,
+            lsd: false,
+            encryption: EncryptionPolicy::Disabled,
+            utp: false,
+            disk: DiskConfig { threads: 2, write_cache: 1 << 20, read_cache: 1 << 20 },
+            file_priorities: vec![Priority::Normal],
+            sequential: false,
+            stream_port: None,
+        }
+    }
+
+    struct Session {
+        shared: Arc<Shared>,
+        storage: Arc<MemoryStorage>,
+        events: mpsc::UnboundedReceiver<SessionEvent>,
+        found: mpsc::UnboundedReceiver<Vec<Peer>>,
+    }
+
+    fn session(meta: Meta, config: &SessionConfig) -> Session {
+        let (events_tx, events) = mpsc::unbounded_channel();
+        let (found_tx, found) = mpsc::unbounded_channel();
+        let storage = Arc::new(MemoryStorage::new(&meta.info));
+        let shared = Arc::new(Shared::new(meta, storage.clone(), config, None, None, events_tx, found_tx));
+        Session { shared, storage, events, found }
+    }
+
+    // a session that already has every piece of `content`
+    fn seeding_session(meta: Meta, content: &[u8]) -> Session {
+        let session = session(meta, &config());
+        for (index, piece) in content.chunks(PIECE_LENGTH).enumerate() {
+            session.storage.write(index, 0, piece).unwrap();
+            session.shared.complete_piece(index);
+        }
+        session
+    }
+
+    // the other end of a connection our session accepted over loopback TCP
+    struct RemotePeer {
+        connection: Framed<TcpStream, PeerCodec>,
+        task: JoinHandle<Result<(), Error>>,
+    }
+
+    impl RemotePeer {
+        async fn connect(shared: &Arc<Shared>, handshake: Handshake) -> RemotePeer {
+            let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+            let mut stream = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+            let (accepted, address) = listener.accept().await.unwrap();
+            let task = tokio::spawn(run_peer(shared.clone(), PeerStream::plain(Transport::Tcp(accepted)), address, Direction::Inbound));
+            stream.write_all(&handshake.to_bytes()).await.unwrap();
+            let mut buf = [0; HANDSHAKE_LENGTH];
+            stream.read_exact(&mut buf).await.unwrap();
+            RemotePeer { connection: Framed::new(stream, PeerCodec), task }
+        }
+
+        async fn send(&mut self, message: PeerMessage) {
+            self.connection.send(message).await.unwrap();
+        }
+
+        async fn next(&mut self) -> PeerMessage {
+            timeout(MESSAGE_TIMEOUT, self.connection.next()).await.expect("no message from the session").unwrap().unwrap()
+        }
+
+        // skips messages until one with `tag`
+        async fn expect(&mut self, tag: PeerMessageTag) -> PeerMessage {
+            loop {
+                let message = self.next().await;
+                if message.tag == tag {
+                    return message;
+                }
+            }
+        }
+
+        // collects everything the session sends until it answers our Interested with an Unchoke
+        async fn sync(&mut self) -> Vec<PeerMessage> {
+            self.send(PeerMessage::new(PeerMessageTag::Interested, vec![])).await;
+            let mut received = vec![];
+            loop {
+                let message = self.next().await;
+                if message.tag == PeerMessageTag::Unchoke {
+                    return received;
+                }
+                received.push(message);
+            }
+        }
+    }
+
+    fn remote_handshake(shared: &Shared) -> Handshake {
+        Handshake::new(shared.info_hash, [9; 20])
+    }
+
+    fn without_fast(mut handshake: Handshake) -> Handshake {
+        handshake.reserved[7] = 0;
+        assert!(!handshake.supports_fast());
+        handshake
+    }
+
+    fn request(index: usize, begin: usize, length: usize) -> PeerMessage {
+        PeerMessage::new(PeerMessageTag::Request, Request::new(index as u32, begin as u32, length as u32).as_bytes_mute())
+    }
+
+    // index, offset and length of a request or reject, index and offset of a piece
+    fn block_of(message: &PeerMessage) -> (usize, usize, usize) {
+        let length = match message.tag {
+            PeerMessageTag::Piece => message.payload.len() - 8,
+            _ => message.read_u32(8).unwrap() as usize,
+        };
+        (message.read_u32(0).unwrap() as usize, message.read_u32(4).unwrap() as usize, length)
+    }
+
+    fn extended(id: u8, payload: Vec<u8>) -> PeerMessage {
+        let mut extended = vec![id];
+        extended.extend(payload);
+        PeerMessage::new(PeerMessageTag::Extended, extended)
+    }
+
+    async fn handshake_pair(outbound: Handshake, inbound: Handshake) -> (Result<Handshake, Error>, Result<Handshake, Error>, DuplexStream, DuplexStream) {
+        let (mut ours, mut theirs) = duplex(64 * 1024);
+        let (outgoing, incoming) = tokio::join!(
+            exchange_handshake(&mut ours, &outbound, Direction::Outbound),
+            exchange_handshake(&mut theirs, &inbound, Direction::Inbound),
+        );
+        (outgoing, incoming, ours, theirs)
+    }
+
+    #[test]
+    fn only_global_ipv6_addresses_are_advertised() {
+        for (ip, global) in [("2001:db8::1", true), ("2a00:1450::1", true), ("::1", false), ("fe80::1", false), ("fd00::1", false), ("ff02::1", false)] {
+            assert_eq!(is_global_ipv6(&ip.parse().unwrap()), global, "{}", ip);
+        }
+    }
+
+    #[tokio::test]
+    async fn handshake_over_an_in_memory_pipe() {
+        let mut outbound = Handshake::new(INFO_HASH, [1; 20]);
+        outbound.enable_dht();
+        let (outgoing, incoming, _, _) = handshake_pair(outbound, Handshake::new(INFO_HASH, [2; 20])).await;
+        let (outgoing, incoming) = (outgoing.unwrap(), incoming.unwrap());
+        assert_eq!((outgoing.peer_id, incoming.peer_id), ([2; 20], [1; 20]));
+        assert!(incoming.supports_dht() && !outgoing.supports_dht());
+        assert!(outgoing.supports_fast() && outgoing.supports_extensions());
+    }
+
+    #[tokio::test]
+    async fn handshake_for_another_torrent_is_refused_before_answering() {
+        let (mut ours, mut theirs) = duplex(64 * 1024);
+        let (outbound, inbound) = (Handshake::new([0x22; 20], [1; 20]), Handshake::new(INFO_HASH, [2; 20]));
+        let (outgoing, incoming) = tokio::join!(exchange_handshake(&mut ours, &outbound, Direction::Outbound), async move {
+            // the connection is dropped as soon as the handshake fails
+            exchange_handshake(&mut theirs, &inbound, Direction::Inbound).await
+        });
+        assert_eq!(incoming.unwrap_err().kind(), ErrorKind::InvalidData);
+        // the inbound side never sent its handshake, so the outbound one sees the pipe close
+        assert_eq!(outgoing.unwrap_err().kind(), ErrorKind::UnexpectedEof);
+    }
+
+    // the messages a download consists of, in both directions
+    async fn exchange_messages(ours: PeerStream<DuplexStream>, theirs: PeerStream<DuplexStream>) {
+        let mut ours = Framed::new(ours, PeerCodec);
+        let mut theirs = Framed::new(theirs, PeerCodec);
+        ours.send(PeerMessage::new(PeerMessageTag::Interested, vec![])).await.unwrap();
+        ours.send(PeerMessage::new(PeerMessageTag::Request, Request::new(3, 0, BLOCK_SIZE as u32).as_bytes_mute())).await.unwrap();
+        // keep-alives are skipped by the decoder
+        ours.get_mut().write_all(&[0, 0, 0, 0]).await.unwrap();
+        ours.send(PeerMessage::have(7)).await.unwrap();
+
+        let message = theirs.next().await.unwrap().unwrap();
+        assert_eq!(message.tag, PeerMessageTag::Interested);
+        let request = theirs.next().await.unwrap().unwrap();
+        assert_eq!(request.tag, PeerMessageTag::Request);
+        assert_eq!((request.read_u32(0).unwrap(), request.read_u32(4).unwrap(), request.read_u32(8).unwrap()), (3, 0, BLOCK_SIZE as u32));
+        let have = theirs.next().await.unwrap().unwrap();
+        assert_eq!((have.tag, have.read_u32(0).unwrap()), (PeerMessageTag::Have, 7));
+
+        let block: Vec<u8> = (0..BLOCK_SIZE).map(|index| index as u8).collect();
+        theirs.send(PeerMessage::new(PeerMessageTag::Unchoke, vec![])).await.unwrap();
+        theirs.send(PeerMessage::piece(3, 0, &block)).await.unwrap();
+        assert_eq!(ours.next().await.unwrap().unwrap().tag, PeerMessageTag::Unchoke);
+        let piece = ours.next().await.unwrap().unwrap();
+        assert_eq!(piece.tag, PeerMessageTag::Piece);
+        assert_eq!((piece.read_u32(0).unwrap(), piece.read_u32(4).unwrap()), (3, 0));
+        assert_eq!(piece.payload[8..], block[..]);
+
+        // the other side hanging up ends the message stream
+        drop(theirs);
+        assert!(ours.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn messages_round_trip_over_a_plain_pipe() {
+        let (outgoing, incoming, ours, theirs) = handshake_pair(Handshake::new(INFO_HASH, [1; 20]), Handshake::new(INFO_HASH, [2; 20])).await;
+        outgoing.unwrap();
+        incoming.unwrap();
+        exchange_messages(PeerStream::plain(ours), PeerStream::plain(theirs)).await;
+    }
+
+    #[tokio::test]
+    async fn handshake_and_messages_over_an_encrypted_pipe() {
+        let (ours, theirs) = duplex(64 * 1024);
+        let (ours, theirs) = tokio::join!(
+            mse::initiate(ours, &INFO_HASH, EncryptionPolicy::Require),
+            mse::accept(theirs, &[INFO_HASH], EncryptionPolicy::Require),
+        );
+        let (mut ours, (mut theirs, _)) = (ours.unwrap(), theirs.unwrap());
+        let (outbound, inbound) = (Handshake::new(INFO_HASH, [1; 20]), Handshake::new(INFO_HASH, [2; 20]));
+        let (outgoing, incoming) = tokio::join!(
+            exchange_handshake(&mut ours, &outbound, Direction::Outbound),
+            exchange_handshake(&mut theirs, &inbound, Direction::Inbound),
+        );
+        assert_eq!((outgoing.unwrap().peer_id, incoming.unwrap().peer_id), ([2; 20], [1; 20]));
+        exchange_messages(ours, theirs).await;
+    }
+
+    #[tokio::test]
+    async fn pex_arriving_too_soon_after_the_previous_one_is_ignored() {
+        let content = content(4);
+        let mut session = session(meta(&content, false), &config());
+        let mut remote = RemotePeer::connect(&session.shared, remote_handshake(&session.shared)).await;
+        let handshake = remote.expect(PeerMessageTag::Extended).await;
+        let (id, payload) = split_extended(&handshake).unwrap();
+        assert_eq!(id, HANDSHAKE_ID);
+        assert_eq!(ExtensionHandshake::from_payload(payload).unwrap().m.get(pex::EXTENSION_NAME), Some(&(pex::LOCAL_ID as i64)));
+
+        let first = SocketAddr::from(([10, 0, 0, 1], 6881));
+        remote.send(extended(pex::LOCAL_ID, serde_bencode::to_bytes(&PexMessage::new(&[(first, 0)], &[])).unwrap())).await;
+        let second = SocketAddr::from(([10, 0, 0, 2], 6881));
+        remote.send(extended(pex::LOCAL_ID, serde_bencode::to_bytes(&PexMessage::new(&[(second, 0)], &[])).unwrap())).await;
+        remote.sync().await;
+        let found: Vec<SocketAddr> = session.found.try_recv().unwrap().iter().map(Peer::address).collect();
+        assert_eq!(found, vec![first]);
+        assert!(session.found.try_recv().is_err());
+        remote.task.abort();
+    }
+
+    #[tokio::test]
+    async fn private_torrents_neither_offer_nor_accept_pex() {
+        let content = content(4);
+        let mut session = session(meta(&content, true), &config());
+        assert!(!session.shared.pex);
+        let mut remote = RemotePeer::connect(&session.shared, remote_handshake(&session.shared)).await;
+        let handshake = remote.expect(PeerMessageTag::Extended).await;
+        let (_, payload) = split_extended(&handshake).unwrap();
+        assert!(ExtensionHandshake::from_payload(payload).unwrap().m.is_empty());
+
+        let mut ours = ExtensionHandshake::default();
+        ours.m.insert(pex::EXTENSION_NAME.to_string(), 3);
+        remote.send(ours.to_message()).await;
+        let peer = SocketAddr::from(([10, 0, 0, 1], 6881));
+        remote.send(extended(pex::LOCAL_ID, serde_bencode::to_bytes(&PexMessage::new(&[(peer, 0)], &[])).unwrap())).await;
+        remote.sync().await;
+        assert!(session.found.try_recv().is_err());
+        remote.task.abort();
+    }
+
+    #[tokio::test]
+    async fn opening_message_tells_fast_peers_have_all_or_have_none() {
+        let content = content(4);
+        let empty = session(meta(&content, false), &config());
+        let mut remote = RemotePeer::connect(&empty.shared, remote_handshake(&empty.shared)).await;
+        remote.expect(PeerMessageTag::HaveNone).await;
+        remote.task.abort();
+
+        let seeding = seeding_session(meta(&content, false), &content);
+        let mut remote = RemotePeer::connect(&seeding.shared, remote_handshake(&seeding.shared)).await;
+        remote.expect(PeerMessageTag::HaveAll).await;
+        remote.task.abort();
+
+        // peers without the extension get a bitfield instead, and nothing at all while we have nothing
+        let seeding = seeding_session(meta(&content, false), &content);
+        let mut remote = RemotePeer::connect(&seeding.shared, without_fast(remote_handshake(&seeding.shared))).await;
+        let bitfield = remote.expect(PeerMessageTag::Bitfield).await;
+        assert_eq!(Bitfield::from_bytes(&bitfield.payload, 4), Some(Bitfield::full(4)));
+        remote.task.abort();
+    }
+
+    #[tokio::test]
+    async fn have_all_and_have_none_from_the_peer_set_our_interest() {
+        let content = content(4);
+        let session = session(meta(&content, false), &config());
+        let mut remote = RemotePeer::connect(&session.shared, remote_handshake(&session.shared)).await;
+        remote.send(PeerMessage::new(PeerMessageTag::HaveAll, vec![])).await;
+        remote.expect(PeerMessageTag::Interested).await;
+        remote.send(PeerMessage::new(PeerMessageTag::HaveNone, vec![])).await;
+        remote.expect(PeerMessageTag::NotInterested).await;
+        remote.task.abort();
+
+        // both are protocol errors without the extension
+        let mut remote = RemotePeer::connect(&session.shared, without_fast(Handshake::new(session.shared.info_hash, [8; 20]))).await;
+        remote.send(PeerMessage::new(PeerMessageTag::HaveAll, vec![])).await;
+        assert_eq!(remote.task.await.unwrap().unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn rejected_request_is_sent_again() {
+        let content = content(4);
+        let session = session(meta(&content, false), &config());
+        let mut remote = RemotePeer::connect(&session.shared, remote_handshake(&session.shared)).await;
+        remote.send(PeerMessage::new(PeerMessageTag::HaveAll, vec![])).await;
+        remote.send(PeerMessage::new(PeerMessageTag::Unchoke, vec![])).await;
+        let first = remote.expect(PeerMessageTag::Request).await;
+        let second = remote.expect(PeerMessageTag::Request).await;
+        remote.send(PeerMessage::reject(&first)).await;
+        let again = remote.expect(PeerMessageTag::Request).await;
+        assert_eq!(block_of(&again), block_of(&first));
+
+        // once both blocks arrive the piece is verified and written
+        let (index, _, _) = block_of(&first);
+        let piece = &content[index * PIECE_LENGTH..((index + 1) * PIECE_LENGTH).min(content.len())];
+        for block in [&second, &again] {
+            let (_, begin, length) = block_of(block);
+            remote.send(PeerMessage::piece(index as u32, begin as u32, &piece[begin..begin + length])).await;
+        }
+        remote.sync().await;
+        assert!(session.shared.lock().have.get(index));
+        assert_eq!(session.storage.read(index, 0, piece.len()).unwrap(), piece);
+        remote.task.abort();
+    }
+
+    #[tokio::test]
+    async fn allowed_fast_pieces_are_served_while_choking() {
+        let content = content(16);
+        let seeding = seeding_session(meta(&content, false), &content);
+        let mut remote = RemotePeer::connect(&seeding.shared, remote_handshake(&seeding.shared)).await;
+        let mut allowed = vec![];
+        while allowed.len() < fast::ALLOWED_FAST_COUNT {
+            allowed.push(remote.expect(PeerMessageTag::AllowedFast).await.read_u32(0).unwrap() as usize);
+        }
+        assert_eq!(allowed, allowed_fast_set(&seeding.shared.info_hash, IpAddr::from(Ipv4Addr::LOCALHOST), 16, fast::ALLOWED_FAST_COUNT)
+            .into_iter().map(|index| index as usize).collect::<Vec<_>>());
+        let denied = (0..16).find(|index| !allowed.contains(index)).unwrap();
+
+        // we have not said we are interested, so the session still chokes us
+        remote.send(request(allowed[0], 0, BLOCK_SIZE)).await;
+        remote.send(request(denied, 0, BLOCK_SIZE)).await;
+        let piece = remote.expect(PeerMessageTag::Piece).await;
+        assert_eq!(block_of(&piece), (allowed[0], 0, BLOCK_SIZE));
+        assert_eq!(piece.payload[8..], content[allowed[0] * PIECE_LENGTH..][..BLOCK_SIZE]);
+        let reject = remote.expect(PeerMessageTag::RejectRequest).await;
+        assert_eq!(block_of(&reject), (denied, 0, BLOCK_SIZE));
+
+        // past the end of the piece or too large is refused too
+        remote.send(request(allowed[0], PIECE_LENGTH - 10, 20)).await;
+        assert_eq!(remote.expect(PeerMessageTag::RejectRequest).await.tag, PeerMessageTag::RejectRequest);
+        remote.task.abort();
+    }
+
+    // the requests of the piece the session downloads from us after we unchoked it, choked and unchoked it again
+    async fn requests_after_choke(handshake: fn(Handshake) -> Handshake) -> (Vec<(usize, usize, usize)>, Vec<(usize, usize, usize)>) {
+        let content = content(4);
+        let session = session(meta(&content, false), &config());
+        let mut remote = RemotePeer::connect(&session.shared, handshake(remote_handshake(&session.shared))).await;
+        remote.send(PeerMessage::new(PeerMessageTag::Bitfield, Bitfield::full(4).as_bytes().to_vec())).await;
+        remote.send(PeerMessage::new(PeerMessageTag::Unchoke, vec![])).await;
+        let first = vec![block_of(&remote.expect(PeerMessageTag::Request).await), block_of(&remote.expect(PeerMessageTag::Request).await)];
+        remote.send(PeerMessage::new(PeerMessageTag::Choke, vec![])).await;
+        remote.send(PeerMessage::new(PeerMessageTag::Unchoke, vec![])).await;
+        let requested = remote.sync().await.iter().filter(|message| message.tag == PeerMessageTag::Request).map(block_of).collect();
+        remote.task.abort();
+        (first, requested)
+    }
+
+    #[tokio::test]
+    async fn choke_keeps_requests_queued_with_the_fast_extension() {
+        let (first, again) = requests_after_choke(|handshake| handshake).await;
+        assert_eq!(first.len(), 2);
+        assert!(again.is_empty(), "{:?}", again);
+        // without it a choking peer dropped them, so they are requested again
+        let (first, again) = requests_after_choke(without_fast).await;
+        assert_eq!(again, first);
+    }
+
+    // the response head and body of one HTTP request to the stream server
+    async fn http(address: SocketAddr, request: &str) -> (String, Vec<u8>) {
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        stream.write_all(format!("{}\r\n\r\n", request).as_bytes()).await.unwrap();
+        let mut response = vec![];
+        timeout(MESSAGE_TIMEOUT, stream.read_to_end(&mut response)).await.unwrap().unwrap();
+        let end = response.windows(4).position(|window| window == b"\r\n\r\n").unwrap();
+        (String::from_utf8(response[..end].to_vec()).unwrap(), response[end + 4..].to_vec())
+    }
+
+    #[tokio::test]
+    async fn stream_server_answers_byte_ranges() {
+        let content = content(4);
+        let length = content.len();
+        let session = seeding_session(meta(&content, false), &content);
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = tokio::spawn(stream::serve(listener, TorrentReader::new(session.shared.clone(), &[Priority::Normal])));
+
+        let (head, body) = http(address, "GET / HTTP/1.1").await;
+        assert!(head.starts_with("HTTP/1.1 200 OK"), "{}", head);
+        assert_eq!(body, format!("0\t{}\tfile\n", length).as_bytes());
+
+        let (head, body) = http(address, "GET /0 HTTP/1.1").await;
+        assert!(head.starts_with("HTTP/1.1 200 OK"), "{}", head);
+        assert_eq!(body, content);
+
+        // a range across a piece boundary
+        let (head, body) = http(address, &format!("GET /0 HTTP/1.1\r\nRange: bytes={}-{}", PIECE_LENGTH - 10, PIECE_LENGTH + 9)).await;
+        assert!(head.starts_with("HTTP/1.1 206 Partial Content"), "{}", head);
+        assert!(head.contains(&format!("Content-Range: bytes {}-{}/{}", PIECE_LENGTH - 10, PIECE_LENGTH + 9, length)), "{}", head);
+        assert!(head.contains("Content-Length: 20"), "{}", head);
+        assert_eq!(body, content[PIECE_LENGTH - 10..PIECE_LENGTH + 10]);
+
+        let (head, body) = http(address, "GET /0 HTTP/1.1\r\nRange: bytes=-100").await;
+        assert!(head.contains(&format!("Content-Range: bytes {}-{}/{}", length - 100, length - 1, length)), "{}", head);
+        assert_eq!(body, content[length - 100..]);
+
+        let (head, body) = http(address, &format!("HEAD /0 HTTP/1.1\r\nRange: bytes={}-", length - 1)).await;
+        assert!(head.starts_with("HTTP/1.1 206 Partial Content"), "{}", head);
+        assert!(head.contains("Content-Length: 1"), "{}", head);
+        assert!(body.is_empty());
+
+        let (head, body) = http(address, &format!("GET /0 HTTP/1.1\r\nRange: bytes={}-", length)).await;
+        assert!(head.starts_with("HTTP/1.1 416 Range Not Satisfiable"), "{}", head);
+        assert!(head.contains(&format!("Content-Range: bytes */{}", length)), "{}", head);
+        assert!(body.is_empty());
+
+        let (head, _) = http(address, "GET /1 HTTP/1.1").await;
+        assert!(head.starts_with("HTTP/1.1 404 Not Found"), "{}", head);
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn reads_of_skipped_pieces_fail_instead_of_waiting() {
+        let content = content(4);
+        let mut config = config();
+        config.file_priorities = vec![Priority::Skip];
+        let session = session(meta(&content, false), &config);
+        // a reader that believes the file is wanted
+        let reader = TorrentReader::new(session.shared.clone(), &[Priority::Normal]);
+        let err = timeout(MESSAGE_TIMEOUT, reader.read(0, 0, 100)).await.expect("read waits for a skipped piece").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+    }
+
+    // a v2-only torrent of `content` with its piece layer, and the leaf hashes of each piece
+    fn v2_meta(content: &[u8]) -> (Meta, Vec<Vec<Hash>>) {
+        let pair = |nodes: &[Hash]| -> Vec<Hash> { nodes.chunks(2).map(|pair| merkle::hash_block(&pair.concat())).collect() };
+        let leaves: Vec<Vec<Hash>> = content.chunks(PIECE_LENGTH).map(|piece| {
+            let mut leaves = merkle::block_hashes(piece);
+            leaves.resize(PIECE_LENGTH / BLOCK_SIZE, [0; 32]);
+            leaves
+        }).collect();
+        let layer: Vec<Hash> = leaves.iter().flat_map(|leaves| pair(leaves)).collect();
+        let mut root = layer.clone();
+        while root.len() > 1 {
+            root = pair(&root);
+        }
+        let info = Info {
+            name: "file".to_string(),
+            length: None,
+            files: None,
+            piece_length: PIECE_LENGTH,
+            pieces: vec![],
+            private: None,
+            attr: None,
+            meta_version: Some(2),
+            file_tree: Some(BTreeMap::from([("file".to_string(), FileTree::File {
+                file: TreeFile { length: content.len(), pieces_root: Some(ByteBuf::from(root[0].to_vec())) },
+            })])),
+        };
+        let mut torrent = b"d4:info".to_vec();
+        torrent.extend(serde_bencode::to_bytes(&info).unwrap());
+        torrent.extend(b"12:piece layersd32:");
+        torrent.extend(root[0]);
+        torrent.extend(format!("{}:", layer.len() * 32).as_bytes());
+        torrent.extend(layer.concat());
+        torrent.extend(b"ee");
+        (serde_bencode::from_bytes(&torrent).unwrap(), leaves)
+    }
+
+    #[tokio::test]
+    async fn corrupt_v2_block_bans_the_peer_on_arrival() {
+        let content = content(4);
+        assert_eq!(content.len().div_ceil(PIECE_LENGTH), 4);
+        let (meta, leaves) = v2_meta(&content);
+        let mut session = session(meta, &config());
+        let mut remote = RemotePeer::connect(&session.shared, remote_handshake(&session.shared)).await;
+        remote.send(PeerMessage::new(PeerMessageTag::Bitfield, Bitfield::full(4).as_bytes().to_vec())).await;
+        remote.send(PeerMessage::new(PeerMessageTag::Unchoke, vec![])).await;
+        // the session asks for the leaf hashes of the piece it picked, then for its blocks
+        let hash_request = HashRequest::from_payload(&remote.expect(PeerMessageTag::HashRequest).await.payload).unwrap();
+        assert_eq!((hash_request.base_layer, hash_request.length), (0, 2));
+        let piece = hash_request.index as usize / 2;
+        remote.send(PeerMessage::hashes(&hash_request, &leaves[piece])).await;
+        let (index, begin, length) = block_of(&remote.expect(PeerMessageTag::Request).await);
+        assert_eq!(index, piece);
+        let mut block = content[index * PIECE_LENGTH + begin..index * PIECE_LENGTH + begin + length].to_vec();
+        block[0] ^= 1;
+        remote.send(PeerMessage::piece(index as u32, begin as u32, &block)).await;
+
+        match timeout(MESSAGE_TIMEOUT, session.events.recv()).await.unwrap().unwrap() {
+            SessionEvent::Ban(ip) => assert_eq!(ip, Ipv4Addr::LOCALHOST),
+            _ => panic!("expected a ban"),
+        }
+        let err = timeout(MESSAGE_TIMEOUT, remote.task).await.unwrap().unwrap().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert!(!session.shared.lock().have.get(index));
+    }
+}
@@ -0,0 +1,985 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::io::{Error, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use bytes::Bytes;
+use memmap2::{MmapOptions, MmapRaw};
+
+use crate::metainfo::Info;
+use crate::selection::{self, Priority};
+
+// how files get their disk space
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Allocation {
+    // every file's blocks are reserved up front, which keeps files contiguous on spinning disks
+    Full,
+    // files get their final size right away but only written ranges take up space
+    #[default]
+    Sparse,
+    // files only grow as far as pieces have been written
+    Compact,
+}
+
+// how DiskStorage and MmapStorage reach the files
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Backend {
+    // positioned reads and writes on file handles
+    #[default]
+    File,
+    // copies in and out of memory mapped windows of the files
+    Mmap,
+}
+
+/*
+    Where the torrent's content lives. Pieces are addressed as (piece, offset) ranges, the storage maps
+    them onto its backing files or memory. Implementations are shared between peer tasks.
+*/
+pub trait Storage: Send + Sync {
+    fn write(&self, piece: usize, offset: usize, data: &[u8]) -> Result<(), Error>;
+    fn read(&self, piece: usize, offset: usize, length: usize) -> Result<Vec<u8>, Error>;
+
+    // like `read`, for storages that can hand out their content without copying it
+    fn read_bytes(&self, piece: usize, offset: usize, length: usize) -> Result<Bytes, Error> {
+        self.read(piece, offset, length).map(Bytes::from)
+    }
+}
+
+// whole content in one buffer
+pub struct MemoryStorage {
+    piece_length: usize,
+    data: Mutex<Vec<u8>>,
+}
+
+impl MemoryStorage {
+    pub fn new(info: &Info) -> Self {
+        MemoryStorage { piece_length: info.piece_length, data: Mutex::new(vec![0; info.total_length()]) }
+    }
+
+    pub fn contents(&self) -> Vec<u8> {
+        self.data.lock().expect("storage lock poisoned").clone()
+    }
+
+    fn range(&self, piece: usize, offset: usize, length: usize, total: usize) -> Result<std::ops::Range<usize>, Error> {
+        let start = piece * self.piece_length + offset;
+        match start.checked_add(length) {
+            Some(end) if end <= total => Ok(start..end),
+            _ => Err(Error::new(ErrorKind::InvalidInput, "range is past the end of the torrent")),
+        }
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn write(&self, piece: usize, offset: usize, data: &[u8]) -> Result<(), Error> {
+        let mut content = self.data.lock().expect("storage lock poisoned");
+        let range = self.range(piece, offset, data.len(), content.len())?;
+        content[range].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn read(&self, piece: usize, offset: usize, length: usize) -> Result<Vec<u8>, Error> {
+        let content = self.data.lock().expect("storage lock poisoned");
+        let range = self.range(piece, offset, length, content.len())?;
+        Ok(content[range].to_vec())
+    }
+}
+
+// longest file name most file systems accept, in bytes
+const MAX_COMPONENT_LENGTH: usize = 255;
+
+// names Windows reserves for devices, with or without an extension
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL",
+    "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
+    "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/*
+    Joins a file's path components from the metainfo below `root`. The components come from an untrusted
+    torrent, so anything that could leave `root` (`..`, separators, NUL) rejects the torrent, while names
+    that are merely awkward are rewritten: `.` and empty components are dropped, `:` (drive letters and
+    streams on Windows) becomes `_`, reserved device names get a `_` suffix, trailing dots and spaces are
+    trimmed and overlong components are shortened.
+*/
+fn safe_path(root: &Path, components: &[String]) -> Result<PathBuf, Error> {
+    let mut path = root.to_path_buf();
+    let mut depth = 0;
+    for component in components {
+        if component == ".." || component.contains(['/', '\\', '\0']) {
+            return Err(Error::new(ErrorKind::InvalidData, format!("unsafe path component {:?} in torrent", component)));
+        }
+        let mut name = component.trim_end_matches(['.', ' ']).replace(':', "_");
+        if name.is_empty() {
+            continue;
+        }
+        let stem = name.split('.').next().unwrap_or_default();
+        if RESERVED_NAMES.iter().any(|reserved| reserved.eq_ignore_ascii_case(stem.trim_end())) {
+            name.insert(stem.len(), '_');
+        }
+        if name.len() > MAX_COMPONENT_LENGTH {
+            name = shorten(&name);
+        }
+        path.push(name);
+        depth += 1;
+    }
+    if depth == 0 {
+        return Err(Error::new(ErrorKind::InvalidData, format!("empty path {:?} in torrent", components)));
+    }
+    Ok(path)
+}
+
+// cuts a name down to MAX_COMPONENT_LENGTH bytes on a char boundary, keeping a short extension
+fn shorten(name: &str) -> String {
+    let extension = name.rfind('.').map(|dot| &name[dot..]).filter(|extension| extension.len() <= 16).unwrap_or("");
+    let mut end = MAX_COMPONENT_LENGTH - extension.len();
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}{}", &name[..end], extension)
+}
+
+struct StorageFile {
+    path: PathBuf,
+    // position of the file's first byte in the torrent's content
+    offset: u64,
+    length: u64,
+    // never created, its bytes in pieces shared with wanted files go to the partfile
+    skipped: bool,
+    // BEP 47 padding, reads as zeros and drops writes
+    padding: bool,
+}
+
+// open files are shared by the reads and writes in flight on them
+type SharedFile = Arc<Mutex<File>>;
+
+// file handles kept open across all files, well below the usual limit of 1024 descriptors
+const MAX_OPEN_FILES: usize = 128;
+
+/*
+    Handles of the files in use, opened on first use and closed least recently used first, so a
+    torrent of thousands of files does not run out of descriptors. A handle closed while a read or
+    write still uses it stays open until that is done.
+*/
+struct OpenFiles {
+    // (path, handle), least recently used first
+    handles: Mutex<VecDeque<(PathBuf, SharedFile)>>,
+}
+
+impl OpenFiles {
+    fn new() -> Self {
+        OpenFiles { handles: Mutex::new(VecDeque::new()) }
+    }
+
+    fn with_handle<T>(&self, path: &Path, operation: impl FnOnce(&mut File) -> Result<T, Error>) -> Result<T, Error> {
+        let handle = {
+            let mut handles = self.handles.lock().expect("storage lock poisoned");
+            let entry = match handles.iter().position(|(open, _)| open == path) {
+                Some(slot) => handles.remove(slot).expect("slot is in range"),
+                None => {
+                    if handles.len() == MAX_OPEN_FILES {
+                        handles.pop_front();
+                    }
+                    (path.to_path_buf(), Arc::new(Mutex::new(open(path)?)))
+                }
+            };
+            let handle = entry.1.clone();
+            handles.push_back(entry);
+            handle
+        };
+        let mut handle = handle.lock().expect("storage lock poisoned");
+        operation(&mut handle)
+    }
+}
+
+fn open(path: &Path) -> Result<File, Error> {
+    OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)
+}
+
+/*
+    Holds the bytes of skipped files that share a piece with wanted files, so the piece can be
+    verified and served without creating the skipped file. Each such piece has a slot of one piece
+    length in the file, which is only created once something is written to it.
+*/
+struct PartFile {
+    file: StorageFile,
+    piece_length: u64,
+    slots: HashMap<usize, u64>,
+}
+
+impl PartFile {
+    fn position(&self, piece: usize, offset: usize) -> Result<u64, Error> {
+        match self.slots.get(&piece) {
+            Some(slot) => Ok(slot * self.piece_length + offset as u64),
+            None => Err(Error::new(ErrorKind::InvalidInput, format!("piece {} only overlaps skipped files", piece))),
+        }
+    }
+
+    fn write(&self, open: &OpenFiles, piece: usize, offset: usize, data: &[u8]) -> Result<(), Error> {
+        let position = self.position(piece, offset)?;
+        open.with_handle(&self.file.path, |handle| {
+            handle.seek(SeekFrom::Start(position))?;
+            handle.write_all(data)
+        })
+    }
+
+    fn read(&self, open: &OpenFiles, piece: usize, offset: usize, data: &mut [u8]) -> Result<(), Error> {
+        let position = self.position(piece, offset)?;
+        open.with_handle(&self.file.path, |handle| read_at(handle, position, data))
+    }
+}
+
+// bytes past the end of the file were never written and read as zeros, like holes in a sparse file
+fn read_at(handle: &mut File, position: u64, data: &mut [u8]) -> Result<(), Error> {
+    handle.seek(SeekFrom::Start(position))?;
+    let mut done = 0;
+    while done < data.len() {
+        match handle.read(&mut data[done..]) {
+            Ok(0) => {
+                data[done..].fill(0);
+                break;
+            }
+            Ok(read) => done += read,
+            Err(err) if err.kind() == ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+/*
+    Content in files on disk. A single-file torrent is written to `root` itself, a multi-file torrent
+    below the `root` directory. Files are created with their directories up front, allocated as
+    `allocation` says, and written in place. Skipped files are left out, see PartFile, and padding
+    files only exist as zeros in the pieces. Creation fails when the disk cannot hold what is missing.
+*/
+pub struct DiskStorage {
+    piece_length: u64,
+    files: Vec<StorageFile>,
+    part: PartFile,
+    open: OpenFiles,
+}
+
+impl DiskStorage {
+    pub fn new(info: &Info, root: &Path, allocation: Allocation, priorities: &[Priority]) -> Result<DiskStorage, Error> {
+        let (files, part) = create_files(info, root, allocation, priorities)?;
+        Ok(DiskStorage { piece_length: info.piece_length as u64, files, part, open: OpenFiles::new() })
+    }
+}
+
+// lays the torrent's files out below `root` and creates the ones that are not skipped
+fn create_files(info: &Info, root: &Path, allocation: Allocation, priorities: &[Priority]) -> Result<(Vec<StorageFile>, PartFile), Error> {
+    let mut files = vec![];
+    let mut offset = 0;
+    // (link, target) of symlinks and the executable files, applied once the files exist
+    let mut symlinks = vec![];
+    let mut executables = vec![];
+    for (entry, priority) in info.file_list().into_iter().zip(priorities) {
+        let path = if info.is_multi_file() {
+            safe_path(root, &entry.path)?
+        } else {
+            root.to_path_buf()
+        };
+        let target = entry.symlink_target().filter(|_| info.is_multi_file());
+        if let Some(target) = target {
+            symlinks.push((path.clone(), safe_path(root, target)?));
+        }
+        let skipped = *priority == Priority::Skip || target.is_some();
+        if entry.is_executable() && !skipped {
+            executables.push(files.len());
+        }
+        let padding = entry.is_padding();
+        files.push(StorageFile { path, offset, length: entry.length as u64, skipped, padding });
+        offset += entry.length as u64;
+    }
+    // only the first and last piece of a skipped file can be shared with a wanted one
+    let piece_priorities = selection::piece_priorities(info, priorities);
+    let mut shared_pieces: Vec<usize> = files.iter()
+        .filter(|file| file.skipped && !file.padding && file.length > 0)
+        .flat_map(|file| [file.offset, file.offset + file.length - 1].map(|position| (position / info.piece_length as u64) as usize))
+        .filter(|piece| piece_priorities[*piece] != Priority::Skip)
+        .collect();
+    shared_pieces.dedup();
+    let part = PartFile {
+        // only multi-file torrents have skipped files next to wanted ones
+        file: StorageFile { path: root.join(".parts"), offset: 0, length: 0, skipped: false, padding: false },
+        piece_length: info.piece_length as u64,
+        slots: shared_pieces.into_iter().zip(0..).collect(),
+    };
+    check_free_space(&files, &part, root, available_space)?;
+    for file in files.iter().filter(|file| !file.skipped) {
+        if let Some(parent) = file.path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+            std::fs::create_dir_all(parent)?;
+        }
+        match allocation {
+            // empty files get no writes, so they are created here
+            Allocation::Compact if file.length == 0 => drop(File::create(&file.path)?),
+            Allocation::Compact => {}
+            Allocation::Sparse | Allocation::Full => {
+                let handle = open(&file.path)?;
+                if allocation == Allocation::Full {
+                    preallocate(&handle, file.length)?;
+                } else if handle.metadata()?.len() < file.length {
+                    handle.set_len(file.length)?;
+                }
+            }
+        }
+    }
+    for file in executables.into_iter().map(|index| &files[index]) {
+        // compact allocation has not created the file yet
+        open(&file.path)?;
+        set_executable(&file.path)?;
+    }
+    for (link, target) in symlinks {
+        create_symlink(root, &link, &target)?;
+    }
+    Ok((files, part))
+}
+
+// adds execute permission wherever read permission is granted, like `chmod +x` under the umask
+#[cfg(unix)]
+fn set_executable(path: &Path) -> Result<(), Error> {
+    use std::os::unix::fs::PermissionsExt;
+    let mut permissions = std::fs::metadata(path)?.permissions();
+    let mode = permissions.mode();
+    permissions.set_mode(mode | ((mode & 0o444) >> 2));
+    std::fs::set_permissions(path, permissions)
+}
+
+#[cfg(not(unix))]
+fn set_executable(_path: &Path) -> Result<(), Error> {
+    Ok(())
+}
+
+/*
+    Creates `link` pointing at `target`, both already checked by safe_path to be below `root`. The link
+    is relative so the download can be moved as a whole. Only a previous symlink is replaced, anything
+    else at `link` is left alone.
+*/
+#[cfg(unix)]
+fn create_symlink(root: &Path, link: &Path, target: &Path) -> Result<(), Error> {
+    let (Ok(relative_link), Ok(relative_target)) = (link.strip_prefix(root), target.strip_prefix(root)) else {
+        return Err(Error::new(ErrorKind::InvalidData, format!("symlink {} leaves the download", link.display())));
+    };
+    let mut relative = PathBuf::new();
+    for _ in relative_link.components().skip(1) {
+        relative.push("..");
+    }
+    relative.push(relative_target);
+    match std::fs::symlink_metadata(link) {
+        Ok(metadata) if metadata.file_type().is_symlink() => std::fs::remove_file(link)?,
+        Ok(_) => {
+            eprintln!("not creating symlink {}, a file is in the way", link.display());
+            return Ok(());
+        }
+        Err(_) => {}
+    }
+    if let Some(parent) = link.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::os::unix::fs::symlink(relative, link)
+}
+
+#[cfg(not(unix))]
+fn create_symlink(_root: &Path, link: &Path, _target: &Path) -> Result<(), Error> {
+    eprintln!("not creating symlink {}, symlinks are only supported on unix", link.display());
+    Ok(())
+}
+
+/*
+    Calls `operation` for every file a (piece, offset, length) range touches with the file's index,
+    the offset in the file and the range's slice bounds.
+*/
+fn for_each_file(files: &[StorageFile], piece_length: u64, piece: usize, offset: usize, length: usize,
+                 mut operation: impl FnMut(usize, u64, std::ops::Range<usize>) -> Result<(), Error>) -> Result<(), Error> {
+    let start = piece as u64 * piece_length + offset as u64;
+    let end = start + length as u64;
+    let total = files.last().map_or(0, |file| file.offset + file.length);
+    if end > total {
+        return Err(Error::new(ErrorKind::InvalidInput, "range is past the end of the torrent"));
+    }
+    for (index, file) in files.iter().enumerate().filter(|(_, file)| file.length > 0 && file.offset < end && file.offset + file.length > start) {
+        let from = start.max(file.offset);
+        let to = end.min(file.offset + file.length);
+        operation(index, from - file.offset, (from - start) as usize..(to - start) as usize)?;
+    }
+    Ok(())
+}
+
+// fails before anything is created when the files' missing bytes do not fit on the disk
+fn check_free_space(files: &[StorageFile], part: &PartFile, root: &Path,
+                    available_space: impl FnOnce(&Path) -> Result<u64, Error>) -> Result<(), Error> {
+    let needed = files.iter()
+        .filter(|file| !file.skipped)
+        .map(|file| file.length.saturating_sub(std::fs::metadata(&file.path).map_or(0, |metadata| allocated(&metadata))))
+        .sum::<u64>() + part.slots.len() as u64 * part.piece_length;
+    // the closest directory that already exists is on the disk the files end up on
+    let existing = root.ancestors().find(|path| path.is_dir()).unwrap_or(Path::new("."));
+    let available = available_space(existing)?;
+    if needed > available {
+        return Err(Error::new(ErrorKind::StorageFull, format!("not enough free space in {}: {} bytes needed, {} available",
+                                                              existing.display(), needed, available)));
+    }
+    Ok(())
+}
+
+// bytes a file already occupies on disk, smaller than its length when sparse
+#[cfg(unix)]
+fn allocated(metadata: &std::fs::Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    metadata.len().min(metadata.blocks() * 512)
+}
+
+#[cfg(not(unix))]
+fn allocated(metadata: &std::fs::Metadata) -> u64 {
+    metadata.len()
+}
+
+#[cfg(unix)]
+fn available_space(path: &Path) -> Result<u64, Error> {
+    use std::os::unix::ffi::OsStrExt;
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes()).map_err(|err| Error::new(ErrorKind::InvalidInput, err))?;
+    let mut stats = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: `path` is NUL terminated and `stats` is only read after statvfs filled it in
+    if unsafe { libc::statvfs(path.as_ptr(), stats.as_mut_ptr()) } != 0 {
+        return Err(Error::last_os_error());
+    }
+    let stats = unsafe { stats.assume_init() };
+    // the field types differ between platforms
+    #[allow(clippy::unnecessary_cast)]
+    Ok(stats.f_bavail as u64 * stats.f_frsize as u64)
+}
+
+// no portable way to ask, the check is skipped
+#[cfg(not(unix))]
+fn available_space(_path: &Path) -> Result<u64, Error> {
+    Ok(u64::MAX)
+}
+
+// reserves the blocks of the whole file without touching data already in it
+#[cfg(target_os = "linux")]
+fn preallocate(file: &File, length: u64) -> Result<(), Error> {
+    use std::os::unix::io::AsRawFd;
+    if length == 0 {
+        return Ok(());
+    }
+    // SAFETY: the descriptor stays open for the duration of the call
+    match unsafe { libc::posix_fallocate(file.as_raw_fd(), 0, length as libc::off_t) } {
+        0 => Ok(()),
+        code => Err(Error::from_raw_os_error(code)),
+    }
+}
+
+// without fallocate the missing tail is written out as zeros
+#[cfg(not(target_os = "linux"))]
+fn preallocate(mut file: &File, length: u64) -> Result<(), Error> {
+    let mut position = file.seek(SeekFrom::End(0))?;
+    let zeros = vec![0; 1 << 16];
+    while position < length {
+        let chunk = zeros.len().min((length - position) as usize);
+        file.write_all(&zeros[..chunk])?;
+        position += chunk as u64;
+    }
+    Ok(())
+}
+
+impl Storage for DiskStorage {
+    fn write(&self, piece: usize, offset: usize, data: &[u8]) -> Result<(), Error> {
+        for_each_file(&self.files, self.piece_length, piece, offset, data.len(), |file, position, range| {
+            if self.files[file].padding {
+                return Ok(());
+            }
+            if self.files[file].skipped {
+                return self.part.write(&self.open, piece, offset + range.start, &data[range]);
+            }
+            self.open.with_handle(&self.files[file].path, |handle| {
+                handle.seek(SeekFrom::Start(position))?;
+                handle.write_all(&data[range])
+            })
+        })
+    }
+
+    fn read(&self, piece: usize, offset: usize, length: usize) -> Result<Vec<u8>, Error> {
+        let mut data = vec![0; length];
+        for_each_file(&self.files, self.piece_length, piece, offset, length, |file, position, range| {
+            // padding stays as zeros
+            if self.files[file].padding {
+                return Ok(());
+            }
+            if self.files[file].skipped {
+                return self.part.read(&self.open, piece, offset + range.start, &mut data[range]);
+            }
+            self.open.with_handle(&self.files[file].path, |handle| read_at(handle, position, &mut data[range]))
+        })?;
+        Ok(data)
+    }
+}
+
+// files are mapped in windows of this size, a multiple of every page size
+const MAP_WINDOW_SIZE: u64 = 64 << 20;
+// mapped windows kept across all files, bounds the address space in use
+const MAX_MAPPED_WINDOWS: usize = 64;
+
+/*
+    Content in memory mapped files, laid out like DiskStorage. Writes copy straight into the kernel's
+    page cache and reads within one window hand out the mapping itself, so pieces are hashed and sent
+    to peers without a copy. Files are mapped in windows of MAP_WINDOW_SIZE that are unmapped least
+    recently used first, so files of any size fit the address space; a window still referenced by a
+    read stays mapped until the last reference is gone. Each file's windows have their own lock, copies
+    and page faults run outside of any lock. Mapping needs files at their full length, compact
+    allocation is treated as sparse. The partfile is not mapped.
+*/
+pub struct MmapStorage {
+    piece_length: u64,
+    files: Vec<StorageFile>,
+    part: PartFile,
+    open: OpenFiles,
+    // per file
+    windows: Vec<Mutex<Windows>>,
+    // (file index, window index) of every mapped window, only locked to map and unmap windows
+    mapped: Mutex<Vec<(usize, u64)>>,
+    clock: AtomicU64,
+}
+
+// a file's mapped windows by window index, with the clock reading of their last use
+type Windows = HashMap<u64, (Arc<MmapRaw>, u64)>;
+
+// a range of a mapped window handed out by reads, keeps the window mapped
+struct MappedRange {
+    mapping: Arc<MmapRaw>,
+    range: std::ops::Range<usize>,
+}
+
+impl AsRef<[u8]> for MappedRange {
+    fn as_ref(&self) -> &[u8] {
+        // SAFETY: the range is inside the mapping, which lives as long as `self`. Pieces are only
+        // read once verified and verified pieces are never written again
+        unsafe { std::slice::from_raw_parts(self.mapping.as_ptr().add(self.range.start), self.range.len()) }
+    }
+}
+
+impl MmapStorage {
+    pub fn new(info: &Info, root: &Path, allocation: Allocation, priorities: &[Priority]) -> Result<MmapStorage, Error> {
+        let allocation = if allocation == Allocation::Compact { Allocation::Sparse } else { allocation };
+        let (files, part) = create_files(info, root, allocation, priorities)?;
+        Ok(MmapStorage {
+            piece_length: info.piece_length as u64,
+            windows: files.iter().map(|_| Mutex::new(HashMap::new())).collect(),
+            files,
+            part,
+            open: OpenFiles::new(),
+            mapped: Mutex::new(vec![]),
+            clock: AtomicU64::new(0),
+        })
+    }
+
+    // the mapping of one of the file's windows, mapped and unmapping the least recently used one if needed
+    fn window(&self, file: usize, window: u64) -> Result<Arc<MmapRaw>, Error> {
+        let now = self.clock.fetch_add(1, Ordering::Relaxed);
+        let lookup = || self.windows[file].lock().expect("storage lock poisoned").get_mut(&window).map(|(mapping, used)| {
+            *used = now;
+            mapping.clone()
+        });
+        if let Some(mapping) = lookup() {
+            return Ok(mapping);
+        }
+        let mut mapped = self.mapped.lock().expect("storage lock poisoned");
+        // another read or write mapped it meanwhile
+        if let Some(mapping) = lookup() {
+            return Ok(mapping);
+        }
+        if mapped.len() >= MAX_MAPPED_WINDOWS {
+            let oldest = mapped.iter().enumerate()
+                .min_by_key(|(_, (file, window))| self.windows[*file].lock().expect("storage lock poisoned").get(window).map_or(0, |(_, used)| *used))
+                .map(|(slot, _)| slot)
+                .expect("windows are mapped");
+            let (file, window) = mapped.swap_remove(oldest);
+            self.windows[file].lock().expect("storage lock poisoned").remove(&window);
+        }
+        let start = window * MAP_WINDOW_SIZE;
+        let length = MAP_WINDOW_SIZE.min(self.files[file].length - start);
+        let mapping = Arc::new(self.open.with_handle(&self.files[file].path, |handle| {
+            MmapOptions::new().offset(start).len(length as usize).map_raw(&*handle)
+        })?);
+        self.windows[file].lock().expect("storage lock poisoned").insert(window, (mapping.clone(), now));
+        mapped.push((file, window));
+        Ok(mapping)
+    }
+
+    // splits a file range at window boundaries, handing each chunk's mapping and offset in it to `operation`
+    fn for_each_window(&self, file: usize, position: u64, range: std::ops::Range<usize>,
+                       mut operation: impl FnMut(&Arc<MmapRaw>, usize, std::ops::Range<usize>)) -> Result<(), Error> {
+        let mut done = range.start;
+        while done < range.end {
+            let at = position + (done - range.start) as u64;
+            let in_window = (MAP_WINDOW_SIZE - at % MAP_WINDOW_SIZE) as usize;
+            let chunk = done..range.end.min(done + in_window);
+            operation(&self.window(file, at / MAP_WINDOW_SIZE)?, (at % MAP_WINDOW_SIZE) as usize, chunk.clone());
+            done = chunk.end;
+        }
+        Ok(())
+    }
+}
+
+impl Storage for MmapStorage {
+    fn write(&self, piece: usize, offset: usize, data: &[u8]) -> Result<(), Error> {
+        for_each_file(&self.files, self.piece_length, piece, offset, data.len(), |file, position, range| {
+            if self.files[file].padding {
+                return Ok(());
+            }
+            if self.files[file].skipped {
+                return self.part.write(&self.open, piece, offset + range.start, &data[range]);
+            }
+            self.for_each_window(file, position, range, |mapping, at, chunk| {
+                // SAFETY: `for_each_window` keeps the chunk inside the mapping
+                unsafe { std::ptr::copy_nonoverlapping(data[chunk.clone()].as_ptr(), mapping.as_mut_ptr().add(at), chunk.len()) }
+            })
+        })
+    }
+
+    fn read(&self, piece: usize, offset: usize, length: usize) -> Result<Vec<u8>, Error> {
+        let mut data = vec![0; length];
+        for_each_file(&self.files, self.piece_length, piece, offset, length, |file, position, range| {
+            // padding stays as zeros
+            if self.files[file].padding {
+                return Ok(());
+            }
+            if self.files[file].skipped {
+                return self.part.read(&self.open, piece, offset + range.start, &mut data[range]);
+            }
+            self.for_each_window(file, position, range, |mapping, at, chunk| {
+                data[chunk.clone()].copy_from_slice(MappedRange { mapping: mapping.clone(), range: at..at + chunk.len() }.as_ref())
+            })
+        })?;
+        Ok(data)
+    }
+
+    fn read_bytes(&self, piece: usize, offset: usize, length: usize) -> Result<Bytes, Error> {
+        let mut single = None;
+        for_each_file(&self.files, self.piece_length, piece, offset, length, |file, position, range| {
+            single = (range.len() == length).then_some((file, position));
+            Ok(())
+        })?;
+        // only a range inside one window of a wanted file is the mapping itself
+        let (file, position) = match single {
+            Some((file, position)) if !self.files[file].padding && !self.files[file].skipped
+                && position / MAP_WINDOW_SIZE == (position + length as u64 - 1) / MAP_WINDOW_SIZE => (file, position),
+            _ => return self.read(piece, offset, length).map(Bytes::from),
+        };
+        let mapping = self.window(file, position / MAP_WINDOW_SIZE)?;
+        let at = (position % MAP_WINDOW_SIZE) as usize;
+        Ok(Bytes::from_owner(MappedRange { mapping, range: at..at + length }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metainfo::FileEntry;
+
+    const PIECE_LENGTH: usize = 64;
+
+    // multi-file torrent of (length, path, attributes)
+    fn info(files: &[(usize, &str, Option<&str>)]) -> Info {
+        let total: usize = files.iter().map(|(length, _, _)| length).sum();
+        Info {
+            name: "torrent".to_string(),
+            length: None,
+            files: Some(files.iter().map(|(length, path, attr)| FileEntry {
+                length: *length,
+                path: path.split('/').map(str::to_string).collect(),
+                attr: attr.map(str::to_string),
+                symlink_path: None,
+                sha1: None,
+            }).collect()),
+            piece_length: PIECE_LENGTH,
+            pieces: vec![0; total.div_ceil(PIECE_LENGTH) * 20],
+            private: None,
+            attr: None,
+            meta_version: None,
+            file_tree: None,
+        }
+    }
+
+    fn content(length: usize) -> Vec<u8> {
+        (0..length).map(|index| (index * 7 + index / 251) as u8).collect()
+    }
+
+    // writes the content in blocks that cross file boundaries and reads it back in other ranges
+    fn write_and_compare(storage: &dyn Storage, info: &Info, expected: &MemoryStorage) {
+        let total = info.total_length();
+        let data = expected.contents();
+        for (piece, chunk) in data.chunks(PIECE_LENGTH).enumerate() {
+            for (block, part) in chunk.chunks(24).enumerate() {
+                storage.write(piece, block * 24, part).unwrap();
+            }
+        }
+        for piece in 0..info.piece_count() {
+            let length = PIECE_LENGTH.min(total - piece * PIECE_LENGTH);
+            assert_eq!(storage.read(piece, 0, length).unwrap(), expected.read(piece, 0, length).unwrap(), "piece {}", piece);
+            let skip = length.min(5);
+            assert_eq!(storage.read(piece, skip, length - skip).unwrap(), expected.read(piece, skip, length - skip).unwrap(), "piece {}", piece);
+        }
+        assert!(storage.read(info.piece_count(), 0, 1).is_err());
+        assert!(storage.read(0, 0, total + 1).is_err());
+    }
+
+    #[test]
+    fn pieces_map_onto_files_across_boundaries() {
+        let files = [(10, "a", None), (0, "empty", None), (100, "dir/b", None), (18, ".pad/18", Some("p")), (64, "dir/sub/c", None), (3, "d", None)];
+        let info = info(&files);
+        let expected = MemoryStorage::new(&info);
+        let mut data = content(info.total_length());
+        // padding is zeros on the wire too
+        data[110..128].fill(0);
+        expected.write(0, 0, &data).unwrap();
+        let priorities = selection::file_priorities(&info, &[], &[]).unwrap();
+
+        for backend in [Backend::File, Backend::Mmap] {
+            let directory = tempfile::tempdir().unwrap();
+            let root = directory.path().join("torrent");
+            let storage: Box<dyn Storage> = match backend {
+                Backend::File => Box::new(DiskStorage::new(&info, &root, Allocation::Compact, &priorities).unwrap()),
+                Backend::Mmap => Box::new(MmapStorage::new(&info, &root, Allocation::Sparse, &priorities).unwrap()),
+            };
+            write_and_compare(storage.as_ref(), &info, &expected);
+            drop(storage);
+            assert_eq!(std::fs::read(root.join("a")).unwrap(), data[..10]);
+            assert_eq!(std::fs::read(root.join("empty")).unwrap(), b"");
+            assert_eq!(std::fs::read(root.join("dir/b")).unwrap(), data[10..110]);
+            assert!(!root.join(".pad").exists());
+            assert_eq!(std::fs::read(root.join("dir/sub/c")).unwrap(), data[128..192]);
+            assert_eq!(std::fs::read(root.join("d")).unwrap(), data[192..]);
+        }
+    }
+
+    #[test]
+    fn skipped_files_keep_shared_pieces_in_the_partfile() {
+        // every piece has wanted bytes
+        let files = [(40, "a", None), (50, "b", None), (40, "c", None)];
+        let info = info(&files);
+        let expected = MemoryStorage::new(&info);
+        expected.write(0, 0, &content(info.total_length())).unwrap();
+        let directory = tempfile::tempdir().unwrap();
+        let root = directory.path().join("torrent");
+        let storage = DiskStorage::new(&info, &root, Allocation::Sparse, &[Priority::Normal, Priority::Skip, Priority::Normal]).unwrap();
+        write_and_compare(&storage, &info, &expected);
+        assert!(!root.join("b").exists());
+        assert_eq!(std::fs::read(root.join("a")).unwrap(), content(130)[..40]);
+        assert_eq!(std::fs::read(root.join("c")).unwrap(), content(130)[90..]);
+    }
+
+    #[test]
+    fn open_handles_stay_bounded() {
+        let names: Vec<String> = (0..MAX_OPEN_FILES * 3).map(|index| format!("files/{}", index)).collect();
+        let files: Vec<_> = names.iter().map(|name| (5, name.as_str(), None)).collect();
+        let info = info(&files);
+        let expected = MemoryStorage::new(&info);
+        expected.write(0, 0, &content(info.total_length())).unwrap();
+        let directory = tempfile::tempdir().unwrap();
+        let storage = DiskStorage::new(&info, directory.path(), Allocation::Sparse, &selection::file_priorities(&info, &[], &[]).unwrap()).unwrap();
+        write_and_compare(&storage, &info, &expected);
+        assert_eq!(storage.open.handles.lock().unwrap().len(), MAX_OPEN_FILES);
+        assert_eq!(std::fs::read(directory.path().join("files/200")).unwrap(), content(info.total_length())[1000..1005]);
+    }
+
+    fn components(path: &[&str]) -> Vec<String> {
+        path.iter().map(|component| component.to_string()).collect()
+    }
+
+    fn joined(path: &[&str]) -> Result<PathBuf, Error> {
+        safe_path(Path::new("/downloads/torrent"), &components(path))
+    }
+
+    #[test]
+    fn path_escapes_are_refused() {
+        for path in [
+            &["..", "etc", "passwd"][..],
+            &["dir", "..", "..", "escape"],
+            &["/etc/passwd"],
+            &["/", "etc", "passwd"],
+            &["dir/../../escape"],
+            &["C:\\Windows", "system32"],
+            &["C:\\"],
+            &["..\\escape"],
+            &["name\0.txt"],
+        ] {
+            assert_eq!(joined(path).unwrap_err().kind(), ErrorKind::InvalidData, "{:?}", path);
+        }
+        assert!(joined(&[]).is_err());
+        assert!(joined(&["", ".", " .. "]).is_err());
+    }
+
+    #[test]
+    fn awkward_names_are_rewritten() {
+        for (path, expected) in [
+            (&["dir", "file.txt"][..], "dir/file.txt"),
+            (&["", ".", "dir", "file"], "dir/file"),
+            (&["Ep 1: Pilot.mkv"], "Ep 1_ Pilot.mkv"),
+            (&["C:", "file"], "C_/file"),
+            (&["file.txt:stream"], "file.txt_stream"),
+            (&["CON"], "CON_"),
+            (&["nul.txt"], "nul_.txt"),
+            (&["Com1.tar.gz"], "Com1_.tar.gz"),
+            (&["aux .txt"], "aux _.txt"),
+            (&["CONSOLE.txt"], "CONSOLE.txt"),
+            (&["trailing. . ", "dots..."], "trailing/dots"),
+        ] {
+            assert_eq!(joined(path).unwrap(), Path::new("/downloads/torrent").join(expected), "{:?}", path);
+        }
+    }
+
+    #[test]
+    fn overlong_names_are_shortened() {
+        let long = format!("{}.mkv", "x".repeat(300));
+        let path = joined(&[&long]).unwrap();
+        let name = path.file_name().unwrap().to_str().unwrap();
+        assert_eq!(name.len(), MAX_COMPONENT_LENGTH);
+        assert!(name.ends_with("x.mkv"));
+        // never split inside a character
+        let long = "é".repeat(200);
+        let name = joined(&[&long]).unwrap().file_name().unwrap().to_str().unwrap().to_string();
+        assert!(name.len() <= MAX_COMPONENT_LENGTH && name.chars().all(|char| char == 'é'));
+    }
+
+    #[test]
+    fn mmap_ranges_cross_window_boundaries() {
+        const PIECE: usize = 3 << 20;
+        let length = MAP_WINDOW_SIZE as usize + PIECE;
+        let mut info = info(&[(length, "a", None)]);
+        info.piece_length = PIECE;
+        info.pieces = vec![0; length.div_ceil(PIECE) * 20];
+        let directory = tempfile::tempdir().unwrap();
+        let root = directory.path().join("torrent");
+        let storage = MmapStorage::new(&info, &root, Allocation::Sparse, &[Priority::Normal]).unwrap();
+        // piece 21 spans the first window's last and the second window's first megabyte
+        let piece = content(PIECE);
+        storage.write(21, 0, &piece).unwrap();
+        assert_eq!(storage.read(21, 0, PIECE).unwrap(), piece);
+        assert_eq!(storage.read_bytes(21, 0, PIECE).unwrap(), piece);
+        assert_eq!(storage.read_bytes(21, (1 << 20) - 10, 20).unwrap(), piece[(1 << 20) - 10..(1 << 20) + 10]);
+        // within a window the mapping is handed out as is
+        assert_eq!(storage.read_bytes(21, 0, 1 << 20).unwrap(), piece[..1 << 20]);
+        assert_eq!(storage.mapped.lock().unwrap().len(), 2);
+        drop(storage);
+        let file = std::fs::read(root.join("a")).unwrap();
+        assert_eq!(file[21 * PIECE..22 * PIECE], piece);
+    }
+
+    #[test]
+    fn mmap_windows_are_unmapped_least_recently_used_first() {
+        let files: Vec<(usize, String, Option<&str>)> = (0..MAX_MAPPED_WINDOWS + 10).map(|index| (100, format!("{}", index), None)).collect();
+        let files: Vec<(usize, &str, Option<&str>)> = files.iter().map(|(length, path, attr)| (*length, path.as_str(), *attr)).collect();
+        let info = info(&files);
+        let total = info.total_length();
+        let directory = tempfile::tempdir().unwrap();
+        let storage = MmapStorage::new(&info, &directory.path().join("torrent"), Allocation::Sparse, &vec![Priority::Normal; files.len()]).unwrap();
+        // a read of the first file outlives the unmapping of its window
+        storage.write(0, 0, &content(64)).unwrap();
+        let first = storage.read_bytes(0, 0, 64).unwrap();
+        // every file gets mapped in order, the first ones get unmapped again
+        for piece in 0..info.piece_count() {
+            let length = PIECE_LENGTH.min(total - piece * PIECE_LENGTH);
+            storage.write(piece, 0, &content(total)[piece * PIECE_LENGTH..piece * PIECE_LENGTH + length]).unwrap();
+        }
+        assert_eq!(storage.mapped.lock().unwrap().len(), MAX_MAPPED_WINDOWS);
+        assert!(storage.windows[0].lock().unwrap().is_empty());
+        assert_eq!(first, content(64));
+        // the most recently used windows are the ones kept
+        let kept: usize = storage.windows.iter().rev().take(MAX_MAPPED_WINDOWS).map(|windows| windows.lock().unwrap().len()).sum();
+        assert_eq!(kept, MAX_MAPPED_WINDOWS);
+        // unmapped windows are mapped again with what was written to them
+        assert_eq!(storage.read(0, 0, total).unwrap(), content(total));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn allocation_modes() {
+        const LENGTH: usize = 1 << 20;
+        let info = info(&[(LENGTH, "a", None)]);
+        let priorities = [Priority::Normal];
+        let directory = tempfile::tempdir().unwrap();
+        let metadata = |root: &Path| std::fs::metadata(root.join("a")).unwrap();
+
+        let root = directory.path().join("full");
+        DiskStorage::new(&info, &root, Allocation::Full, &priorities).unwrap();
+        assert_eq!(metadata(&root).len(), LENGTH as u64);
+        assert_eq!(allocated(&metadata(&root)), LENGTH as u64);
+
+        let root = directory.path().join("sparse");
+        DiskStorage::new(&info, &root, Allocation::Sparse, &priorities).unwrap();
+        assert_eq!(metadata(&root).len(), LENGTH as u64);
+        assert!(allocated(&metadata(&root)) < LENGTH as u64);
+
+        let root = directory.path().join("compact");
+        let storage = DiskStorage::new(&info, &root, Allocation::Compact, &priorities).unwrap();
+        assert!(!root.join("a").exists());
+        storage.write(1, 0, &content(PIECE_LENGTH)).unwrap();
+        assert_eq!(metadata(&root).len(), 2 * PIECE_LENGTH as u64);
+        storage.write(10, 0, &content(PIECE_LENGTH)).unwrap();
+        assert_eq!(metadata(&root).len(), 11 * PIECE_LENGTH as u64);
+        assert_eq!(storage.read(1, 0, PIECE_LENGTH).unwrap(), content(PIECE_LENGTH));
+    }
+
+    #[test]
+    fn free_space_check_counts_missing_bytes() {
+        let directory = tempfile::tempdir().unwrap();
+        let root = directory.path().join("torrent");
+        let file = |name: &str, offset, length, skipped| StorageFile {
+            path: root.join(name), offset, length, skipped, padding: false,
+        };
+        let files = [file("a", 0, 1000, false), file("b", 1000, 5000, true), file("c", 6000, 2000, false)];
+        let part = PartFile {
+            file: file(".parts", 0, 0, false),
+            piece_length: 100,
+            slots: HashMap::from([(9, 0), (60, 1)]),
+        };
+        // the skipped file is not counted, the partfile slots are
+        let needed = 1000 + 2000 + 2 * 100;
+        let existing = directory.path().to_path_buf();
+        let available = |space: u64| {
+            let existing = existing.clone();
+            move |path: &Path| {
+                // the closest existing ancestor is asked, not the missing download directory
+                assert_eq!(path, existing);
+                Ok(space)
+            }
+        };
+        check_free_space(&files, &part, &root, available(needed)).unwrap();
+        let err = check_free_space(&files, &part, &root, available(needed - 1)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::StorageFull);
+        assert!(!root.exists());
+
+        // bytes already allocated on the disk are not needed again
+        std::fs::create_dir(&root).unwrap();
+        std::fs::write(root.join("c"), vec![1; 2000]).unwrap();
+        check_free_space(&files, &part, &root, |_: &Path| Ok(1200)).unwrap();
+        let err = check_free_space(&files, &part, &root, |_: &Path| Ok(1199)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::StorageFull);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_stay_inside_the_download() {
+        let symlink = |target: &[&str]| {
+            let mut info = info(&[(10, "file", None), (0, "dir/link", Some("l"))]);
+            info.files.as_mut().unwrap()[1].symlink_path = Some(components(target));
+            info
+        };
+        let directory = tempfile::tempdir().unwrap();
+        let root = directory.path().join("torrent");
+        for target in [&["..", "outside"][..], &["dir", "..", "..", "outside"], &["/etc/passwd"], &[]] {
+            let info = symlink(target);
+            let priorities = selection::file_priorities(&info, &[], &[]).unwrap();
+            assert!(DiskStorage::new(&info, &root, Allocation::Sparse, &priorities).is_err(), "{:?}", target);
+            assert!(std::fs::symlink_metadata(root.join("dir/link")).is_err());
+        }
+
+        let info = symlink(&["file"]);
+        let priorities = selection::file_priorities(&info, &[], &[]).unwrap();
+        DiskStorage::new(&info, &root, Allocation::Sparse, &priorities).unwrap();
+        assert_eq!(std::fs::read_link(root.join("dir/link")).unwrap(), Path::new("../file"));
+        assert!(root.join("dir/link").canonicalize().unwrap().starts_with(root.canonicalize().unwrap()));
+    }
+}
@@ -0,0 +1,195 @@
+use std::io::{Error, ErrorKind};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::session::TorrentReader;
+
+// longest request line and headers accepted from a client
+const MAX_HEAD_LENGTH: usize = 8192;
+// bytes read from the torrent per write to the client
+const CHUNK_SIZE: usize = 256 << 10;
+
+/*
+    Minimal HTTP/1.1 server for players streaming the torrent's files while they download. `GET /`
+    lists the files as index, length and path, `GET /<index>` returns a file and honors a single byte
+    Range, HEAD works on both. The body is written as pieces get verified. One request per connection.
+*/
+pub async fn serve(listener: TcpListener, reader: TorrentReader) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let reader = reader.clone();
+                tokio::spawn(async move {
+                    if let Err(err) = handle(stream, reader).await {
+                        eprintln!("stream request failed: {}", err);
+                    }
+                });
+            }
+            Err(err) => {
+                eprintln!("failed to accept stream connection: {}", err);
+                tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+            }
+        }
+    }
+}
+
+async fn handle(mut stream: TcpStream, reader: TorrentReader) -> Result<(), Error> {
+    let head = read_head(&mut stream).await?;
+    let mut lines = head.split("\r\n");
+    let mut request = lines.next().unwrap_or_default().split(' ');
+    let (method, target) = (request.next().unwrap_or_default(), request.next().unwrap_or_default());
+    // several ranges would need a multipart body, the header is ignored then as HTTP allows
+    let range = lines.filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("range"))
+        .map(|(_, value)| value.trim())
+        .filter(|value| !value.contains(','));
+    if method != "GET" && method != "HEAD" {
+        return respond(&mut stream, "405 Method Not Allowed", &[("Allow", "GET, HEAD".into())], b"").await;
+    }
+    let with_body = method == "GET";
+    let files = reader.files();
+    if target == "/" {
+        let listing: String = files.iter().map(|(index, path, length)| format!("{}\t{}\t{}\n", index, length, path)).collect();
+        let headers = [("Content-Type", "text/plain; charset=utf-8".into())];
+        return respond(&mut stream, "200 OK", &headers, if with_body { listing.as_bytes() } else { b"" }).await;
+    }
+    let file = target.strip_prefix('/')
+        .and_then(|index| index.parse::<usize>().ok())
+        .and_then(|index| files.into_iter().find(|(file, _, _)| *file == index));
+    let Some((index, path, length)) = file else {
+        return respond(&mut stream, "404 Not Found", &[], b"").await;
+    };
+    let (status, start, end) = match range.map(|range| parse_range(range, length)) {
+        None => ("200 OK", 0, length),
+        Some(Some((start, end))) => ("206 Partial Content", start, end),
+        Some(None) => {
+            let headers = [("Content-Range", format!("bytes */{}", length))];
+            return respond(&mut stream, "416 Range Not Satisfiable", &headers, b"").await;
+        }
+    };
+    let mut headers = vec![
+        ("Content-Type", content_type(&path).to_string()),
+        ("Content-Length", (end - start).to_string()),
+        ("Accept-Ranges", "bytes".to_string()),
+    ];
+    if status.starts_with("206") {
+        headers.push(("Content-Range", format!("bytes {}-{}/{}", start, end - 1, length)));
+    }
+    stream.write_all(response_head(status, &headers).as_bytes()).await?;
+    let mut position = start;
+    while with_body && position < end {
+        let chunk = (CHUNK_SIZE as u64).min(end - position);
+        let data = reader.read(index, position, chunk as usize).await?;
+        stream.write_all(&data).await?;
+        position += chunk;
+    }
+    stream.shutdown().await
+}
+
+async fn read_head(stream: &mut TcpStream) -> Result<String, Error> {
+    let mut head = vec![];
+    let mut buffer = [0; 1024];
+    loop {
+        let read = stream.read(&mut buffer).await?;
+        if read == 0 {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "client closed the connection before the request ended"));
+        }
+        head.extend_from_slice(&buffer[..read]);
+        if let Some(end) = head.windows(4).position(|window| window == b"\r\n\r\n") {
+            head.truncate(end);
+            return Ok(String::from_utf8_lossy(&head).into_owned());
+        }
+        if head.len() > MAX_HEAD_LENGTH {
+            return Err(Error::new(ErrorKind::InvalidData, "request head is too long"));
+        }
+    }
+}
+
+fn response_head(status: &str, headers: &[(&str, String)]) -> String {
+    let mut head = format!("HTTP/1.1 {}\r\n", status);
+    for (name, value) in headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("Connection: close\r\n\r\n");
+    head
+}
+
+async fn respond(stream: &mut TcpStream, status: &str, headers: &[(&str, String)], body: &[u8]) -> Result<(), Error> {
+    let mut headers = headers.to_vec();
+    headers.push(("Content-Length", body.len().to_string()));
+    stream.write_all(response_head(status, &headers).as_bytes()).await?;
+    stream.write_all(body).await?;
+    stream.shutdown().await
+}
+
+// `bytes=start-end`, `bytes=start-` or `bytes=-suffix` as a half-open range, None when it cannot be served
+fn parse_range(value: &str, length: u64) -> Option<(u64, u64)> {
+    let (first, last) = value.strip_prefix("bytes=")?.split_once('-')?;
+    match (first.trim(), last.trim()) {
+        ("", suffix) => {
+            let suffix = suffix.parse::<u64>().ok().filter(|suffix| *suffix > 0 && length > 0)?;
+            Some((length.saturating_sub(suffix), length))
+        }
+        (first, "") => {
+            let start = first.parse::<u64>().ok()?;
+            (start < length).then_some((start, length))
+        }
+        (first, last) => {
+            let (start, last) = (first.parse::<u64>().ok()?, last.parse::<u64>().ok()?);
+            (start <= last && start < length).then_some((start, (last + 1).min(length)))
+        }
+    }
+}
+
+// enough for players to pick a decoder, everything else is a download
+fn content_type(path: &str) -> &'static str {
+    let extension = path.rsplit_once('.').map(|(_, extension)| extension.to_ascii_lowercase()).unwrap_or_default();
+    match extension.as_str() {
+        "mp4" | "m4v" => "video/mp4",
+        "mkv" => "video/x-matroska",
+        "webm" => "video/webm",
+        "avi" => "video/x-msvideo",
+        "mp3" => "audio/mpeg",
+        "flac" => "audio/flac",
+        "ogg" => "audio/ogg",
+        "txt" | "log" => "text/plain; charset=utf-8",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn byte_ranges() {
+        assert_eq!(parse_range("bytes=0-99", 1000), Some((0, 100)));
+        assert_eq!(parse_range("bytes=100-100", 1000), Some((100, 101)));
+        // the end is clamped to the file
+        assert_eq!(parse_range("bytes=900-5000", 1000), Some((900, 1000)));
+        assert_eq!(parse_range("bytes=500-", 1000), Some((500, 1000)));
+        assert_eq!(parse_range("bytes=-100", 1000), Some((900, 1000)));
+        assert_eq!(parse_range("bytes=-5000", 1000), Some((0, 1000)));
+        assert_eq!(parse_range("bytes= 10 - 19 ", 1000), Some((10, 20)));
+    }
+
+    #[test]
+    fn unsatisfiable_ranges() {
+        // starting at or past the end
+        assert_eq!(parse_range("bytes=1000-", 1000), None);
+        assert_eq!(parse_range("bytes=1000-1100", 1000), None);
+        // start after the last byte
+        assert_eq!(parse_range("bytes=20-10", 1000), None);
+        assert_eq!(parse_range("bytes=-0", 1000), None);
+        // nothing of an empty file can be served
+        assert_eq!(parse_range("bytes=0-", 0), None);
+        assert_eq!(parse_range("bytes=0-0", 0), None);
+        assert_eq!(parse_range("bytes=-10", 0), None);
+        // not a byte range at all
+        assert_eq!(parse_range("items=0-10", 1000), None);
+        assert_eq!(parse_range("bytes=10", 1000), None);
+        assert_eq!(parse_range("bytes=a-b", 1000), None);
+        assert_eq!(parse_range("bytes=-", 1000), None);
+    }
+}
@@ -1,54 +1,185 @@
+#[allow(clippy::module_inception)]
 pub mod tracker {
+    use std::collections::hash_map::RandomState;
+    use std::hash::BuildHasher;
     use std::io::{Error, ErrorKind};
     use std::net::{IpAddr, SocketAddr};
     use std::path::PathBuf;
+    use std::time::{Duration, Instant};
 
-    use bytes::{Buf, BufMut, BytesMut};
-    use reqwest::{Client};
+    use bytes::BufMut;
+    use reqwest::Client;
     use serde::{Deserialize, Serialize};
     use tokio::io::{AsyncReadExt, AsyncWriteExt, Interest};
     use tokio::net::TcpStream;
+    use tokio_util::codec::Framed;
+    use futures::{SinkExt, StreamExt};
     use crate::metainfo::Meta;
-    use crate::peers::{PeerMessage, PeerMessageTag, PeersContainer, Request};
+    use crate::peers::{merge_peers, Peer, PeerCodec, PeerMessage, PeerMessageTag, Peers6Container, PeersContainer, Request};
 
     const MAX_BLOCK_SIZE: usize = 1 << 14;
+    pub const PEER_ID: &str = "00112233445566778899";
 
-    pub async fn connect_to_tracker(meta_data: &Meta) -> Result<PeersContainer, Error> {
-        let hash = meta_data.calculate_info_hash();
-        let tracker_request = TrackerRequest {
-            peer_id: "00112233445566778899".to_string(),
-            port: 6881,
-            uploaded: 0,
-            downloaded: 0,
-            left: meta_data.info.length,
-            compact: 1,
-        };
-        let url_params = serde_urlencoded::to_string(tracker_request).expect("url params encode failed");
+    // used when tracker did not tell us how often to announce
+    const DEFAULT_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(30 * 60);
+    const BASE_RETRY_DELAY: Duration = Duration::from_secs(15);
+    const MAX_RETRY_DELAY: Duration = Duration::from_secs(30 * 60);
+
+    #[derive(Debug, thiserror::Error)]
+    pub enum TrackerError {
+        #[error("tracker {url} responded with failure: {reason}")]
+        Failure { url: String, reason: String },
+        #[error("error sending the request to {url}: {source}")]
+        Request { url: String, source: reqwest::Error },
+        #[error("cannot deserialize response from {url}: {source}")]
+        InvalidResponse { url: String, source: serde_bencode::Error },
+        #[error("tracker {url} is backing off for another {remaining:?}")]
+        BackingOff { url: String, remaining: Duration },
+        #[error("torrent does not list any trackers")]
+        NoTrackers,
+    }
+
+    pub async fn connect_to_tracker(meta_data: &Meta) -> Result<TrackerResponse, TrackerError> {
+        let mut manager = TrackerManager::from_meta(meta_data);
+        let request = TrackerRequest::new(meta_data.info.total_length());
+        manager.announce(&request, &meta_data.calculate_info_hash()).await
+    }
+
+    pub async fn announce(announce_url: &str, request: &TrackerRequest, info_hash: &Vec<u8>) -> Result<TrackerResponse, TrackerError> {
+        let url_params = serde_urlencoded::to_string(request).expect("url params encode failed");
         let tracker_request = format!(
             "{}?{}&info_hash={}",
-            meta_data.announce,
+            announce_url,
             url_params,
-            &url_encode(&hash)
+            &url_encode(info_hash)
         );
         let client = Client::new();
         //cannot use query() method since it does url-encode differently for hashes
-        // let body = client.request(Method::GET, meta_data.announce)
-        //     .query(&[("peer_id", String::from("00112233445566778899")), ("info_hash", url_encode(&hash)), ("port", String::from("6881")), ("uploaded", String::from("0")), ("downloaded", String::from("0")), ("left", meta_data.info.length.to_string()), ("compact", String::from("1"))])
-        //     .build();
         let body = client.get(tracker_request).build();
-        let result = client.execute(body.unwrap()).await;
-        match result {
-            Ok(response) => {
-                let body = response.bytes().await.unwrap();
-                let peers: PeersResponse = serde_bencode::from_bytes(body.as_ref()).expect("cannot deserialize response");
-                for peer in &peers.peers_container.peers {
-                    println!("{}:{}", peer.ip_address, peer.port)
+        let to_request_error = |source| TrackerError::Request { url: announce_url.to_string(), source };
+        let response = client.execute(body.map_err(to_request_error)?).await.map_err(to_request_error)?;
+        let body = response.bytes().await.map_err(to_request_error)?;
+        let response: TrackerResponse = serde_bencode::from_bytes(body.as_ref())
+            .map_err(|source| TrackerError::InvalidResponse { url: announce_url.to_string(), source })?;
+        if let Some(reason) = response.failure_reason {
+            return Err(TrackerError::Failure { url: announce_url.to_string(), reason });
+        }
+        if let Some(warning) = &response.warning_message {
+            eprintln!("tracker {} warning: {}", announce_url, warning);
+        }
+        Ok(response)
+    }
+
+    /*
+        Keeps announce-list tiers (BEP 12). Trackers within a tier are shuffled once and then tried in order,
+        a tracker that answered is moved to the front of its tier, and a tracker that failed is skipped until
+        its backoff expires.
+    */
+    pub struct TrackerManager {
+        tiers: Vec<Vec<TrackerState>>,
+        next_announce: Duration,
+    }
+
+    struct TrackerState {
+        url: String,
+        tracker_id: Option<String>,
+        failures: u32,
+        retry_at: Option<Instant>,
+        not_before: Option<Instant>,
+    }
+
+    impl TrackerState {
+        fn new(url: String) -> Self {
+            TrackerState { url, tracker_id: None, failures: 0, retry_at: None, not_before: None }
+        }
+
+        fn register_failure(&mut self, error: &TrackerError) {
+            self.failures += 1;
+            let mut delay = BASE_RETRY_DELAY.saturating_mul(1 << self.failures.min(10)).min(MAX_RETRY_DELAY);
+            // failure reason means the tracker is alive but refuses us, asking again soon will not help
+            if let TrackerError::Failure { .. } = error {
+                delay = delay.max(Duration::from_secs(5 * 60));
+            }
+            self.retry_at = Some(Instant::now() + delay);
+        }
+    }
+
+    impl TrackerManager {
+        pub fn from_meta(meta_data: &Meta) -> Self {
+            match &meta_data.announce_list {
+                Some(list) if !list.is_empty() => TrackerManager::from_tiers(list.clone()),
+                _ => TrackerManager::from_tiers(vec![vec![meta_data.announce.clone()]]),
+            }
+        }
+
+        fn from_tiers(tiers: Vec<Vec<String>>) -> Self {
+            // trackerless torrents rely on DHT only
+            let tiers = tiers.into_iter()
+                .map(|tier| {
+                    let mut tier: Vec<_> = tier.into_iter().filter(|url| !url.is_empty()).map(TrackerState::new).collect();
+                    shuffle(&mut tier);
+                    tier
+                })
+                .filter(|tier| !tier.is_empty())
+                .collect();
+            TrackerManager { tiers, next_announce: DEFAULT_ANNOUNCE_INTERVAL }
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.tiers.is_empty()
+        }
+
+        pub async fn announce(&mut self, request: &TrackerRequest, info_hash: &Vec<u8>) -> Result<TrackerResponse, TrackerError> {
+            let mut last_error = TrackerError::NoTrackers;
+            for tier in self.tiers.iter_mut() {
+                for position in 0..tier.len() {
+                    let tracker = &mut tier[position];
+                    // events must always reach the tracker, min interval only applies to regular announces
+                    let not_before = if request.event.is_none() { tracker.not_before } else { None };
+                    if let Some(retry_at) = tracker.retry_at.max(not_before) {
+                        let now = Instant::now();
+                        if retry_at > now {
+                            last_error = TrackerError::BackingOff { url: tracker.url.clone(), remaining: retry_at - now };
+                            continue;
+                        }
+                    }
+                    let mut tracker_request = request.clone();
+                    tracker_request.trackerid = tracker.tracker_id.clone();
+                    match announce(&tracker.url, &tracker_request, info_hash).await {
+                        Ok(response) => {
+                            tracker.failures = 0;
+                            tracker.retry_at = None;
+                            // trackers ask not to be re-announced to more often than min interval
+                            tracker.not_before = response.min_interval
+                                .map(|seconds| Instant::now() + Duration::from_secs(seconds));
+                            if response.tracker_id.is_some() {
+                                tracker.tracker_id = response.tracker_id.clone();
+                            }
+                            self.next_announce = response.announce_interval();
+                            let tracker = tier.remove(position);
+                            tier.insert(0, tracker);
+                            return Ok(response);
+                        }
+                        Err(error) => {
+                            tracker.register_failure(&error);
+                            last_error = error;
+                        }
+                    }
                 }
-                Ok(peers.peers_container)
             }
-            Err(error) => {
-                eprintln!("error sending the request: {}", error);
-                Err(Error::new(ErrorKind::NotConnected, error))
+            Err(last_error)
+        }
+
+        // how long to wait before the next regular announce, or before retrying after all trackers failed
+        pub fn next_announce(&self) -> Duration {
+            let now = Instant::now();
+            let earliest_retry = self.tiers.iter().flatten()
+                .filter_map(|tracker| tracker.retry_at)
+                .map(|retry_at| retry_at.saturating_duration_since(now))
+                .min();
+            match earliest_retry {
+                Some(retry) if self.tiers.iter().flatten().all(|tracker| tracker.failures > 0) => retry,
+                _ => self.next_announce,
             }
         }
     }
@@ -63,7 +194,7 @@ pub mod tracker {
                 let reserve: [u8; 8] = [0; 8];
                 buf.put_slice(&reserve);
                 buf.put_slice(meta_data.calculate_info_hash().as_slice());
-                buf.put_slice("00112233445566778899".as_bytes());
+                buf.put_slice(PEER_ID.as_bytes());
 
                 safe_stream.write_all(buf.as_slice()).await?;
                 let mut response_buf: Vec<u8> = vec![];
@@ -81,9 +212,10 @@ pub mod tracker {
     }
 
     pub async fn download_piece(piece_file_path: &PathBuf, meta_data: Meta, piece_index: &usize) -> Result<(), Error> {
-        let peers = connect_to_tracker(&meta_data).await?;
-        if !peers.peers.is_empty() {
-            let stream = TcpStream::connect(SocketAddr::new(peers.peers[0].ip_address, peers.peers[0].port)).await;
+        let response = connect_to_tracker(&meta_data).await.map_err(Error::other)?;
+        let peers = response.peers();
+        if !peers.is_empty() {
+            let stream = TcpStream::connect(SocketAddr::new(peers[0].ip_address, peers[0].port)).await;
             match stream {
                 Ok(mut safe_stream) => {
                     let mut buf: Vec<u8> = Vec::new();
@@ -92,31 +224,27 @@ pub mod tracker {
                     let reserve: [u8; 8] = [0; 8];
                     buf.put_slice(&reserve);
                     buf.put_slice(meta_data.calculate_info_hash().as_slice());
-                    buf.put_slice("00112233445566778899".as_bytes());
+                    buf.put_slice(PEER_ID.as_bytes());
 
                     safe_stream.write_all(buf.as_slice()).await?;
                     loop {
                         let ready_to_read = safe_stream.ready(Interest::READABLE).await?;
                         if ready_to_read.is_readable() {
-                            let mut response_buf: Vec<u8> = vec![];
-                            response_buf.resize(buf.len(), 0);
+                            let mut response_buf: Vec<u8> = vec![0; buf.len()];
                             safe_stream.read_exact(&mut response_buf).await?;
                             break;
                         }
                     }
 
-                    let mut connection = FrameConnection::new(safe_stream);
-                    let bitfield = connection.read_frame().await.expect("peer should response with bitfield")
+                    let mut connection = Framed::new(safe_stream, PeerCodec);
+                    let bitfield = connection.next().await.transpose().expect("peer should response with bitfield")
                         .ok_or(Error::new(ErrorKind::InvalidData, "peer not responded with bitfield"))?;
                     assert_eq!(bitfield.tag, PeerMessageTag::Bitfield);
-                    connection.write_frame(PeerMessage {
-                        tag: PeerMessageTag::Interested,
-                        payload: Vec::new(),
-                    }).await.expect("our client should respond with INTERESTED");
-                    let _ = connection.read_frame().await.expect("peer should respond to INTERESTED with UNCHOKE message")
+                    connection.send(PeerMessage::new(PeerMessageTag::Interested, Vec::new())).await.expect("our client should respond with INTERESTED");
+                    let _ = connection.next().await.transpose().expect("peer should respond to INTERESTED with UNCHOKE message")
                         .ok_or(Error::new(ErrorKind::InvalidData, "peer not responded with UNCHOKE"))?;
 
-                    let block_count = (meta_data.info.piece_length + (MAX_BLOCK_SIZE - 1)) / MAX_BLOCK_SIZE;
+                    let block_count = meta_data.info.piece_length.div_ceil(MAX_BLOCK_SIZE);
                     let mut result: Vec<u8> = vec![];
                     for block in 0..block_count {
                         let block_size : usize = if block == block_count - 1 {
@@ -131,11 +259,8 @@ pub mod tracker {
                             MAX_BLOCK_SIZE
                         };
                         let request = Request::new(*piece_index as u32, (block * block_size) as u32, block_size as u32);
-                        connection.write_frame(PeerMessage {
-                            tag: PeerMessageTag::Request,
-                            payload: request.as_bytes_mute(),
-                        }).await?;
-                        let piece = connection.read_frame().await.expect("peer should respond with PIECE")
+                        connection.send(PeerMessage::new(PeerMessageTag::Request, request.as_bytes_mute())).await?;
+                        let piece = connection.next().await.transpose().expect("peer should respond with PIECE")
                             .ok_or(Error::new(ErrorKind::InvalidData, "peer not responded with piece"))?;
 
                         assert_eq!(piece.tag, PeerMessageTag::Piece);
@@ -154,113 +279,253 @@ pub mod tracker {
         }
     }
 
+
+    // Fisher-Yates with randomly keyed hashes, good enough to spread the load over a tier's trackers
+    fn shuffle<T>(items: &mut [T]) {
+        let state = RandomState::new();
+        for position in (1..items.len()).rev() {
+            let other = (state.hash_one(position) % (position as u64 + 1)) as usize;
+            items.swap(position, other);
+        }
+    }
+
     fn url_encode(input: &Vec<u8>) -> String {
         let mut encoded = String::with_capacity(3 * input.len());
         for &b in input.as_slice() {
             encoded.push('%');
-            encoded.push_str(&hex::encode(&[b]))
+            encoded.push_str(&hex::encode([b]))
         }
         encoded
     }
 
     #[derive(Debug, Clone, Serialize)]
-    struct TrackerRequest {
+    pub struct TrackerRequest {
         pub peer_id: String,
         pub port: u16,
         pub uploaded: usize,
         pub downloaded: usize,
         pub left: usize,
         pub compact: u8,
+        pub trackerid: Option<String>,
+        // started, completed or stopped. omitted for regular announces
+        pub event: Option<String>,
+        // BEP 7, our IPv6 address so that IPv6 peers can reach us through the tracker
+        pub ipv6: Option<String>,
     }
 
-    #[derive(Debug, Deserialize)]
-    struct PeersResponse {
-        pub interval: usize,
-        #[serde(rename = "peers")]
-        pub peers_container: PeersContainer,
+    impl TrackerRequest {
+        pub fn new(left: usize) -> Self {
+            TrackerRequest {
+                peer_id: PEER_ID.to_string(),
+                port: 6881,
+                uploaded: 0,
+                downloaded: 0,
+                left,
+                compact: 1,
+                trackerid: None,
+                event: None,
+                ipv6: None,
+            }
+        }
     }
 
-    struct FrameConnection {
-        stream: TcpStream,
-        buffer: BytesMut,
+    #[derive(Debug, Deserialize)]
+    pub struct TrackerResponse {
+        #[serde(rename = "failure reason", default)]
+        pub failure_reason: Option<String>,
+        #[serde(rename = "warning message", default)]
+        pub warning_message: Option<String>,
+        #[serde(default)]
+        pub interval: Option<u64>,
+        #[serde(rename = "min interval", default)]
+        pub min_interval: Option<u64>,
+        #[serde(rename = "tracker id", default)]
+        pub tracker_id: Option<String>,
+        // number of seeders
+        #[serde(default)]
+        pub complete: Option<u64>,
+        // number of leechers
+        #[serde(default)]
+        pub incomplete: Option<u64>,
+        #[serde(rename = "peers", default)]
+        pub peers_container: Option<PeersContainer>,
+        #[serde(rename = "peers6", default)]
+        pub peers6_container: Option<Peers6Container>,
     }
 
-    impl FrameConnection {
-        pub fn new(stream: TcpStream) -> FrameConnection {
-            FrameConnection {
-                stream,
-                buffer: BytesMut::with_capacity(1 << 16),
+    impl TrackerResponse {
+        pub fn peers(&self) -> Vec<Peer> {
+            let peers = self.peers_container.as_ref().map(|container| container.peers.clone()).unwrap_or_default();
+            let peers6 = self.peers6_container.as_ref().map(|container| container.peers.clone()).unwrap_or_default();
+            merge_peers([peers, peers6])
+        }
+
+        pub fn announce_interval(&self) -> Duration {
+            let interval = self.interval.map(Duration::from_secs).unwrap_or(DEFAULT_ANNOUNCE_INTERVAL);
+            match self.min_interval {
+                Some(min_interval) => interval.max(Duration::from_secs(min_interval)),
+                None => interval,
             }
         }
+    }
 
-        pub async fn read_frame(&mut self) -> Result<Option<PeerMessage>, Error> {
-            loop {
-                if let Some(frame) = self.parse_frame()? {
-                    return Ok(Some(frame));
-                }
-                if 0 == self.stream.read_buf(&mut self.buffer).await? {
-                    // The remote closed the connection. For this to be
-                    // a clean shutdown, there should be no data in the
-                    // read buffer. If there is, this means that the
-                    // peer closed the socket while sending a frame.
-                    return if self.buffer.is_empty() {
-                        Ok(None)
-                    } else {
-                        Err(Error::new(ErrorKind::NotConnected, "connection reset by peer"))
-                    };
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use std::sync::{Arc, Mutex};
+        use tokio::net::TcpListener;
+
+        type Queries = Arc<Mutex<Vec<String>>>;
+
+        // an HTTP tracker answering every announce with `body`, recording the query strings
+        async fn serve(body: &'static [u8]) -> (String, Queries) {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("http://{}/announce", listener.local_addr().unwrap());
+            let queries = Queries::default();
+            let log = queries.clone();
+            tokio::spawn(async move {
+                while let Ok((mut stream, _)) = listener.accept().await {
+                    let mut request = vec![];
+                    let mut buf = [0; 1024];
+                    while !request.ends_with(b"\r\n\r\n") {
+                        match stream.read(&mut buf).await {
+                            Ok(0) | Err(_) => break,
+                            Ok(read) => request.extend_from_slice(&buf[..read]),
+                        }
+                    }
+                    let request = String::from_utf8_lossy(&request).to_string();
+                    let target = request.split(' ').nth(1).unwrap_or_default();
+                    log.lock().unwrap().push(target.split_once('?').map_or("", |(_, query)| query).to_string());
+                    let head = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len());
+                    let _ = stream.write_all(head.as_bytes()).await;
+                    let _ = stream.write_all(body).await;
+                    let _ = stream.shutdown().await;
                 }
-            }
+            });
+            (url, queries)
         }
 
-        fn parse_frame(&mut self) -> Result<Option<PeerMessage>, Error> {
-            // length prefix (4 bytes)
-            if self.buffer.len() < 4 {
-                return Ok(None);
-            }
+        // a tracker nobody listens on
+        async fn dead_tracker() -> String {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            format!("http://{}/announce", listener.local_addr().unwrap())
+        }
 
-            let mut message_length = [0u8; 4];
-            message_length.copy_from_slice(&self.buffer[0..4]);
-            let message_length = u32::from_be_bytes(message_length) as usize;
+        fn info_hash() -> Vec<u8> {
+            vec![0xab; 20]
+        }
+
+        fn urls(manager: &TrackerManager) -> Vec<Vec<String>> {
+            manager.tiers.iter().map(|tier| tier.iter().map(|tracker| tracker.url.clone()).collect()).collect()
+        }
 
-            if message_length == 0 {
-                self.buffer.advance(4);
-                return self.parse_frame();
+        #[test]
+        fn response_fields() {
+            let response: TrackerResponse = serde_bencode::from_bytes(
+                b"d8:completei3e10:incompletei2e8:intervali1800e12:min intervali900e5:peers6:\x7f\x00\x00\x01\x1a\xe110:tracker id3:abc15:warning message7:be nicee",
+            ).unwrap();
+            assert_eq!((response.complete, response.incomplete), (Some(3), Some(2)));
+            assert_eq!(response.tracker_id.as_deref(), Some("abc"));
+            assert_eq!(response.warning_message.as_deref(), Some("be nice"));
+            assert_eq!(response.peers().len(), 1);
+            assert_eq!(response.announce_interval(), Duration::from_secs(1800));
+            // the regular interval never undercuts the minimum
+            let response: TrackerResponse = serde_bencode::from_bytes(b"d8:intervali60e12:min intervali300ee").unwrap();
+            assert_eq!(response.announce_interval(), Duration::from_secs(300));
+            let response: TrackerResponse = serde_bencode::from_bytes(b"de").unwrap();
+            assert_eq!(response.announce_interval(), DEFAULT_ANNOUNCE_INTERVAL);
+        }
+
+        #[tokio::test]
+        async fn failure_reason_is_an_error() {
+            let (url, _) = serve(b"d14:failure reason22:torrent not registerede").await;
+            match announce(&url, &TrackerRequest::new(100), &info_hash()).await {
+                Err(TrackerError::Failure { reason, .. }) => assert_eq!(reason, "torrent not registered"),
+                other => panic!("unexpected {:?}", other.map(|_| ())),
             }
+            let (url, _) = serve(b"not bencode").await;
+            assert!(matches!(announce(&url, &TrackerRequest::new(100), &info_hash()).await, Err(TrackerError::InvalidResponse { .. })));
+        }
 
-            if self.buffer.len() < message_length {
-                return Ok(None)
+        #[tokio::test]
+        async fn warning_message_is_surfaced() {
+            let (url, queries) = serve(b"d8:intervali60e5:peers0:15:warning message7:be nicee").await;
+            let response = announce(&url, &TrackerRequest::new(100), &info_hash()).await.unwrap();
+            assert_eq!(response.warning_message.as_deref(), Some("be nice"));
+            let query = queries.lock().unwrap()[0].clone();
+            assert!(query.contains("left=100") && query.ends_with(&format!("info_hash={}", url_encode(&info_hash()))), "{}", query);
+        }
+
+        #[tokio::test]
+        async fn announce_carries_our_ipv6_address() {
+            let (url, queries) = serve(b"d8:intervali60e5:peers0:e").await;
+            let mut request = TrackerRequest::new(100);
+            announce(&url, &request, &info_hash()).await.unwrap();
+            request.ipv6 = Some("2001:db8::1".to_string());
+            announce(&url, &request, &info_hash()).await.unwrap();
+            let queries = queries.lock().unwrap();
+            assert!(!queries[0].contains("ipv6"));
+            assert!(queries[1].contains("&ipv6=2001%3Adb8%3A%3A1"), "{}", queries[1]);
+        }
+
+        #[tokio::test]
+        async fn min_interval_holds_back_regular_announces_only() {
+            let (url, queries) = serve(b"d8:intervali60e12:min intervali600e5:peers0:10:tracker id2:t1e").await;
+            let mut manager = TrackerManager::from_tiers(vec![vec![url]]);
+            manager.announce(&TrackerRequest::new(100), &info_hash()).await.unwrap();
+            assert_eq!(manager.next_announce(), Duration::from_secs(600));
+            assert!(matches!(manager.announce(&TrackerRequest::new(100), &info_hash()).await, Err(TrackerError::BackingOff { .. })));
+            let mut stopped = TrackerRequest::new(100);
+            stopped.event = Some("stopped".to_string());
+            manager.announce(&stopped, &info_hash()).await.unwrap();
+            let queries = queries.lock().unwrap();
+            assert_eq!(queries.len(), 2);
+            // the tracker id from the first response is sent back
+            assert!(queries[1].contains("trackerid=t1") && queries[1].contains("event=stopped"));
+        }
+
+        #[tokio::test]
+        async fn failed_tracker_backs_off() {
+            let mut manager = TrackerManager::from_tiers(vec![vec![dead_tracker().await]]);
+            assert!(matches!(manager.announce(&TrackerRequest::new(100), &info_hash()).await, Err(TrackerError::Request { .. })));
+            match manager.announce(&TrackerRequest::new(100), &info_hash()).await {
+                Err(TrackerError::BackingOff { remaining, .. }) => assert!(remaining > Duration::from_secs(20)),
+                other => panic!("unexpected {:?}", other.map(|_| ())),
             }
+            // all trackers failed, the next announce waits for the backoff instead of the interval
+            assert!(manager.next_announce() <= BASE_RETRY_DELAY * 2);
+            assert!(matches!(TrackerManager::from_tiers(vec![vec![String::new()]]).announce(&TrackerRequest::new(1), &info_hash()).await,
+                             Err(TrackerError::NoTrackers)));
+        }
 
-            let message_tag = self.buffer[4];
-
-            let tag = match message_tag {
-                0 => PeerMessageTag::Heartbeat,
-                1 => PeerMessageTag::Unchoke,
-                2 => PeerMessageTag::Interested,
-                5 => PeerMessageTag::Bitfield,
-                6 => PeerMessageTag::Request,
-                7 => PeerMessageTag::Piece,
-                tag => {
-                    return Err(Error::new(ErrorKind::InvalidData, format!("unknown message tag received {}", tag)));
-                }
-            };
-            let data = if self.buffer.len() >= message_length - 1 {
-                self.buffer[5..4 + message_length].to_vec()
-            } else {
-                vec![]
-            };
-            self.buffer.advance(message_length + 4);
-            Ok(Some(PeerMessage { tag, payload: data.to_vec() }))
+        #[tokio::test]
+        async fn responding_tracker_moves_to_the_front_of_its_tier() {
+            let (alive, _) = serve(b"d8:intervali60e5:peers0:e").await;
+            let (dead, backup) = (dead_tracker().await, dead_tracker().await);
+            let mut manager = TrackerManager::from_tiers(vec![vec![dead.clone(), alive.clone()], vec![backup.clone()]]);
+            manager.announce(&TrackerRequest::new(100), &info_hash()).await.unwrap();
+            assert_eq!(manager.tiers[0][0].url, alive);
+            // the second tier is only asked when the first one fails
+            assert_eq!(manager.tiers[1][0].failures, 0);
+            manager.announce(&TrackerRequest::new(100), &info_hash()).await.unwrap();
+            assert_eq!(urls(&manager)[0], vec![alive, dead]);
         }
 
-        pub async fn write_frame(&mut self, frame: PeerMessage) -> Result<(), Error> {
-            let mut complete: Vec<u8> = vec![];
-            let len_slice = u32::to_be_bytes(frame.payload.len() as u32 + 1);
-            complete.extend_from_slice(&len_slice);
-            complete.push(frame.tag as u8);
-            complete.extend_from_slice(frame.payload.as_slice());
-            self.stream.write_all(complete.as_slice()).await?;
-            Ok(())
+        #[test]
+        fn tiers_are_shuffled_within_themselves() {
+            let tier: Vec<String> = (0..20).map(|index| format!("http://tracker{}/announce", index)).collect();
+            let managers: Vec<_> = (0..4).map(|_| TrackerManager::from_tiers(vec![tier.clone(), vec!["http://last/announce".to_string()]])).collect();
+            for manager in &managers {
+                let mut urls = urls(manager);
+                assert_eq!(urls[1], vec!["http://last/announce"]);
+                urls[0].sort();
+                let mut sorted = tier.clone();
+                sorted.sort();
+                assert_eq!(urls[0], sorted);
+            }
+            // 20! orders, four identical ones would not be shuffled
+            assert!(managers.iter().any(|manager| urls(manager)[0] != tier));
+            assert!(TrackerManager::from_tiers(vec![vec![String::new()], vec![]]).is_empty());
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,1045 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, oneshot, Notify};
+use tokio::time::sleep_until;
+
+use crate::random;
+
+/*
+    uTP, the Micro Transport Protocol (BEP 29). A reliable, ordered byte stream over UDP whose LEDBAT
+    congestion control backs off as soon as it sees queuing delay grow, so a download does not choke
+    the rest of the user's traffic. Connections share the UDP socket of the listen port with the DHT.
+*/
+const VERSION: u8 = 1;
+const HEADER_LENGTH: usize = 20;
+const EXTENSION_SELECTIVE_ACK: u8 = 1;
+// keeps packets below the common internet MTU, including tunnels
+const MAX_PACKET_SIZE: usize = 1400;
+const MAX_PAYLOAD: usize = MAX_PACKET_SIZE - HEADER_LENGTH;
+// LEDBAT aims to add no more than this much delay to the path
+const TARGET_DELAY: u32 = 100_000;
+// the congestion window grows by at most this many bytes per round trip
+const MAX_WINDOW_INCREASE: f64 = 3000.0;
+const MIN_WINDOW: f64 = MAX_PAYLOAD as f64;
+const INITIAL_WINDOW: f64 = (4 * MAX_PAYLOAD) as f64;
+const MAX_WINDOW: f64 = (1 << 20) as f64;
+// base delay is the lowest delay seen over the last two minutes, kept as two one minute halves
+const BASE_DELAY_INTERVAL: Duration = Duration::from_secs(60);
+const MIN_TIMEOUT: Duration = Duration::from_millis(500);
+const INITIAL_TIMEOUT: Duration = Duration::from_secs(1);
+const MAX_TIMEOUT: Duration = Duration::from_secs(60);
+// consecutive timeouts after which the peer is considered gone
+const MAX_TIMEOUTS: u32 = 6;
+// a peer that did not answer two SYNs most likely has no uTP, connecting falls back to TCP then
+const MAX_SYN_TIMEOUTS: u32 = 1;
+// duplicate acks, or selectively acked packets past a hole, that make us resend without waiting for a timeout
+const DUPLICATE_ACKS: usize = 3;
+const RECEIVE_BUFFER: usize = 1 << 20;
+const SEND_BUFFER: usize = 1 << 20;
+// out of order packets further ahead than this are dropped
+const REORDER_LIMIT: u16 = 1024;
+// bits in the selective ack bitmask we send
+const SELECTIVE_ACK_BITS: u16 = 32;
+// incoming connections waiting for the session to take them, SYNs beyond that are dropped
+const MAX_PENDING_ACCEPTS: usize = 64;
+// incoming connections the remote sent nothing but its SYN on, which spoofed SYNs would pile up
+const MAX_HALF_OPEN: usize = 128;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum PacketType {
+    Data = 0,
+    Fin = 1,
+    State = 2,
+    Reset = 3,
+    Syn = 4,
+}
+
+impl PacketType {
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(PacketType::Data),
+            1 => Some(PacketType::Fin),
+            2 => Some(PacketType::State),
+            3 => Some(PacketType::Reset),
+            4 => Some(PacketType::Syn),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Packet {
+    kind: PacketType,
+    connection_id: u16,
+    timestamp: u32,
+    timestamp_difference: u32,
+    window: u32,
+    seq_nr: u16,
+    ack_nr: u16,
+    selective_ack: Option<Vec<u8>>,
+    payload: Vec<u8>,
+}
+
+impl Packet {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_LENGTH + self.payload.len() + 6);
+        bytes.push((self.kind as u8) << 4 | VERSION);
+        bytes.push(if self.selective_ack.is_some() { EXTENSION_SELECTIVE_ACK } else { 0 });
+        bytes.extend_from_slice(&self.connection_id.to_be_bytes());
+        bytes.extend_from_slice(&self.timestamp.to_be_bytes());
+        bytes.extend_from_slice(&self.timestamp_difference.to_be_bytes());
+        bytes.extend_from_slice(&self.window.to_be_bytes());
+        bytes.extend_from_slice(&self.seq_nr.to_be_bytes());
+        bytes.extend_from_slice(&self.ack_nr.to_be_bytes());
+        if let Some(mask) = &self.selective_ack {
+            bytes.push(0);
+            bytes.push(mask.len() as u8);
+            bytes.extend_from_slice(mask);
+        }
+        bytes.extend_from_slice(&self.payload);
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < HEADER_LENGTH || bytes[0] & 0x0f != VERSION {
+            return None;
+        }
+        let kind = PacketType::from_u8(bytes[0] >> 4)?;
+        let u16_at = |at: usize| u16::from_be_bytes([bytes[at], bytes[at + 1]]);
+        let u32_at = |at: usize| u32::from_be_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]]);
+        let mut selective_ack = None;
+        let mut extension = bytes[1];
+        let mut offset = HEADER_LENGTH;
+        while extension != 0 {
+            let (next, length) = (*bytes.get(offset)?, *bytes.get(offset + 1)? as usize);
+            let data = bytes.get(offset + 2..offset + 2 + length)?;
+            if extension == EXTENSION_SELECTIVE_ACK {
+                selective_ack = Some(data.to_vec());
+            }
+            extension = next;
+            offset += 2 + length;
+        }
+        Some(Packet {
+            kind,
+            connection_id: u16_at(2),
+            timestamp: u32_at(4),
+            timestamp_difference: u32_at(8),
+            window: u32_at(12),
+            seq_nr: u16_at(16),
+            ack_nr: u16_at(18),
+            selective_ack,
+            payload: bytes[offset..].to_vec(),
+        })
+    }
+}
+
+// first byte of a uTP packet is the type in the high nibble and version 1, which a bencoded DHT message never starts with
+pub fn is_utp_packet(bytes: &[u8]) -> bool {
+    bytes.len() >= HEADER_LENGTH && bytes[0] & 0x0f == VERSION && bytes[0] >> 4 <= PacketType::Syn as u8
+}
+
+// sequence numbers wrap around, `a` is before or at `b` when it is less than half the space behind it
+fn seq_less_equal(a: u16, b: u16) -> bool {
+    b.wrapping_sub(a) < 0x8000
+}
+
+fn now_micros() -> u32 {
+    static EPOCH: OnceLock<Instant> = OnceLock::new();
+    EPOCH.get_or_init(Instant::now).elapsed().as_micros() as u32
+}
+
+type ConnectionKey = (SocketAddr, u16);
+
+/*
+    Demultiplexes the uTP packets arriving on the UDP sockets to their connections. Every connection is
+    driven by its own task, keyed by the remote address and the connection id the remote puts in its packets.
+*/
+pub struct UtpSocket {
+    ipv4: Option<Arc<UdpSocket>>,
+    ipv6: Option<Arc<UdpSocket>>,
+    connections: Mutex<HashMap<ConnectionKey, mpsc::UnboundedSender<Packet>>>,
+    accepted: mpsc::Sender<(UtpStream, SocketAddr)>,
+    half_open: AtomicUsize,
+}
+
+impl UtpSocket {
+    // connections initiated by remote peers come out of the returned receiver
+    pub fn new(ipv4: Option<Arc<UdpSocket>>, ipv6: Option<Arc<UdpSocket>>) -> (Arc<UtpSocket>, mpsc::Receiver<(UtpStream, SocketAddr)>) {
+        let (accepted, incoming) = mpsc::channel(MAX_PENDING_ACCEPTS);
+        (Arc::new(UtpSocket { ipv4, ipv6, connections: Mutex::new(HashMap::new()), accepted, half_open: AtomicUsize::new(0) }), incoming)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<ConnectionKey, mpsc::UnboundedSender<Packet>>> {
+        self.connections.lock().expect("utp lock poisoned")
+    }
+
+    fn socket_for(&self, address: &SocketAddr) -> Option<Arc<UdpSocket>> {
+        if address.is_ipv4() { self.ipv4.clone() } else { self.ipv6.clone() }
+    }
+
+    pub async fn connect(self: &Arc<Self>, address: SocketAddr) -> Result<UtpStream, Error> {
+        let socket = self.socket_for(&address)
+            .ok_or(Error::new(ErrorKind::AddrNotAvailable, "no uTP socket for the address family"))?;
+        let (packets_tx, packets) = mpsc::unbounded_channel();
+        let recv_id = {
+            let mut connections = self.lock();
+            let recv_id = loop {
+                let recv_id = u16::from_be_bytes(random::bytes());
+                if !connections.contains_key(&(address, recv_id)) && !connections.contains_key(&(address, recv_id.wrapping_add(1))) {
+                    break recv_id;
+                }
+            };
+            connections.insert((address, recv_id), packets_tx);
+            recv_id
+        };
+        let (connected_tx, connected) = oneshot::channel();
+        let stream = UtpStream::new();
+        let mut connection = Connection::new(self.clone(), socket, address, recv_id, recv_id.wrapping_add(1), stream.shared.clone());
+        connection.connected = Some(connected_tx);
+        connection.send_syn();
+        tokio::spawn(connection.run(packets));
+        connected.await.map_err(|_| Error::new(ErrorKind::ConnectionAborted, "uTP connection closed"))??;
+        Ok(stream)
+    }
+
+    pub fn handle_packet(self: &Arc<Self>, bytes: &[u8], from: SocketAddr) {
+        let Some(packet) = Packet::from_bytes(bytes) else {
+            return;
+        };
+        let from = SocketAddr::new(from.ip().to_canonical(), from.port());
+        if packet.kind == PacketType::Syn {
+            self.accept(packet, from);
+            return;
+        }
+        let connection = {
+            let connections = self.lock();
+            let id = packet.connection_id;
+            match packet.kind {
+                // a reset for a connection the remote does not know carries our send id, one off our receive id
+                PacketType::Reset => [id, id.wrapping_sub(1), id.wrapping_add(1)].iter()
+                    .find_map(|id| connections.get(&(from, *id)))
+                    .cloned(),
+                _ => connections.get(&(from, id)).cloned(),
+            }
+        };
+        match connection {
+            Some(connection) => {
+                let _ = connection.send(packet);
+            }
+            None if packet.kind != PacketType::Reset => self.reset(&packet, from),
+            None => {}
+        }
+    }
+
+    fn accept(self: &Arc<Self>, syn: Packet, from: SocketAddr) {
+        let recv_id = syn.connection_id.wrapping_add(1);
+        let Some(socket) = self.socket_for(&from) else {
+            return;
+        };
+        let packets = {
+            let mut connections = self.lock();
+            // a retransmitted SYN goes to the connection it already created
+            if let Some(connection) = connections.get(&(from, recv_id)) {
+                let _ = connection.send(syn);
+                return;
+            }
+            if self.half_open.load(Ordering::Relaxed) >= MAX_HALF_OPEN {
+                return;
+            }
+            let (packets_tx, packets) = mpsc::unbounded_channel();
+            connections.insert((from, recv_id), packets_tx);
+            packets
+        };
+        let stream = UtpStream::new();
+        let mut connection = Connection::new(self.clone(), socket, from, recv_id, syn.connection_id, stream.shared.clone());
+        connection.state = ConnectionState::Connected;
+        connection.ack_nr = syn.seq_nr;
+        connection.seq_nr = u16::from_be_bytes(random::bytes());
+        connection.reply_micro = now_micros().wrapping_sub(syn.timestamp);
+        connection.peer_window = syn.window;
+        connection.ack_pending = true;
+        if self.accepted.try_send((stream, from)).is_err() {
+            self.lock().remove(&(from, recv_id));
+            return;
+        }
+        connection.half_open = true;
+        self.half_open.fetch_add(1, Ordering::Relaxed);
+        tokio::spawn(connection.run(packets));
+    }
+
+    fn reset(&self, packet: &Packet, from: SocketAddr) {
+        let Some(socket) = self.socket_for(&from) else {
+            return;
+        };
+        let reset = Packet {
+            kind: PacketType::Reset,
+            connection_id: packet.connection_id,
+            timestamp: now_micros(),
+            timestamp_difference: 0,
+            window: 0,
+            seq_nr: 0,
+            ack_nr: packet.seq_nr,
+            selective_ack: None,
+            payload: vec![],
+        };
+        let _ = socket.try_send_to(&reset.to_bytes(), from);
+    }
+}
+
+// buffers shared between a stream and the task driving its connection
+#[derive(Default)]
+struct StreamState {
+    // in order bytes not read yet
+    received: VecDeque<u8>,
+    eof: bool,
+    error: Option<ErrorKind>,
+    reader: Option<Waker>,
+    // bytes written but not packetized yet
+    unsent: VecDeque<u8>,
+    writer: Option<Waker>,
+    // shut down for writing
+    closing: bool,
+    dropped: bool,
+}
+
+#[derive(Default)]
+struct StreamShared {
+    state: Mutex<StreamState>,
+    // wakes the connection task when the stream wrote, read or closed
+    changed: Notify,
+}
+
+impl StreamShared {
+    fn lock(&self) -> std::sync::MutexGuard<'_, StreamState> {
+        self.state.lock().expect("utp stream lock poisoned")
+    }
+}
+
+pub struct UtpStream {
+    shared: Arc<StreamShared>,
+}
+
+impl UtpStream {
+    fn new() -> Self {
+        UtpStream { shared: Arc::new(StreamShared::default()) }
+    }
+}
+
+impl Drop for UtpStream {
+    fn drop(&mut self) {
+        let mut state = self.shared.lock();
+        state.closing = true;
+        state.dropped = true;
+        drop(state);
+        self.shared.changed.notify_one();
+    }
+}
+
+impl AsyncRead for UtpStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<Result<(), Error>> {
+        let mut state = self.shared.lock();
+        if !state.received.is_empty() {
+            let length = state.received.len().min(buf.remaining());
+            let (front, back) = state.received.as_slices();
+            let from_front = length.min(front.len());
+            buf.put_slice(&front[..from_front]);
+            buf.put_slice(&back[..length - from_front]);
+            state.received.drain(..length);
+            drop(state);
+            // the advertised window just grew
+            self.shared.changed.notify_one();
+            return Poll::Ready(Ok(()));
+        }
+        if let Some(kind) = state.error {
+            return Poll::Ready(Err(Error::new(kind, "uTP connection failed")));
+        }
+        if state.eof {
+            return Poll::Ready(Ok(()));
+        }
+        state.reader = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl AsyncWrite for UtpStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize, Error>> {
+        let mut state = self.shared.lock();
+        if let Some(kind) = state.error {
+            return Poll::Ready(Err(Error::new(kind, "uTP connection failed")));
+        }
+        if state.closing {
+            return Poll::Ready(Err(Error::new(ErrorKind::BrokenPipe, "uTP stream is shut down")));
+        }
+        let room = SEND_BUFFER.saturating_sub(state.unsent.len());
+        if room == 0 {
+            state.writer = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let length = room.min(buf.len());
+        state.unsent.extend(&buf[..length]);
+        drop(state);
+        self.shared.changed.notify_one();
+        Poll::Ready(Ok(length))
+    }
+
+    // written bytes are already queued for the connection task, like in a kernel socket buffer
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        self.shared.lock().closing = true;
+        self.shared.changed.notify_one();
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ConnectionState {
+    SynSent,
+    Connected,
+}
+
+struct SentPacket {
+    seq_nr: u16,
+    kind: PacketType,
+    payload: Vec<u8>,
+    sent_at: Instant,
+    transmissions: u32,
+    // resent because later packets were acked, done once per loss
+    fast_resent: bool,
+}
+
+struct Connection {
+    utp: Arc<UtpSocket>,
+    socket: Arc<UdpSocket>,
+    remote: SocketAddr,
+    recv_id: u16,
+    send_id: u16,
+    state: ConnectionState,
+    stream: Arc<StreamShared>,
+    connected: Option<oneshot::Sender<Result<(), Error>>>,
+    // accepted connection the remote has not sent anything on after its SYN, counted in `UtpSocket::half_open`
+    half_open: bool,
+    // next sequence number we send
+    seq_nr: u16,
+    // last sequence number received in order
+    ack_nr: u16,
+    ack_pending: bool,
+    // microseconds between the peer sending its last packet and us receiving it, echoed back to it
+    reply_micro: u32,
+    peer_window: u32,
+    // out of order packets by sequence number
+    reorder: HashMap<u16, Vec<u8>>,
+    reorder_bytes: usize,
+    // sequence number of the FIN the peer sent
+    fin_received: Option<u16>,
+    fin_sent: bool,
+    in_flight: VecDeque<SentPacket>,
+    in_flight_bytes: usize,
+    duplicate_acks: usize,
+    // LEDBAT congestion window in bytes
+    max_window: f64,
+    base_delay_current: Option<u32>,
+    base_delay_previous: Option<u32>,
+    base_delay_rotated: Instant,
+    rtt: Option<Duration>,
+    rtt_variance: Duration,
+    timeout: Duration,
+    timeouts: u32,
+    // window we advertised last, to tell the peer when reading opens it up again
+    advertised_window: u32,
+}
+
+impl Connection {
+    fn new(utp: Arc<UtpSocket>, socket: Arc<UdpSocket>, remote: SocketAddr, recv_id: u16, send_id: u16, stream: Arc<StreamShared>) -> Self {
+        Connection {
+            utp,
+            socket,
+            remote,
+            recv_id,
+            send_id,
+            state: ConnectionState::SynSent,
+            stream,
+            connected: None,
+            half_open: false,
+            seq_nr: 1,
+            ack_nr: 0,
+            ack_pending: false,
+            reply_micro: 0,
+            peer_window: MAX_PACKET_SIZE as u32,
+            reorder: HashMap::new(),
+            reorder_bytes: 0,
+            fin_received: None,
+            fin_sent: false,
+            in_flight: VecDeque::new(),
+            in_flight_bytes: 0,
+            duplicate_acks: 0,
+            max_window: INITIAL_WINDOW,
+            base_delay_current: None,
+            base_delay_previous: None,
+            base_delay_rotated: Instant::now(),
+            rtt: None,
+            rtt_variance: Duration::ZERO,
+            timeout: INITIAL_TIMEOUT,
+            timeouts: 0,
+            advertised_window: RECEIVE_BUFFER as u32,
+        }
+    }
+
+    async fn run(mut self, mut packets: mpsc::UnboundedReceiver<Packet>) {
+        let stream = self.stream.clone();
+        let result = loop {
+            self.flush();
+            if self.is_finished() {
+                break Ok(());
+            }
+            let deadline = self.in_flight.front().map(|packet| packet.sent_at + self.timeout);
+            tokio::select! {
+                packet = packets.recv() => match packet {
+                    Some(packet) => if let Err(err) = self.handle(packet) {
+                        break Err(err);
+                    },
+                    None => break Ok(()),
+                },
+                _ = stream.changed.notified() => {}
+                _ = sleep_until(deadline.unwrap_or_else(Instant::now).into()), if deadline.is_some() => {
+                    if let Err(err) = self.on_timeout() {
+                        break Err(err);
+                    }
+                }
+            }
+        };
+        self.utp.lock().remove(&(self.remote, self.recv_id));
+        self.leave_half_open();
+        let mut state = stream.lock();
+        match result {
+            Err(err) => {
+                if let Some(connected) = self.connected.take() {
+                    let _ = connected.send(Err(Error::new(err.kind(), err.to_string())));
+                }
+                state.error = Some(err.kind());
+            }
+            // whatever the peer did not finish sending will never arrive
+            Ok(()) => state.eof = true,
+        }
+        if let Some(reader) = state.reader.take() {
+            reader.wake();
+        }
+        if let Some(writer) = state.writer.take() {
+            writer.wake();
+        }
+    }
+
+    fn leave_half_open(&mut self) {
+        if self.half_open {
+            self.half_open = false;
+            self.utp.half_open.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    // done once our FIN is acked and nobody reads anymore or the peer finished as well
+    fn is_finished(&self) -> bool {
+        let state = self.stream.lock();
+        self.fin_sent && self.in_flight.is_empty() && (state.dropped || state.eof)
+    }
+
+    fn packet(&self, kind: PacketType, seq_nr: u16, payload: Vec<u8>) -> Packet {
+        Packet {
+            kind,
+            connection_id: if kind == PacketType::Syn { self.recv_id } else { self.send_id },
+            timestamp: now_micros(),
+            timestamp_difference: self.reply_micro,
+            window: self.receive_window(),
+            seq_nr,
+            ack_nr: self.ack_nr,
+            selective_ack: self.selective_ack(),
+            payload,
+        }
+    }
+
+    fn send(&mut self, packet: &Packet) {
+        // a full socket buffer loses the packet like the network would, retransmission takes care of it
+        let _ = self.socket.try_send_to(&packet.to_bytes(), self.remote);
+        self.advertised_window = packet.window;
+        self.ack_pending = false;
+    }
+
+    fn send_syn(&mut self) {
+        self.transmit(PacketType::Syn, vec![]);
+    }
+
+    // sends a packet that takes a sequence number and has to be acked
+    fn transmit(&mut self, kind: PacketType, payload: Vec<u8>) {
+        let seq_nr = self.seq_nr;
+        self.seq_nr = self.seq_nr.wrapping_add(1);
+        let packet = self.packet(kind, seq_nr, payload);
+        self.send(&packet);
+        self.in_flight_bytes += packet.payload.len();
+        self.in_flight.push_back(SentPacket { seq_nr, kind, payload: packet.payload, sent_at: Instant::now(), transmissions: 1, fast_resent: false });
+    }
+
+    fn resend(&mut self, index: usize) {
+        let sent = &self.in_flight[index];
+        let packet = self.packet(sent.kind, sent.seq_nr, sent.payload.clone());
+        self.send(&packet);
+        let sent = &mut self.in_flight[index];
+        sent.sent_at = Instant::now();
+        sent.transmissions += 1;
+    }
+
+    fn receive_window(&self) -> u32 {
+        let buffered = self.stream.lock().received.len() + self.reorder_bytes;
+        RECEIVE_BUFFER.saturating_sub(buffered) as u32
+    }
+
+    // bit i says whether ack_nr + 2 + i arrived, least significant bit first within each byte
+    fn selective_ack(&self) -> Option<Vec<u8>> {
+        if self.reorder.is_empty() {
+            return None;
+        }
+        let mut mask = vec![0u8; SELECTIVE_ACK_BITS as usize / 8];
+        for bit in 0..SELECTIVE_ACK_BITS {
+            if self.reorder.contains_key(&self.ack_nr.wrapping_add(2 + bit)) {
+                mask[bit as usize / 8] |= 1 << (bit % 8);
+            }
+        }
+        Some(mask)
+    }
+
+    // sends as much of the written data as both windows allow, then an ack if nothing carried one
+    fn flush(&mut self) {
+        if self.state == ConnectionState::SynSent {
+            return;
+        }
+        let window = (self.max_window as usize).min(self.peer_window as usize);
+        loop {
+            let payload: Vec<u8> = {
+                let mut state = self.stream.lock();
+                let room = window.saturating_sub(self.in_flight_bytes);
+                // one packet is always allowed in flight so that a zero window gets probed
+                let length = if self.in_flight.is_empty() { MAX_PAYLOAD } else { room.min(MAX_PAYLOAD) };
+                let length = length.min(state.unsent.len());
+                // rather than a runt packet squeezed into what is left of the window, wait for acks to open it
+                if length == 0 || (length < MAX_PAYLOAD && length < state.unsent.len()) {
+                    break;
+                }
+                let payload = state.unsent.drain(..length).collect();
+                if let Some(writer) = state.writer.take() {
+                    writer.wake();
+                }
+                payload
+            };
+            self.transmit(PacketType::Data, payload);
+        }
+        let (closing, unsent) = {
+            let state = self.stream.lock();
+            (state.closing, state.unsent.len())
+        };
+        if closing && unsent == 0 && !self.fin_sent {
+            self.fin_sent = true;
+            self.transmit(PacketType::Fin, vec![]);
+        }
+        let window_opened = self.advertised_window < MAX_PACKET_SIZE as u32 && self.receive_window() >= MAX_PACKET_SIZE as u32;
+        if self.ack_pending || window_opened {
+            let state = self.packet(PacketType::State, self.seq_nr, vec![]);
+            self.send(&state);
+        }
+    }
+
+    fn handle(&mut self, packet: Packet) -> Result<(), Error> {
+        match packet.kind {
+            PacketType::Reset => return Err(Error::new(ErrorKind::ConnectionReset, "uTP connection reset by peer")),
+            // the SYN was retransmitted because our ack got lost
+            PacketType::Syn => {
+                self.ack_pending = self.state == ConnectionState::Connected;
+                return Ok(());
+            }
+            _ => {}
+        }
+        self.leave_half_open();
+        self.reply_micro = now_micros().wrapping_sub(packet.timestamp);
+        self.peer_window = packet.window;
+        if self.state == ConnectionState::SynSent {
+            if packet.kind != PacketType::State || packet.ack_nr != self.in_flight.front().map_or(0, |syn| syn.seq_nr) {
+                return Ok(());
+            }
+            self.state = ConnectionState::Connected;
+            self.ack_nr = packet.seq_nr.wrapping_sub(1);
+            if let Some(connected) = self.connected.take() {
+                let _ = connected.send(Ok(()));
+            }
+        }
+        self.process_ack(&packet);
+        match packet.kind {
+            PacketType::Data => self.receive(packet.seq_nr, packet.payload),
+            PacketType::Fin => {
+                self.fin_received = Some(packet.seq_nr);
+                self.receive(packet.seq_nr, vec![]);
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn receive(&mut self, seq_nr: u16, payload: Vec<u8>) {
+        self.ack_pending = true;
+        let distance = seq_nr.wrapping_sub(self.ack_nr);
+        // already delivered, or too far ahead to buffer
+        if distance == 0 || distance > REORDER_LIMIT || self.fin_received.is_some_and(|fin| !seq_less_equal(seq_nr, fin)) {
+            return;
+        }
+        if !self.reorder.contains_key(&seq_nr) {
+            self.reorder_bytes += payload.len();
+            self.reorder.insert(seq_nr, payload);
+        }
+        let mut state = self.stream.lock();
+        while let Some(payload) = self.reorder.remove(&self.ack_nr.wrapping_add(1)) {
+            self.ack_nr = self.ack_nr.wrapping_add(1);
+            self.reorder_bytes -= payload.len();
+            state.received.extend(payload);
+        }
+        if self.fin_received == Some(self.ack_nr) {
+            state.eof = true;
+        }
+        if let Some(reader) = state.reader.take() {
+            reader.wake();
+        }
+    }
+
+    fn process_ack(&mut self, packet: &Packet) {
+        let mut acked_bytes = 0;
+        let mut rtt_sample = None;
+        let mut acked = |sent: SentPacket, in_flight_bytes: &mut usize| {
+            *in_flight_bytes -= sent.payload.len();
+            acked_bytes += sent.payload.len();
+            // Karn's algorithm: retransmitted packets say nothing about the round trip time
+            if sent.transmissions == 1 {
+                rtt_sample = Some(sent.sent_at.elapsed());
+            }
+        };
+        let mut progress = false;
+        while self.in_flight.front().is_some_and(|sent| seq_less_equal(sent.seq_nr, packet.ack_nr)) {
+            let sent = self.in_flight.pop_front().expect("checked above");
+            acked(sent, &mut self.in_flight_bytes);
+            progress = true;
+        }
+        let mut lost = vec![];
+        if let Some(mask) = &packet.selective_ack {
+            let is_acked = |seq_nr: u16| {
+                let bit = seq_nr.wrapping_sub(packet.ack_nr.wrapping_add(2)) as usize;
+                bit < mask.len() * 8 && mask[bit / 8] & (1 << (bit % 8)) != 0
+            };
+            let mut index = 0;
+            while index < self.in_flight.len() {
+                if is_acked(self.in_flight[index].seq_nr) {
+                    let sent = self.in_flight.remove(index).expect("index in bounds");
+                    acked(sent, &mut self.in_flight_bytes);
+                    progress = true;
+                } else {
+                    index += 1;
+                }
+            }
+            // a packet with enough later packets acked is considered lost
+            let bits = mask.len() as u16 * 8;
+            for (index, sent) in self.in_flight.iter().enumerate() {
+                let offset = sent.seq_nr.wrapping_sub(packet.ack_nr.wrapping_add(2));
+                let acked_after = (offset.saturating_add(1)..bits).filter(|&bit| mask[bit as usize / 8] & (1 << (bit % 8)) != 0).count();
+                if acked_after >= DUPLICATE_ACKS && !sent.fast_resent {
+                    lost.push(index);
+                }
+            }
+        }
+        if progress {
+            self.duplicate_acks = 0;
+            self.timeouts = 0;
+        } else if packet.kind == PacketType::State && packet.payload.is_empty() && !self.in_flight.is_empty() {
+            self.duplicate_acks += 1;
+            if self.duplicate_acks == DUPLICATE_ACKS && !self.in_flight[0].fast_resent {
+                lost.push(0);
+            }
+        }
+        if !lost.is_empty() {
+            // packet loss is a congestion signal, like in TCP the window is halved
+            self.max_window = (self.max_window / 2.0).max(MIN_WINDOW);
+            for index in lost {
+                self.in_flight[index].fast_resent = true;
+                self.resend(index);
+            }
+        }
+        if let Some(rtt) = rtt_sample {
+            self.update_timeout(rtt);
+        }
+        if acked_bytes > 0 {
+            self.update_window(packet.timestamp_difference, acked_bytes);
+        }
+    }
+
+    // RFC 6298 retransmission timeout
+    fn update_timeout(&mut self, rtt: Duration) {
+        match self.rtt {
+            None => {
+                self.rtt = Some(rtt);
+                self.rtt_variance = rtt / 2;
+            }
+            Some(smoothed) => {
+                let delta = smoothed.abs_diff(rtt);
+                self.rtt_variance = (self.rtt_variance * 3 + delta) / 4;
+                self.rtt = Some((smoothed * 7 + rtt) / 8);
+            }
+        }
+        self.timeout = (self.rtt.expect("set above") + self.rtt_variance * 4).clamp(MIN_TIMEOUT, MAX_TIMEOUT);
+    }
+
+    /*
+        LEDBAT: the one way delay the peer measured for our packets, minus the lowest delay seen recently,
+        is the queuing delay we cause. Below the target the window grows, above it shrinks, proportionally
+        to how far off target it is and to the share of the window the ack covers.
+    */
+    fn update_window(&mut self, delay: u32, acked_bytes: usize) {
+        // the peer had no timestamp of ours to measure against yet
+        if delay == 0 {
+            return;
+        }
+        if self.base_delay_rotated.elapsed() >= BASE_DELAY_INTERVAL {
+            self.base_delay_previous = self.base_delay_current.take();
+            self.base_delay_rotated = Instant::now();
+        }
+        let current = self.base_delay_current.map_or(delay, |current| current.min(delay));
+        self.base_delay_current = Some(current);
+        let base_delay = self.base_delay_previous.map_or(current, |previous| previous.min(current));
+        let queuing_delay = delay.wrapping_sub(base_delay) as f64;
+        let off_target = (TARGET_DELAY as f64 - queuing_delay) / TARGET_DELAY as f64;
+        let window_factor = acked_bytes as f64 / self.max_window.max(acked_bytes as f64);
+        self.max_window = (self.max_window + MAX_WINDOW_INCREASE * off_target * window_factor).clamp(MIN_WINDOW, MAX_WINDOW);
+    }
+
+    fn on_timeout(&mut self) -> Result<(), Error> {
+        self.timeouts += 1;
+        let limit = if self.state == ConnectionState::SynSent { MAX_SYN_TIMEOUTS } else { MAX_TIMEOUTS };
+        if self.timeouts > limit {
+            return Err(Error::new(ErrorKind::TimedOut, "uTP connection timed out"));
+        }
+        self.timeout = (self.timeout * 2).min(MAX_TIMEOUT);
+        self.max_window = MIN_WINDOW;
+        self.resend(0);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    type Incoming = mpsc::Receiver<(UtpStream, SocketAddr)>;
+
+    // loopback UDP socket whose packets pass through `filter` on their way to the uTP socket
+    async fn endpoint(mut filter: impl FnMut(&[u8]) -> Vec<Vec<u8>> + Send + 'static) -> (Arc<UtpSocket>, Incoming, SocketAddr) {
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        // packets are sent with try_send_to, which drops them until the reactor saw the socket writable
+        socket.writable().await.unwrap();
+        let address = socket.local_addr().unwrap();
+        let (utp, incoming) = UtpSocket::new(Some(socket.clone()), None);
+        let receiver = utp.clone();
+        tokio::spawn(async move {
+            let mut buf = vec![0; 2048];
+            while let Ok((length, from)) = socket.recv_from(&mut buf).await {
+                for packet in filter(&buf[..length]) {
+                    receiver.handle_packet(&packet, from);
+                }
+            }
+        });
+        (utp, incoming, address)
+    }
+
+    fn is_data(packet: &[u8]) -> bool {
+        packet[0] >> 4 == PacketType::Data as u8
+    }
+
+    fn pattern(length: usize) -> Vec<u8> {
+        (0..length).map(|index| (index % 251) as u8).collect()
+    }
+
+    // sends `data` both ways over a fresh connection and checks it arrives whole and in order
+    async fn transfer(filter: impl FnMut(&[u8]) -> Vec<Vec<u8>> + Send + 'static, data: Vec<u8>) {
+        let (client, _, _) = endpoint(|packet| vec![packet.to_vec()]).await;
+        let (_, mut incoming, address) = endpoint(filter).await;
+        let mut outgoing = client.connect(address).await.unwrap();
+        let (mut accepted, from) = incoming.recv().await.unwrap();
+        assert_eq!(from, client.ipv4.as_ref().unwrap().local_addr().unwrap());
+
+        let sent = data.clone();
+        let upload = async move {
+            outgoing.write_all(&sent).await.unwrap();
+            outgoing.shutdown().await.unwrap();
+            let mut echoed = vec![];
+            outgoing.read_to_end(&mut echoed).await.unwrap();
+            echoed
+        };
+        let echo = async move {
+            let mut received = vec![];
+            accepted.read_to_end(&mut received).await.unwrap();
+            accepted.write_all(&received[..1000]).await.unwrap();
+            accepted.shutdown().await.unwrap();
+            received
+        };
+        let (echoed, received) = tokio::time::timeout(Duration::from_secs(30), async { tokio::join!(upload, echo) }).await
+            .expect("transfer stalled");
+        assert!(received == data, "received {} bytes out of {}", received.len(), data.len());
+        assert_eq!(echoed, data[..1000]);
+    }
+
+    #[test]
+    fn packet_round_trip() {
+        let packet = Packet {
+            kind: PacketType::State,
+            connection_id: 0x1234,
+            timestamp: 1,
+            timestamp_difference: 2,
+            window: 3,
+            seq_nr: 0xfffe,
+            ack_nr: 5,
+            selective_ack: Some(vec![0b101, 0, 0, 0x80]),
+            payload: b"payload".to_vec(),
+        };
+        let bytes = packet.to_bytes();
+        assert!(is_utp_packet(&bytes));
+        let decoded = Packet::from_bytes(&bytes).unwrap();
+        assert_eq!((decoded.kind, decoded.connection_id, decoded.seq_nr, decoded.ack_nr), (PacketType::State, 0x1234, 0xfffe, 5));
+        assert_eq!((decoded.timestamp, decoded.timestamp_difference, decoded.window), (1, 2, 3));
+        assert_eq!(decoded.selective_ack, packet.selective_ack);
+        assert_eq!(decoded.payload, b"payload");
+        // an extension running past the end of the packet
+        assert!(Packet::from_bytes(&bytes[..HEADER_LENGTH + 3]).is_none());
+        assert!(!is_utp_packet(b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe"));
+    }
+
+    #[test]
+    fn sequence_numbers_wrap() {
+        assert!(seq_less_equal(1, 2));
+        assert!(seq_less_equal(2, 2));
+        assert!(!seq_less_equal(3, 2));
+        assert!(seq_less_equal(0xfffe, 1));
+        assert!(!seq_less_equal(1, 0xfffe));
+    }
+
+    #[tokio::test]
+    async fn connects_transfers_and_closes() {
+        transfer(|packet| vec![packet.to_vec()], pattern(300 * 1024)).await;
+    }
+
+    #[tokio::test]
+    async fn reassembles_reordered_packets() {
+        let mut held: Option<Vec<u8>> = None;
+        transfer(move |packet| {
+            if !is_data(packet) {
+                return vec![packet.to_vec()];
+            }
+            // every pair of data packets arrives swapped
+            match held.take() {
+                Some(earlier) => vec![packet.to_vec(), earlier],
+                None => {
+                    held = Some(packet.to_vec());
+                    vec![]
+                }
+            }
+        }, pattern(200 * 1024)).await;
+    }
+
+    #[tokio::test]
+    async fn resends_lost_packets() {
+        let mut seen = std::collections::HashSet::new();
+        transfer(move |packet| {
+            // every seventh data packet is lost the first time it is sent
+            let seq_nr = u16::from_be_bytes([packet[16], packet[17]]);
+            if is_data(packet) && seen.insert(seq_nr) && seq_nr % 7 == 3 {
+                return vec![];
+            }
+            vec![packet.to_vec()]
+        }, pattern(200 * 1024)).await;
+    }
+
+    #[tokio::test]
+    async fn connect_fails_when_nobody_answers() {
+        let (client, _, _) = endpoint(|packet| vec![packet.to_vec()]).await;
+        // a socket that never reads, like a peer without uTP
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let error = client.connect(silent.local_addr().unwrap()).await.err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::TimedOut);
+    }
+
+    #[tokio::test]
+    async fn unknown_connections_are_reset() {
+        let (client, _, _) = endpoint(|packet| vec![packet.to_vec()]).await;
+        let (server, mut incoming, address) = endpoint(|packet| vec![packet.to_vec()]).await;
+        let mut outgoing = client.connect(address).await.unwrap();
+        let (accepted, _) = incoming.recv().await.unwrap();
+        // the server forgets the connection, as after a restart
+        server.lock().clear();
+        drop(accepted);
+        outgoing.write_all(b"hello").await.unwrap();
+        let mut buf = [0u8; 8];
+        let error = tokio::time::timeout(Duration::from_secs(10), outgoing.read(&mut buf)).await.unwrap().unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::ConnectionReset);
+    }
+
+    fn packet(kind: PacketType, connection_id: u16) -> Vec<u8> {
+        Packet {
+            kind,
+            connection_id,
+            timestamp: now_micros(),
+            timestamp_difference: 0,
+            window: RECEIVE_BUFFER as u32,
+            seq_nr: 1,
+            ack_nr: 0,
+            selective_ack: None,
+            payload: vec![],
+        }.to_bytes()
+    }
+
+    #[tokio::test]
+    async fn syns_beyond_the_accept_backlog_and_half_open_limit_are_dropped() {
+        let (server, mut incoming, _) = endpoint(|packet| vec![packet.to_vec()]).await;
+        // every SYN from its own port, nobody listens there to answer our acks
+        let from = |index: usize| SocketAddr::from(([127, 0, 0, 1], 20000 + index as u16));
+        for index in 0..MAX_PENDING_ACCEPTS + 10 {
+            server.handle_packet(&packet(PacketType::Syn, 7), from(index));
+        }
+        assert_eq!(server.lock().len(), MAX_PENDING_ACCEPTS);
+
+        // taken connections make room in the backlog, but stay half-open while their remote is silent
+        let mut accepted = vec![];
+        while let Ok(connection) = incoming.try_recv() {
+            accepted.push(connection);
+        }
+        for index in MAX_PENDING_ACCEPTS + 10..MAX_HALF_OPEN + 20 {
+            server.handle_packet(&packet(PacketType::Syn, 7), from(index));
+            while let Ok(connection) = incoming.try_recv() {
+                accepted.push(connection);
+            }
+        }
+        assert_eq!(accepted.len(), MAX_HALF_OPEN);
+        assert_eq!(server.half_open.load(Ordering::Relaxed), MAX_HALF_OPEN);
+
+        // anything past the SYN proves the remote is there and frees its slot
+        let first = accepted[0].1;
+        server.handle_packet(&packet(PacketType::State, 8), first);
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while server.half_open.load(Ordering::Relaxed) == MAX_HALF_OPEN {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }).await.unwrap();
+        server.handle_packet(&packet(PacketType::Syn, 7), from(1000));
+        assert_eq!(incoming.try_recv().unwrap().1, from(1000));
+    }
+}
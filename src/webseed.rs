@@ -0,0 +1,382 @@
+use std::io::{Error, ErrorKind};
+use std::time::Duration;
+
+use reqwest::header::RANGE;
+use reqwest::{Client, StatusCode};
+
+use crate::metainfo::Info;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+const BASE_RETRY_DELAY: Duration = Duration::from_secs(15);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(10 * 60);
+// BEP 17 servers that are busy without saying for how long
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(60);
+
+enum Protocol {
+    // BEP 19, the files themselves served by any HTTP server
+    GetRight,
+    // BEP 17, a script answering `?info_hash=&piece=&ranges=` with piece data
+    Hoffman { info_hash: [u8; 20] },
+}
+
+/*
+    HTTP source of pieces, from the torrent's `url-list` or `httpseeds`.
+    GetRight seeds hold the files and pieces are fetched with Range requests on the files they span.
+    The URL of a single-file torrent is the file itself unless it ends with `/`, for multi-file
+    torrents it is the directory holding the torrent's root directory.
+    Hoffman seeds are asked for whole pieces and answer 503 with the seconds to wait when busy.
+*/
+pub struct WebSeed {
+    pub url: String,
+    protocol: Protocol,
+    client: Client,
+    failures: u32,
+    // from a 503 response, replaces the backoff once
+    retry_after: Option<Duration>,
+}
+
+impl WebSeed {
+    pub fn url_seed(url: String) -> WebSeed {
+        WebSeed::new(url, Protocol::GetRight)
+    }
+
+    pub fn http_seed(url: String, info_hash: [u8; 20]) -> WebSeed {
+        WebSeed::new(url, Protocol::Hoffman { info_hash })
+    }
+
+    fn new(url: String, protocol: Protocol) -> WebSeed {
+        let client = Client::builder().timeout(REQUEST_TIMEOUT).build().unwrap_or_default();
+        WebSeed { url, protocol, client, failures: 0, retry_after: None }
+    }
+
+    pub async fn fetch_piece(&mut self, info: &Info, piece: usize) -> Result<Vec<u8>, Error> {
+        if let Protocol::Hoffman { info_hash } = self.protocol {
+            return self.fetch_hoffman(&info_hash, piece, info.piece_len(piece)).await;
+        }
+        let start = piece * info.piece_length;
+        let end = start + info.piece_len(piece);
+        let mut data = Vec::with_capacity(end - start);
+        let mut offset = 0;
+        for file in info.file_list() {
+            let (from, to) = (start.max(offset), end.min(offset + file.length));
+            // padding files do not exist on the server
+            if from < to && file.is_padding() {
+                data.resize(data.len() + to - from, 0);
+            } else if from < to {
+                data.extend(self.fetch_range(&self.file_url(info, &file.path), from - offset, to - offset, file.length).await?);
+            }
+            offset += file.length;
+        }
+        Ok(data)
+    }
+
+    async fn fetch_hoffman(&mut self, info_hash: &[u8; 20], piece: usize, length: usize) -> Result<Vec<u8>, Error> {
+        let separator = if self.url.contains('?') { '&' } else { '?' };
+        let url = format!("{}{}info_hash={}&piece={}&ranges=0-{}", self.url, separator, percent_encode(info_hash), piece, length - 1);
+        let to_error = |err: reqwest::Error| Error::other(format!("request to {} failed: {}", self.url, err));
+        let response = self.client.get(url).send().await.map_err(to_error)?;
+        let status = response.status();
+        let body = response.bytes().await.map_err(to_error)?;
+        match status {
+            StatusCode::OK if body.len() == length => Ok(body.to_vec()),
+            StatusCode::OK => {
+                Err(Error::new(ErrorKind::InvalidData, format!("{} sent {} bytes for a {} byte piece", self.url, body.len(), length)))
+            }
+            // the body is the number of seconds to wait
+            StatusCode::SERVICE_UNAVAILABLE => {
+                let seconds = std::str::from_utf8(&body).ok().and_then(|body| body.trim().parse().ok());
+                let delay = seconds.map_or(DEFAULT_RETRY_AFTER, Duration::from_secs);
+                self.retry_after = Some(delay);
+                Err(Error::other(format!("{} is busy for {:?}", self.url, delay)))
+            }
+            status => Err(Error::other(format!("{} responded with {}", self.url, status))),
+        }
+    }
+
+    // bytes `from..to` of a file of `length` bytes
+    async fn fetch_range(&self, url: &str, from: usize, to: usize, length: usize) -> Result<Vec<u8>, Error> {
+        let to_error = |err: reqwest::Error| Error::other(format!("request to {} failed: {}", url, err));
+        let response = self.client.get(url).header(RANGE, format!("bytes={}-{}", from, to - 1)).send().await.map_err(to_error)?;
+        let status = response.status();
+        let body = response.bytes().await.map_err(to_error)?;
+        match status {
+            StatusCode::PARTIAL_CONTENT if body.len() == to - from => Ok(body.to_vec()),
+            // servers without Range support send the whole file
+            StatusCode::OK if body.len() == length => Ok(body[from..to].to_vec()),
+            StatusCode::OK | StatusCode::PARTIAL_CONTENT => {
+                Err(Error::new(ErrorKind::InvalidData, format!("{} sent {} bytes for a {} byte range", url, body.len(), to - from)))
+            }
+            status => Err(Error::other(format!("{} responded with {}", url, status))),
+        }
+    }
+
+    fn file_url(&self, info: &Info, path: &[String]) -> String {
+        if !info.is_multi_file() {
+            return if self.url.ends_with('/') {
+                format!("{}{}", self.url, percent_encode(&info.name))
+            } else {
+                self.url.clone()
+            };
+        }
+        let mut url = self.url.clone();
+        for component in std::iter::once(&info.name).chain(path) {
+            if !url.ends_with('/') {
+                url.push('/');
+            }
+            url.push_str(&percent_encode(component));
+        }
+        url
+    }
+
+    // how long to leave the server alone after a failed piece
+    pub fn register_failure(&mut self) -> Duration {
+        if let Some(delay) = self.retry_after.take() {
+            return delay;
+        }
+        self.failures += 1;
+        BASE_RETRY_DELAY.saturating_mul(1 << self.failures.min(10)).min(MAX_RETRY_DELAY)
+    }
+
+    pub fn register_success(&mut self) {
+        self.failures = 0;
+    }
+}
+
+// everything but unreserved characters, so names with spaces or `?` stay one path component
+fn percent_encode(bytes: impl AsRef<[u8]>) -> String {
+    let mut encoded = String::with_capacity(bytes.as_ref().len());
+    for &byte in bytes.as_ref() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => encoded.push(byte as char),
+            byte => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use crate::metainfo::FileEntry;
+
+    const PIECE_LENGTH: usize = 16;
+
+    type Requests = Arc<Mutex<Vec<String>>>;
+
+    fn entry(length: usize, path: &[&str], attr: Option<&str>) -> FileEntry {
+        FileEntry {
+            length,
+            path: path.iter().map(|component| component.to_string()).collect(),
+            attr: attr.map(str::to_string),
+            symlink_path: None,
+            sha1: None,
+        }
+    }
+
+    fn info(length: Option<usize>, files: Option<Vec<FileEntry>>) -> Info {
+        let total = length.unwrap_or_else(|| files.iter().flatten().map(|file| file.length).sum());
+        Info {
+            name: "my torrent".to_string(),
+            length,
+            files,
+            piece_length: PIECE_LENGTH,
+            pieces: vec![0; total.div_ceil(PIECE_LENGTH) * 20],
+            private: None,
+            attr: None,
+            meta_version: None,
+            file_tree: None,
+        }
+    }
+
+    fn content(length: usize, seed: u8) -> Vec<u8> {
+        (0..length).map(|index| seed.wrapping_add(index as u8)).collect()
+    }
+
+    // a minimal HTTP server answering each request's target and byte range with `respond`'s status and body
+    async fn serve_with(respond: impl Fn(&str, Option<(usize, usize)>) -> (&'static str, Vec<u8>) + Send + 'static) -> (String, Requests) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Requests::default();
+        let log = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut request = vec![];
+                let mut buf = [0; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    match stream.read(&mut buf).await {
+                        Ok(0) | Err(_) => break,
+                        Ok(read) => request.extend_from_slice(&buf[..read]),
+                    }
+                }
+                let request = String::from_utf8_lossy(&request).to_string();
+                let path = request.split(' ').nth(1).unwrap_or_default().to_string();
+                let range = request.lines()
+                    .find_map(|line| line.to_ascii_lowercase().strip_prefix("range: bytes=").map(str::to_string))
+                    .and_then(|range| range.split_once('-').map(|(from, to)| (from.parse::<usize>().unwrap(), to.parse::<usize>().unwrap())));
+                log.lock().unwrap().push(format!("{} {:?}", path, range));
+                let (status, body) = respond(&path, range);
+                let head = format!("HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", status, body.len());
+                let _ = stream.write_all(head.as_bytes()).await;
+                let _ = stream.write_all(&body).await;
+                let _ = stream.shutdown().await;
+            }
+        });
+        (url, requests)
+    }
+
+    // a file server, answering Range requests with 206 unless `ranges` is off
+    async fn serve(files: HashMap<&'static str, Vec<u8>>, ranges: bool) -> (String, Requests) {
+        serve_with(move |path, range| match (files.get(path), range) {
+            (None, _) => ("404 Not Found", vec![]),
+            (Some(file), Some((from, to))) if ranges => ("206 Partial Content", file[from.min(file.len())..file.len().min(to + 1)].to_vec()),
+            (Some(file), _) => ("200 OK", file.clone()),
+        }).await
+    }
+
+    #[test]
+    fn file_urls() {
+        let single = info(Some(20), None);
+        assert_eq!(WebSeed::url_seed("http://host/file.iso".into()).file_url(&single, &[]), "http://host/file.iso");
+        assert_eq!(WebSeed::url_seed("http://host/dir/".into()).file_url(&single, &[]), "http://host/dir/my%20torrent");
+
+        let multi = info(None, Some(vec![entry(20, &["sub dir", "a?b#c.txt"], None)]));
+        let path = multi.file_list()[0].path.clone();
+        assert_eq!(WebSeed::url_seed("http://host/seeds".into()).file_url(&multi, &path), "http://host/seeds/my%20torrent/sub%20dir/a%3Fb%23c.txt");
+        assert_eq!(WebSeed::url_seed("http://host/seeds/".into()).file_url(&multi, &path), "http://host/seeds/my%20torrent/sub%20dir/a%3Fb%23c.txt");
+    }
+
+    // files of 10, 6 padding, 40 and 3 bytes: pieces span files and the padding is never fetched
+    fn multi_file() -> (Info, Vec<u8>, HashMap<&'static str, Vec<u8>>) {
+        let info = info(None, Some(vec![
+            entry(10, &["a.bin"], None),
+            entry(6, &[".pad", "6"], Some("p")),
+            entry(40, &["dir", "b c.bin"], None),
+            entry(3, &["d"], None),
+        ]));
+        let (a, b, d) = (content(10, 1), content(40, 100), content(3, 200));
+        let whole = [a.clone(), vec![0; 6], b.clone(), d.clone()].concat();
+        let files = HashMap::from([
+            ("/my%20torrent/a.bin", a),
+            ("/my%20torrent/dir/b%20c.bin", b),
+            ("/my%20torrent/d", d),
+        ]);
+        (info, whole, files)
+    }
+
+    #[tokio::test]
+    async fn pieces_are_fetched_from_the_files_they_span() {
+        let (info, whole, files) = multi_file();
+        let (url, requests) = serve(files, true).await;
+        let mut seed = WebSeed::url_seed(url);
+        for piece in 0..info.piece_count() {
+            let expected = &whole[piece * PIECE_LENGTH..(piece * PIECE_LENGTH + PIECE_LENGTH).min(whole.len())];
+            assert_eq!(seed.fetch_piece(&info, piece).await.unwrap(), expected, "piece {}", piece);
+        }
+        let requests = requests.lock().unwrap().clone();
+        assert_eq!(requests, vec![
+            "/my%20torrent/a.bin Some((0, 9))",
+            "/my%20torrent/dir/b%20c.bin Some((0, 15))",
+            "/my%20torrent/dir/b%20c.bin Some((16, 31))",
+            "/my%20torrent/dir/b%20c.bin Some((32, 39))",
+            "/my%20torrent/d Some((0, 2))",
+        ]);
+    }
+
+    #[tokio::test]
+    async fn whole_file_answers_are_cut_to_the_range() {
+        let (info, whole, files) = multi_file();
+        let (url, _) = serve(files, false).await;
+        let mut seed = WebSeed::url_seed(format!("{}/", url));
+        assert_eq!(seed.fetch_piece(&info, 2).await.unwrap(), whole[32..48]);
+        assert_eq!(seed.fetch_piece(&info, 3).await.unwrap(), whole[48..]);
+    }
+
+    #[tokio::test]
+    async fn single_file_and_server_errors() {
+        let info = info(Some(20), None);
+        let data = content(20, 7);
+        let (url, _) = serve(HashMap::from([("/files/my%20torrent", data.clone()), ("/short", vec![0; 5])]), true).await;
+        let mut seed = WebSeed::url_seed(format!("{}/files/", url));
+        assert_eq!(seed.fetch_piece(&info, 1).await.unwrap(), data[16..]);
+
+        let mut missing = WebSeed::url_seed(format!("{}/missing", url));
+        assert!(missing.fetch_piece(&info, 0).await.is_err());
+        let mut short = WebSeed::url_seed(format!("{}/short", url));
+        assert_eq!(short.fetch_piece(&info, 0).await.unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    // a BEP 17 script serving the pieces of `content` and answering busy for piece 99 with `busy` as the body
+    async fn serve_hoffman(content: Vec<u8>, busy: &'static str) -> (String, Requests) {
+        serve_with(move |target, _| {
+            let query = target.split_once('?').map_or("", |(_, query)| query);
+            let parameter = |name: &str| query.split('&').find_map(|pair| pair.strip_prefix(name)?.strip_prefix('='));
+            let piece = parameter("piece").and_then(|piece| piece.parse::<usize>().ok());
+            let range = parameter("ranges").and_then(|ranges| ranges.split_once('-'))
+                .and_then(|(from, to)| Some((from.parse::<usize>().ok()?, to.parse::<usize>().ok()?)));
+            match (piece, range) {
+                (Some(99), _) => ("503 Service Unavailable", busy.as_bytes().to_vec()),
+                (Some(piece), Some((from, to))) if piece * PIECE_LENGTH + from < content.len() => {
+                    ("200 OK", content[piece * PIECE_LENGTH + from..content.len().min(piece * PIECE_LENGTH + to + 1)].to_vec())
+                }
+                _ => ("400 Bad Request", vec![]),
+            }
+        }).await
+    }
+
+    #[tokio::test]
+    async fn hoffman_seeds_are_asked_for_whole_pieces() {
+        let info = info(Some(20), None);
+        let data = content(20, 3);
+        let (url, requests) = serve_hoffman(data.clone(), "").await;
+        let info_hash = *b"\xab\x00 ~info-hash/.12345";
+        let mut seed = WebSeed::http_seed(format!("{}/seed", url), info_hash);
+        assert_eq!(seed.fetch_piece(&info, 0).await.unwrap(), data[..16]);
+        assert_eq!(seed.fetch_piece(&info, 1).await.unwrap(), data[16..]);
+        // parameters already in the URL are kept
+        let mut seed = WebSeed::http_seed(format!("{}/seed.php?key=1", url), info_hash);
+        assert_eq!(seed.fetch_piece(&info, 1).await.unwrap(), data[16..]);
+        let requests = requests.lock().unwrap().clone();
+        assert_eq!(requests, vec![
+            "/seed?info_hash=%AB%00%20~info-hash%2F.12345&piece=0&ranges=0-15 None",
+            "/seed?info_hash=%AB%00%20~info-hash%2F.12345&piece=1&ranges=0-3 None",
+            "/seed.php?key=1&info_hash=%AB%00%20~info-hash%2F.12345&piece=1&ranges=0-3 None",
+        ]);
+    }
+
+    #[tokio::test]
+    async fn busy_hoffman_seeds_set_the_next_delay() {
+        let mut info = info(Some(100 * PIECE_LENGTH), None);
+        info.pieces = vec![0; 100 * 20];
+        for (body, delay) in [("120", Duration::from_secs(120)), (" 5\n", Duration::from_secs(5)),
+                              ("", DEFAULT_RETRY_AFTER), ("soon", DEFAULT_RETRY_AFTER), ("-1", DEFAULT_RETRY_AFTER)] {
+            let (url, _) = serve_hoffman(content(100 * PIECE_LENGTH, 0), body).await;
+            let mut seed = WebSeed::http_seed(url, [0; 20]);
+            assert!(seed.fetch_piece(&info, 99).await.is_err());
+            assert_eq!(seed.register_failure(), delay, "{:?}", body);
+            // the delay the server asked for is used once, then the backoff goes on
+            assert_eq!(seed.register_failure(), BASE_RETRY_DELAY * 2, "{:?}", body);
+        }
+        // a short answer is not taken for the piece
+        let (url, _) = serve_hoffman(content(10, 0), "").await;
+        let mut seed = WebSeed::http_seed(url, [0; 20]);
+        assert_eq!(seed.fetch_piece(&info, 0).await.unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        let mut seed = WebSeed::url_seed("http://host/".into());
+        let delays: Vec<Duration> = (0..8).map(|_| seed.register_failure()).collect();
+        let seconds: Vec<u64> = delays.iter().map(Duration::as_secs).collect();
+        assert_eq!(seconds, [30, 60, 120, 240, 480, 600, 600, 600]);
+        for _ in 0..100 {
+            assert_eq!(seed.register_failure(), MAX_RETRY_DELAY);
+        }
+        seed.register_success();
+        assert_eq!(seed.register_failure(), BASE_RETRY_DELAY * 2);
+    }
+}